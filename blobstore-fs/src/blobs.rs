@@ -0,0 +1,353 @@
+//! Content-addressed storage of object bytes, used when the link value `DEDUP=true` is set.
+//!
+//! The bytes of each object are stored once, in `$ROOT/.blobstore-blobs/<xx>/<sha256>`, where `<xx>`
+//! is the first two characters of the hex-encoded SHA-256 hash of the contents. The object files
+//! in the containers are hard links to these blobs, so objects are read exactly as in the default
+//! mode, and the file system keeps the reference count of each blob: its number of links.
+//! A blob whose only remaining link is the one in the blob store is no longer referenced by any
+//! object, and is removed when the last object referencing it is removed or replaced, or by the
+//! periodic garbage collection pass, which also catches objects removed by other processes.
+//!
+//! Since all objects with the same contents are links to the same file, writing to one of them in
+//! place would change all of them, and their versions. Blobs are therefore made read-only: objects
+//! are changed by replacing their file, as uploads do, which leaves the blob untouched.
+//!
+//! The hash of an object is recorded in its metadata. The blobstore interface has no field for it,
+//! so actors read it with the `BlobstoreHashes` service, which the provider implements alongside `Blobstore`.
+//!
+//! Deduplication relies on hard links and link counts, so it is only available on unix.
+
+use crate::sidecar::{self, ObjectSidecar};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::io::{ErrorKind, Read};
+use std::path::{Path, PathBuf};
+use tracing::{info, warn};
+use wasmbus_rpc::common::{deserialize, serialize};
+use wasmbus_rpc::provider::prelude::*;
+
+/// Link value enabling content-addressed storage
+pub const DEDUP: &str = "DEDUP";
+/// Link value with the number of seconds between garbage collection passes
+pub const DEDUP_GC_INTERVAL_SECS: &str = "DEDUP_GC_INTERVAL_SECS";
+
+/// Name of the directory, inside the root, holding the blobs.
+/// Starts with `paths::RESERVED_PREFIX` so it can't be an actor id.
+pub const BLOBS_DIR: &str = ".blobstore-blobs";
+
+/// default time between garbage collection passes (1 hour)
+pub const DEFAULT_GC_INTERVAL: std::time::Duration = std::time::Duration::from_secs(3600);
+
+/// Argument of `BlobstoreHashes.GetObjectHash`
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GetObjectHashRequest {
+    pub container_id: String,
+    pub object_id: String,
+}
+
+/// The hash of an object's contents
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ObjectHash {
+    pub container_id: String,
+    pub object_id: String,
+    /// hex-encoded SHA-256 hash of the contents, or None if the object was stored without deduplication
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+}
+
+/// Hashes of object contents, an extension of the blobstore interface.
+/// Actors call them as `BlobstoreHashes.<Operation>` on the blobstore link.
+#[async_trait]
+pub trait BlobstoreHashes {
+    /// Returns the hash recorded when the object was stored
+    async fn get_object_hash(
+        &self,
+        ctx: &Context,
+        arg: &GetObjectHashRequest,
+    ) -> RpcResult<ObjectHash>;
+}
+
+/// Receives `BlobstoreHashes` messages, like the receivers generated for smithy interfaces
+#[async_trait]
+pub trait BlobstoreHashesReceiver: MessageDispatch + BlobstoreHashes {
+    async fn dispatch(&self, ctx: &Context, message: Message<'_>) -> RpcResult<Vec<u8>> {
+        match message.method {
+            "GetObjectHash" => {
+                let value: GetObjectHashRequest = deserialize(&message.arg)
+                    .map_err(|e| RpcError::Deser(format!("'GetObjectHashRequest': {}", e)))?;
+                let resp = BlobstoreHashes::get_object_hash(self, ctx, &value).await?;
+                serialize(&resp)
+            }
+            _ => Err(RpcError::MethodNotHandled(format!(
+                "BlobstoreHashes::{}",
+                message.method
+            ))),
+        }
+    }
+}
+
+/// Returns the blob store of a root directory
+pub fn store_dir(root: &Path) -> PathBuf {
+    root.join(BLOBS_DIR)
+}
+
+/// Returns the location of the blob with the given hash
+pub fn blob_path(store: &Path, sha256: &str) -> PathBuf {
+    store.join(&sha256[..2]).join(sha256)
+}
+
+/// Computes the hex-encoded SHA-256 hash of a file
+pub fn hash_file(path: &Path) -> std::io::Result<String> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// Makes a blob read-only, so the objects linked to it can't be modified in place
+fn make_read_only(blob: &Path) -> std::io::Result<()> {
+    let mut permissions = std::fs::metadata(blob)?.permissions();
+    if !permissions.readonly() {
+        permissions.set_readonly(true);
+        std::fs::set_permissions(blob, permissions)?;
+    }
+    Ok(())
+}
+
+/// Makes `path` a link to the blob holding its contents, storing the contents as a new blob
+/// if they are not in the store yet. Returns the hash of the contents.
+/// `path` is replaced atomically, so it always holds the same contents. The blob, and so `path`,
+/// is read-only afterwards.
+pub fn deduplicate(store: &Path, path: &Path) -> RpcResult<String> {
+    let sha256 = hash_file(path)?;
+    let blob = blob_path(store, &sha256);
+    if let Some(parent) = blob.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    // the blob may be removed by garbage collection between the two steps, so try twice
+    for _ in 0..2 {
+        match std::fs::hard_link(path, &blob) {
+            // new contents: the file becomes the blob
+            Ok(()) => {
+                make_read_only(&blob)?;
+                return Ok(sha256);
+            }
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => {}
+            Err(e) => return Err(e.into()),
+        }
+        // the contents are already stored: replace the file with a link to the existing blob
+        let link = path.with_extension("link");
+        match std::fs::hard_link(&blob, &link) {
+            Ok(()) => {
+                std::fs::rename(&link, path)?;
+                // blobs stored by earlier versions of the provider may still be writable
+                make_read_only(&blob)?;
+                return Ok(sha256);
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Err(RpcError::Other(format!(
+        "Could not store blob {}: it was removed while being linked",
+        sha256
+    )))
+}
+
+/// Returns the number of links to a file, i.e., the number of references to a blob plus one
+#[cfg(unix)]
+fn link_count(path: &Path) -> std::io::Result<u64> {
+    use std::os::unix::fs::MetadataExt;
+    Ok(std::fs::symlink_metadata(path)?.nlink())
+}
+
+#[cfg(not(unix))]
+fn link_count(_path: &Path) -> std::io::Result<u64> {
+    Err(std::io::Error::new(
+        ErrorKind::Unsupported,
+        "link counts are not available on this platform",
+    ))
+}
+
+/// Returns true if `s` is a hex-encoded SHA-256 hash. Hashes read from object metadata
+/// are checked before they are used in a path, as the metadata files can be edited by other processes.
+fn is_sha256(s: &str) -> bool {
+    s.len() == 64 && s.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/// Returns the hash recorded in the metadata of an object, if it is a valid hash
+pub fn object_hash(container_dir: &Path, object_id: &str) -> Option<String> {
+    sidecar::read(container_dir, object_id)
+        .and_then(|s| s.sha256)
+        .filter(|sha256| is_sha256(sha256))
+}
+
+/// Removes the blob with the given hash if no object refers to it any more
+pub fn release(store: &Path, sha256: &str) {
+    if !is_sha256(sha256) {
+        warn!("Ignoring invalid blob hash '{}'", sha256);
+        return;
+    }
+    let blob = blob_path(store, sha256);
+    match link_count(&blob) {
+        Ok(1) => {
+            if let Err(e) = std::fs::remove_file(&blob) {
+                warn!("Could not remove unreferenced blob {:?}: {}", blob, e);
+            }
+        }
+        Ok(_) => {}
+        Err(e) if e.kind() == ErrorKind::NotFound => {}
+        Err(e) => warn!("Could not check references to blob {:?}: {}", blob, e),
+    }
+}
+
+/// Returns the hashes of the objects stored below `dir`, read from their metadata
+pub fn hashes_below(dir: &Path) -> Vec<String> {
+    let mut hashes = Vec::new();
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return hashes,
+    };
+    for entry in entries.flatten() {
+        if !entry.file_type().map(|t| t.is_dir()).unwrap_or(false) {
+            continue;
+        }
+        if entry.file_name() == sidecar::METADATA_DIR {
+            let files = match std::fs::read_dir(entry.path()) {
+                Ok(files) => files,
+                Err(_) => continue,
+            };
+            hashes.extend(
+                files
+                    .flatten()
+                    .filter_map(|f| std::fs::read(f.path()).ok())
+                    .filter_map(|data| serde_json::from_slice::<ObjectSidecar>(&data).ok())
+                    .filter_map(|sidecar| sidecar.sha256),
+            );
+        } else {
+            hashes.extend(hashes_below(&entry.path()));
+        }
+    }
+    hashes
+}
+
+/// Removes all blobs that are not referenced by any object.
+/// Returns the number of blobs removed and the number of bytes freed.
+pub fn collect_garbage(store: &Path) -> (u64, u64) {
+    let mut removed = (0u64, 0u64);
+    let prefixes = match std::fs::read_dir(store) {
+        Ok(prefixes) => prefixes,
+        Err(_) => return removed,
+    };
+    for prefix in prefixes.flatten() {
+        let blobs = match std::fs::read_dir(prefix.path()) {
+            Ok(blobs) => blobs,
+            Err(_) => continue,
+        };
+        for blob in blobs.flatten() {
+            let path = blob.path();
+            if let (Ok(1), Ok(metadata)) = (link_count(&path), blob.metadata()) {
+                match std::fs::remove_file(&path) {
+                    Ok(()) => {
+                        removed.0 += 1;
+                        removed.1 += metadata.len();
+                    }
+                    Err(e) => warn!("Could not remove unreferenced blob {:?}: {}", path, e),
+                }
+            }
+        }
+    }
+    if removed.0 > 0 {
+        info!(
+            "Removed {} unreferenced blobs ({} bytes) from {:?}",
+            removed.0, removed.1, store
+        );
+    }
+    removed
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::fs_utils::clear_state;
+    use std::fs::create_dir_all;
+
+    #[test]
+    fn blobs_dir_is_reserved() {
+        assert!(BLOBS_DIR.starts_with(crate::paths::RESERVED_PREFIX));
+    }
+
+    #[test]
+    fn identical_contents_stored_once() {
+        let root = Path::new("/tmp/rust_test/blobs1");
+        let store = store_dir(root);
+        create_dir_all(root.join("cont")).unwrap();
+        let (obj1, obj2, obj3) = (
+            root.join("cont/obj1"),
+            root.join("cont/obj2"),
+            root.join("cont/obj3"),
+        );
+        std::fs::write(&obj1, b"artifact").unwrap();
+        std::fs::write(&obj2, b"artifact").unwrap();
+        std::fs::write(&obj3, b"other").unwrap();
+
+        let hash1 = deduplicate(&store, &obj1).unwrap();
+        let hash2 = deduplicate(&store, &obj2).unwrap();
+        let hash3 = deduplicate(&store, &obj3).unwrap();
+        let links_shared = link_count(&blob_path(&store, &hash1)).unwrap();
+        let contents2 = std::fs::read(&obj2).unwrap();
+        // objects sharing a blob can't be changed in place
+        let read_only = std::fs::metadata(&obj1).unwrap().permissions().readonly();
+
+        // removing one reference keeps the blob, removing the last one releases it
+        std::fs::remove_file(&obj1).unwrap();
+        release(&store, &hash1);
+        let kept = blob_path(&store, &hash1).exists();
+        std::fs::remove_file(&obj2).unwrap();
+        release(&store, &hash1);
+        let released = !blob_path(&store, &hash1).exists();
+
+        // hashes are read from the objects' metadata
+        let sidecar = ObjectSidecar {
+            sha256: Some(hash3.clone()),
+            ..Default::default()
+        };
+        sidecar::write(&root.join("cont"), "obj3", &sidecar).unwrap();
+        let hashes = hashes_below(root);
+        let recorded = object_hash(&root.join("cont"), "obj3");
+        sidecar::remove(&root.join("cont"), "obj3").unwrap();
+        let not_recorded = object_hash(&root.join("cont"), "obj3");
+
+        // garbage collection removes blobs of objects removed without releasing them
+        std::fs::remove_file(&obj3).unwrap();
+        let collected = collect_garbage(&store);
+        let blob3_exists = blob_path(&store, &hash3).exists();
+
+        clear_state(root);
+
+        assert_eq!(
+            hash1,
+            "c7c5c1d70c5dec4416ab6158afd0b223ef40c29b1dc1f97ed9428b94d4cadb1c"
+        );
+        assert_eq!(hash1, hash2);
+        assert_eq!(hashes, vec![hash3.clone()]);
+        assert_ne!(hash1, hash3);
+        assert_eq!(links_shared, 3);
+        assert_eq!(contents2, b"artifact".to_vec());
+        assert!(read_only);
+        assert_eq!(recorded, Some(hash3.clone()));
+        assert_eq!(not_recorded, None);
+        assert!(kept);
+        assert!(released);
+        assert_eq!(collected, (1, 5));
+        assert!(!blob3_exists);
+    }
+}
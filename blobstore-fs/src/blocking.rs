@@ -0,0 +1,119 @@
+//! Runs file system operations off the async executor.
+//!
+//! `std::fs` calls block the thread they run on, so a slow disk would stall every task
+//! scheduled on the same executor thread, including requests of unrelated actors that don't
+//! touch the disk at all. All file system work of the provider goes through a `BlockingPool`,
+//! which runs it on tokio's blocking thread pool. The number of operations running at the
+//! same time is limited, so that a burst of requests can't start an unbounded number of threads;
+//! further operations wait for a permit without blocking the executor.
+
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+use wasmbus_rpc::provider::prelude::*;
+
+/// Environment variable with the maximum number of file system operations running at the same time
+pub const MAX_CONCURRENT_IO: &str = "MAX_CONCURRENT_IO";
+
+/// default maximum number of file system operations running at the same time
+pub const DEFAULT_MAX_CONCURRENT_IO: usize = 64;
+
+/// Runs blocking closures with bounded concurrency
+#[derive(Clone, Debug)]
+pub struct BlockingPool {
+    permits: Arc<Semaphore>,
+}
+
+impl Default for BlockingPool {
+    /// Creates a pool with the limit from `MAX_CONCURRENT_IO`, or the default limit
+    fn default() -> Self {
+        let limit = std::env::var(MAX_CONCURRENT_IO)
+            .ok()
+            .and_then(|v| v.parse::<usize>().ok())
+            .filter(|n| *n > 0)
+            .unwrap_or(DEFAULT_MAX_CONCURRENT_IO);
+        BlockingPool::new(limit)
+    }
+}
+
+impl BlockingPool {
+    /// Creates a pool running at most `limit` operations at the same time
+    pub fn new(limit: usize) -> Self {
+        BlockingPool {
+            permits: Arc::new(Semaphore::new(limit)),
+        }
+    }
+
+    /// Runs `f` on a blocking thread once a permit is available, and returns its result.
+    /// A panic in `f` is returned as an error.
+    pub async fn run<T, F>(&self, f: F) -> RpcResult<T>
+    where
+        F: FnOnce() -> RpcResult<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let _permit = self
+            .permits
+            .acquire()
+            .await
+            .map_err(|e| RpcError::Other(format!("file system operations stopped: {}", e)))?;
+        tokio::task::spawn_blocking(f)
+            .await
+            .map_err(|e| RpcError::Other(format!("file system operation failed: {}", e)))?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    // single-threaded runtime: if the closure ran on the executor, the message
+    // it waits for could never be sent
+    #[tokio::test]
+    async fn executor_not_blocked() {
+        let pool = BlockingPool::new(4);
+        let (tx, rx) = mpsc::channel();
+        let slow_read = pool.run(move || Ok(rx.recv_timeout(Duration::from_secs(5)).is_ok()));
+        let other_request = async move {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            tx.send(()).unwrap();
+        };
+
+        let (received, _) = tokio::join!(slow_read, other_request);
+
+        assert!(received.unwrap());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn concurrency_is_bounded() {
+        let pool = BlockingPool::new(2);
+        let running = Arc::new(AtomicUsize::new(0));
+        let max_running = Arc::new(AtomicUsize::new(0));
+
+        let ops = (0..8).map(|_| {
+            let running = running.clone();
+            let max_running = max_running.clone();
+            pool.run(move || {
+                let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                max_running.fetch_max(now, Ordering::SeqCst);
+                std::thread::sleep(Duration::from_millis(20));
+                running.fetch_sub(1, Ordering::SeqCst);
+                Ok(())
+            })
+        });
+        let results = futures_util::future::join_all(ops).await;
+
+        assert!(results.iter().all(|r| r.is_ok()));
+        assert_eq!(max_running.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn panic_is_an_error() {
+        let pool = BlockingPool::new(1);
+        let result: RpcResult<()> = pool.run(|| panic!("disk on fire")).await;
+        assert!(result.is_err());
+        // the permit is released
+        assert!(pool.run(|| Ok(())).await.is_ok());
+    }
+}
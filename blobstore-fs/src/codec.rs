@@ -0,0 +1,878 @@
+//! Compression and encryption of objects at rest.
+//!
+//! With the link value `COMPRESSION=zstd`, or an encryption key set with `ENCRYPTION_KEY` or
+//! `ENCRYPTION_KEY_FILE`, objects are stored encoded. The contents are split into frames of
+//! `FRAME_SIZE` bytes, and each frame is compressed with zstd and/or encrypted with AES-256-GCM
+//! on its own, so that a range of an object can be read by decoding only the frames it overlaps.
+//! An encoded object file is laid out as:
+//!
+//! ```text
+//! header:  MAGIC (8 bytes) | version (1) | flags (1) | reserved (2) | frame size (u32) | file id (16)
+//! frames:  stored bytes of each frame; for encrypted frames, a 12 byte nonce followed by the ciphertext
+//! index:   stored length of each frame (u32)
+//! trailer: logical length (u64) | number of frames (u64) | MAGIC (8 bytes)
+//! ```
+//!
+//! All integers are little-endian. Each encrypted frame is authenticated together with the header,
+//! its position and whether it is the last frame, so frames can't be altered, reordered, moved between
+//! objects or dropped without the read failing. The logical length in the trailer is only used to
+//! report the size of the object; reads check it against the decoded frames.
+//!
+//! Whether an object was stored encoded is recorded in its metadata (see `sidecar`), rather than
+//! guessed from the contents of the file, so a plain object that happens to start with `MAGIC` is
+//! never decoded. If the link encodes objects, files that were not stored encoded, e.g., objects
+//! stored before encoding was enabled or copied into a container by other processes, are refused;
+//! otherwise they are read as they are.
+//!
+//! While an upload is in progress, its chunks are kept in a staging file. If objects are encrypted,
+//! each chunk is encrypted before it is written there (see `StagingLog`), so plain contents
+//! never reach the disk.
+
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use aes_gcm::{Aes256Gcm, Nonce};
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::Arc;
+use wasmbus_rpc::provider::prelude::*;
+
+/// Link value selecting the compression of stored objects: `zstd` or `none`
+pub const COMPRESSION: &str = "COMPRESSION";
+/// Link value with the zstd compression level
+pub const COMPRESSION_LEVEL: &str = "COMPRESSION_LEVEL";
+/// Link value with the base64-encoded 256-bit key used to encrypt stored objects
+pub const ENCRYPTION_KEY: &str = "ENCRYPTION_KEY";
+/// Link value with the path of a file holding the encryption key, as 32 raw bytes or base64
+pub const ENCRYPTION_KEY_FILE: &str = "ENCRYPTION_KEY_FILE";
+
+/// default zstd compression level
+pub const DEFAULT_COMPRESSION_LEVEL: i32 = 3;
+
+/// number of bytes of an object in each frame
+pub const FRAME_SIZE: usize = 64 * 1024;
+
+const MAGIC: &[u8; 8] = b"BSFSOBJ\x01";
+const VERSION: u8 = 1;
+const FLAG_ZSTD: u8 = 1;
+const FLAG_AES_GCM: u8 = 2;
+const HEADER_LEN: usize = 32;
+const TRAILER_LEN: usize = 24;
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+const KEY_LEN: usize = 32;
+
+/// How objects are encoded when they are stored
+#[derive(Clone)]
+pub struct Codec {
+    /// zstd compression level, or None if objects are not compressed
+    compression_level: Option<i32>,
+    /// cipher for the key, or None if objects are not encrypted
+    cipher: Option<Arc<Aes256Gcm>>,
+}
+
+// the cipher holds the key, so it must never be logged
+impl fmt::Debug for Codec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Codec")
+            .field("compression_level", &self.compression_level)
+            .field("encrypted", &self.cipher.is_some())
+            .finish()
+    }
+}
+
+impl Codec {
+    /// Reads the encoding from link definition values.
+    /// Returns None if objects are stored as plain files.
+    pub fn from_values(values: &HashMap<String, String>) -> RpcResult<Option<Self>> {
+        let compression_level = match values.get(COMPRESSION).map(String::as_str) {
+            None | Some("none") => None,
+            Some("zstd") => Some(match values.get(COMPRESSION_LEVEL) {
+                None => DEFAULT_COMPRESSION_LEVEL,
+                Some(v) => v
+                    .parse::<i32>()
+                    .ok()
+                    .filter(|l| (1..=22).contains(l))
+                    .ok_or_else(|| {
+                        RpcError::InvalidParameter(format!(
+                            "{} must be a number from 1 to 22, got '{}'",
+                            COMPRESSION_LEVEL, v
+                        ))
+                    })?,
+            }),
+            Some(v) => {
+                return Err(RpcError::InvalidParameter(format!(
+                    "{} must be 'zstd' or 'none', got '{}'",
+                    COMPRESSION, v
+                )))
+            }
+        };
+        let key = match (values.get(ENCRYPTION_KEY), values.get(ENCRYPTION_KEY_FILE)) {
+            (None, None) => None,
+            (Some(_), Some(_)) => {
+                return Err(RpcError::InvalidParameter(format!(
+                    "only one of {} and {} may be set",
+                    ENCRYPTION_KEY, ENCRYPTION_KEY_FILE
+                )))
+            }
+            (Some(key), None) => Some(decode_key(key.as_bytes(), ENCRYPTION_KEY)?),
+            (None, Some(path)) => {
+                let data = std::fs::read(path).map_err(|e| {
+                    RpcError::InvalidParameter(format!(
+                        "Could not read {} '{}': {}",
+                        ENCRYPTION_KEY_FILE, path, e
+                    ))
+                })?;
+                Some(decode_key(&data, ENCRYPTION_KEY_FILE)?)
+            }
+        };
+        if compression_level.is_none() && key.is_none() {
+            return Ok(None);
+        }
+        Ok(Some(Codec {
+            compression_level,
+            cipher: key.map(|key| Arc::new(Aes256Gcm::new(&key.into()))),
+        }))
+    }
+
+    /// Returns true if objects are encrypted
+    pub fn encrypts(&self) -> bool {
+        self.cipher.is_some()
+    }
+
+    fn cipher(&self) -> RpcResult<&Aes256Gcm> {
+        self.cipher.as_deref().ok_or_else(no_key)
+    }
+
+    fn header(&self, file_id: &[u8; 16]) -> [u8; HEADER_LEN] {
+        let mut flags = 0;
+        if self.compression_level.is_some() {
+            flags |= FLAG_ZSTD;
+        }
+        if self.cipher.is_some() {
+            flags |= FLAG_AES_GCM;
+        }
+        let mut header = [0u8; HEADER_LEN];
+        header[..8].copy_from_slice(MAGIC);
+        header[8] = VERSION;
+        header[9] = flags;
+        header[12..16].copy_from_slice(&(FRAME_SIZE as u32).to_le_bytes());
+        header[16..].copy_from_slice(file_id);
+        header
+    }
+
+    /// Writes the encoded form of an object of `len` bytes to `out`.
+    /// `read(offset, len)` returns the plain bytes of the object at `offset`.
+    pub fn encode<W, R>(&self, len: u64, out: &mut W, mut read: R) -> RpcResult<()>
+    where
+        W: Write,
+        R: FnMut(u64, usize) -> RpcResult<Vec<u8>>,
+    {
+        let header = self.header(uuid::Uuid::new_v4().as_bytes());
+        out.write_all(&header)?;
+        let frame_count = len.div_ceil(FRAME_SIZE as u64);
+        let mut index = Vec::with_capacity(frame_count as usize * 4);
+        for frame in 0..frame_count {
+            let offset = frame * FRAME_SIZE as u64;
+            let plain = read(offset, (len - offset).min(FRAME_SIZE as u64) as usize)?;
+            let stored = self.encode_frame(&header, frame, frame + 1 == frame_count, &plain)?;
+            index.extend_from_slice(&(stored.len() as u32).to_le_bytes());
+            out.write_all(&stored)?;
+        }
+        out.write_all(&index)?;
+        out.write_all(&len.to_le_bytes())?;
+        out.write_all(&frame_count.to_le_bytes())?;
+        out.write_all(MAGIC)?;
+        Ok(())
+    }
+
+    fn encode_frame(
+        &self,
+        header: &[u8; HEADER_LEN],
+        frame: u64,
+        is_last: bool,
+        plain: &[u8],
+    ) -> RpcResult<Vec<u8>> {
+        let compressed = match self.compression_level {
+            Some(level) => zstd::bulk::compress(plain, level)?,
+            None => plain.to_vec(),
+        };
+        match &self.cipher {
+            Some(cipher) => {
+                let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+                let aad = frame_aad(header, frame, is_last);
+                let ciphertext = cipher
+                    .encrypt(
+                        &nonce,
+                        Payload {
+                            msg: &compressed,
+                            aad: &aad,
+                        },
+                    )
+                    .map_err(|e| RpcError::Other(format!("Could not encrypt object: {}", e)))?;
+                let mut stored = nonce.to_vec();
+                stored.extend_from_slice(&ciphertext);
+                Ok(stored)
+            }
+            None => Ok(compressed),
+        }
+    }
+}
+
+fn no_key() -> RpcError {
+    RpcError::InvalidParameter(format!(
+        "object is encrypted, but no {} or {} is configured",
+        ENCRYPTION_KEY, ENCRYPTION_KEY_FILE
+    ))
+}
+
+fn decode_key(data: &[u8], source: &str) -> RpcResult<[u8; KEY_LEN]> {
+    let key = match <[u8; KEY_LEN]>::try_from(data) {
+        // a key file may hold the raw key
+        Ok(key) if source == ENCRYPTION_KEY_FILE => Some(key),
+        _ => String::from_utf8(data.to_vec())
+            .ok()
+            .and_then(|text| base64::decode(text.trim()).ok())
+            .and_then(|bytes| <[u8; KEY_LEN]>::try_from(bytes.as_slice()).ok()),
+    };
+    key.ok_or_else(|| {
+        RpcError::InvalidParameter(format!("{} must hold a base64-encoded 256-bit key", source))
+    })
+}
+
+/// Additional authenticated data of a frame: the header, which holds the encoding and the
+/// file's unique id, the position of the frame, and whether it is the last one
+fn frame_aad(header: &[u8; HEADER_LEN], frame: u64, is_last: bool) -> Vec<u8> {
+    let mut aad = header.to_vec();
+    aad.extend_from_slice(&frame.to_le_bytes());
+    aad.push(u8::from(is_last));
+    aad
+}
+
+fn corrupt(path_hint: &str) -> RpcError {
+    RpcError::Other(format!("Stored object is corrupt: {}", path_hint))
+}
+
+/// Returns the logical size of an object file, i.e., the number of bytes of the object,
+/// given the file's metadata and whether the object was stored encoded. No key is needed.
+pub fn logical_len(
+    path: &Path,
+    metadata: &std::fs::Metadata,
+    encoded: bool,
+) -> std::io::Result<u64> {
+    if !encoded {
+        return Ok(metadata.len());
+    }
+    let invalid = || {
+        std::io::Error::new(
+            ErrorKind::InvalidData,
+            format!("Stored object is corrupt: {:?} has no valid trailer", path),
+        )
+    };
+    if metadata.len() < (HEADER_LEN + TRAILER_LEN) as u64 {
+        return Err(invalid());
+    }
+    let mut file = File::open(path)?;
+    let mut trailer = [0u8; TRAILER_LEN];
+    file.seek(SeekFrom::End(-(TRAILER_LEN as i64)))?;
+    file.read_exact(&mut trailer)?;
+    if &trailer[16..] != MAGIC {
+        return Err(invalid());
+    }
+    Ok(u64::from_le_bytes(trailer[..8].try_into().unwrap()))
+}
+
+/// An open object, read as plain bytes
+pub enum ObjectReader {
+    /// a file stored as it is
+    Plain { file: File, len: u64 },
+    /// an encoded file
+    Encoded(EncodedObject),
+}
+
+impl ObjectReader {
+    /// Opens an object file. `encoded` is whether the object was stored encoded, as recorded in
+    /// its metadata. Encoded files are decoded with `codec`, which is only required if the file
+    /// is encrypted. If the link encodes objects, i.e., `codec` is set, plain files are refused.
+    pub fn open(file: File, encoded: bool, codec: Option<&Codec>) -> RpcResult<Self> {
+        let len = file.metadata()?.len();
+        match (encoded, codec) {
+            (true, _) => Ok(ObjectReader::Encoded(EncodedObject::open(
+                file, len, codec,
+            )?)),
+            (false, None) => Ok(ObjectReader::Plain { file, len }),
+            (false, Some(_)) => Err(RpcError::Other(
+                "Object was not stored encoded, but this link stores objects encoded; \
+                 it may have been written by another process"
+                    .to_string(),
+            )),
+        }
+    }
+
+    /// Returns the number of bytes of the object
+    pub fn len(&self) -> u64 {
+        match self {
+            ObjectReader::Plain { len, .. } => *len,
+            ObjectReader::Encoded(object) => object.len,
+        }
+    }
+
+    /// Reads `len` bytes of the object, starting at `offset`
+    pub fn read_range(&mut self, offset: u64, len: usize) -> RpcResult<Vec<u8>> {
+        match self {
+            ObjectReader::Plain { file, .. } => {
+                file.seek(SeekFrom::Start(offset))?;
+                let mut bytes = vec![0u8; len];
+                file.read_exact(&mut bytes)?;
+                Ok(bytes)
+            }
+            ObjectReader::Encoded(object) => object.read_range(offset, len),
+        }
+    }
+}
+
+/// An open encoded object file
+pub struct EncodedObject {
+    file: File,
+    header: [u8; HEADER_LEN],
+    compressed: bool,
+    cipher: Option<Arc<Aes256Gcm>>,
+    frame_size: u64,
+    /// number of bytes of the object
+    len: u64,
+    /// position of each frame in the file, and of the end of the last frame
+    frame_offsets: Vec<u64>,
+}
+
+impl EncodedObject {
+    fn open(mut file: File, file_len: u64, codec: Option<&Codec>) -> RpcResult<Self> {
+        if file_len < (HEADER_LEN + TRAILER_LEN) as u64 {
+            return Err(corrupt("file too short"));
+        }
+        let mut header = [0u8; HEADER_LEN];
+        file.seek(SeekFrom::Start(0))?;
+        file.read_exact(&mut header)?;
+        if &header[..8] != MAGIC {
+            return Err(corrupt("missing header"));
+        }
+        if header[8] != VERSION {
+            return Err(RpcError::Other(format!(
+                "Unsupported stored object version {}",
+                header[8]
+            )));
+        }
+        let flags = header[9];
+        let cipher = if flags & FLAG_AES_GCM != 0 {
+            Some(codec.and_then(|c| c.cipher.clone()).ok_or_else(no_key)?)
+        } else if codec.map(Codec::encrypts).unwrap_or(false) {
+            // a file that should be encrypted but isn't was not written by the provider
+            return Err(RpcError::Other(
+                "Object is not encrypted, but this link encrypts objects".to_string(),
+            ));
+        } else {
+            None
+        };
+        let frame_size = u32::from_le_bytes(header[12..16].try_into().unwrap()) as u64;
+
+        let mut trailer = [0u8; TRAILER_LEN];
+        file.seek(SeekFrom::End(-(TRAILER_LEN as i64)))?;
+        file.read_exact(&mut trailer)?;
+        let len = u64::from_le_bytes(trailer[..8].try_into().unwrap());
+        let frame_count = u64::from_le_bytes(trailer[8..16].try_into().unwrap());
+        let index_len = frame_count.saturating_mul(4);
+        if &trailer[16..] != MAGIC
+            || frame_size == 0
+            || frame_count != len.div_ceil(frame_size)
+            || index_len > file_len - (HEADER_LEN + TRAILER_LEN) as u64
+        {
+            return Err(corrupt("invalid trailer"));
+        }
+        let index_start = file_len - TRAILER_LEN as u64 - index_len;
+        let mut index = vec![0u8; index_len as usize];
+        file.seek(SeekFrom::Start(index_start))?;
+        file.read_exact(&mut index)?;
+        let mut frame_offsets = Vec::with_capacity(frame_count as usize + 1);
+        let mut offset = HEADER_LEN as u64;
+        frame_offsets.push(offset);
+        for stored_len in index.chunks_exact(4) {
+            offset += u32::from_le_bytes(stored_len.try_into().unwrap()) as u64;
+            frame_offsets.push(offset);
+        }
+        if offset != index_start {
+            return Err(corrupt("frame index doesn't match the file"));
+        }
+        Ok(EncodedObject {
+            file,
+            header,
+            compressed: flags & FLAG_ZSTD != 0,
+            cipher,
+            frame_size,
+            len,
+            frame_offsets,
+        })
+    }
+
+    fn read_range(&mut self, offset: u64, len: usize) -> RpcResult<Vec<u8>> {
+        let end = offset + len as u64;
+        if end > self.len {
+            return Err(RpcError::InvalidParameter(format!(
+                "range {}..{} is beyond the end of the object ({} bytes)",
+                offset, end, self.len
+            )));
+        }
+        let mut bytes = Vec::with_capacity(len);
+        let mut frame = offset / self.frame_size;
+        while (bytes.len() as u64) < len as u64 {
+            let plain = self.read_frame(frame)?;
+            let frame_start = frame * self.frame_size;
+            let from = (offset.max(frame_start) - frame_start) as usize;
+            let to = ((end - frame_start) as usize).min(plain.len());
+            bytes.extend_from_slice(&plain[from..to]);
+            frame += 1;
+        }
+        Ok(bytes)
+    }
+
+    fn read_frame(&mut self, frame: u64) -> RpcResult<Vec<u8>> {
+        let frame_count = self.frame_offsets.len() as u64 - 1;
+        let start = self.frame_offsets[frame as usize];
+        let stored_len = self.frame_offsets[frame as usize + 1] - start;
+        let mut stored = vec![0u8; stored_len as usize];
+        self.file.seek(SeekFrom::Start(start))?;
+        self.file.read_exact(&mut stored)?;
+        let compressed = match &self.cipher {
+            Some(cipher) => {
+                if stored.len() < NONCE_LEN + TAG_LEN {
+                    return Err(corrupt("frame too short"));
+                }
+                let (nonce, ciphertext) = stored.split_at(NONCE_LEN);
+                let aad = frame_aad(&self.header, frame, frame + 1 == frame_count);
+                cipher
+                    .decrypt(
+                        Nonce::from_slice(nonce),
+                        Payload {
+                            msg: ciphertext,
+                            aad: &aad,
+                        },
+                    )
+                    .map_err(|_| {
+                        RpcError::Other(
+                            "Could not decrypt stored object: wrong key, or the file was modified"
+                                .to_string(),
+                        )
+                    })?
+            }
+            None => stored,
+        };
+        let expected_len = (self.len - frame * self.frame_size).min(self.frame_size) as usize;
+        let plain = if self.compressed {
+            zstd::bulk::decompress(&compressed, expected_len)
+                .map_err(|e| corrupt(&format!("could not decompress frame {}: {}", frame, e)))?
+        } else {
+            compressed
+        };
+        if plain.len() != expected_len {
+            return Err(corrupt("frame has the wrong length"));
+        }
+        Ok(plain)
+    }
+}
+
+/// A chunk in a staging log
+#[derive(Clone, Copy, Debug)]
+struct StagedChunk {
+    /// offset of the chunk in the object
+    offset: u64,
+    /// number of bytes of the chunk
+    len: u64,
+    /// position of the encrypted chunk in the staging file
+    position: u64,
+}
+
+/// The chunks of an encrypted upload in progress. Each chunk is encrypted and appended to the
+/// staging file as it arrives. When the upload completes, the object is read back from the log,
+/// where a chunk received later overrides the bytes of earlier chunks at the same offsets.
+#[derive(Debug, Default)]
+pub struct StagingLog {
+    chunks: Vec<StagedChunk>,
+    end: u64,
+}
+
+impl StagingLog {
+    /// Encrypts a chunk of the object and appends it to the staging file.
+    /// `upload_id` ties the encrypted chunks to this upload.
+    pub fn append(
+        &mut self,
+        file: &mut File,
+        codec: &Codec,
+        upload_id: &str,
+        offset: u64,
+        bytes: &[u8],
+    ) -> RpcResult<()> {
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = codec
+            .cipher()?
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: bytes,
+                    aad: &staging_aad(upload_id, offset),
+                },
+            )
+            .map_err(|e| RpcError::Other(format!("Could not encrypt chunk: {}", e)))?;
+        file.seek(SeekFrom::Start(self.end))?;
+        file.write_all(&nonce)?;
+        file.write_all(&ciphertext)?;
+        self.chunks.push(StagedChunk {
+            offset,
+            len: bytes.len() as u64,
+            position: self.end,
+        });
+        self.end += (NONCE_LEN + ciphertext.len()) as u64;
+        Ok(())
+    }
+
+    /// Returns a function reading ranges of the object from the staging file, for `Codec::encode`.
+    /// Chunks are decrypted once for all the ranges they overlap, as long as ranges are read in order.
+    pub fn reader<'a>(
+        &'a self,
+        file: &'a mut File,
+        codec: &'a Codec,
+        upload_id: &'a str,
+    ) -> impl FnMut(u64, usize) -> RpcResult<Vec<u8>> + 'a {
+        let mut decrypted: HashMap<usize, Vec<u8>> = HashMap::new();
+        move |offset, len| {
+            let end = offset + len as u64;
+            decrypted.retain(|i, _| self.chunks[*i].offset + self.chunks[*i].len > offset);
+            let mut bytes = vec![0u8; len];
+            for (i, chunk) in self.chunks.iter().enumerate() {
+                let chunk_end = chunk.offset + chunk.len;
+                if chunk.offset >= end || chunk_end <= offset {
+                    continue;
+                }
+                let plain = match decrypted.entry(i) {
+                    Entry::Occupied(entry) => entry.into_mut(),
+                    Entry::Vacant(entry) => {
+                        entry.insert(Self::read_chunk(file, codec, upload_id, chunk)?)
+                    }
+                };
+                let from = offset.max(chunk.offset);
+                let to = end.min(chunk_end);
+                bytes[(from - offset) as usize..(to - offset) as usize].copy_from_slice(
+                    &plain[(from - chunk.offset) as usize..(to - chunk.offset) as usize],
+                );
+            }
+            Ok(bytes)
+        }
+    }
+
+    fn read_chunk(
+        file: &mut File,
+        codec: &Codec,
+        upload_id: &str,
+        chunk: &StagedChunk,
+    ) -> RpcResult<Vec<u8>> {
+        let mut stored = vec![0u8; NONCE_LEN + chunk.len as usize + TAG_LEN];
+        file.seek(SeekFrom::Start(chunk.position))?;
+        file.read_exact(&mut stored).map_err(|e| match e.kind() {
+            ErrorKind::UnexpectedEof => corrupt("staging file is truncated"),
+            _ => e.into(),
+        })?;
+        let (nonce, ciphertext) = stored.split_at(NONCE_LEN);
+        codec
+            .cipher()?
+            .decrypt(
+                Nonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: &staging_aad(upload_id, chunk.offset),
+                },
+            )
+            .map_err(|_| RpcError::Other("Could not decrypt staged chunk".to_string()))
+    }
+}
+
+fn staging_aad(upload_id: &str, offset: u64) -> Vec<u8> {
+    let mut aad = upload_id.as_bytes().to_vec();
+    aad.extend_from_slice(&offset.to_le_bytes());
+    aad
+}
+
+/// Returns a copy of link definition values that is safe to log
+pub fn redacted(values: &HashMap<String, String>) -> HashMap<String, String> {
+    values
+        .iter()
+        .map(|(k, v)| match k.as_str() {
+            ENCRYPTION_KEY => (k.clone(), "<redacted>".to_string()),
+            _ => (k.clone(), v.clone()),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs_utils::clear_state;
+    use std::fs::{create_dir_all, OpenOptions};
+
+    const KEY: &str = "AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8=";
+
+    fn codec(compression: bool, key: Option<&str>) -> Codec {
+        let mut values = HashMap::new();
+        if compression {
+            values.insert(COMPRESSION.to_string(), "zstd".to_string());
+        }
+        if let Some(key) = key {
+            values.insert(ENCRYPTION_KEY.to_string(), key.to_string());
+        }
+        Codec::from_values(&values).unwrap().unwrap()
+    }
+
+    fn encode_to(path: &Path, codec: &Codec, contents: &[u8]) {
+        let mut out = File::create(path).unwrap();
+        codec
+            .encode(contents.len() as u64, &mut out, |offset, len| {
+                Ok(contents[offset as usize..offset as usize + len].to_vec())
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn parse_values() {
+        assert!(Codec::from_values(&HashMap::new()).unwrap().is_none());
+        let mut values = HashMap::new();
+        values.insert(COMPRESSION.to_string(), "none".to_string());
+        assert!(Codec::from_values(&values).unwrap().is_none());
+        values.insert(COMPRESSION.to_string(), "gzip".to_string());
+        assert!(Codec::from_values(&values).is_err());
+        values.insert(COMPRESSION.to_string(), "zstd".to_string());
+        values.insert(COMPRESSION_LEVEL.to_string(), "30".to_string());
+        assert!(Codec::from_values(&values).is_err());
+        values.remove(COMPRESSION_LEVEL);
+        values.insert(ENCRYPTION_KEY.to_string(), "c2hvcnQ=".to_string());
+        assert!(Codec::from_values(&values).is_err());
+        values.insert(ENCRYPTION_KEY.to_string(), KEY.to_string());
+        let codec = Codec::from_values(&values).unwrap().unwrap();
+        assert!(codec.encrypts());
+        assert!(!format!("{:?}", codec).contains(KEY));
+        assert_eq!(redacted(&values)[ENCRYPTION_KEY], "<redacted>");
+        values.insert(ENCRYPTION_KEY_FILE.to_string(), "/tmp/key".to_string());
+        assert!(Codec::from_values(&values).is_err());
+    }
+
+    #[test]
+    fn key_file() {
+        let root = Path::new("/tmp/rust_test/codec1");
+        create_dir_all(root).unwrap();
+        std::fs::write(root.join("raw"), [7u8; 32]).unwrap();
+        std::fs::write(root.join("text"), format!("{}\n", KEY)).unwrap();
+        let mut values = HashMap::new();
+        values.insert(
+            ENCRYPTION_KEY_FILE.to_string(),
+            root.join("raw").display().to_string(),
+        );
+        let raw = Codec::from_values(&values);
+        values.insert(
+            ENCRYPTION_KEY_FILE.to_string(),
+            root.join("text").display().to_string(),
+        );
+        let text = Codec::from_values(&values);
+        values.insert(
+            ENCRYPTION_KEY_FILE.to_string(),
+            root.join("missing").display().to_string(),
+        );
+        let missing = Codec::from_values(&values);
+
+        clear_state(root);
+
+        assert!(raw.unwrap().unwrap().encrypts());
+        assert!(text.unwrap().unwrap().encrypts());
+        assert!(missing.is_err());
+    }
+
+    #[test]
+    fn encode_and_read_ranges() {
+        let root = Path::new("/tmp/rust_test/codec2");
+        create_dir_all(root).unwrap();
+        // compressible, and a few frames long with a partial last frame
+        let contents: Vec<u8> = (0..FRAME_SIZE * 3 + 1000)
+            .map(|i| (i / 100) as u8)
+            .collect();
+        let mut results = Vec::new();
+        for (name, compression, key) in [
+            ("zstd", true, None),
+            ("aes", false, Some(KEY)),
+            ("both", true, Some(KEY)),
+        ] {
+            let path = root.join(name);
+            let codec = codec(compression, key);
+            encode_to(&path, &codec, &contents);
+            let metadata = std::fs::metadata(&path).unwrap();
+            let mut reader =
+                ObjectReader::open(File::open(&path).unwrap(), true, Some(&codec)).unwrap();
+            results.push((
+                name,
+                metadata.len(),
+                logical_len(&path, &metadata, true).unwrap(),
+                reader.len(),
+                reader.read_range(0, contents.len()).unwrap(),
+                // a range across a frame boundary
+                reader.read_range(FRAME_SIZE as u64 - 10, 20).unwrap(),
+                reader.read_range(contents.len() as u64 - 5, 5).unwrap(),
+                reader.read_range(contents.len() as u64 - 5, 6).is_err(),
+            ));
+        }
+        // the plain bytes are not in the encrypted file
+        let stored = std::fs::read(root.join("aes")).unwrap();
+        let leaked = stored
+            .windows(FRAME_SIZE / 2)
+            .any(|w| w == &contents[..FRAME_SIZE / 2]);
+
+        clear_state(root);
+
+        for (name, stored_len, logical, len, all, boundary, tail, beyond) in results {
+            let size = contents.len() as u64;
+            assert_eq!(logical, size, "{}", name);
+            assert_eq!(len, size, "{}", name);
+            assert_eq!(all, contents, "{}", name);
+            assert_eq!(
+                boundary,
+                contents[FRAME_SIZE - 10..FRAME_SIZE + 10].to_vec(),
+                "{}",
+                name
+            );
+            assert_eq!(tail, contents[contents.len() - 5..].to_vec(), "{}", name);
+            assert!(beyond, "{}", name);
+            if name != "aes" {
+                assert!(stored_len < size / 10, "{} is not compressed", name);
+            }
+        }
+        assert!(!leaked);
+    }
+
+    #[test]
+    fn empty_and_plain_objects() {
+        let root = Path::new("/tmp/rust_test/codec3");
+        create_dir_all(root).unwrap();
+        let codec = codec(true, Some(KEY));
+        encode_to(&root.join("empty"), &codec, b"");
+        encode_to(
+            &root.join("compressed"),
+            &self::codec(true, None),
+            b"not secret",
+        );
+        std::fs::write(root.join("plain"), b"stored before encryption").unwrap();
+        // a plain object that looks like an encoded one
+        let mut lookalike = MAGIC.to_vec();
+        lookalike.extend_from_slice(&[0u8; HEADER_LEN + TRAILER_LEN]);
+        lookalike.extend_from_slice(MAGIC);
+        std::fs::write(root.join("lookalike"), &lookalike).unwrap();
+        let open = |name: &str, encoded: bool, codec: Option<&Codec>| {
+            ObjectReader::open(File::open(root.join(name)).unwrap(), encoded, codec)
+        };
+        let len = |name: &str, encoded: bool| {
+            let path = root.join(name);
+            logical_len(&path, &std::fs::metadata(&path).unwrap(), encoded)
+        };
+
+        let mut empty = open("empty", true, Some(&codec));
+        let mut plain = open("plain", false, None);
+        let plain_len = len("plain", false);
+        // plain files are refused when the link encodes objects
+        let plain_refused = open("plain", false, Some(&codec)).is_err();
+        // and so are unencrypted files when it encrypts them
+        let unencrypted_refused = open("compressed", true, Some(&codec)).is_err();
+        let mut lookalike_reader = open("lookalike", false, None);
+        let lookalike_len = len("lookalike", false);
+        // a file recorded as encoded must have the header and trailer
+        let not_encoded = open("plain", true, None).is_err();
+        let not_encoded_len = len("plain", true).is_err();
+
+        clear_state(root);
+
+        let empty = empty.as_mut().unwrap();
+        assert_eq!(empty.len(), 0);
+        assert_eq!(empty.read_range(0, 0).unwrap(), Vec::<u8>::new());
+        let plain = plain.as_mut().unwrap();
+        assert_eq!(plain.len(), 24);
+        assert_eq!(plain.read_range(7, 6).unwrap(), b"before".to_vec());
+        assert_eq!(plain_len.unwrap(), 24);
+        assert!(plain_refused);
+        assert!(unencrypted_refused);
+        let lookalike_reader = lookalike_reader.as_mut().unwrap();
+        assert_eq!(lookalike_reader.len(), lookalike.len() as u64);
+        assert_eq!(lookalike_reader.read_range(0, 8).unwrap(), MAGIC.to_vec());
+        assert_eq!(lookalike_len.unwrap(), lookalike.len() as u64);
+        assert!(not_encoded);
+        assert!(not_encoded_len);
+    }
+
+    #[test]
+    fn wrong_key_and_tampering() {
+        let root = Path::new("/tmp/rust_test/codec4");
+        create_dir_all(root).unwrap();
+        let contents = vec![42u8; FRAME_SIZE + 10];
+        let codec = codec(false, Some(KEY));
+        let other = self::codec(false, Some("HxwdHhsaGRgXFhUUExIREA8ODQwLCgkIBwYFBAMCAQA="));
+        let path = root.join("obj");
+        encode_to(&path, &codec, &contents);
+
+        let wrong_key = ObjectReader::open(File::open(&path).unwrap(), true, Some(&other))
+            .unwrap()
+            .read_range(0, 10);
+        let no_key = ObjectReader::open(File::open(&path).unwrap(), true, None).is_err();
+        // flip a bit of the first frame's ciphertext
+        let mut stored = std::fs::read(&path).unwrap();
+        stored[HEADER_LEN + NONCE_LEN + 1] ^= 1;
+        std::fs::write(&path, &stored).unwrap();
+        let mut reader =
+            ObjectReader::open(File::open(&path).unwrap(), true, Some(&codec)).unwrap();
+        let tampered = reader.read_range(0, 10);
+        let intact_frame = reader.read_range(FRAME_SIZE as u64, 10);
+
+        clear_state(root);
+
+        assert!(wrong_key.is_err());
+        assert!(no_key);
+        assert!(tampered.is_err());
+        assert_eq!(intact_frame.unwrap(), vec![42u8; 10]);
+    }
+
+    #[test]
+    fn staging_log() {
+        let root = Path::new("/tmp/rust_test/codec5");
+        create_dir_all(root).unwrap();
+        let codec = codec(true, Some(KEY));
+        let path = root.join("staging");
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        let mut log = StagingLog::default();
+        // out of order, with a chunk sent again with other bytes
+        log.append(&mut file, &codec, "upload1", 6, b"world!")
+            .unwrap();
+        log.append(&mut file, &codec, "upload1", 0, b"hello,")
+            .unwrap();
+        log.append(&mut file, &codec, "upload1", 5, b" ").unwrap();
+        let staged = std::fs::read(&path).unwrap();
+
+        let mut read = log.reader(&mut file, &codec, "upload1");
+        let all = read(0, 12).unwrap();
+        let part = read(4, 4).unwrap();
+        drop(read);
+        let wrong_upload = log.reader(&mut file, &codec, "upload2")(0, 12);
+
+        clear_state(root);
+
+        assert_eq!(all, b"hello world!".to_vec());
+        assert_eq!(part, b"o wo".to_vec());
+        assert!(!staged.windows(5).any(|w| w == b"hello"));
+        assert!(wrong_upload.is_err());
+    }
+}
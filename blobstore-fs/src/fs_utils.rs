@@ -1,46 +1,275 @@
+use crate::paths;
+use crate::sidecar;
+use crate::{blobs, versions};
+use std::io::ErrorKind;
 use std::path::{Path, PathBuf};
+use std::time::SystemTime;
 use std::vec::Vec;
+use tracing::warn;
+use wasmbus_rpc::provider::prelude::*;
+use wasmcloud_interface_blobstore::ListObjectsRequest;
+
+/// number of items to return in list_objects if max_items not specified
+pub const DEFAULT_MAX_ITEMS: u32 = 1000;
+
+/// Name of the file marking a directory nested inside a container as a container of its own.
+/// Starts with `paths::RESERVED_PREFIX` so it is hidden from actors.
+///
+/// Object ids and container ids may both contain `/`, which maps to nested directories, so a
+/// directory inside a container is either a nested container or a "folder" holding the objects
+/// whose ids start with its path. Directories created with `create_container` carry this marker
+/// and are listed as containers; any other directory below the top level is a folder, and the
+/// objects in it are listed with the folder's path in their ids, e.g., `images/2023/a.png`.
+pub const CONTAINER_MARKER: &str = ".blobstore-container";
+
+/// Returns true if `dir` was created as a container, rather than holding objects with a common prefix
+pub fn is_container(dir: &Path) -> bool {
+    dir.join(CONTAINER_MARKER).is_file()
+}
+
+/// Creates a container directory and marks it as a container
+pub fn create_container(dir: &Path) -> std::io::Result<()> {
+    std::fs::create_dir_all(dir)?;
+    match std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(dir.join(CONTAINER_MARKER))
+    {
+        Err(e) if e.kind() != ErrorKind::AlreadyExists => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// Name of the file recording that the directories below an actor's directory or a shared namespace
+/// follow the layout with folders, in which only directories with `CONTAINER_MARKER` are nested containers.
+/// Starts with `paths::RESERVED_PREFIX` so it is hidden from actors.
+pub const LAYOUT_MARKER: &str = ".blobstore-layout";
+
+/// Marks the directories below `dir` that are nested in containers as containers, unless it was done before.
+/// Before folders were introduced, every directory was a container, so directories created then keep
+/// being listed as containers and their objects keep their ids. Directories at `top_level` depth,
+/// 1 for an actor's directory and 0 for a shared namespace, are containers without a marker.
+/// Returns the number of directories marked.
+pub fn upgrade_layout(dir: &Path, top_level: usize) -> std::io::Result<usize> {
+    let layout_marker = dir.join(LAYOUT_MARKER);
+    if layout_marker.is_file() {
+        return Ok(0);
+    }
+    let mut marked = 0;
+    for sub in all_dirs(dir, dir)? {
+        if paths::is_reserved(&sub) || sub.components().count() <= top_level {
+            continue;
+        }
+        let path = dir.join(&sub);
+        if !is_container(&path) {
+            create_container(&path)?;
+            marked += 1;
+        }
+    }
+    std::fs::write(&layout_marker, b"")?;
+    Ok(marked)
+}
 
 /// Traverses a file system starting at location `root` and returning a list of all directories
 /// contained in that directory, recursively, relative to the original root at level 0.
-pub fn all_dirs(root: &Path, prefix: &Path) -> Vec<PathBuf> {
-    let mut dirs: Vec<PathBuf> = match std::fs::read_dir(root) {
-        Ok(rd) => rd
-            .filter(|e| match e {
-                Ok(entry) => match entry.file_type() {
-                    Ok(ft) => ft.is_dir(),
-                    _ => false,
-                },
-                _ => false,
-            })
-            .map(|e| PathBuf::from(e.unwrap().path().as_path().strip_prefix(prefix).unwrap()))
-            .collect(),
-        Err(e) => {
-            panic!("Could not read directories at {:?}: {}", root, e)
+/// Returns an error if a directory can't be read.
+pub fn all_dirs(root: &Path, prefix: &Path) -> std::io::Result<Vec<PathBuf>> {
+    let mut dirs: Vec<PathBuf> = Vec::new();
+    for entry in std::fs::read_dir(root)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            let path = entry.path();
+            let relative = path.strip_prefix(prefix).map_err(|_| {
+                std::io::Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("{:?} is not below {:?}", path, prefix),
+                )
+            })?;
+            dirs.push(relative.to_path_buf());
         }
-    };
+    }
 
     // Now recursively go in all directories and collect all sub-directories
     let mut subdirs: Vec<PathBuf> = Vec::new();
     for dir in &dirs {
-        let mut local_subdirs = all_dirs(prefix.join(dir.as_path()).as_path(), prefix);
+        let mut local_subdirs = all_dirs(prefix.join(dir.as_path()).as_path(), prefix)?;
         subdirs.append(&mut local_subdirs);
     }
     dirs.append(&mut subdirs);
-    dirs
+    Ok(dirs)
+}
+
+/// Returns the ids of the objects in a container, sorted, for the objects below `folder`
+/// (a path relative to the container, which may be empty). Objects in folders have ids with
+/// the folder's path, separated by `/`. Nested containers and the provider's internal files
+/// are skipped. A missing folder, or one that is an object or a nested container, has no objects.
+pub fn object_keys(container_dir: &Path, folder: &str) -> std::io::Result<Vec<String>> {
+    let mut keys = Vec::new();
+    let prefix = match folder.trim_end_matches('/') {
+        "" => String::new(),
+        folder => format!("{}/", folder),
+    };
+    let dir = container_dir.join(&prefix);
+    if !prefix.is_empty() && (!dir.is_dir() || is_container(&dir)) {
+        return Ok(keys);
+    }
+    collect_keys(&dir, &prefix, &mut keys)?;
+    keys.sort();
+    Ok(keys)
+}
+
+fn collect_keys(dir: &Path, prefix: &str, keys: &mut Vec<String>) -> std::io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = match entry.file_name().into_string() {
+            Ok(name) => name,
+            Err(name) => {
+                return Err(std::io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("file name {:?} is not valid UTF-8", name),
+                ))
+            }
+        };
+        if paths::is_reserved(Path::new(&name)) {
+            continue;
+        }
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            if !is_container(&entry.path()) {
+                collect_keys(&entry.path(), &format!("{}{}/", prefix, name), keys)?;
+            }
+        } else if file_type.is_file() {
+            keys.push(format!("{}{}", prefix, name));
+        }
+    }
+    Ok(())
+}
+
+/// Removes the folders of an object that was removed from a container, if they hold
+/// nothing else, so that folders exist only as long as objects are stored in them.
+/// The container itself and nested containers are never removed.
+pub fn remove_empty_folders(container_dir: &Path, object_path: &Path) {
+    let mut dir = object_path.parent();
+    while let Some(folder) = dir.filter(|d| d.starts_with(container_dir) && *d != container_dir) {
+        // the folder's metadata directory is empty once its objects are gone
+        let _ = std::fs::remove_dir(folder.join(sidecar::METADATA_DIR));
+        if std::fs::remove_dir(folder).is_err() {
+            break;
+        }
+        dir = folder.parent();
+    }
+}
+
+/// Removes an object with its metadata, releases its blob if contents are deduplicated,
+/// and removes the folders left empty. With versioning, the object is kept as a noncurrent
+/// version behind a delete marker. Errors that occur before the object is removed are
+/// returned; later ones are logged, since the object is gone by then.
+pub fn remove_object(
+    container_dir: &Path,
+    object_id: &str,
+    versioning: Option<&versions::Retention>,
+    blob_store: Option<&Path>,
+) -> std::io::Result<()> {
+    let object_path = container_dir.join(object_id);
+    let sha256 = sidecar::read(container_dir, object_id).and_then(|s| s.sha256);
+    if versioning.is_some() {
+        versions::keep_current(container_dir, object_id).map_err(|e| {
+            std::io::Error::new(e.kind(), format!("Could not keep version: {:?}", e))
+        })?;
+    }
+    std::fs::remove_file(&object_path)?;
+    if let Err(e) = sidecar::remove(container_dir, object_id) {
+        warn!("Could not remove metadata of {:?}: {}", object_path, e);
+    }
+    if let (Some(store), Some(sha256)) = (blob_store, sha256) {
+        blobs::release(store, &sha256);
+    }
+    if let Some(retention) = versioning {
+        if let Err(e) = versions::add_delete_marker(container_dir, object_id).and_then(|_| {
+            versions::apply_retention(
+                container_dir,
+                object_id,
+                retention,
+                blob_store,
+                SystemTime::now(),
+            )
+        }) {
+            warn!("Could not record removal of {:?}: {}", object_path, e);
+        }
+    }
+    remove_empty_folders(container_dir, &object_path);
+    Ok(())
+}
+
+/// Encodes the name of the last object returned in a page as an opaque continuation token.
+pub fn encode_continuation(last_name: &str) -> String {
+    base64::encode_config(last_name, base64::URL_SAFE_NO_PAD)
+}
+
+/// Decodes a continuation token created by `encode_continuation`
+pub fn decode_continuation(token: &str) -> RpcResult<String> {
+    base64::decode_config(token, base64::URL_SAFE_NO_PAD)
+        .ok()
+        .and_then(|bytes| String::from_utf8(bytes).ok())
+        .ok_or_else(|| RpcError::InvalidParameter(format!("invalid continuation token: {}", token)))
+}
+
+/// Selects one page of object names for a list_objects request.
+/// `names` must be sorted. The page starts at `start_with` (inclusive), or just after
+/// the last name of the previous page if a continuation token is provided, and stops at
+/// `end_with` (inclusive) or `end_before` (exclusive), returning at most `max_items` names.
+/// Because the continuation token holds the last name returned rather than a position,
+/// objects added or removed between requests do not cause items to be skipped or repeated.
+/// Returns the page and, if more names remain in the requested range, the continuation token
+/// for the next page.
+pub fn page_names<'n>(
+    names: &'n [String],
+    req: &ListObjectsRequest,
+) -> RpcResult<(&'n [String], Option<String>)> {
+    let start = match (&req.continuation, &req.start_with) {
+        (Some(token), _) => {
+            let last_name = decode_continuation(token)?;
+            names.partition_point(|n| n <= &last_name)
+        }
+        (None, Some(start_with)) => names.partition_point(|n| n < start_with),
+        (None, None) => 0,
+    };
+    let mut end = names.len();
+    if let Some(end_with) = &req.end_with {
+        end = end.min(names.partition_point(|n| n <= end_with));
+    }
+    if let Some(end_before) = &req.end_before {
+        end = end.min(names.partition_point(|n| n < end_before));
+    }
+    if start >= end {
+        return Ok((&[], None));
+    }
+    let max_items = req
+        .max_items
+        .filter(|max| *max > 0)
+        .unwrap_or(DEFAULT_MAX_ITEMS) as usize;
+    if end - start > max_items {
+        let page = &names[start..start + max_items];
+        let continuation = page.last().map(|n| encode_continuation(n));
+        Ok((page, continuation))
+    } else {
+        Ok((&names[start..end], None))
+    }
+}
+
+/// Removes a directory tree created by a test, reporting rather than failing if it can't
+#[cfg(test)]
+pub fn clear_state(r: &Path) {
+    if let Err(e) = std::fs::remove_dir_all(r) {
+        println!("Error in remove_dir_all: {}", e);
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use std::fs::File;
-    use std::fs::{create_dir_all, remove_dir_all};
-
-    fn clear_state(r: &Path) {
-        if let Err(e) = remove_dir_all(r) {
-            println!("Error in remove_dir_all: {}", e);
-        }
-    }
+    use std::fs::{create_dir_all, remove_file};
 
     #[test]
     fn one_dir() {
@@ -54,7 +283,7 @@ mod tests {
             );
         }
 
-        let dirs = all_dirs(root, root);
+        let dirs = all_dirs(root, root).unwrap();
 
         clear_state(root);
 
@@ -72,7 +301,7 @@ mod tests {
             panic!("Error in create_dir_all: {}", e);
         }
 
-        let dirs = all_dirs(root, root);
+        let dirs = all_dirs(root, root).unwrap();
 
         clear_state(root);
 
@@ -94,7 +323,7 @@ mod tests {
 
         File::create(root.join("dir2/foo.txt").as_path()).unwrap();
 
-        let dirs = all_dirs(root, root);
+        let dirs = all_dirs(root, root).unwrap();
 
         clear_state(root);
 
@@ -103,4 +332,146 @@ mod tests {
         assert!(!dirs.contains(&PathBuf::from(r"foo.txt")));
         assert!(dirs.contains(&PathBuf::from(r"dir2/dir3")));
     }
+
+    #[test]
+    fn unreadable_dir() {
+        let root = Path::new("/tmp/rust_test/test4");
+        assert!(all_dirs(root, root).is_err());
+    }
+
+    #[test]
+    fn keys_in_folders() {
+        let root = Path::new("/tmp/rust_test/test5");
+        create_dir_all(root.join("images/2023")).unwrap();
+        create_dir_all(root.join(".blobstore-meta")).unwrap();
+        File::create(root.join("a.txt")).unwrap();
+        File::create(root.join("images/logo.png")).unwrap();
+        File::create(root.join("images/2023/a.png")).unwrap();
+        File::create(root.join(".blobstore-meta/a.txt.json")).unwrap();
+        create_container(&root.join("nested")).unwrap();
+        File::create(root.join("nested/b.txt")).unwrap();
+
+        let all = object_keys(root, "").unwrap();
+        let images = object_keys(root, "images/").unwrap();
+        let missing = object_keys(root, "videos").unwrap();
+        let object = object_keys(root, "a.txt").unwrap();
+        let container = object_keys(root, "nested").unwrap();
+        remove_file(root.join("images/2023/a.png")).unwrap();
+        remove_empty_folders(root, &root.join("images/2023/a.png"));
+        let folder_2023 = root.join("images/2023").exists();
+        let folder_images = root.join("images").exists();
+
+        clear_state(root);
+
+        assert_eq!(
+            all,
+            names(&["a.txt", "images/2023/a.png", "images/logo.png"])
+        );
+        assert_eq!(images, names(&["images/2023/a.png", "images/logo.png"]));
+        assert!(missing.is_empty());
+        assert!(object.is_empty());
+        assert!(container.is_empty());
+        assert!(!folder_2023);
+        assert!(folder_images);
+    }
+
+    #[test]
+    fn upgrade_existing_layout() {
+        let root = Path::new("/tmp/rust_test/test6");
+        // an actor's directory written before folders existed
+        create_dir_all(root.join("cont/nested/deeper")).unwrap();
+        create_dir_all(root.join("cont/.blobstore-meta")).unwrap();
+        File::create(root.join("cont/nested/a.txt")).unwrap();
+
+        let marked = upgrade_layout(root, 1).unwrap();
+        let nested = is_container(&root.join("cont/nested"));
+        let deeper = is_container(&root.join("cont/nested/deeper"));
+        let top_level = is_container(&root.join("cont"));
+        let internal = is_container(&root.join("cont/.blobstore-meta"));
+        let keys = object_keys(&root.join("cont"), "").unwrap();
+        // folders created afterwards stay folders
+        create_dir_all(root.join("cont/images")).unwrap();
+        let marked_again = upgrade_layout(root, 1).unwrap();
+        let images = is_container(&root.join("cont/images"));
+
+        clear_state(root);
+
+        assert!(LAYOUT_MARKER.starts_with(paths::RESERVED_PREFIX));
+        assert_eq!(marked, 2);
+        assert!(nested);
+        assert!(deeper);
+        assert!(!top_level);
+        assert!(!internal);
+        assert!(keys.is_empty());
+        assert_eq!(marked_again, 0);
+        assert!(!images);
+    }
+
+    fn names(list: &[&str]) -> Vec<String> {
+        list.iter().map(|n| n.to_string()).collect()
+    }
+
+    fn list_request() -> ListObjectsRequest {
+        ListObjectsRequest {
+            container_id: "cont".to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn page_all() {
+        let all = names(&["a", "b", "c"]);
+        let (page, continuation) = page_names(&all, &list_request()).unwrap();
+        assert_eq!(page, &all[..]);
+        assert_eq!(continuation, None);
+    }
+
+    #[test]
+    fn page_range() {
+        let all = names(&["a", "b", "c", "d", "e"]);
+        let mut req = list_request();
+        req.start_with = Some("b".to_string());
+        req.end_with = Some("d".to_string());
+        let (page, _) = page_names(&all, &req).unwrap();
+        assert_eq!(page, &names(&["b", "c", "d"])[..]);
+
+        req.end_with = None;
+        req.end_before = Some("d".to_string());
+        let (page, _) = page_names(&all, &req).unwrap();
+        assert_eq!(page, &names(&["b", "c"])[..]);
+
+        req.start_with = Some("x".to_string());
+        let (page, continuation) = page_names(&all, &req).unwrap();
+        assert!(page.is_empty());
+        assert_eq!(continuation, None);
+    }
+
+    #[test]
+    fn page_continuation() {
+        let mut all = names(&["a", "b", "c", "d", "e"]);
+        let mut req = list_request();
+        req.max_items = Some(2);
+        let (page, continuation) = page_names(&all, &req).unwrap();
+        assert_eq!(page, &names(&["a", "b"])[..]);
+        assert!(continuation.is_some());
+
+        // an object added before the resume point must not shift the next page
+        all.insert(0, "0".to_string());
+        req.continuation = continuation;
+        let (page, continuation) = page_names(&all, &req).unwrap();
+        assert_eq!(page, &names(&["c", "d"])[..]);
+
+        req.continuation = continuation;
+        let (page, continuation) = page_names(&all, &req).unwrap();
+        assert_eq!(page, &names(&["e"])[..]);
+        assert_eq!(continuation, None);
+    }
+
+    #[test]
+    fn page_invalid_continuation() {
+        let all = names(&["a"]);
+        let mut req = list_request();
+        req.continuation = Some("not a token!".to_string());
+        assert!(page_names(&all, &req).is_err());
+    }
 }
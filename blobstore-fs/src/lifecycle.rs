@@ -0,0 +1,393 @@
+//! Lifecycle rules: objects expire some time after they were last modified, or the oldest
+//! objects of a container are removed when it holds more than a maximum number of objects.
+//!
+//! Rules for all of an actor's containers are set with the link values `LIFECYCLE_EXPIRE_SECS`
+//! and `LIFECYCLE_MAX_OBJECTS`. A container can have its own rules in a json file named
+//! `.blobstore-lifecycle.json` in its directory, e.g., `{"expire_after_secs": 86400}`, which
+//! replace the link's rules for that container; `{}` exempts the container from them.
+//! The file's name is reserved, so actors can't change it; it is placed by the operator.
+//! Shared namespaces, and the containers nested in them, hold objects of several actors, so the
+//! rules of one actor's link never apply to them: only their own lifecycle files do.
+//!
+//! Rules are enforced by a background task of each link that sweeps the actor's containers and
+//! the shared namespaces it can write to. The task only runs for links with rules or with
+//! `LIFECYCLE_SWEEP_INTERVAL_SECS`, so lifecycle files only take effect for those links. Expired objects are removed the same way as by
+//! `remove_objects`, so they become noncurrent versions if objects are versioned.
+
+use crate::{blocking, fs_utils, paths, versions};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use tracing::{error, info};
+use wasmbus_rpc::provider::prelude::*;
+
+/// Link value: seconds after the last modification of an object when it is removed
+pub const LIFECYCLE_EXPIRE_SECS: &str = "LIFECYCLE_EXPIRE_SECS";
+
+/// Link value: maximum number of objects in a container; the oldest objects are removed first
+pub const LIFECYCLE_MAX_OBJECTS: &str = "LIFECYCLE_MAX_OBJECTS";
+
+/// Link value: seconds between two sweeps of the actor's containers, 0 to disable lifecycle rules.
+/// Links without it or any rules aren't swept.
+pub const LIFECYCLE_SWEEP_INTERVAL_SECS: &str = "LIFECYCLE_SWEEP_INTERVAL_SECS";
+
+/// Name of the file holding the lifecycle rules of a container
+pub const LIFECYCLE_FILE: &str = ".blobstore-lifecycle.json";
+
+/// time between two sweeps of a link with rules but no interval
+pub const DEFAULT_SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Lifecycle rules of a container
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Lifecycle {
+    /// objects are removed this many seconds after their last modification
+    #[serde(default)]
+    pub expire_after_secs: Option<u64>,
+    /// when the container holds more objects, the least recently modified ones are removed
+    #[serde(default)]
+    pub max_objects: Option<u64>,
+}
+
+impl Lifecycle {
+    /// Reads the rules of the link's values. Without them, no objects expire.
+    pub fn from_values(values: &HashMap<String, String>) -> RpcResult<Lifecycle> {
+        let number = |name: &str| -> RpcResult<Option<u64>> {
+            match values.get(name) {
+                None => Ok(None),
+                Some(v) => v.parse::<u64>().map(Some).map_err(|_| {
+                    RpcError::InvalidParameter(format!("{} must be a number, got '{}'", name, v))
+                }),
+            }
+        };
+        Ok(Lifecycle {
+            expire_after_secs: number(LIFECYCLE_EXPIRE_SECS)?,
+            max_objects: number(LIFECYCLE_MAX_OBJECTS)?,
+        })
+    }
+
+    /// Returns the rules of a container: those of its lifecycle file, if it has one, or `defaults`
+    pub fn of_container(container_dir: &Path, defaults: &Lifecycle) -> std::io::Result<Lifecycle> {
+        match std::fs::read(container_dir.join(LIFECYCLE_FILE)) {
+            Ok(bytes) => serde_json::from_slice(&bytes).map_err(|e| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("invalid {:?}: {}", container_dir.join(LIFECYCLE_FILE), e),
+                )
+            }),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(*defaults),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Returns true if no objects expire by these rules
+    pub fn is_empty(&self) -> bool {
+        self.expire_after_secs.is_none() && self.max_objects.is_none()
+    }
+}
+
+/// Applies lifecycle rules to one container, returning the number of objects removed.
+/// Objects modified before `now` minus the expiry are removed, then the least recently
+/// modified objects beyond the maximum number of objects.
+pub fn apply(
+    container_dir: &Path,
+    rules: &Lifecycle,
+    versioning: Option<&versions::Retention>,
+    blob_store: Option<&Path>,
+    now: SystemTime,
+) -> std::io::Result<usize> {
+    if rules.is_empty() {
+        return Ok(0);
+    }
+    let mut objects = Vec::new();
+    for object_id in fs_utils::object_keys(container_dir, "")? {
+        // the object may have been removed since it was listed
+        if let Ok(modified) =
+            std::fs::metadata(container_dir.join(&object_id)).and_then(|m| m.modified())
+        {
+            objects.push((modified, object_id));
+        }
+    }
+    // oldest first
+    objects.sort();
+
+    let expired = match rules.expire_after_secs {
+        Some(secs) => {
+            let cutoff = now
+                .checked_sub(Duration::from_secs(secs))
+                .unwrap_or(SystemTime::UNIX_EPOCH);
+            objects.partition_point(|(modified, _)| *modified <= cutoff)
+        }
+        None => 0,
+    };
+    let evicted = match rules.max_objects {
+        Some(max) => objects.len().saturating_sub(max as usize),
+        None => 0,
+    };
+
+    let mut removed = 0;
+    for (_, object_id) in objects.iter().take(expired.max(evicted)) {
+        match fs_utils::remove_object(container_dir, object_id, versioning, blob_store) {
+            Ok(()) => removed += 1,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => error!(
+                "Could not remove expired object {:?}: {}",
+                container_dir.join(object_id),
+                e
+            ),
+        }
+    }
+    Ok(removed)
+}
+
+/// The containers of one actor swept by a `LifecycleSweeper`
+#[derive(Clone, Debug)]
+pub struct Sweep {
+    /// the actor's directory, whose top-level directories are containers
+    pub actor_dir: PathBuf,
+    /// shared namespaces the actor can write to, which are containers themselves
+    pub namespace_dirs: Vec<PathBuf>,
+    /// rules of the actor's containers without a lifecycle file
+    pub defaults: Lifecycle,
+    pub versioning: Option<versions::Retention>,
+    pub blob_store: Option<PathBuf>,
+}
+
+impl Sweep {
+    /// Applies the lifecycle rules of every container, returning the number of objects removed.
+    /// A container whose rules can't be read or applied is skipped, so it doesn't stop the sweep
+    /// of the others.
+    pub fn run(&self, now: SystemTime) -> std::io::Result<usize> {
+        let mut removed = 0;
+        for (container_dir, shared) in self.containers()? {
+            // containers of shared namespaces only have the rules of their lifecycle files
+            let defaults = if shared {
+                Lifecycle::default()
+            } else {
+                self.defaults
+            };
+            let rules = match Lifecycle::of_container(&container_dir, &defaults) {
+                Ok(rules) => rules,
+                Err(e) => {
+                    error!("Skipping lifecycle rules of {:?}: {}", container_dir, e);
+                    continue;
+                }
+            };
+            match apply(
+                &container_dir,
+                &rules,
+                self.versioning.as_ref(),
+                self.blob_store.as_deref(),
+                now,
+            ) {
+                Ok(n) => removed += n,
+                Err(e) => {
+                    error!(
+                        "Could not apply lifecycle rules of {:?}: {}",
+                        container_dir, e
+                    );
+                    continue;
+                }
+            }
+        }
+        Ok(removed)
+    }
+
+    /// Returns the directories of the containers: top-level directories of the actor's directory,
+    /// the shared namespaces, and the containers nested in them. Each directory comes with
+    /// whether it is in a shared namespace.
+    fn containers(&self) -> std::io::Result<Vec<(PathBuf, bool)>> {
+        let mut containers = Vec::new();
+        for (dir, shared) in std::iter::once((&self.actor_dir, false))
+            .chain(self.namespace_dirs.iter().map(|dir| (dir, true)))
+        {
+            if !dir.is_dir() {
+                continue;
+            }
+            // the top-level directories of the actor's directory are containers,
+            // while a shared namespace is a container itself
+            if shared {
+                containers.push((dir.clone(), shared));
+            }
+            for sub in fs_utils::all_dirs(dir, dir)? {
+                if paths::is_reserved(&sub) {
+                    continue;
+                }
+                let is_top_level = sub.components().count() == 1;
+                if (!shared && is_top_level) || fs_utils::is_container(&dir.join(&sub)) {
+                    containers.push((dir.join(sub), shared));
+                }
+            }
+        }
+        Ok(containers)
+    }
+}
+
+/// Periodically applies the lifecycle rules to the containers of one actor.
+/// Dropping it stops the task.
+pub struct LifecycleSweeper {
+    task: tokio::task::JoinHandle<()>,
+}
+
+impl Drop for LifecycleSweeper {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+impl LifecycleSweeper {
+    /// Starts sweeping every `interval`
+    pub fn start(io: blocking::BlockingPool, sweep: Sweep, interval: Duration) -> Self {
+        let task = tokio::spawn(async move {
+            let mut interval = tokio::time::interval(interval);
+            loop {
+                interval.tick().await;
+                let sweep = sweep.clone();
+                match io.run(move || Ok(sweep.run(SystemTime::now())?)).await {
+                    Ok(0) => {}
+                    Ok(removed) => info!("Removed {} expired objects", removed),
+                    Err(e) => error!("Could not apply lifecycle rules: {}", e),
+                }
+            }
+        });
+        LifecycleSweeper { task }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs_utils::clear_state;
+    use std::fs::create_dir_all;
+
+    fn names(list: &[&str]) -> Vec<String> {
+        list.iter().map(|n| n.to_string()).collect()
+    }
+
+    #[test]
+    fn lifecycle_file_is_reserved() {
+        assert!(LIFECYCLE_FILE.starts_with(paths::RESERVED_PREFIX));
+    }
+
+    #[test]
+    fn parse_values() {
+        let mut values = HashMap::new();
+        assert_eq!(
+            Lifecycle::from_values(&values).unwrap(),
+            Lifecycle::default()
+        );
+
+        values.insert(LIFECYCLE_EXPIRE_SECS.to_string(), "86400".to_string());
+        values.insert(LIFECYCLE_MAX_OBJECTS.to_string(), "100".to_string());
+        assert_eq!(
+            Lifecycle::from_values(&values).unwrap(),
+            Lifecycle {
+                expire_after_secs: Some(86400),
+                max_objects: Some(100),
+            }
+        );
+
+        values.insert(LIFECYCLE_MAX_OBJECTS.to_string(), "many".to_string());
+        assert!(Lifecycle::from_values(&values).is_err());
+    }
+
+    #[test]
+    fn expire_and_evict() {
+        let cdir = Path::new("/tmp/rust_test/lifecycle1");
+        create_dir_all(cdir.join("folder")).unwrap();
+        for object_id in ["a", "folder/b", "c", "d"] {
+            std::fs::write(cdir.join(object_id), object_id).unwrap();
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        let now = SystemTime::now();
+        let keep_all = Lifecycle {
+            expire_after_secs: Some(3600),
+            max_objects: Some(10),
+        };
+        let evict = Lifecycle {
+            expire_after_secs: Some(3600),
+            max_objects: Some(3),
+        };
+        let expire = Lifecycle {
+            expire_after_secs: Some(60),
+            max_objects: None,
+        };
+
+        let removed_none = apply(cdir, &keep_all, None, None, now).unwrap();
+        let removed_oldest = apply(cdir, &evict, None, None, now).unwrap();
+        let after_evict = fs_utils::object_keys(cdir, "").unwrap();
+        let removed_expired =
+            apply(cdir, &expire, None, None, now + Duration::from_secs(120)).unwrap();
+        let after_expire = fs_utils::object_keys(cdir, "").unwrap();
+        let folder_removed = !cdir.join("folder").exists();
+
+        clear_state(cdir);
+
+        assert_eq!(removed_none, 0);
+        assert_eq!(removed_oldest, 1);
+        assert_eq!(after_evict, names(&["c", "d", "folder/b"]));
+        assert_eq!(removed_expired, 3);
+        assert!(after_expire.is_empty());
+        assert!(folder_removed);
+    }
+
+    #[test]
+    fn container_files_replace_link_rules() {
+        let root = Path::new("/tmp/rust_test/lifecycle2");
+        let actor_dir = root.join("actor");
+        let namespace_dir = root.join(".blobstore-shared/team");
+        let expiring_namespace_dir = root.join(".blobstore-shared/scratch");
+        for dir in ["cont1", "cont2", "cont1/nested"] {
+            fs_utils::create_container(&actor_dir.join(dir)).unwrap();
+            std::fs::write(actor_dir.join(dir).join("obj"), b"data").unwrap();
+        }
+        create_dir_all(&namespace_dir).unwrap();
+        std::fs::write(namespace_dir.join("obj"), b"data").unwrap();
+        fs_utils::create_container(&namespace_dir.join("nested")).unwrap();
+        std::fs::write(namespace_dir.join("nested/obj"), b"data").unwrap();
+        // the link's rules don't apply to shared namespaces, only their own files do
+        create_dir_all(&expiring_namespace_dir).unwrap();
+        std::fs::write(expiring_namespace_dir.join("obj"), b"data").unwrap();
+        std::fs::write(
+            expiring_namespace_dir.join(LIFECYCLE_FILE),
+            br#"{"expire_after_secs": 60}"#,
+        )
+        .unwrap();
+        // cont2 is exempt, cont1/nested keeps objects for longer
+        std::fs::write(actor_dir.join("cont2").join(LIFECYCLE_FILE), b"{}").unwrap();
+        std::fs::write(
+            actor_dir.join("cont1/nested").join(LIFECYCLE_FILE),
+            br#"{"expire_after_secs": 86400}"#,
+        )
+        .unwrap();
+        let sweep = Sweep {
+            actor_dir: actor_dir.clone(),
+            namespace_dirs: vec![namespace_dir.clone(), expiring_namespace_dir.clone()],
+            defaults: Lifecycle {
+                expire_after_secs: Some(60),
+                max_objects: None,
+            },
+            versioning: None,
+            blob_store: None,
+        };
+
+        let removed = sweep
+            .run(SystemTime::now() + Duration::from_secs(120))
+            .unwrap();
+        let remaining = ["cont1/obj", "cont2/obj", "cont1/nested/obj"]
+            .map(|object| actor_dir.join(object).exists());
+        let shared_remaining = [
+            namespace_dir.join("obj"),
+            namespace_dir.join("nested/obj"),
+            expiring_namespace_dir.join("obj"),
+        ]
+        .map(|object| object.exists());
+
+        clear_state(root);
+
+        assert_eq!(removed, 2);
+        assert_eq!(remaining, [false, true, true]);
+        assert_eq!(shared_remaining, [true, true, false]);
+    }
+}
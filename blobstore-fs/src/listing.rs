@@ -0,0 +1,205 @@
+//! Listing objects by prefix, with a delimiter.
+//!
+//! `list_objects` returns every object of a container with its full id, like an S3 listing
+//! without a delimiter. To browse folders, actors call `BlobstoreListing.ListObjectsByPrefix`,
+//! which works like S3's ListObjectsV2 with `Prefix` and `Delimiter`: only objects whose ids
+//! start with the prefix are returned, and ids that contain the delimiter after the prefix are
+//! rolled up into a single common prefix, e.g., `images/` for `images/2023/a.png` and
+//! `images/logo.png` when listing with the delimiter `/` and no prefix. Common prefixes and
+//! objects share one sorted sequence, so they are paged together.
+
+use crate::fs_utils::page_names;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use wasmbus_rpc::common::{deserialize, serialize};
+use wasmbus_rpc::provider::prelude::*;
+use wasmcloud_interface_blobstore::{ListObjectsRequest, ObjectMetadata};
+
+/// Argument of `BlobstoreListing.ListObjectsByPrefix`
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ListByPrefixRequest {
+    pub container_id: String,
+    /// only objects whose ids start with this prefix are listed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prefix: Option<String>,
+    /// ids containing the delimiter after the prefix are listed as common prefixes, usually `/`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub delimiter: Option<String>,
+    /// token returned with the previous page
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub continuation: Option<String>,
+    /// maximum number of objects and common prefixes returned
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_items: Option<u32>,
+}
+
+/// Result of `BlobstoreListing.ListObjectsByPrefix`
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ListByPrefixResponse {
+    pub objects: Vec<ObjectMetadata>,
+    /// common prefixes, each ending with the delimiter, sorted
+    pub common_prefixes: Vec<String>,
+    pub is_last: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub continuation: Option<String>,
+}
+
+/// Listing operations, an extension of the blobstore interface.
+/// Actors call them as `BlobstoreListing.<Operation>` on the blobstore link.
+#[async_trait]
+pub trait BlobstoreListing {
+    /// Lists the objects and common prefixes of a container below a prefix
+    async fn list_objects_by_prefix(
+        &self,
+        ctx: &Context,
+        arg: &ListByPrefixRequest,
+    ) -> RpcResult<ListByPrefixResponse>;
+}
+
+/// Receives `BlobstoreListing` messages, like the receivers generated for smithy interfaces
+#[async_trait]
+pub trait BlobstoreListingReceiver: MessageDispatch + BlobstoreListing {
+    async fn dispatch(&self, ctx: &Context, message: Message<'_>) -> RpcResult<Vec<u8>> {
+        match message.method {
+            "ListObjectsByPrefix" => {
+                let value: ListByPrefixRequest = deserialize(&message.arg)
+                    .map_err(|e| RpcError::Deser(format!("'ListByPrefixRequest': {}", e)))?;
+                let resp = BlobstoreListing::list_objects_by_prefix(self, ctx, &value).await?;
+                serialize(&resp)
+            }
+            _ => Err(RpcError::MethodNotHandled(format!(
+                "BlobstoreListing::{}",
+                message.method
+            ))),
+        }
+    }
+}
+
+/// Returns the folder to search for the objects with a prefix: the part of the
+/// prefix up to its last `/`, e.g., `images/2023` for `images/2023/a`
+pub fn prefix_folder(prefix: &str) -> &str {
+    match prefix.rfind('/') {
+        Some(end) => &prefix[..end],
+        None => "",
+    }
+}
+
+/// One page of a listing by prefix
+#[derive(Debug, Default)]
+pub struct Page {
+    pub object_ids: Vec<String>,
+    pub common_prefixes: Vec<String>,
+    pub continuation: Option<String>,
+}
+
+/// Selects the page of a listing by prefix from the sorted ids of a container's objects
+pub fn page(ids: &[String], req: &ListByPrefixRequest) -> RpcResult<Page> {
+    let prefix = req.prefix.as_deref().unwrap_or_default();
+    let delimiter = req.delimiter.as_deref().filter(|d| !d.is_empty());
+    let mut names = Vec::new();
+    let mut common_prefixes = BTreeSet::new();
+    for id in ids.iter().filter(|id| id.starts_with(prefix)) {
+        let rolled_up = delimiter.and_then(|d| {
+            id[prefix.len()..]
+                .find(d)
+                .map(|pos| &id[..prefix.len() + pos + d.len()])
+        });
+        match rolled_up {
+            Some(common) => {
+                if common_prefixes.insert(common.to_string()) {
+                    names.push(common.to_string());
+                }
+            }
+            None => names.push(id.clone()),
+        }
+    }
+    names.sort();
+    let (page, continuation) = page_names(
+        &names,
+        &ListObjectsRequest {
+            container_id: req.container_id.clone(),
+            continuation: req.continuation.clone(),
+            max_items: req.max_items,
+            ..Default::default()
+        },
+    )?;
+    let (prefixes, object_ids): (Vec<String>, Vec<String>) = page
+        .iter()
+        .cloned()
+        .partition(|name| common_prefixes.contains(name));
+    Ok(Page {
+        object_ids,
+        common_prefixes: prefixes,
+        continuation,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(list: &[&str]) -> Vec<String> {
+        list.iter().map(|n| n.to_string()).collect()
+    }
+
+    fn request(prefix: Option<&str>, delimiter: Option<&str>) -> ListByPrefixRequest {
+        ListByPrefixRequest {
+            container_id: "cont".to_string(),
+            prefix: prefix.map(String::from),
+            delimiter: delimiter.map(String::from),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn folder_of_prefix() {
+        assert_eq!(prefix_folder("images/2023/a"), "images/2023");
+        assert_eq!(prefix_folder("images/"), "images");
+        assert_eq!(prefix_folder("ima"), "");
+    }
+
+    #[test]
+    fn common_prefixes() {
+        let ids = names(&[
+            "a.txt",
+            "images/2023/a.png",
+            "images/2023/b.png",
+            "images/logo.png",
+            "videos/intro.mp4",
+        ]);
+
+        let top = page(&ids, &request(None, Some("/"))).unwrap();
+        let images = page(&ids, &request(Some("images/"), Some("/"))).unwrap();
+        let no_delimiter = page(&ids, &request(Some("images/"), None)).unwrap();
+        let partial = page(&ids, &request(Some("im"), Some("/"))).unwrap();
+
+        assert_eq!(top.object_ids, names(&["a.txt"]));
+        assert_eq!(top.common_prefixes, names(&["images/", "videos/"]));
+        assert_eq!(images.object_ids, names(&["images/logo.png"]));
+        assert_eq!(images.common_prefixes, names(&["images/2023/"]));
+        assert_eq!(
+            no_delimiter.object_ids,
+            names(&["images/2023/a.png", "images/2023/b.png", "images/logo.png"])
+        );
+        assert!(no_delimiter.common_prefixes.is_empty());
+        assert_eq!(partial.common_prefixes, names(&["images/"]));
+        assert!(partial.object_ids.is_empty());
+    }
+
+    #[test]
+    fn prefixes_and_objects_paged_together() {
+        let ids = names(&["a", "b/1", "b/2", "c", "d/1"]);
+        let mut req = request(None, Some("/"));
+        req.max_items = Some(2);
+
+        let first = page(&ids, &req).unwrap();
+        req.continuation = first.continuation.clone();
+        let second = page(&ids, &req).unwrap();
+
+        assert_eq!(first.object_ids, names(&["a"]));
+        assert_eq!(first.common_prefixes, names(&["b/"]));
+        assert_eq!(second.object_ids, names(&["c"]));
+        assert_eq!(second.common_prefixes, names(&["d/"]));
+        assert!(second.continuation.is_none());
+    }
+}
@@ -4,29 +4,56 @@
 
 #[allow(unused_imports)]
 use serde::Deserialize;
-use std::time::SystemTime;
+use std::time::{Duration, Instant, SystemTime};
 #[allow(unused_imports)]
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     fs::OpenOptions,
     fs::{metadata, read, read_dir, remove_file, File},
-    io::{BufReader, Write},
+    io::{BufReader, Read, Seek, SeekFrom, Write},
     path::{Path, PathBuf},
+    sync::atomic::{AtomicBool, Ordering},
     sync::Arc,
 };
-use tokio::sync::RwLock;
-use tracing::{error, info};
+use tokio::sync::{Mutex, OwnedMutexGuard, RwLock};
+use tracing::{error, info, warn};
 use wasmbus_rpc::provider::prelude::*;
 use wasmbus_rpc::Timestamp;
 use wasmcloud_interface_blobstore::*;
+mod blobs;
+mod blocking;
+mod codec;
 mod fs_utils;
-pub use fs_utils::all_dirs;
+mod lifecycle;
+mod listing;
+mod paths;
+mod quota;
+mod shared;
+mod sidecar;
+mod upload;
+mod versions;
+mod watch;
+use blobs::{BlobstoreHashes, BlobstoreHashesReceiver};
+use codec::ObjectReader;
+pub use fs_utils::{all_dirs, page_names};
+use listing::{BlobstoreListing, BlobstoreListingReceiver};
+use quota::{BlobstoreQuota, BlobstoreQuotaReceiver};
+use shared::Access;
+use sidecar::{BlobstoreMetadata, BlobstoreMetadataReceiver};
+use upload::Upload;
+use versions::{BlobstoreVersions, BlobstoreVersionsReceiver};
 
 #[allow(unused)]
 const CAPABILITY_ID: &str = "wasmcloud:blobstore";
 #[allow(unused)]
 const FIRST_SEQ_NBR: u64 = 0;
 
+/// maximum number of bytes returned to the actor in a single chunk (512KB)
+const MAX_CHUNK_SIZE: usize = 512 * 1024;
+
+/// how often the reaper looks for abandoned uploads
+const REAPER_INTERVAL: Duration = Duration::from_secs(30);
+
 // main (via provider_main) initializes the threaded tokio executor,
 // listens to lattice rpcs, handles actor links,
 // and returns only when it receives a shutdown message
@@ -43,16 +70,59 @@ pub type ChunkOffsetKey = (String, usize);
 struct FsProviderConfig {
     ld: LinkDefinition,
     root: PathBuf,
+    /// whether symbolic links below the actor's directory may be followed
+    /// (links are never followed outside of the actor's directory)
+    follow_symlinks: bool,
+    /// time after which an upload that has not received a chunk is removed
+    upload_timeout: Duration,
+    /// storage limits of the actor
+    quota: quota::Quota,
+    /// shared namespaces mounted into the actor's container space, by name
+    shared: HashMap<String, shared::Mode>,
+    /// whether object contents are stored once in the content-addressed blob store
+    dedup: bool,
+    /// compression and encryption of stored objects, if enabled
+    #[serde(skip)]
+    codec: Option<codec::Codec>,
+    /// retention policy of object versions, if objects are versioned
+    versioning: Option<versions::Retention>,
+    /// lifecycle rules of containers without a lifecycle file
+    lifecycle: lifecycle::Lifecycle,
+}
+
+impl FsProviderConfig {
+    /// Returns the directories of the shared namespaces the actor can write to
+    fn writable_namespace_dirs(&self) -> Vec<PathBuf> {
+        self.shared
+            .iter()
+            .filter(|(_, mode)| **mode == shared::Mode::ReadWrite)
+            .map(|(name, _)| shared::namespace_dir(&self.root, name))
+            .collect()
+    }
 }
 
 /// fs capability provider implementation
 #[allow(dead_code)]
 #[derive(Clone, Provider)]
-#[services(Blobstore)]
+#[services(
+    Blobstore,
+    BlobstoreVersions,
+    BlobstoreListing,
+    BlobstoreQuota,
+    BlobstoreHashes,
+    BlobstoreMetadata
+)]
 struct FsProvider {
     config: Arc<RwLock<HashMap<String, FsProviderConfig>>>,
-    upload_chunks: Arc<RwLock<HashMap<String, u64>>>, // kee track of the next offset for chunks to be uploaded
+    upload_chunks: Arc<RwLock<HashMap<String, Arc<Mutex<Upload>>>>>, // uploads in progress, by stream id
+    accounts: Arc<RwLock<HashMap<String, Arc<Mutex<quota::Account>>>>>, // storage usage, by actor id
     download_chunks: Arc<RwLock<HashMap<ChunkOffsetKey, Chunk>>>,
+    reaper_started: Arc<AtomicBool>,
+    watchers: Arc<RwLock<HashMap<String, watch::ObjectWatcher>>>, // file watchers, by actor id
+    retention_sweepers: Arc<RwLock<HashMap<String, versions::RetentionSweeper>>>, // by actor id
+    lifecycle_sweepers: Arc<RwLock<HashMap<String, lifecycle::LifecycleSweeper>>>, // by actor id
+    blob_gc_roots: Arc<RwLock<HashSet<PathBuf>>>, // roots with a blob garbage collection task
+    io: blocking::BlockingPool,                   // runs file system operations off the executor
 }
 
 impl Default for FsProvider {
@@ -60,7 +130,14 @@ impl Default for FsProvider {
         FsProvider {
             config: Arc::new(RwLock::new(HashMap::new())),
             upload_chunks: Arc::new(RwLock::new(HashMap::new())),
+            accounts: Arc::new(RwLock::new(HashMap::new())),
             download_chunks: Arc::new(RwLock::new(HashMap::new())),
+            reaper_started: Arc::new(AtomicBool::new(false)),
+            watchers: Arc::new(RwLock::new(HashMap::new())),
+            retention_sweepers: Arc::new(RwLock::new(HashMap::new())),
+            lifecycle_sweepers: Arc::new(RwLock::new(HashMap::new())),
+            blob_gc_roots: Arc::new(RwLock::new(HashSet::new())),
+            io: blocking::BlockingPool::default(),
         }
     }
 }
@@ -105,86 +182,527 @@ impl FsProvider {
                 )));
             }
         };
+        paths::validate_id("actor", &actor_id)?;
         root.push(actor_id.clone());
         Ok(root)
     }
 
-    /// Stores a file chunk in right order.
-    async fn store_chunk(
+    /// Returns the directory and mode of the shared namespace that a container id refers to,
+    /// or None if the container belongs to the actor
+    async fn shared_namespace(
         &self,
         ctx: &Context,
-        chunk: &Chunk,
-        stream_id: &Option<String>,
-    ) -> RpcResult<()> {
+        container_id: &str,
+    ) -> RpcResult<Option<(PathBuf, shared::Mode)>> {
+        let actor_id = self.get_actor_id(ctx).await?;
+        let (name, _) = shared::split_container_id(container_id);
+        Ok(match self.config.read().await.get(&actor_id) {
+            Some(config) => config
+                .shared
+                .get(name)
+                .map(|mode| (shared::namespace_dir(&config.root, name), *mode)),
+            None => None,
+        })
+    }
+
+    /// Resolves actor-supplied ids to a path under the actor's root directory, or under
+    /// a shared namespace mounted for the actor, rejecting ids that would escape it.
+    /// See `paths::resolve`. Writing to a read-only shared namespace is rejected.
+    async fn resolve_path(
+        &self,
+        ctx: &Context,
+        ids: &[(&str, &str)],
+        access: Access,
+    ) -> RpcResult<PathBuf> {
         let root = self.get_root(ctx).await?;
-        let cdir = Path::new(&root).join(&chunk.container_id);
-        let bfile = Path::join(&cdir, &chunk.object_id);
-
-        // create an empty file if it's the first chunk
-        if chunk.offset == 0 {
-            let resp = File::create(&bfile);
-            if resp.is_err() {
-                let error_string = format!("Could not create file: {:?}", bfile).to_string();
-                error!("{:?}", &error_string);
-                return Err(RpcError::InvalidParameter(error_string));
-            }
-            if let Some(s_id) = stream_id {
-                let mut upload_chunks = self.upload_chunks.write().await;
-                let next_offset: u64 = 0;
-                upload_chunks.insert(s_id.clone(), next_offset);
-            } else if !chunk.is_last {
-                return Err(RpcError::InvalidParameter(format!(
-                    "Chunked storage is missing stream id"
-                )));
+        let actor_id = self.get_actor_id(ctx).await?;
+        let follow_symlinks = match self.config.read().await.get(&actor_id) {
+            Some(config) => config.follow_symlinks,
+            None => false,
+        };
+        if let Some(&(kind, container_id)) = ids.first() {
+            if let Some((namespace_dir, mode)) = self.shared_namespace(ctx, container_id).await? {
+                paths::validate_id(kind, container_id)?;
+                let (name, rest) = shared::split_container_id(container_id);
+                shared::check_access(name, mode, access)?;
+                // the rest of the container id is resolved inside the shared namespace
+                let mut shared_ids = Vec::with_capacity(ids.len());
+                if !rest.is_empty() {
+                    shared_ids.push((kind, rest));
+                }
+                shared_ids.extend_from_slice(&ids[1..]);
+                return self
+                    .resolve_below(namespace_dir, &shared_ids, follow_symlinks)
+                    .await;
             }
         }
+        self.resolve_below(root, ids, follow_symlinks).await
+    }
 
-        // for continuing chunk storage, check that the chunk's offset matches the expected next one
-        // which it should as theput_object calls are generated by an actor.
-        if let Some(s_id) = stream_id {
-            let mut upload_chunks = self.upload_chunks.write().await;
-            let expected_offset = upload_chunks.get(s_id).unwrap();
-            if *expected_offset != chunk.offset {
-                return Err(RpcError::InvalidParameter(format!(
-                    "Chunk offset {} not the same as the expected offset: {}",
-                    chunk.offset, *expected_offset
-                )));
+    /// Runs `paths::resolve`, which checks the existing part of the path for symbolic links,
+    /// on the blocking pool
+    async fn resolve_below(
+        &self,
+        root: PathBuf,
+        ids: &[(&str, &str)],
+        follow_symlinks: bool,
+    ) -> RpcResult<PathBuf> {
+        let ids: Vec<(String, String)> = ids
+            .iter()
+            .map(|(kind, id)| (kind.to_string(), id.to_string()))
+            .collect();
+        self.io
+            .run(move || {
+                let ids: Vec<(&str, &str)> = ids
+                    .iter()
+                    .map(|(kind, id)| (kind.as_str(), id.as_str()))
+                    .collect();
+                paths::resolve(&root, &ids, follow_symlinks)
+            })
+            .await
+    }
+
+    /// Returns the directory of a container
+    async fn container_path(
+        &self,
+        ctx: &Context,
+        container_id: &str,
+        access: Access,
+    ) -> RpcResult<PathBuf> {
+        self.resolve_path(ctx, &[("container", container_id)], access)
+            .await
+    }
+
+    /// Returns the file path of an object
+    async fn object_path(
+        &self,
+        ctx: &Context,
+        container_id: &str,
+        object_id: &str,
+        access: Access,
+    ) -> RpcResult<PathBuf> {
+        self.resolve_path(
+            ctx,
+            &[("container", container_id), ("object", object_id)],
+            access,
+        )
+        .await
+    }
+
+    /// Returns the blob store holding the actor's objects, or None if they are not deduplicated
+    async fn blob_store(&self, ctx: &Context) -> RpcResult<Option<PathBuf>> {
+        let actor_id = self.get_actor_id(ctx).await?;
+        Ok(match self.config.read().await.get(&actor_id) {
+            Some(config) if config.dedup => Some(blobs::store_dir(&config.root)),
+            _ => None,
+        })
+    }
+
+    /// Returns the retention policy of the actor's object versions, or None if they are not versioned
+    async fn versioning(&self, ctx: &Context) -> RpcResult<Option<versions::Retention>> {
+        let actor_id = self.get_actor_id(ctx).await?;
+        Ok(match self.config.read().await.get(&actor_id) {
+            Some(config) => config.versioning,
+            None => None,
+        })
+    }
+
+    /// Starts the background task that removes unreferenced blobs from the blob store
+    /// of `root`. Only one task is started per root.
+    async fn start_blob_gc(&self, root: &Path, interval: Duration) {
+        if !self.blob_gc_roots.write().await.insert(root.to_path_buf()) {
+            return;
+        }
+        let store = blobs::store_dir(root);
+        let io = self.io.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(interval);
+            loop {
+                interval.tick().await;
+                let store = store.clone();
+                if let Err(e) = io.run(move || Ok(blobs::collect_garbage(&store))).await {
+                    error!("Blob garbage collection failed: {}", e);
+                }
             }
+        });
+    }
+
+    /// Returns the storage limits of the actor
+    async fn get_quota(&self, ctx: &Context) -> RpcResult<quota::Quota> {
+        let actor_id = self.get_actor_id(ctx).await?;
+        match self.config.read().await.get(&actor_id) {
+            Some(config) => Ok(config.quota),
+            None => Ok(quota::Quota::default()),
+        }
+    }
 
-            // Update the next expected offset
-            let next_offset = if chunk.is_last {
-                0u64
-            } else {
-                chunk.offset + chunk.bytes.len() as u64
+    /// Returns the storage usage account of an actor
+    async fn account(&self, actor_id: &str) -> Arc<Mutex<quota::Account>> {
+        if let Some(account) = self.accounts.read().await.get(actor_id) {
+            return account.clone();
+        }
+        self.accounts
+            .write()
+            .await
+            .entry(actor_id.to_string())
+            .or_default()
+            .clone()
+    }
+
+    /// Locks the actor's usage account, measuring the usage of its stored objects if needed.
+    /// Uploads are checked while the account is locked, so their reservations don't race.
+    async fn locked_account(&self, ctx: &Context) -> RpcResult<OwnedMutexGuard<quota::Account>> {
+        let actor_id = self.get_actor_id(ctx).await?;
+        let mut account = self.account(&actor_id).await.lock_owned().await;
+        let now = Instant::now();
+        if account.stored(now).is_none() {
+            let root = self.get_root(ctx).await?;
+            // objects the actor stored in shared namespaces count towards its usage
+            let namespace_dirs = match self.config.read().await.get(&actor_id) {
+                Some(config) => config.writable_namespace_dirs(),
+                None => Vec::new(),
             };
-            upload_chunks.insert(s_id.clone(), next_offset);
+            let usage = self
+                .io
+                .run(move || {
+                    let mut usage = quota::usage(&root)?;
+                    for dir in namespace_dirs {
+                        let owned = quota::owned_usage(&dir, &actor_id)?;
+                        usage.bytes += owned.bytes;
+                        usage.objects += owned.objects;
+                    }
+                    Ok(usage)
+                })
+                .await?;
+            account.measured(usage, now);
         }
+        Ok(account)
+    }
 
-        let bpath = Path::join(&Path::join(&root, &chunk.container_id), &chunk.object_id);
+    /// Checks that storing an object of `size` bytes would not exceed the actor's quota,
+    /// and reserves the space for upload `upload_id` until it is committed or aborted.
+    async fn reserve_quota(
+        &self,
+        ctx: &Context,
+        upload_id: &str,
+        container_id: &str,
+        object_id: &str,
+        size: u64,
+    ) -> RpcResult<()> {
+        let quota = self.get_quota(ctx).await?;
+        quota.check_object_size(object_id, size)?;
+        if quota.limits_usage() {
+            let actor_id = self.get_actor_id(ctx).await?;
+            // in a shared namespace, the object being replaced only counts if the actor stored it
+            let is_shared = self.shared_namespace(ctx, container_id).await?.is_some();
+            let cdir = self.container_path(ctx, container_id, Access::Read).await?;
+            let object_path = self
+                .object_path(ctx, container_id, object_id, Access::Read)
+                .await?;
+            let oid = object_id.to_string();
+            let replaced_size = self
+                .io
+                .run(move || {
+                    Ok(metadata(&object_path)
+                        .ok()
+                        .filter(|m| m.is_file())
+                        .filter(|_| !is_shared || is_owner(&cdir, &oid, &actor_id))
+                        .map(|m| m.len()))
+                })
+                .await?;
+            self.locked_account(ctx).await?.reserve(
+                &quota,
+                upload_id,
+                object_id,
+                size,
+                replaced_size,
+            )?;
+        }
+        Ok(())
+    }
 
-        let mut file = OpenOptions::new().create(false).append(true).open(bpath)?;
-        info!(
-            "Receiving file chunk offset {} for {}/{}, size {}",
-            chunk.offset,
-            chunk.container_id,
-            chunk.object_id,
-            chunk.bytes.len()
+    /// Updates the actor's usage once an upload has been committed: the space reserved
+    /// for it is counted as stored if the object was stored, and released otherwise
+    async fn settle_reservation(
+        &self,
+        actor_id: &str,
+        upload_id: &str,
+        stored: RpcResult<u64>,
+    ) -> RpcResult<()> {
+        let versioned = matches!(
+            self.config.read().await.get(actor_id),
+            Some(config) if config.versioning.is_some()
         );
+        let account = self.account(actor_id).await;
+        let mut account = account.lock().await;
+        match stored {
+            Ok(stored_len) => {
+                account.commit(upload_id, Some(stored_len), versioned);
+                Ok(())
+            }
+            Err(e) => {
+                account.release(upload_id);
+                Err(e)
+            }
+        }
+    }
 
-        let count = file.write(chunk.bytes.as_ref())?;
-        if count != chunk.bytes.len() {
-            let msg = format!(
-                "Failed to fully write chunk: {} of {} bytes",
-                count,
-                chunk.bytes.len()
-            );
-            error!("{}", &msg);
-            return Err(msg.into());
+    /// Stores the first chunk of an object sent with put_object, with the metadata to store
+    /// with the object. If it is the only chunk, the object replaces any previous version;
+    /// otherwise the upload continues with put_chunk, using the returned stream id.
+    async fn put_with_sidecar(
+        &self,
+        ctx: &Context,
+        chunk: &Chunk,
+        object_sidecar: sidecar::ObjectSidecar,
+    ) -> RpcResult<PutObjectResponse> {
+        if chunk.bytes.is_empty() {
+            error!("put_object with zero bytes");
+            return Err(RpcError::InvalidParameter(
+                "cannot put zero-length objects".to_string(),
+            ));
         }
 
+        let mut upload = self.begin_upload(ctx, chunk, object_sidecar).await?;
+        if let Err(e) = self
+            .reserve_quota(
+                ctx,
+                &upload.id,
+                &chunk.container_id,
+                &chunk.object_id,
+                chunk.offset + chunk.bytes.len() as u64,
+            )
+            .await
+        {
+            self.io
+                .run(move || {
+                    upload.abort();
+                    Ok(())
+                })
+                .await?;
+            return Err(e);
+        }
+        let (actor_id, upload_id) = (upload.actor_id.clone(), upload.id.clone());
+        let chunk = chunk.clone();
+        let written = self
+            .io
+            .run(move || match upload.write_chunk(&chunk) {
+                Ok(complete) => Ok((upload, complete)),
+                Err(e) => {
+                    upload.abort();
+                    Err(e)
+                }
+            })
+            .await;
+        let (mut upload, complete) = match written {
+            Ok(written) => written,
+            Err(e) => {
+                self.account(&actor_id)
+                    .await
+                    .lock()
+                    .await
+                    .release(&upload_id);
+                return Err(e);
+            }
+        };
+
+        // the object, with its metadata, replaces any previous version
+        // only when all chunks have been received. Each upload has its own stream id,
+        // so several uploads of the same object can be in progress at the same time.
+        let stream_id = if complete {
+            let stored = self.io.run(move || upload.commit()).await;
+            self.settle_reservation(&actor_id, &upload_id, stored)
+                .await?;
+            None
+        } else {
+            self.upload_chunks
+                .write()
+                .await
+                .insert(upload_id.clone(), Arc::new(Mutex::new(upload)));
+            Some(upload_id)
+        };
+
+        Ok(PutObjectResponse { stream_id })
+    }
+
+    /// Starts an upload of the object in `chunk`, which was sent with put_object.
+    /// The chunks are written to a staging file, which replaces the object when
+    /// the upload is committed.
+    async fn begin_upload(
+        &self,
+        ctx: &Context,
+        chunk: &Chunk,
+        mut sidecar: sidecar::ObjectSidecar,
+    ) -> RpcResult<Upload> {
+        let actor_id = self.get_actor_id(ctx).await?;
+        let cdir = self
+            .container_path(ctx, &chunk.container_id, Access::Write)
+            .await?;
+        // objects in shared namespaces count towards the quota of the actor that stored them
+        if self
+            .shared_namespace(ctx, &chunk.container_id)
+            .await?
+            .is_some()
+        {
+            sidecar.owner = Some(actor_id.clone());
+        }
+        let object_path = self
+            .object_path(ctx, &chunk.container_id, &chunk.object_id, Access::Write)
+            .await?;
+        let upload_timeout = match self.config.read().await.get(&actor_id) {
+            Some(config) => config.upload_timeout,
+            None => upload::DEFAULT_UPLOAD_TIMEOUT,
+        };
+        let (container_id, object_id) = (chunk.container_id.clone(), chunk.object_id.clone());
+        let mut upload = self
+            .io
+            .run(move || {
+                Upload::create(
+                    &actor_id,
+                    &container_id,
+                    &object_id,
+                    cdir,
+                    object_path,
+                    sidecar,
+                    upload_timeout,
+                )
+            })
+            .await?;
+        if let Some(store) = self.blob_store(ctx).await? {
+            upload.deduplicate_into(store);
+        }
+        if let Some(codec) = self.codec(ctx).await? {
+            upload.encode_with(codec);
+        }
+        if let Some(retention) = self.versioning(ctx).await? {
+            upload.keep_versions(retention);
+        }
+        Ok(upload)
+    }
+
+    /// Stores a chunk of an upload in progress. Chunks of an upload may arrive in any order,
+    /// and chunks of different uploads are stored concurrently.
+    /// When all chunks have been received, the object is moved into place.
+    async fn store_chunk(&self, ctx: &Context, chunk: &Chunk, stream_id: &str) -> RpcResult<()> {
+        let actor_id = self.get_actor_id(ctx).await?;
+        let not_found = || {
+            RpcError::InvalidParameter(format!(
+                "No upload in progress for {}/{} with stream id {}",
+                &chunk.container_id, &chunk.object_id, stream_id
+            ))
+        };
+        let upload = self
+            .upload_chunks
+            .read()
+            .await
+            .get(stream_id)
+            .cloned()
+            .ok_or_else(not_found)?;
+        let upload = upload.lock_owned().await;
+        if upload.actor_id != actor_id
+            || upload.container_id != chunk.container_id
+            || upload.object_id != chunk.object_id
+        {
+            return Err(not_found());
+        }
+        let size = upload
+            .received_len()
+            .max(chunk.offset + chunk.bytes.len() as u64);
+        if let Err(e) = self
+            .reserve_quota(ctx, &upload.id, &chunk.container_id, &chunk.object_id, size)
+            .await
+        {
+            // the upload can't complete, so free the space used by its staging file
+            self.upload_chunks.write().await.remove(stream_id);
+            self.abort_upload(upload).await;
+            return Err(e);
+        }
+        let (upload, complete) = self.write_upload_chunk(upload, chunk).await?;
+        if complete {
+            self.upload_chunks.write().await.remove(stream_id);
+            self.commit_upload(upload).await?;
+        }
         Ok(())
     }
 
+    /// Writes a chunk to the staging file of an upload.
+    /// Returns the upload, and true if all chunks have been received.
+    async fn write_upload_chunk(
+        &self,
+        mut upload: OwnedMutexGuard<Upload>,
+        chunk: &Chunk,
+    ) -> RpcResult<(OwnedMutexGuard<Upload>, bool)> {
+        let chunk = chunk.clone();
+        self.io
+            .run(move || {
+                let complete = upload.write_chunk(&chunk)?;
+                Ok((upload, complete))
+            })
+            .await
+    }
+
+    /// Moves a complete upload into place
+    async fn commit_upload(&self, mut upload: OwnedMutexGuard<Upload>) -> RpcResult<()> {
+        let (actor_id, upload_id) = (upload.actor_id.clone(), upload.id.clone());
+        let stored = self.io.run(move || upload.commit()).await;
+        self.settle_reservation(&actor_id, &upload_id, stored).await
+    }
+
+    /// Removes the staging file of an upload that won't be completed,
+    /// and releases the space reserved for it
+    async fn abort_upload(&self, mut upload: OwnedMutexGuard<Upload>) {
+        self.account(&upload.actor_id)
+            .await
+            .lock()
+            .await
+            .release(&upload.id);
+        if let Err(e) = self
+            .io
+            .run(move || {
+                upload.abort();
+                Ok(())
+            })
+            .await
+        {
+            warn!("Could not remove upload: {}", e);
+        }
+    }
+
+    /// Starts the background task that removes uploads that have not received
+    /// a chunk within their timeout. Only one task is started per provider.
+    fn start_upload_reaper(&self) {
+        if self.reaper_started.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        let this = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(REAPER_INTERVAL);
+            loop {
+                interval.tick().await;
+                let now = Instant::now();
+                let mut expired = Vec::new();
+                // uploads that are locked are receiving a chunk, so they are not abandoned
+                this.upload_chunks
+                    .write()
+                    .await
+                    .retain(|stream_id, upload| match upload.clone().try_lock_owned() {
+                        Ok(upload) if upload.is_expired(now) => {
+                            warn!(
+                                "Removing abandoned upload of {}/{} (stream id {})",
+                                &upload.container_id, &upload.object_id, stream_id
+                            );
+                            expired.push(upload);
+                            false
+                        }
+                        _ => true,
+                    });
+                for upload in expired {
+                    this.abort_upload(upload).await;
+                }
+            }
+        });
+    }
+
     /// Sends bytes to actor in a single rpc message.
     /// If successful, returns number of bytes sent (same as chunk.content_length)
     #[allow(unused)]
@@ -215,6 +733,155 @@ impl FsProvider {
             }
         }
     }
+
+    // allow overriding chunk size for testing. A size of 0 would never make progress,
+    // so it is ignored like any other invalid value.
+    fn max_chunk_size(&self) -> usize {
+        if let Ok(var) = std::env::var("MAX_CHUNK_SIZE") {
+            match var.parse::<u32>() {
+                Ok(size) if size > 0 => return size as usize,
+                _ => warn!("Ignoring invalid MAX_CHUNK_SIZE '{}'", var),
+            }
+        }
+        MAX_CHUNK_SIZE
+    }
+
+    /// Returns the encoding of the actor's objects, or None if they are stored as plain files
+    async fn codec(&self, ctx: &Context) -> RpcResult<Option<codec::Codec>> {
+        let actor_id = self.get_actor_id(ctx).await?;
+        Ok(match self.config.read().await.get(&actor_id) {
+            Some(config) => config.codec.clone(),
+            None => None,
+        })
+    }
+
+    /// Reads `len` bytes of an object, starting at `offset`, on the blocking pool.
+    /// Returns the reader, so that the next range can be read from it.
+    async fn read_object_range(
+        &self,
+        mut reader: ObjectReader,
+        offset: u64,
+        len: usize,
+    ) -> RpcResult<(ObjectReader, Vec<u8>)> {
+        self.io
+            .run(move || {
+                let bytes = reader.read_range(offset, len)?;
+                Ok((reader, bytes))
+            })
+            .await
+    }
+
+    /// Reads an object from `offset` up to `end_offset` (exclusive) and sends
+    /// it to the actor in chunks of at most `max_chunk_size()` bytes.
+    /// Returns early, without error, if the actor responds with `cancel_download`.
+    async fn send_file_chunks(
+        &self,
+        ctx: &Context,
+        container_object: &ContainerObject,
+        reader: ObjectReader,
+        offset: u64,
+        end_offset: u64,
+    ) -> RpcResult<()> {
+        let chunk_size = self.max_chunk_size() as u64;
+        let mut offset = offset;
+        let mut reader = reader;
+        while offset < end_offset {
+            let len = chunk_size.min(end_offset - offset);
+            let (next, bytes) = self.read_object_range(reader, offset, len as usize).await?;
+            reader = next;
+            let chunk = Chunk {
+                object_id: container_object.object_id.clone(),
+                container_id: container_object.container_id.clone(),
+                bytes,
+                offset,
+                is_last: offset + len >= end_offset,
+            };
+            if self.send_chunk(ctx, &chunk).await? == 0 {
+                warn!(
+                    "download of {}/{} cancelled by actor at offset {}",
+                    &container_object.container_id, &container_object.object_id, offset
+                );
+                break;
+            }
+            offset += len;
+        }
+        Ok(())
+    }
+
+    /// Spawns a tokio task to send the rest of a file to the actor.
+    /// `container_object` has the names of the container and object to be streamed,
+    /// `reader` reads the open file, so the stream is not affected if the object is removed,
+    /// `offset` is the current offset within the object that we are returning to the actor
+    ///    (on entry, this should be the initial range offset requested plus the number
+    ///    of bytes already sent to the actor in the GetObjectResponse)
+    /// `end_offset` the offset (exclusive) after the last byte to be returned to the actor
+    fn stream_from_file(
+        &self,
+        ctx: &Context,
+        container_object: ContainerObject,
+        reader: ObjectReader,
+        offset: u64,
+        end_offset: u64,
+    ) {
+        let ctx = ctx.clone();
+        let this = self.clone();
+        tokio::spawn(async move {
+            if let Err(e) = this
+                .send_file_chunks(&ctx, &container_object, reader, offset, end_offset)
+                .await
+            {
+                error!(
+                    "streaming {}/{} failed at or after offset {}: {}",
+                    &container_object.container_id, &container_object.object_id, offset, e
+                );
+            }
+        });
+    }
+}
+
+/// Marks the nested containers created before folders existed, see `fs_utils::upgrade_layout`.
+/// Runs on the blocking pool. If it fails, the directory is upgraded when the actor is linked again.
+fn upgrade_layout(dir: &Path, top_level: usize) {
+    match fs_utils::upgrade_layout(dir, top_level) {
+        Ok(0) => {}
+        Ok(marked) => info!("Marked {} nested containers in {:?}", marked, dir),
+        Err(e) => warn!("Could not mark the nested containers in {:?}: {}", dir, e),
+    }
+}
+
+/// Returns true if the object was stored by the actor, according to its metadata
+fn is_owner(cdir: &Path, object_id: &str, actor_id: &str) -> bool {
+    sidecar::read(cdir, object_id)
+        .and_then(|s| s.owner)
+        .as_deref()
+        == Some(actor_id)
+}
+
+/// Reads the metadata of an object for a listing. Runs on the blocking pool.
+fn object_metadata(container_id: &str, cdir: &Path, object_id: &str) -> RpcResult<ObjectMetadata> {
+    let file_path = cdir.join(object_id);
+    let metadata = metadata(&file_path)?;
+    let modified = match metadata.modified()?.duration_since(SystemTime::UNIX_EPOCH) {
+        Ok(s) => Timestamp {
+            sec: s.as_secs() as i64,
+            nsec: 0u32,
+        },
+        Err(e) => return Err(RpcError::InvalidParameter(format!("{:?}", e))),
+    };
+    let (content_type, content_encoding) = sidecar::content_headers(cdir, object_id);
+
+    Ok(ObjectMetadata {
+        container_id: container_id.to_string(),
+        content_encoding,
+        content_length: codec::logical_len(
+            &file_path,
+            &metadata,
+            sidecar::is_encoded(cdir, object_id),
+        )?,
+        content_type,
+        last_modified: Some(modified),
+        object_id: object_id.to_string(),
+    })
 }
 
 /// use default implementations of provider message handlers
@@ -227,7 +894,7 @@ impl ProviderHandler for FsProvider {
     async fn put_link(&self, ld: &LinkDefinition) -> RpcResult<bool> {
         let values = &ld.values;
 
-        for val in values {
+        for val in codec::redacted(values) {
             info!("ld conf {:?}", val);
         }
 
@@ -236,12 +903,127 @@ impl ProviderHandler for FsProvider {
             Some(r) => r.as_str(),
         };
 
+        let follow_symlinks = match values.get("FOLLOW_SYMLINKS") {
+            None => false,
+            Some(v) => v.parse::<bool>().map_err(|_| {
+                RpcError::InvalidParameter(format!(
+                    "FOLLOW_SYMLINKS must be 'true' or 'false', got '{}'",
+                    v
+                ))
+            })?,
+        };
+
+        if let Err(e) = paths::validate_id("actor", &ld.actor_id) {
+            error!("Refusing link: {}", e);
+            return Err(e);
+        }
+
+        let upload_timeout = match values.get("UPLOAD_TIMEOUT_SECS") {
+            None => upload::DEFAULT_UPLOAD_TIMEOUT,
+            Some(v) => Duration::from_secs(v.parse::<u64>().map_err(|_| {
+                RpcError::InvalidParameter(format!(
+                    "UPLOAD_TIMEOUT_SECS must be a number of seconds, got '{}'",
+                    v
+                ))
+            })?),
+        };
+
+        let quota = quota::Quota::from_values(values)?;
+
+        let shared_mounts = match values.get(shared::SHARED_CONTAINERS) {
+            None => HashMap::new(),
+            Some(v) => shared::parse_mounts(v)?,
+        };
+
+        let dedup = match values.get(blobs::DEDUP) {
+            None => false,
+            Some(v) => v.parse::<bool>().map_err(|_| {
+                RpcError::InvalidParameter(format!(
+                    "{} must be 'true' or 'false', got '{}'",
+                    blobs::DEDUP,
+                    v
+                ))
+            })?,
+        };
+        if dedup && !cfg!(unix) {
+            return Err(RpcError::InvalidParameter(format!(
+                "{} is only supported on unix",
+                blobs::DEDUP
+            )));
+        }
+
+        let gc_interval = match values.get(blobs::DEDUP_GC_INTERVAL_SECS) {
+            None => blobs::DEFAULT_GC_INTERVAL,
+            Some(v) => Duration::from_secs(v.parse::<u64>().map_err(|_| {
+                RpcError::InvalidParameter(format!(
+                    "{} must be a number of seconds, got '{}'",
+                    blobs::DEDUP_GC_INTERVAL_SECS,
+                    v
+                ))
+            })?),
+        };
+
+        let watch_debounce = match values.get(watch::WATCH_DEBOUNCE_MS) {
+            None => watch::DEFAULT_DEBOUNCE,
+            Some(v) => Duration::from_millis(v.parse::<u64>().map_err(|_| {
+                RpcError::InvalidParameter(format!(
+                    "{} must be a number of milliseconds, got '{}'",
+                    watch::WATCH_DEBOUNCE_MS,
+                    v
+                ))
+            })?),
+        };
+
+        let codec = codec::Codec::from_values(values)?;
+        if dedup && codec.is_some() {
+            return Err(RpcError::InvalidParameter(format!(
+                "{} can't be combined with {}, {} or {}",
+                blobs::DEDUP,
+                codec::COMPRESSION,
+                codec::ENCRYPTION_KEY,
+                codec::ENCRYPTION_KEY_FILE
+            )));
+        }
+
+        let versioning = versions::Retention::from_values(values)?;
+
+        let lifecycle = lifecycle::Lifecycle::from_values(values)?;
+        // containers' lifecycle files are only applied if the link asks for a sweep
+        let lifecycle_interval = match values.get(lifecycle::LIFECYCLE_SWEEP_INTERVAL_SECS) {
+            None if lifecycle.is_empty() => Duration::ZERO,
+            None => lifecycle::DEFAULT_SWEEP_INTERVAL,
+            Some(v) => Duration::from_secs(v.parse::<u64>().map_err(|_| {
+                RpcError::InvalidParameter(format!(
+                    "{} must be a number of seconds, got '{}'",
+                    lifecycle::LIFECYCLE_SWEEP_INTERVAL_SECS,
+                    v
+                ))
+            })?),
+        };
+
         let config = FsProviderConfig {
             ld: ld.clone(),
             root: PathBuf::from(root_val),
+            follow_symlinks,
+            upload_timeout,
+            quota,
+            shared: shared_mounts,
+            dedup,
+            codec,
+            versioning,
+            lifecycle,
         };
 
-        info!("Config: {:?}", config);
+        info!(
+            "Config: {:?}",
+            FsProviderConfig {
+                ld: LinkDefinition {
+                    values: codec::redacted(values),
+                    ..ld.clone()
+                },
+                ..config.clone()
+            }
+        );
 
         info!(
             "File System Blob Store Container Root: '{:?}'",
@@ -255,14 +1037,131 @@ impl ProviderHandler for FsProvider {
 
         // Create a directory named from the actor id:
         let cdir = Path::new(&config.root).join(Path::new(&ld.actor_id));
+        let namespace_dirs: Vec<PathBuf> = config
+            .shared
+            .keys()
+            .map(|name| shared::namespace_dir(&config.root, name))
+            .collect();
+        let upload_timeout = config.upload_timeout;
+        let (actor_dir, actor_id) = (cdir.clone(), ld.actor_id.clone());
+        self.io
+            .run(move || {
+                if let Err(e) = std::fs::create_dir_all(actor_dir.as_path()) {
+                    return Err(RpcError::InvalidParameter(format!(
+                        "Could not create actor directory: {:?}",
+                        e
+                    )));
+                }
 
-        match std::fs::create_dir_all(cdir.as_path()) {
-            Ok(()) => Ok(true),
-            Err(e) => Err(RpcError::InvalidParameter(format!(
-                "Could not create actor directory: {:?}",
-                e
-            ))),
+                // staging files left behind by a previous run can't be resumed
+                upload::remove_stale_staging_files(&actor_dir, upload_timeout, None);
+                upgrade_layout(&actor_dir, 1);
+
+                // Create the shared namespaces mounted for the actor
+                for namespace_dir in namespace_dirs {
+                    if let Err(e) = std::fs::create_dir_all(&namespace_dir) {
+                        return Err(RpcError::InvalidParameter(format!(
+                            "Could not create shared container directory {:?}: {:?}",
+                            namespace_dir, e
+                        )));
+                    }
+                    // other actors' uploads may still be in progress
+                    upload::remove_stale_staging_files(
+                        &namespace_dir,
+                        upload_timeout,
+                        Some(&actor_id),
+                    );
+                    upgrade_layout(&namespace_dir, 0);
+                }
+                Ok(())
+            })
+            .await?;
+
+        if config.dedup {
+            self.start_blob_gc(&config.root, gc_interval).await;
+        }
+
+        // Remove versions that exceed their maximum age, in the actor's containers and
+        // the shared namespaces it can write to
+        let sweeper = config.versioning.and_then(|retention| {
+            let mut dirs = vec![cdir.clone()];
+            dirs.extend(config.writable_namespace_dirs());
+            let blob_store = config.dedup.then(|| blobs::store_dir(&config.root));
+            versions::RetentionSweeper::start(self.io.clone(), dirs, retention, blob_store)
+        });
+        {
+            let mut sweepers = self.retention_sweepers.write().await;
+            match sweeper {
+                Some(sweeper) => sweepers.insert(ld.actor_id.clone(), sweeper),
+                None => sweepers.remove(&ld.actor_id),
+            };
+        }
+
+        // Remove objects that expired by the lifecycle rules of the link or of the containers'
+        // lifecycle files, if the link has rules or a sweep interval
+        let sweeper = (!lifecycle_interval.is_zero()).then(|| {
+            let sweep = lifecycle::Sweep {
+                actor_dir: cdir.clone(),
+                namespace_dirs: config.writable_namespace_dirs(),
+                defaults: config.lifecycle,
+                versioning: config.versioning,
+                blob_store: config.dedup.then(|| blobs::store_dir(&config.root)),
+            };
+            lifecycle::LifecycleSweeper::start(self.io.clone(), sweep, lifecycle_interval)
+        });
+        {
+            let mut sweepers = self.lifecycle_sweepers.write().await;
+            match sweeper {
+                Some(sweeper) => sweepers.insert(ld.actor_id.clone(), sweeper),
+                None => sweepers.remove(&ld.actor_id),
+            };
+        }
+
+        // Notify the actor of changes to its objects, if it asked for it
+        let watcher = match values.get(watch::WATCH_OPERATION) {
+            None => None,
+            Some(operation) => {
+                let mut dirs = vec![watch::WatchedDir {
+                    dir: cdir.clone(),
+                    container_id: None,
+                }];
+                dirs.extend(config.shared.keys().map(|name| watch::WatchedDir {
+                    dir: shared::namespace_dir(&config.root, name),
+                    container_id: Some(name.clone()),
+                }));
+                info!(
+                    "Sending object events for actor {} to {}",
+                    &ld.actor_id, operation
+                );
+                // watching a directory tree reads all of its directories
+                let (ld, operation) = (ld.clone(), operation.clone());
+                Some(
+                    self.io
+                        .run(move || {
+                            watch::ObjectWatcher::start(ld, operation, dirs, watch_debounce)
+                        })
+                        .await?,
+                )
+            }
+        };
+        let mut watchers = self.watchers.write().await;
+        match watcher {
+            Some(watcher) => watchers.insert(ld.actor_id.clone(), watcher),
+            None => watchers.remove(&ld.actor_id),
+        };
+        self.start_upload_reaper();
+
+        Ok(true)
+    }
+
+    /// Stops the file watcher, version retention and lifecycle tasks of an actor when its link is removed
+    async fn delete_link(&self, actor_id: &str) {
+        if self.watchers.write().await.remove(actor_id).is_some() {
+            info!("Stopped sending object events to actor {}", actor_id);
         }
+        self.retention_sweepers.write().await.remove(actor_id);
+        self.lifecycle_sweepers.write().await.remove(actor_id);
+        self.accounts.write().await.remove(actor_id);
     }
 }
 
@@ -274,31 +1173,28 @@ impl Blobstore for FsProvider {
     async fn container_exists(&self, ctx: &Context, arg: &ContainerId) -> RpcResult<bool> {
         info!("Called container_exists({:?})", arg);
 
-        let root = self.get_root(ctx).await?;
-        let cdir = Path::new(&root).join(&arg);
+        let cdir = self.container_path(ctx, arg, Access::Read).await?;
 
-        match read_dir(&cdir) {
-            Ok(_) => Ok(true),
-            Err(_) => Ok(false),
-        }
+        self.io.run(move || Ok(read_dir(&cdir).is_ok())).await
     }
 
     /// Creates a container by name, returning success if it worked
     /// Note that container names may not be globally unique - just unique within the
     /// "namespace" of the connecting actor and linkdef
     async fn create_container(&self, ctx: &Context, arg: &ContainerId) -> RpcResult<()> {
-        let root = self.get_root(ctx).await?;
-        let cdir = Path::new(&root).join(arg.to_string());
+        let cdir = self.container_path(ctx, arg, Access::Write).await?;
 
         info!("create dir: {:?}", cdir);
 
-        match std::fs::create_dir_all(cdir) {
-            Ok(()) => Ok(()),
-            Err(e) => Err(RpcError::InvalidParameter(format!(
-                "Could not create container: {:?}",
-                e
-            ))),
-        }
+        self.io
+            .run(move || match fs_utils::create_container(&cdir) {
+                Ok(()) => Ok(()),
+                Err(e) => Err(RpcError::InvalidParameter(format!(
+                    "Could not create container: {:?}",
+                    e
+                ))),
+            })
+            .await
     }
 
     /// Retrieves information about the container.
@@ -309,10 +1205,9 @@ impl Blobstore for FsProvider {
         ctx: &Context,
         arg: &ContainerId,
     ) -> RpcResult<ContainerMetadata> {
-        let root = self.get_root(ctx).await?;
-        let dir_path = Path::new(&root).join(&arg);
+        let dir_path = self.container_path(ctx, arg, Access::Read).await?;
 
-        let dir_info = metadata(dir_path)?;
+        let dir_info = self.io.run(move || Ok(metadata(dir_path)?)).await?;
 
         let modified = match dir_info.modified()?.duration_since(SystemTime::UNIX_EPOCH) {
             Ok(s) => Timestamp {
@@ -332,11 +1227,57 @@ impl Blobstore for FsProvider {
     #[allow(unused)]
     async fn list_containers(&self, ctx: &Context) -> RpcResult<ContainersInfo> {
         let root = self.get_root(ctx).await?;
+        let actor_id = self.get_actor_id(ctx).await?;
+        let shared_namespaces: Vec<(String, PathBuf)> =
+            match self.config.read().await.get(&actor_id) {
+                Some(config) => config
+                    .shared
+                    .keys()
+                    .map(|name| (name.clone(), shared::namespace_dir(&config.root, name)))
+                    .collect(),
+                None => Vec::new(),
+            };
 
-        let containers = all_dirs(&Path::new(&root), &root)
-            .iter()
-            .map(|c| ContainerMetadata {
-                container_id: c.as_path().display().to_string(),
+        let container_ids = self
+            .io
+            .run(move || {
+                // the actor's own containers, except those hidden by a shared namespace with the same name.
+                // Directories below the top level are containers if they were created as containers,
+                // otherwise they are folders of the objects in them
+                let mut container_ids: Vec<String> = all_dirs(Path::new(&root), &root)?
+                    .iter()
+                    .filter(|c| !paths::is_reserved(c))
+                    .filter(|c| {
+                        c.components().count() == 1 || fs_utils::is_container(&root.join(c))
+                    })
+                    .map(|c| c.as_path().display().to_string())
+                    .filter(|c| {
+                        let (name, _) = shared::split_container_id(c);
+                        !shared_namespaces
+                            .iter()
+                            .any(|(shared_name, _)| shared_name == name)
+                    })
+                    .collect();
+                for (name, dir) in &shared_namespaces {
+                    container_ids.push(name.clone());
+                    if dir.is_dir() {
+                        container_ids.extend(
+                            all_dirs(dir, dir)?
+                                .iter()
+                                .filter(|c| !paths::is_reserved(c))
+                                .filter(|c| fs_utils::is_container(&dir.join(c)))
+                                .map(|c| format!("{}/{}", name, c.display())),
+                        );
+                    }
+                }
+                Ok(container_ids)
+            })
+            .await?;
+
+        let containers = container_ids
+            .into_iter()
+            .map(|container_id| ContainerMetadata {
+                container_id,
                 created_at: None,
             })
             .collect();
@@ -352,24 +1293,73 @@ impl Blobstore for FsProvider {
     async fn remove_containers(&self, ctx: &Context, arg: &ContainerIds) -> RpcResult<MultiResult> {
         info!("Called remove_containers({:?})", arg);
 
-        let root = self.get_root(ctx).await?;
-
         let mut remove_errors = vec![];
+        let blob_store = self.blob_store(ctx).await?;
 
         for cid in arg {
-            let mut croot = root.clone();
-            croot.push(cid);
-
-            if let Err(e) = std::fs::remove_dir_all(&croot.as_path()) {
-                if read_dir(&croot.as_path()).is_ok() {
+            // a shared namespace is used by other actors, so only the containers inside it can be removed
+            if !cid.contains('/') && matches!(self.shared_namespace(ctx, cid).await, Ok(Some(_))) {
+                remove_errors.push(ItemResult {
+                    error: Some(format!("shared container '{}' can't be removed", cid)),
+                    key: cid.clone(),
+                    success: false,
+                });
+                continue;
+            }
+            let croot = match self.container_path(ctx, cid, Access::Write).await {
+                Ok(path) => path,
+                Err(e) => {
                     remove_errors.push(ItemResult {
-                        error: Some(format!("{:?}", e.into_inner())),
+                        error: Some(e.to_string()),
                         key: cid.clone(),
-                        success: true,
+                        success: false,
                     });
+                    continue;
                 }
+            };
+
+            let blob_store = blob_store.clone();
+            let removed = self
+                .io
+                .run(move || {
+                    // blobs referenced by the container's objects, released once they are removed
+                    let hashes = match &blob_store {
+                        Some(_) => blobs::hashes_below(&croot),
+                        None => Vec::new(),
+                    };
+
+                    let mut result = Ok(());
+                    if let Err(e) = std::fs::remove_dir_all(croot.as_path()) {
+                        if read_dir(croot.as_path()).is_ok() {
+                            result = Err(e);
+                        }
+                    }
+
+                    if let Some(store) = &blob_store {
+                        for sha256 in hashes {
+                            blobs::release(store, &sha256);
+                        }
+                    }
+                    Ok(result)
+                })
+                .await;
+            match removed {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => remove_errors.push(ItemResult {
+                    error: Some(format!("{:?}", e.into_inner())),
+                    key: cid.clone(),
+                    success: true,
+                }),
+                Err(e) => remove_errors.push(ItemResult {
+                    error: Some(e.to_string()),
+                    key: cid.clone(),
+                    success: false,
+                }),
             }
         }
+        // the removed objects are no longer counted once the usage is measured again
+        let actor_id = self.get_actor_id(ctx).await?;
+        self.account(&actor_id).await.lock().await.invalidate();
 
         Ok(remove_errors)
     }
@@ -379,15 +1369,14 @@ impl Blobstore for FsProvider {
     async fn object_exists(&self, ctx: &Context, arg: &ContainerObject) -> RpcResult<bool> {
         info!("Called object_exists({:?})", arg);
 
-        let root = self.get_root(ctx).await?;
-        let file_path = Path::new(&root)
-            .join(&arg.container_id)
-            .join(&arg.object_id);
+        let file_path = self
+            .object_path(ctx, &arg.container_id, &arg.object_id, Access::Read)
+            .await?;
 
-        match File::open(file_path) {
-            Ok(_) => Ok(true),
-            Err(_) => Ok(false),
-        }
+        // a folder is not an object
+        self.io
+            .run(move || Ok(metadata(file_path).map(|m| m.is_file()).unwrap_or(false)))
+            .await
     }
 
     /// Retrieves information about the object.
@@ -400,12 +1389,30 @@ impl Blobstore for FsProvider {
     ) -> RpcResult<ObjectMetadata> {
         info!("Called get_object_info({:?})", arg);
 
-        let root = self.get_root(ctx).await?;
-        let file_path = Path::new(&root)
-            .join(&arg.container_id)
-            .join(&arg.object_id);
-
-        let metadata = metadata(file_path)?;
+        let file_path = self
+            .object_path(ctx, &arg.container_id, &arg.object_id, Access::Read)
+            .await?;
+        let cdir = self
+            .container_path(ctx, &arg.container_id, Access::Read)
+            .await?;
+
+        // the size of an encoded object is the size of its decoded contents
+        let object_id = arg.object_id.clone();
+        let (content_length, metadata, (content_type, content_encoding)) = self
+            .io
+            .run(move || {
+                let metadata = metadata(&file_path)?;
+                Ok((
+                    codec::logical_len(
+                        &file_path,
+                        &metadata,
+                        sidecar::is_encoded(&cdir, &object_id),
+                    )?,
+                    metadata,
+                    sidecar::content_headers(&cdir, &object_id),
+                ))
+            })
+            .await?;
 
         let modified = match metadata.modified()?.duration_since(SystemTime::UNIX_EPOCH) {
             Ok(s) => Timestamp {
@@ -417,9 +1424,9 @@ impl Blobstore for FsProvider {
 
         Ok(ObjectMetadata {
             container_id: arg.container_id.clone(),
-            content_encoding: None,
-            content_length: metadata.len() as u64,
-            content_type: None,
+            content_encoding,
+            content_length,
+            content_type,
             last_modified: Some(modified),
             object_id: arg.object_id.clone(),
         })
@@ -433,9 +1440,14 @@ impl Blobstore for FsProvider {
     /// the response contains a `continuation` token that may be submitted in
     /// a subsequent ListObjects request.
     ///
-    /// Optional object metadata fields (i.e., `contentType` and `contentEncoding`) may not be
-    /// filled in for ListObjects response. To get complete object metadata, use GetObjectInfo.
-    /// Currently ignoring need for pagination
+    /// Objects are returned sorted by name. The continuation token encodes the name
+    /// of the last object returned, so the next page resumes after that name even if
+    /// objects were added or removed in the meantime.
+    ///
+    /// Objects in folders are listed with their full ids, e.g., `images/2023/a.png`, like the keys
+    /// of an S3 bucket listed without a delimiter. Nested containers are not part of the listing.
+    ///
+    /// Content type and encoding are read from the metadata stored with each object.
     #[allow(unused)]
     async fn list_objects(
         &self,
@@ -444,53 +1456,29 @@ impl Blobstore for FsProvider {
     ) -> RpcResult<ListObjectsResponse> {
         info!("Called list_objects({:?})", arg);
 
-        let root = self.get_root(ctx).await?;
-        let cdir = Path::new(&root).join(&arg.container_id);
+        let cdir = self
+            .container_path(ctx, &arg.container_id, Access::Read)
+            .await?;
 
-        let mut objects = Vec::new();
+        let arg = arg.clone();
+        self.io
+            .run(move || {
+                let names = fs_utils::object_keys(&cdir, "")?;
 
-        for entry in read_dir(&cdir)? {
-            let entry = entry?;
-            let path = entry.path();
+                let (page, continuation) = page_names(&names, &arg)?;
 
-            if !path.is_dir() {
-                let file_name = match entry.file_name().into_string() {
-                    Ok(name) => name,
-                    Err(_) => {
-                        return Err(RpcError::InvalidParameter(String::from(
-                            "File name conversion failed",
-                        )));
-                    }
-                };
-
-                let modified = match entry
-                    .metadata()?
-                    .modified()?
-                    .duration_since(SystemTime::UNIX_EPOCH)
-                {
-                    Ok(s) => Timestamp {
-                        sec: s.as_secs() as i64,
-                        nsec: 0u32,
-                    },
-                    Err(e) => return Err(RpcError::InvalidParameter(format!("{:?}", e))),
-                };
-
-                objects.push(ObjectMetadata {
-                    container_id: arg.container_id.clone(),
-                    content_encoding: None,
-                    content_length: entry.metadata()?.len(),
-                    content_type: None,
-                    last_modified: Some(modified),
-                    object_id: file_name,
-                });
-            }
-        }
+                let objects = page
+                    .iter()
+                    .map(|object_id| object_metadata(&arg.container_id, &cdir, object_id))
+                    .collect::<RpcResult<_>>()?;
 
-        Ok(ListObjectsResponse {
-            continuation: None,
-            is_last: true,
-            objects,
-        })
+                Ok(ListObjectsResponse {
+                    is_last: continuation.is_none(),
+                    continuation,
+                    objects,
+                })
+            })
+            .await
     }
 
     /// Removes the objects. In the event any of the objects cannot be removed,
@@ -504,18 +1492,74 @@ impl Blobstore for FsProvider {
         arg: &RemoveObjectsRequest,
     ) -> RpcResult<MultiResult> {
         info!("Invoked remove obejcts: {:?}", arg);
-        let root = self.get_root(ctx).await?;
-
         let mut errors = Vec::new();
+        let blob_store = self.blob_store(ctx).await?;
+        let versioning = self.versioning(ctx).await?;
+        // in shared namespaces, only the actor's own objects count towards its usage
+        let is_shared = self
+            .shared_namespace(ctx, &arg.container_id)
+            .await?
+            .is_some();
+        let actor_id = self.get_actor_id(ctx).await?;
+        let account = self.account(&actor_id).await;
 
         for object in &arg.objects {
-            let opath = Path::join(&Path::join(&root, &arg.container_id), &object);
-            if let Err(e) = remove_file(opath.as_path()) {
-                errors.push(ItemResult {
-                    error: Some(format!("{:?}", e)),
-                    key: format!("{:?}", opath),
-                    success: false,
+            let opath = match self
+                .object_path(ctx, &arg.container_id, object, Access::Write)
+                .await
+            {
+                Ok(path) => path,
+                Err(e) => {
+                    errors.push(ItemResult {
+                        error: Some(e.to_string()),
+                        key: object.clone(),
+                        success: false,
+                    });
+                    continue;
+                }
+            };
+            let cdir = match self
+                .container_path(ctx, &arg.container_id, Access::Write)
+                .await
+            {
+                Ok(cdir) => cdir,
+                Err(e) => {
+                    errors.push(ItemResult {
+                        error: Some(e.to_string()),
+                        key: object.clone(),
+                        success: false,
+                    });
+                    continue;
+                }
+            };
+            let (object, blob_store, actor_id) =
+                (object.clone(), blob_store.clone(), actor_id.clone());
+            let removed = self
+                .io
+                .run(move || {
+                    let size = metadata(&opath)
+                        .ok()
+                        .filter(|m| m.is_file())
+                        .filter(|_| !is_shared || is_owner(&cdir, &object, &actor_id))
+                        .map(|m| m.len());
+                    Ok(fs_utils::remove_object(
+                        &cdir,
+                        &object,
+                        versioning.as_ref(),
+                        blob_store.as_deref(),
+                    )
+                    .map(|_| size)
+                    .map_err(|e| ItemResult {
+                        error: Some(format!("{:?}", e)),
+                        key: format!("{:?}", opath),
+                        success: false,
+                    }))
                 })
+                .await?;
+            match removed {
+                Ok(Some(size)) => account.lock().await.removed(size, versioning.is_some()),
+                Ok(None) => {}
+                Err(error) => errors.push(error),
             }
         }
 
@@ -535,28 +1579,9 @@ impl Blobstore for FsProvider {
             arg.chunk.container_id, arg.chunk.object_id
         );
 
-        if arg.chunk.bytes.is_empty() {
-            error!("put_object with zero bytes");
-            return Err(RpcError::InvalidParameter(
-                "cannot put zero-length objects".to_string(),
-            ));
-        }
-
-        let stream_id = if arg.chunk.is_last {
-            None
-        } else {
-            Some(format!(
-                "{}+{}+{}",
-                self.get_actor_id(ctx).await?,
-                arg.chunk.container_id,
-                arg.chunk.object_id
-            ))
-        };
-
-        // store the chunks in order
-        self.store_chunk(ctx, &arg.chunk, &stream_id).await?;
-
-        Ok(PutObjectResponse { stream_id })
+        let object_sidecar =
+            sidecar::ObjectSidecar::new(arg.content_type.clone(), arg.content_encoding.clone());
+        self.put_with_sidecar(ctx, &arg.chunk, object_sidecar).await
     }
 
     /// Uploads a file chunk to a blobstore. This must be called AFTER PutObject
@@ -565,23 +1590,28 @@ impl Blobstore for FsProvider {
     async fn put_chunk(&self, ctx: &Context, arg: &PutChunkRequest) -> RpcResult<()> {
         info!("Called put_chunk: {:?}", arg);
 
-        if arg.cancel_and_remove {
-            // ancel upload and remove file
-
-            let root = &self.get_root(ctx).await?;
-            let cdir = Path::new(root).join(&arg.chunk.container_id);
-            let file_path = Path::join(&cdir, &arg.chunk.object_id);
+        let stream_id = arg.stream_id.as_deref().ok_or_else(|| {
+            RpcError::InvalidParameter(
+                "put_chunk requires the stream id returned by put_object".to_string(),
+            )
+        })?;
 
-            remove_file(file_path.as_path()).map_err(|e| {
-                RpcError::InvalidParameter(format!(
-                    "Could not cancel and remove file: {:?}",
-                    file_path
-                ))
-            })
+        if arg.cancel_and_remove {
+            // cancel upload and remove the staging file. A previously stored object is left intact.
+            let upload = self.upload_chunks.write().await.remove(stream_id);
+            match upload {
+                Some(upload) => {
+                    self.abort_upload(upload.lock_owned().await).await;
+                    Ok(())
+                }
+                None => Err(RpcError::InvalidParameter(format!(
+                    "No upload in progress for {}/{} with stream id {}",
+                    &arg.chunk.container_id, &arg.chunk.object_id, stream_id
+                ))),
+            }
         } else {
             // happy path
-            self.store_chunk(ctx, &arg.chunk, &arg.stream_id).await?;
-            Ok(())
+            self.store_chunk(ctx, &arg.chunk, stream_id).await
         }
     }
 
@@ -596,47 +1626,88 @@ impl Blobstore for FsProvider {
     ) -> RpcResult<GetObjectResponse> {
         info!("Called get_object: {:?}", arg);
 
-        let root = &self.get_root(ctx).await?;
-        let cdir = Path::new(root).join(&arg.container_id);
-        let file_path = Path::join(&cdir, &arg.object_id);
-
-        let file = read(file_path)?;
+        let file_path = self
+            .object_path(ctx, &arg.container_id, &arg.object_id, Access::Read)
+            .await?;
+
+        let cdir = self
+            .container_path(ctx, &arg.container_id, Access::Read)
+            .await?;
+
+        // offsets and lengths refer to the decoded contents of encoded objects
+        let codec = self.codec(ctx).await?;
+        let object_id = arg.object_id.clone();
+        let (reader, file_len, (content_type, content_encoding)) = self
+            .io
+            .run(move || {
+                let reader = ObjectReader::open(
+                    File::open(&file_path)?,
+                    sidecar::is_encoded(&cdir, &object_id),
+                    codec.as_ref(),
+                )?;
+                let file_len = reader.len();
+                Ok((
+                    reader,
+                    file_len,
+                    sidecar::content_headers(&cdir, &object_id),
+                ))
+            })
+            .await?;
 
-        let start_offset = match arg.range_start {
-            Some(o) => o as usize,
-            None => 0,
-        };
+        let start_offset = arg.range_start.unwrap_or(0).min(file_len);
 
         let end_offset = match arg.range_end {
-            Some(o) => std::cmp::min(o as usize + 1, file.len()),
-            None => file.len(),
-        };
-
-        let mut dcm = self.download_chunks.write().await;
-
-        let actor_id = self.get_actor_id(ctx).await?;
+            Some(o) => std::cmp::min(o.saturating_add(1), file_len),
+            None => file_len,
+        }
+        .max(start_offset);
 
-        let slice = &file[start_offset..end_offset];
+        // the first chunk is returned in the response, the rest is streamed to the actor
+        let bytes_requested = end_offset - start_offset;
+        let first_len = bytes_requested.min(self.max_chunk_size() as u64);
 
         info!(
-            "Retriving chunk start offset: {}, end offset: {} (exclusive)",
-            start_offset, end_offset
+            "Retrieving chunk start offset: {}, end offset: {} (exclusive)",
+            start_offset,
+            start_offset + first_len
         );
 
+        let (reader, bytes) = self
+            .read_object_range(reader, start_offset, first_len as usize)
+            .await?;
         let chunk = Chunk {
             object_id: arg.object_id.clone(),
             container_id: arg.container_id.clone(),
-            bytes: slice.to_vec(),
-            offset: start_offset as u64,
-            is_last: end_offset >= file.len(),
+            bytes,
+            offset: start_offset,
+            is_last: first_len >= bytes_requested,
         };
 
+        if !chunk.is_last {
+            info!(
+                "Streaming remaining {} bytes of {}/{}",
+                bytes_requested - first_len,
+                &arg.container_id,
+                &arg.object_id
+            );
+            self.stream_from_file(
+                ctx,
+                ContainerObject {
+                    container_id: arg.container_id.clone(),
+                    object_id: arg.object_id.clone(),
+                },
+                reader,
+                start_offset + first_len,
+                end_offset,
+            );
+        }
+
         Ok(GetObjectResponse {
-            content_encoding: None,
-            content_length: chunk.bytes.len() as u64,
-            content_type: None,
+            content_encoding,
+            content_length: bytes_requested,
+            content_type,
             error: None,
-            initial_chunk: Some(chunk.clone()),
+            initial_chunk: Some(chunk),
             success: true,
         })
     }
@@ -645,3 +1716,828 @@ impl Blobstore for FsProvider {
         "wasmcloud:blobstore"
     }
 }
+
+/// Versions of objects, an extension of the blobstore interface
+#[async_trait]
+impl BlobstoreVersions for FsProvider {
+    /// Lists the versions of an object, latest first.
+    /// Versions of an object that was removed are listed too.
+    async fn list_object_versions(
+        &self,
+        ctx: &Context,
+        arg: &versions::ListObjectVersionsRequest,
+    ) -> RpcResult<versions::ObjectVersions> {
+        info!("Called list_object_versions({:?})", arg);
+
+        // checks the object id
+        self.object_path(ctx, &arg.container_id, &arg.object_id, Access::Read)
+            .await?;
+        let cdir = self
+            .container_path(ctx, &arg.container_id, Access::Read)
+            .await?;
+
+        let arg = arg.clone();
+        self.io
+            .run(move || Ok(versions::list(&arg.container_id, &cdir, &arg.object_id)?))
+            .await
+    }
+
+    /// Copies a noncurrent version over the object, which becomes a noncurrent version itself.
+    /// Returns the restored object, which has a new version id.
+    async fn restore_object_version(
+        &self,
+        ctx: &Context,
+        arg: &versions::RestoreObjectVersionRequest,
+    ) -> RpcResult<versions::ObjectVersion> {
+        info!("Called restore_object_version({:?})", arg);
+
+        let retention = self.versioning(ctx).await?.ok_or_else(|| {
+            RpcError::InvalidParameter(format!(
+                "versions can't be restored unless {}=true is set",
+                versions::VERSIONING
+            ))
+        })?;
+        self.object_path(ctx, &arg.container_id, &arg.object_id, Access::Write)
+            .await?;
+        let cdir = self
+            .container_path(ctx, &arg.container_id, Access::Write)
+            .await?;
+
+        // the restored object counts towards the quota like a new upload
+        let (lookup_dir, object_id, version_id) =
+            (cdir.clone(), arg.object_id.clone(), arg.version_id.clone());
+        let record = self
+            .io
+            .run(move || {
+                // restoring the current version does nothing
+                if versions::is_current(&lookup_dir, &object_id, &version_id) {
+                    return Ok(None);
+                }
+                versions::find(&lookup_dir, &object_id, &version_id).map(Some)
+            })
+            .await?;
+        let reservation = uuid::Uuid::new_v4().to_string();
+        if let Some(record) = record {
+            self.reserve_quota(
+                ctx,
+                &reservation,
+                &arg.container_id,
+                &arg.object_id,
+                record.content_length,
+            )
+            .await?;
+        }
+
+        let actor_id = self.get_actor_id(ctx).await?;
+        let account = self.account(&actor_id).await;
+        // in a shared namespace, the actor restoring the object owns the copy
+        let owner = self
+            .shared_namespace(ctx, &arg.container_id)
+            .await?
+            .map(|_| actor_id);
+        let blob_store = self.blob_store(ctx).await?;
+        let arg = arg.clone();
+        let restored = self
+            .io
+            .run(move || {
+                versions::restore(
+                    &cdir,
+                    &arg.object_id,
+                    &arg.version_id,
+                    blob_store.as_deref(),
+                    owner.as_deref(),
+                )?;
+                if let Err(e) = versions::apply_retention(
+                    &cdir,
+                    &arg.object_id,
+                    &retention,
+                    blob_store.as_deref(),
+                    SystemTime::now(),
+                ) {
+                    warn!(
+                        "Could not prune versions of {}/{}: {}",
+                        &arg.container_id, &arg.object_id, e
+                    );
+                }
+                versions::current(&arg.container_id, &cdir, &arg.object_id)?.ok_or_else(|| {
+                    RpcError::Other(format!(
+                        "object {}/{} was removed while it was restored",
+                        &arg.container_id, &arg.object_id
+                    ))
+                })
+            })
+            .await;
+        // the object that was current is kept as a noncurrent version
+        let mut account = account.lock().await;
+        match &restored {
+            Ok(_) => account.commit(&reservation, None, true),
+            Err(_) => account.release(&reservation),
+        }
+        restored
+    }
+}
+
+/// Storage usage, an extension of the blobstore interface
+#[async_trait]
+impl BlobstoreQuota for FsProvider {
+    /// Returns the actor's usage and limits. The usage of a container is only measured
+    /// when it is requested.
+    async fn get_storage_usage(
+        &self,
+        ctx: &Context,
+        arg: &quota::StorageUsageRequest,
+    ) -> RpcResult<quota::StorageUsage> {
+        info!("Called get_storage_usage({:?})", arg);
+
+        let container = match &arg.container_id {
+            Some(container_id) => {
+                let cdir = self.container_path(ctx, container_id, Access::Read).await?;
+                Some(self.io.run(move || Ok(quota::usage(&cdir)?)).await?)
+            }
+            None => None,
+        };
+        let limits = self.get_quota(ctx).await?;
+        let account = self.locked_account(ctx).await?;
+        Ok(quota::StorageUsage {
+            usage: account.stored(Instant::now()).unwrap_or_default(),
+            reserved_bytes: account.reserved_bytes(),
+            limits,
+            container,
+        })
+    }
+}
+
+/// Hashes of deduplicated objects, an extension of the blobstore interface
+#[async_trait]
+impl BlobstoreHashes for FsProvider {
+    /// Returns the SHA-256 hash of the object's contents. Hashes are recorded when objects
+    /// are stored with `DEDUP=true`; objects stored otherwise have no hash.
+    async fn get_object_hash(
+        &self,
+        ctx: &Context,
+        arg: &blobs::GetObjectHashRequest,
+    ) -> RpcResult<blobs::ObjectHash> {
+        info!("Called get_object_hash({:?})", arg);
+
+        let file_path = self
+            .object_path(ctx, &arg.container_id, &arg.object_id, Access::Read)
+            .await?;
+        let cdir = self
+            .container_path(ctx, &arg.container_id, Access::Read)
+            .await?;
+
+        let object_id = arg.object_id.clone();
+        let sha256 = self
+            .io
+            .run(move || {
+                // fails like get_object_info if there is no such object
+                metadata(&file_path)?;
+                Ok(blobs::object_hash(&cdir, &object_id))
+            })
+            .await?;
+        Ok(blobs::ObjectHash {
+            container_id: arg.container_id.clone(),
+            object_id: arg.object_id.clone(),
+            sha256,
+        })
+    }
+}
+
+/// User-defined metadata of objects, an extension of the blobstore interface
+#[async_trait]
+impl BlobstoreMetadata for FsProvider {
+    /// Puts an object, like `put_object`, storing its user-defined metadata with it
+    async fn put_object_with_metadata(
+        &self,
+        ctx: &Context,
+        arg: &sidecar::PutObjectWithMetadataRequest,
+    ) -> RpcResult<PutObjectResponse> {
+        info!(
+            "Called put_object_with_metadata(): container={:?}, object={:?}",
+            arg.chunk.container_id, arg.chunk.object_id
+        );
+        arg.validate()?;
+
+        let mut object_sidecar =
+            sidecar::ObjectSidecar::new(arg.content_type.clone(), arg.content_encoding.clone());
+        object_sidecar.user_metadata = arg.metadata.clone();
+        self.put_with_sidecar(ctx, &arg.chunk, object_sidecar).await
+    }
+
+    /// Returns the metadata of an object, like `get_object_info`, with its user-defined metadata
+    async fn get_object_metadata(
+        &self,
+        ctx: &Context,
+        arg: &ContainerObject,
+    ) -> RpcResult<sidecar::ObjectMetadataWithHeaders> {
+        info!("Called get_object_metadata({:?})", arg);
+
+        // checks the object id, like get_object_info
+        self.object_path(ctx, &arg.container_id, &arg.object_id, Access::Read)
+            .await?;
+        let cdir = self
+            .container_path(ctx, &arg.container_id, Access::Read)
+            .await?;
+
+        let container_id = arg.container_id.clone();
+        let object_id = arg.object_id.clone();
+        self.io
+            .run(move || {
+                // fails like get_object_info if there is no such object
+                let info = object_metadata(&container_id, &cdir, &object_id)?;
+                let user_metadata = sidecar::read(&cdir, &object_id)
+                    .map(|s| s.user_metadata)
+                    .unwrap_or_default();
+                Ok(sidecar::ObjectMetadataWithHeaders {
+                    container_id: info.container_id,
+                    object_id: info.object_id,
+                    content_length: info.content_length,
+                    last_modified: info.last_modified,
+                    content_type: info.content_type,
+                    content_encoding: info.content_encoding,
+                    metadata: user_metadata,
+                })
+            })
+            .await
+    }
+}
+
+/// Listing objects by prefix, an extension of the blobstore interface
+#[async_trait]
+impl BlobstoreListing for FsProvider {
+    /// Lists the objects whose ids start with the prefix. Ids containing the delimiter
+    /// after the prefix are returned once as a common prefix instead, like folders.
+    /// Objects and common prefixes are sorted and paged together.
+    async fn list_objects_by_prefix(
+        &self,
+        ctx: &Context,
+        arg: &listing::ListByPrefixRequest,
+    ) -> RpcResult<listing::ListByPrefixResponse> {
+        info!("Called list_objects_by_prefix({:?})", arg);
+
+        let folder = listing::prefix_folder(arg.prefix.as_deref().unwrap_or_default());
+        if !folder.is_empty() {
+            paths::validate_id("prefix", folder)?;
+        }
+        let cdir = self
+            .container_path(ctx, &arg.container_id, Access::Read)
+            .await?;
+
+        let arg = arg.clone();
+        self.io
+            .run(move || {
+                let folder = listing::prefix_folder(arg.prefix.as_deref().unwrap_or_default());
+                let ids = fs_utils::object_keys(&cdir, folder)?;
+                let page = listing::page(&ids, &arg)?;
+
+                let objects = page
+                    .object_ids
+                    .iter()
+                    .map(|object_id| object_metadata(&arg.container_id, &cdir, object_id))
+                    .collect::<RpcResult<_>>()?;
+
+                Ok(listing::ListByPrefixResponse {
+                    objects,
+                    common_prefixes: page.common_prefixes,
+                    is_last: page.continuation.is_none(),
+                    continuation: page.continuation,
+                })
+            })
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs_utils::clear_state;
+    use std::fs::create_dir_all;
+
+    /// Links an actor to the provider, and returns the context of its requests
+    async fn link(provider: &FsProvider, actor_id: &str, values: &[(&str, &str)]) -> Context {
+        let ld = LinkDefinition {
+            actor_id: actor_id.to_string(),
+            values: values
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+            ..Default::default()
+        };
+        provider.put_link(&ld).await.unwrap();
+        Context {
+            actor: Some(actor_id.to_string()),
+            ..Default::default()
+        }
+    }
+
+    async fn put(
+        provider: &FsProvider,
+        ctx: &Context,
+        container_id: &str,
+        object_id: &str,
+        bytes: &[u8],
+    ) -> RpcResult<PutObjectResponse> {
+        let req = PutObjectRequest {
+            chunk: Chunk {
+                object_id: object_id.to_string(),
+                container_id: container_id.to_string(),
+                bytes: bytes.to_vec(),
+                is_last: true,
+                offset: 0,
+            },
+            content_encoding: None,
+            content_type: None,
+        };
+        provider.put_object(ctx, &req).await
+    }
+
+    #[tokio::test]
+    async fn shared_namespace_routing() {
+        let root = Path::new("/tmp/rust_test/provider1");
+        let root_value = root.to_string_lossy().to_string();
+        // stale staging files of two actors, left in the namespace by a previous run
+        let staging_dir = shared::namespace_dir(root, "uploads").join(upload::STAGING_DIR);
+        create_dir_all(&staging_dir).unwrap();
+        let own_staging = format!("{}stale", upload::staging_prefix("writer"));
+        let other_staging = format!("{}stale", upload::staging_prefix("reader"));
+        std::fs::write(staging_dir.join(&own_staging), b"partial").unwrap();
+        std::fs::write(staging_dir.join(&other_staging), b"partial").unwrap();
+        std::thread::sleep(Duration::from_millis(10));
+
+        let provider = FsProvider::default();
+        let writer = link(
+            &provider,
+            "writer",
+            &[
+                ("ROOT", &root_value),
+                ("SHARED_CONTAINERS", "uploads:rw"),
+                ("MAX_TOTAL_BYTES", "10"),
+                ("UPLOAD_TIMEOUT_SECS", "0"),
+            ],
+        )
+        .await;
+        let reader = link(
+            &provider,
+            "reader",
+            &[("ROOT", &root_value), ("SHARED_CONTAINERS", "uploads:ro")],
+        )
+        .await;
+        let staging_files: Vec<String> = std::fs::read_dir(&staging_dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+
+        // the writer stores objects in the namespace, and in a container nested in it
+        let created = provider
+            .create_container(&writer, &"uploads/2024".to_string())
+            .await;
+        let stored = put(&provider, &writer, "uploads", "a", b"12345").await;
+        let stored_nested = put(&provider, &writer, "uploads/2024", "b", b"123").await;
+        // which count towards the writer's quota
+        let over_quota = put(&provider, &writer, "uploads", "c", b"123").await;
+        let usage = provider
+            .get_storage_usage(&writer, &quota::StorageUsageRequest::default())
+            .await
+            .unwrap();
+        let owner =
+            sidecar::read(&shared::namespace_dir(root, "uploads"), "a").and_then(|s| s.owner);
+
+        // the reader sees the writer's objects, but can't change them
+        let listed = provider
+            .list_objects(
+                &reader,
+                &ListObjectsRequest {
+                    container_id: "uploads".to_string(),
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+        let read = provider
+            .get_object(
+                &reader,
+                &GetObjectRequest {
+                    container_id: "uploads/2024".to_string(),
+                    object_id: "b".to_string(),
+                    range_start: Some(0),
+                    range_end: None,
+                },
+            )
+            .await
+            .unwrap();
+        let read_only = put(&provider, &reader, "uploads", "d", b"1").await;
+        let remove_by_reader = provider
+            .remove_objects(
+                &reader,
+                &RemoveObjectsRequest {
+                    container_id: "uploads".to_string(),
+                    objects: vec!["a".to_string()],
+                },
+            )
+            .await
+            .unwrap();
+        let reader_usage = provider
+            .get_storage_usage(&reader, &quota::StorageUsageRequest::default())
+            .await
+            .unwrap();
+
+        // the namespace can't be removed, and removing an object frees the writer's quota
+        let remove_namespace = provider
+            .remove_containers(&writer, &vec!["uploads".to_string()])
+            .await
+            .unwrap();
+        let removed = provider
+            .remove_objects(
+                &writer,
+                &RemoveObjectsRequest {
+                    container_id: "uploads".to_string(),
+                    objects: vec!["a".to_string()],
+                },
+            )
+            .await
+            .unwrap();
+        let after_remove = put(&provider, &writer, "uploads", "c", b"123").await;
+        // the namespace hides the actors' own containers, which stay empty
+        let in_actor_dir = root.join("writer").join("uploads").exists();
+
+        clear_state(root);
+
+        assert_eq!(staging_files, vec![other_staging]);
+        assert!(created.is_ok());
+        assert!(stored.is_ok());
+        assert!(stored_nested.is_ok());
+        assert!(over_quota
+            .unwrap_err()
+            .to_string()
+            .contains(quota::QUOTA_EXCEEDED));
+        assert_eq!(
+            usage.usage,
+            quota::Usage {
+                bytes: 8,
+                objects: 2
+            }
+        );
+        assert_eq!(owner.as_deref(), Some("writer"));
+        assert!(listed.objects.iter().any(|o| o.object_id == "a"));
+        assert_eq!(read.initial_chunk.unwrap().bytes, b"123".to_vec());
+        assert!(read_only.is_err());
+        assert_eq!(remove_by_reader.len(), 1);
+        assert_eq!(reader_usage.usage, quota::Usage::default());
+        assert_eq!(remove_namespace.len(), 1);
+        assert!(removed.is_empty());
+        assert!(after_remove.is_ok());
+        assert!(!in_actor_dir);
+    }
+
+    /// nested containers created before folders existed are still containers after an upgrade
+    #[tokio::test]
+    async fn upgrade_existing_tree() {
+        let root = Path::new("/tmp/rust_test/provider5");
+        let root_value = root.to_string_lossy().to_string();
+        create_dir_all(root.join("actor/cont/nested")).unwrap();
+        std::fs::write(root.join("actor/cont/nested/a.txt"), b"nested").unwrap();
+        let namespace_dir = shared::namespace_dir(root, "uploads");
+        create_dir_all(namespace_dir.join("2023")).unwrap();
+        std::fs::write(namespace_dir.join("2023/b.txt"), b"shared").unwrap();
+
+        let provider = FsProvider::default();
+        let ctx = link(
+            &provider,
+            "actor",
+            &[("ROOT", &root_value), ("SHARED_CONTAINERS", "uploads:rw")],
+        )
+        .await;
+        let mut containers: Vec<String> = provider
+            .list_containers(&ctx)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|c| c.container_id)
+            .collect();
+        containers.sort();
+        let list = |container_id: &str| ListObjectsRequest {
+            container_id: container_id.to_string(),
+            ..Default::default()
+        };
+        let in_cont = provider.list_objects(&ctx, &list("cont")).await.unwrap();
+        let in_nested = provider
+            .list_objects(&ctx, &list("cont/nested"))
+            .await
+            .unwrap();
+        let in_namespace = provider.list_objects(&ctx, &list("uploads")).await.unwrap();
+        // objects stored in folders after the upgrade are not containers
+        put(&provider, &ctx, "cont", "images/c.png", b"folder")
+            .await
+            .unwrap();
+        let linked_again = link(&provider, "actor", &[("ROOT", &root_value)]).await;
+        let after_relink = provider.list_objects(&linked_again, &list("cont")).await;
+
+        clear_state(root);
+
+        assert_eq!(
+            containers,
+            vec!["cont", "cont/nested", "uploads", "uploads/2023"]
+        );
+        assert!(in_cont.objects.is_empty());
+        let nested_ids: Vec<String> = in_nested.objects.into_iter().map(|o| o.object_id).collect();
+        assert_eq!(nested_ids, vec!["a.txt"]);
+        assert!(in_namespace.objects.is_empty());
+        let ids: Vec<String> = after_relink
+            .unwrap()
+            .objects
+            .into_iter()
+            .map(|o| o.object_id)
+            .collect();
+        assert_eq!(ids, vec!["images/c.png"]);
+    }
+
+    /// only links with lifecycle rules or a sweep interval start a lifecycle sweeper
+    #[tokio::test]
+    async fn lifecycle_sweep_is_opt_in() {
+        let root = Path::new("/tmp/rust_test/provider6");
+        let root_value = root.to_string_lossy().to_string();
+
+        let provider = FsProvider::default();
+        link(&provider, "plain", &[("ROOT", &root_value)]).await;
+        link(
+            &provider,
+            "expiring",
+            &[("ROOT", &root_value), ("LIFECYCLE_EXPIRE_SECS", "3600")],
+        )
+        .await;
+        link(
+            &provider,
+            "swept",
+            &[
+                ("ROOT", &root_value),
+                ("LIFECYCLE_SWEEP_INTERVAL_SECS", "600"),
+            ],
+        )
+        .await;
+        let mut swept: Vec<String> = provider
+            .lifecycle_sweepers
+            .read()
+            .await
+            .keys()
+            .cloned()
+            .collect();
+        swept.sort();
+
+        clear_state(root);
+
+        assert_eq!(swept, vec!["expiring", "swept"]);
+    }
+
+    /// user-defined metadata put with an object is returned by get_object_metadata
+    #[tokio::test]
+    async fn user_metadata_round_trip() {
+        let root = Path::new("/tmp/rust_test/provider7");
+        let root_value = root.to_string_lossy().to_string();
+
+        let provider = FsProvider::default();
+        let ctx = link(&provider, "actor", &[("ROOT", &root_value)]).await;
+        provider
+            .create_container(&ctx, &"cont".to_string())
+            .await
+            .unwrap();
+        let put_with_metadata =
+            |metadata: HashMap<String, String>| sidecar::PutObjectWithMetadataRequest {
+                chunk: Chunk {
+                    object_id: "report.csv".to_string(),
+                    container_id: "cont".to_string(),
+                    bytes: b"a,b".to_vec(),
+                    is_last: true,
+                    offset: 0,
+                },
+                content_type: Some("text/csv".to_string()),
+                content_encoding: None,
+                metadata,
+            };
+        let object = ContainerObject {
+            container_id: "cont".to_string(),
+            object_id: "report.csv".to_string(),
+        };
+        let metadata: HashMap<String, String> =
+            [("department".to_string(), "finance".to_string())].into();
+        provider
+            .put_object_with_metadata(&ctx, &put_with_metadata(metadata.clone()))
+            .await
+            .unwrap();
+        let with_metadata = provider.get_object_metadata(&ctx, &object).await.unwrap();
+        let too_large = provider
+            .put_object_with_metadata(
+                &ctx,
+                &put_with_metadata([("notes".to_string(), "x".repeat(4096))].into()),
+            )
+            .await;
+        // a plain put replaces the object and its metadata
+        put(&provider, &ctx, "cont", "report.csv", b"a,b,c")
+            .await
+            .unwrap();
+        let replaced = provider.get_object_metadata(&ctx, &object).await.unwrap();
+        let missing = provider
+            .get_object_metadata(
+                &ctx,
+                &ContainerObject {
+                    container_id: "cont".to_string(),
+                    object_id: "missing".to_string(),
+                },
+            )
+            .await;
+
+        clear_state(root);
+
+        assert_eq!(with_metadata.metadata, metadata);
+        assert_eq!(with_metadata.content_type.as_deref(), Some("text/csv"));
+        assert_eq!(with_metadata.content_length, 3);
+        assert!(too_large.is_err());
+        assert!(replaced.metadata.is_empty());
+        assert_eq!(replaced.content_length, 5);
+        assert!(missing.is_err());
+    }
+
+    /// file system operations held up by a slow disk don't delay other requests
+    #[tokio::test]
+    async fn requests_answered_while_reads_are_held() {
+        let root = Path::new("/tmp/rust_test/provider3");
+        let root_value = root.to_string_lossy().to_string();
+        let provider = FsProvider::default();
+        let ctx = link(&provider, "actor", &[("ROOT", &root_value)]).await;
+        let container_id = "cont".to_string();
+        provider
+            .create_container(&ctx, &container_id)
+            .await
+            .unwrap();
+        let (provider_ref, ctx_ref, container_ref) = (&provider, &ctx, &container_id);
+        let probe = move || async move {
+            let started = Instant::now();
+            let exists = tokio::time::timeout(
+                Duration::from_secs(5),
+                provider_ref.container_exists(ctx_ref, container_ref),
+            )
+            .await;
+            (exists, started.elapsed())
+        };
+
+        let mut baseline = Duration::ZERO;
+        for _ in 0..5 {
+            baseline = baseline.max(probe().await.1);
+        }
+        // reads stuck on the disk until the gate opens
+        let gate = Arc::new(std::sync::RwLock::new(()));
+        let closed = gate.write().unwrap();
+        let held: Vec<_> = (0..8)
+            .map(|_| {
+                let (io, gate) = (provider.io.clone(), gate.clone());
+                tokio::spawn(async move {
+                    io.run(move || {
+                        drop(gate.read().unwrap());
+                        Ok(())
+                    })
+                    .await
+                })
+            })
+            .collect();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        let (exists, probe_time) = probe().await;
+        drop(closed);
+        let mut released = true;
+        for read in held {
+            released &= read.await.unwrap().is_ok();
+        }
+
+        clear_state(root);
+
+        assert!(matches!(exists, Ok(Ok(true))));
+        assert!(
+            probe_time <= baseline * 4 + Duration::from_millis(20),
+            "container_exists took {:?} while reads were held, {:?} without them",
+            probe_time,
+            baseline
+        );
+        assert!(released);
+    }
+
+    /// whether an object is decoded depends on how it was stored, not on its contents
+    #[tokio::test]
+    async fn encoding_recorded_in_metadata() {
+        async fn get(provider: &FsProvider, ctx: &Context, object_id: &str) -> RpcResult<Vec<u8>> {
+            let req = GetObjectRequest {
+                container_id: "cont".to_string(),
+                object_id: object_id.to_string(),
+                range_start: Some(0),
+                range_end: None,
+            };
+            let resp = provider.get_object(ctx, &req).await?;
+            Ok(resp.initial_chunk.unwrap_or_default().bytes)
+        }
+
+        let root = Path::new("/tmp/rust_test/provider4");
+        let root_value = root.to_string_lossy().to_string();
+        let provider = FsProvider::default();
+        let encoded = link(
+            &provider,
+            "encoded",
+            &[("ROOT", &root_value), ("COMPRESSION", "zstd")],
+        )
+        .await;
+        let plain = link(&provider, "plain", &[("ROOT", &root_value)]).await;
+        for ctx in [&encoded, &plain] {
+            provider
+                .create_container(ctx, &"cont".to_string())
+                .await
+                .unwrap();
+        }
+        // plain contents that start like an encoded file
+        let mut lookalike = b"BSFSOBJ\x01".to_vec();
+        lookalike.resize(100, 0);
+
+        put(&provider, &encoded, "cont", "a", &lookalike)
+            .await
+            .unwrap();
+        put(&provider, &plain, "cont", "a", &lookalike)
+            .await
+            .unwrap();
+        let decoded = get(&provider, &encoded, "a").await;
+        let as_stored = get(&provider, &plain, "a").await;
+        // a file copied into the container by another process isn't served by the encoding link
+        std::fs::write(root.join("encoded/cont/copied"), b"plain").unwrap();
+        let copied = get(&provider, &encoded, "copied").await;
+
+        clear_state(root);
+
+        assert_eq!(decoded.unwrap(), lookalike);
+        assert_eq!(as_stored.unwrap(), lookalike);
+        assert!(copied.is_err());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn object_hashes() {
+        async fn hash(
+            provider: &FsProvider,
+            ctx: &Context,
+            object_id: &str,
+        ) -> RpcResult<blobs::ObjectHash> {
+            let req = blobs::GetObjectHashRequest {
+                container_id: "cont".to_string(),
+                object_id: object_id.to_string(),
+            };
+            provider.get_object_hash(ctx, &req).await
+        }
+
+        let root = Path::new("/tmp/rust_test/provider2");
+        let root_value = root.to_string_lossy().to_string();
+        let provider = FsProvider::default();
+        let dedup = link(
+            &provider,
+            "dedup",
+            &[("ROOT", &root_value), ("DEDUP", "true")],
+        )
+        .await;
+        let plain = link(&provider, "plain", &[("ROOT", &root_value)]).await;
+
+        provider
+            .create_container(&dedup, &"cont".to_string())
+            .await
+            .unwrap();
+        provider
+            .create_container(&plain, &"cont".to_string())
+            .await
+            .unwrap();
+        put(&provider, &dedup, "cont", "a", b"artifact")
+            .await
+            .unwrap();
+        put(&provider, &dedup, "cont", "b", b"artifact")
+            .await
+            .unwrap();
+        put(&provider, &plain, "cont", "a", b"artifact")
+            .await
+            .unwrap();
+        let hash_a = hash(&provider, &dedup, "a").await.unwrap();
+        let hash_b = hash(&provider, &dedup, "b").await.unwrap();
+        let not_deduplicated = hash(&provider, &plain, "a").await.unwrap();
+        let missing = hash(&provider, &dedup, "c").await;
+        let read_only = metadata(root.join("dedup/cont/a"))
+            .unwrap()
+            .permissions()
+            .readonly();
+
+        clear_state(root);
+
+        assert_eq!(
+            hash_a,
+            blobs::ObjectHash {
+                container_id: "cont".to_string(),
+                object_id: "a".to_string(),
+                sha256: Some(
+                    "c7c5c1d70c5dec4416ab6158afd0b223ef40c29b1dc1f97ed9428b94d4cadb1c".to_string()
+                ),
+            }
+        );
+        assert_eq!(hash_b.sha256, hash_a.sha256);
+        assert_eq!(not_deduplicated.sha256, None);
+        assert!(missing.is_err());
+        assert!(read_only);
+    }
+}
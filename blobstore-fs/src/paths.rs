@@ -0,0 +1,196 @@
+use std::io::ErrorKind;
+use std::path::{Component, Path, PathBuf};
+use wasmbus_rpc::provider::prelude::*;
+
+/// Prefix of the names of files and directories used internally by the provider.
+/// Ids with a segment starting with this prefix are rejected, so these are never visible to actors.
+pub const RESERVED_PREFIX: &str = ".blobstore-";
+
+/// Checks that an actor-supplied container or object id is a non-empty relative path
+/// that cannot leave the directory it is joined onto. Ids are `/`-separated, and each
+/// segment must be non-empty, so that every id names exactly one path.
+/// `kind` is used in the error message, e.g., "container" or "object".
+pub fn validate_id(kind: &str, id: &str) -> RpcResult<()> {
+    if id.is_empty() {
+        return Err(RpcError::InvalidParameter(format!(
+            "{} id must not be empty",
+            kind
+        )));
+    }
+    if id.contains('\0') {
+        return Err(RpcError::InvalidParameter(format!(
+            "{} id '{}' must not contain NUL characters",
+            kind,
+            id.escape_default()
+        )));
+    }
+    if id.split('/').any(str::is_empty) {
+        return Err(RpcError::InvalidParameter(format!(
+            "{} id '{}' must not begin or end with '/' or contain '//'",
+            kind, id
+        )));
+    }
+    if !Path::new(id)
+        .components()
+        .all(|c| matches!(c, Component::Normal(_)))
+    {
+        return Err(RpcError::InvalidParameter(format!(
+            "{} id '{}' must be a relative path without '.' or '..' segments",
+            kind, id
+        )));
+    }
+    if is_reserved(Path::new(id)) {
+        return Err(RpcError::InvalidParameter(format!(
+            "{} id '{}' must not contain segments beginning with '{}'",
+            kind, id, RESERVED_PREFIX
+        )));
+    }
+    Ok(())
+}
+
+/// Returns true if `path` has a segment that is reserved for the provider's internal use
+pub fn is_reserved(path: &Path) -> bool {
+    path.components()
+        .any(|c| c.as_os_str().to_string_lossy().starts_with(RESERVED_PREFIX))
+}
+
+/// Resolves a sequence of actor-supplied ids, e.g., `[("container", "c1"), ("object", "o1")]`,
+/// to a path beneath `root`.
+/// Every id is validated with `validate_id`. The existing part of the resulting path is
+/// then checked for symbolic links: if `follow_symlinks` is false, any symbolic link below
+/// `root` is rejected, otherwise links are allowed as long as they resolve to a location
+/// inside `root`.
+pub fn resolve(root: &Path, ids: &[(&str, &str)], follow_symlinks: bool) -> RpcResult<PathBuf> {
+    let mut path = root.to_path_buf();
+    for (kind, id) in ids {
+        validate_id(kind, id)?;
+        path.push(id);
+    }
+    check_symlinks(root, &path, follow_symlinks)?;
+    Ok(path)
+}
+
+/// Walks `path` from `root` downwards, and checks every existing component for symbolic links.
+fn check_symlinks(root: &Path, path: &Path, follow_symlinks: bool) -> RpcResult<()> {
+    let relative = path.strip_prefix(root).map_err(|_| {
+        RpcError::InvalidParameter(format!("path {:?} is outside of {:?}", path, root))
+    })?;
+    let mut canonical_root: Option<PathBuf> = None;
+    let mut current = root.to_path_buf();
+    for component in relative.components() {
+        current.push(component);
+        let meta = match std::fs::symlink_metadata(&current) {
+            Ok(meta) => meta,
+            // nothing below a missing component can exist
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+        if !meta.file_type().is_symlink() {
+            continue;
+        }
+        if !follow_symlinks {
+            return Err(RpcError::InvalidParameter(format!(
+                "path {:?} contains a symbolic link, which is not allowed",
+                relative
+            )));
+        }
+        if canonical_root.is_none() {
+            canonical_root = Some(root.canonicalize()?);
+        }
+        let target = match current.canonicalize() {
+            Ok(target) => target,
+            // dangling link: it can't be read, and it's not safe to create its target
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                return Err(RpcError::InvalidParameter(format!(
+                    "path {:?} contains a dangling symbolic link",
+                    relative
+                )))
+            }
+            Err(e) => return Err(e.into()),
+        };
+        if !target.starts_with(canonical_root.as_ref().unwrap()) {
+            return Err(RpcError::InvalidParameter(format!(
+                "path {:?} contains a symbolic link that leads outside of the actor's directory",
+                relative
+            )));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs_utils::clear_state;
+    use std::fs::create_dir_all;
+
+    #[test]
+    fn valid_ids() {
+        assert!(validate_id("container", "cont1").is_ok());
+        assert!(validate_id("container", "cont2/cont3").is_ok());
+        assert!(validate_id("object", "file.txt").is_ok());
+        assert!(validate_id("object", "..file").is_ok());
+        assert!(validate_id("object", "images/2023/a.png").is_ok());
+    }
+
+    #[test]
+    fn hostile_ids() {
+        for id in [
+            "",
+            ".",
+            "..",
+            "../escape",
+            "cont1/../../escape",
+            "./cont1",
+            "/etc/passwd",
+            "nul\0byte",
+            ".blobstore-meta",
+            "cont1/.blobstore-meta/file1.json",
+            "images/",
+            "images//a.png",
+        ] {
+            assert!(
+                validate_id("object", id).is_err(),
+                "{:?} should be rejected",
+                id
+            );
+        }
+    }
+
+    #[test]
+    fn resolve_plain_path() {
+        let root = Path::new("/tmp/rust_test/paths1");
+        create_dir_all(root.join("cont1")).unwrap();
+
+        let path = resolve(root, &[("container", "cont1"), ("object", "obj")], false);
+
+        clear_state(root);
+
+        assert_eq!(path.unwrap(), root.join("cont1/obj"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn resolve_symlinks() {
+        use std::os::unix::fs::symlink;
+
+        let base = Path::new("/tmp/rust_test/paths2");
+        let root = base.join("root");
+        create_dir_all(root.join("cont1")).unwrap();
+        create_dir_all(base.join("outside")).unwrap();
+        symlink(base.join("outside"), root.join("escape")).unwrap();
+        symlink(root.join("cont1"), root.join("alias")).unwrap();
+
+        let escape_nofollow = resolve(&root, &[("container", "escape")], false);
+        let escape_follow = resolve(&root, &[("container", "escape")], true);
+        let alias_nofollow = resolve(&root, &[("container", "alias")], false);
+        let alias_follow = resolve(&root, &[("container", "alias"), ("object", "obj")], true);
+
+        clear_state(base);
+
+        assert!(escape_nofollow.is_err());
+        assert!(escape_follow.is_err());
+        assert!(alias_nofollow.is_err());
+        assert!(alias_follow.is_ok());
+    }
+}
@@ -0,0 +1,534 @@
+//! Per-actor storage limits.
+//!
+//! Limits are set with link definition values, and apply to all containers of the actor.
+//! Each actor has an `Account` holding its usage: the objects on disk are measured when the usage
+//! is first needed, and again every `USAGE_REFRESH_INTERVAL`, so files added or removed by other
+//! processes and by the background tasks are taken into account. In between, the account is updated
+//! as the provider stores and removes objects. Uploads are checked when they start and whenever they
+//! receive a chunk, using the size of the object as far as it is known from the chunks received so far,
+//! and that size is reserved in the account until the upload completes or is cancelled, so that
+//! concurrent uploads can't exceed the limits together.
+//!
+//! Actors read their usage and limits with the `BlobstoreQuota` service, which the provider
+//! implements alongside `Blobstore`.
+
+use crate::{paths, sidecar, versions};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::Path;
+use std::time::{Duration, Instant};
+use wasmbus_rpc::common::{deserialize, serialize};
+use wasmbus_rpc::provider::prelude::*;
+
+/// Link value for the maximum number of bytes stored by an actor, in all containers
+pub const MAX_TOTAL_BYTES: &str = "MAX_TOTAL_BYTES";
+/// Link value for the maximum number of objects stored by an actor, in all containers
+pub const MAX_OBJECTS: &str = "MAX_OBJECTS";
+/// Link value for the maximum size of a single object
+pub const MAX_OBJECT_SIZE: &str = "MAX_OBJECT_SIZE";
+
+/// Beginning of the message of errors returned when a limit would be exceeded,
+/// so that actors can tell them apart from other failures
+pub const QUOTA_EXCEEDED: &str = "QuotaExceeded";
+
+/// time after which the usage of an actor is measured on disk again
+pub const USAGE_REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Storage limits of an actor. `None` means unlimited.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Quota {
+    pub max_total_bytes: Option<u64>,
+    pub max_objects: Option<u64>,
+    pub max_object_size: Option<u64>,
+}
+
+/// Bytes and objects stored
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Usage {
+    pub bytes: u64,
+    pub objects: u64,
+}
+
+impl Quota {
+    /// Reads the limits from link definition values
+    pub fn from_values(values: &HashMap<String, String>) -> RpcResult<Self> {
+        Ok(Quota {
+            max_total_bytes: parse_limit(values, MAX_TOTAL_BYTES)?,
+            max_objects: parse_limit(values, MAX_OBJECTS)?,
+            max_object_size: parse_limit(values, MAX_OBJECT_SIZE)?,
+        })
+    }
+
+    /// Returns true if the actor's total usage has to be measured to enforce the limits
+    pub fn limits_usage(&self) -> bool {
+        self.max_total_bytes.is_some() || self.max_objects.is_some()
+    }
+
+    /// Checks the size of an object being stored
+    pub fn check_object_size(&self, object_id: &str, size: u64) -> RpcResult<()> {
+        match self.max_object_size {
+            Some(max) if size > max => Err(exceeded(format!(
+                "object '{}' would be {} bytes, the maximum object size is {} bytes",
+                object_id, size, max
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    /// Checks the actor's usage after storing an object, given the actor's current usage,
+    /// the size of the new object, and the size of the object it replaces, if any.
+    pub fn check_usage(
+        &self,
+        object_id: &str,
+        current: Usage,
+        size: u64,
+        replaced_size: Option<u64>,
+    ) -> RpcResult<()> {
+        if let Some(max) = self.max_objects {
+            let objects = current.objects + u64::from(replaced_size.is_none());
+            if objects > max {
+                return Err(exceeded(format!(
+                    "storing object '{}' would exceed the maximum of {} objects",
+                    object_id, max
+                )));
+            }
+        }
+        if let Some(max) = self.max_total_bytes {
+            let bytes = (current.bytes - replaced_size.unwrap_or(0).min(current.bytes)) + size;
+            if bytes > max {
+                return Err(exceeded(format!(
+                    "storing object '{}' would use {} bytes, the maximum is {} bytes",
+                    object_id, bytes, max
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Usage of an actor's storage, kept between requests so it doesn't have to be measured
+/// on disk for every upload, and the space reserved by the actor's uploads in progress
+#[derive(Debug, Default)]
+pub struct Account {
+    /// usage of the stored objects, and when it was last measured on disk
+    stored: Option<(Usage, Instant)>,
+    /// uploads in progress, by upload id
+    reserved: HashMap<String, Reservation>,
+}
+
+/// Space reserved for an upload in progress
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Reservation {
+    /// size of the object as far as it is known
+    size: u64,
+    /// size of the object it replaces, if there is one
+    replaced_size: Option<u64>,
+}
+
+impl Account {
+    /// Returns the usage of the stored objects, unless it has to be measured (again)
+    pub fn stored(&self, now: Instant) -> Option<Usage> {
+        match self.stored {
+            Some((usage, measured)) if now.duration_since(measured) < USAGE_REFRESH_INTERVAL => {
+                Some(usage)
+            }
+            _ => None,
+        }
+    }
+
+    /// Sets the usage of the stored objects, as measured on disk
+    pub fn measured(&mut self, usage: Usage, now: Instant) {
+        self.stored = Some((usage, now));
+    }
+
+    /// Discards the usage of the stored objects, so it is measured before it is used again
+    pub fn invalidate(&mut self) {
+        self.stored = None;
+    }
+
+    /// Returns the number of bytes reserved by uploads in progress
+    pub fn reserved_bytes(&self) -> u64 {
+        self.reserved.values().map(|r| r.size).sum()
+    }
+
+    /// Reserves `size` bytes for upload `upload_id` of object `object_id`, replacing the upload's
+    /// previous reservation. Fails, without changing the reservation, if the limits would be exceeded
+    /// once this upload and all other uploads in progress are stored. The stored usage must be known.
+    pub fn reserve(
+        &mut self,
+        quota: &Quota,
+        upload_id: &str,
+        object_id: &str,
+        size: u64,
+        replaced_size: Option<u64>,
+    ) -> RpcResult<()> {
+        let mut current = self.stored.map(|(usage, _)| usage).unwrap_or_default();
+        for (_, other) in self.reserved.iter().filter(|(id, _)| *id != upload_id) {
+            // uploads replacing an object don't free any space until they are stored
+            current.bytes += other.size.saturating_sub(other.replaced_size.unwrap_or(0));
+            current.objects += u64::from(other.replaced_size.is_none());
+        }
+        quota.check_usage(object_id, current, size, replaced_size)?;
+        self.reserved.insert(
+            upload_id.to_string(),
+            Reservation {
+                size,
+                replaced_size,
+            },
+        );
+        Ok(())
+    }
+
+    /// Releases the space reserved for an upload that won't be stored
+    pub fn release(&mut self, upload_id: &str) {
+        self.reserved.remove(upload_id);
+    }
+
+    /// Moves the space reserved for an upload to the stored usage, once the object has been stored.
+    /// `stored_len` is the size of the object on disk, if known. When objects are versioned,
+    /// the object that was replaced is kept as a noncurrent version, so its bytes are still used.
+    /// Uploads of actors without usage limits reserve no space, so the usage is measured again.
+    pub fn commit(&mut self, upload_id: &str, stored_len: Option<u64>, versioned: bool) {
+        let reservation = match self.reserved.remove(upload_id) {
+            Some(reservation) => reservation,
+            None => {
+                self.invalidate();
+                return;
+            }
+        };
+        if let Some((usage, _)) = &mut self.stored {
+            usage.bytes += stored_len.unwrap_or(reservation.size);
+            match reservation.replaced_size {
+                Some(replaced) if !versioned => usage.bytes = usage.bytes.saturating_sub(replaced),
+                Some(_) => {}
+                None => usage.objects += 1,
+            }
+        }
+    }
+
+    /// Records that an object of `size` bytes has been removed. When objects are versioned,
+    /// the object is kept as a noncurrent version, so its bytes are still used.
+    pub fn removed(&mut self, size: u64, versioned: bool) {
+        if let Some((usage, _)) = &mut self.stored {
+            if !versioned {
+                usage.bytes = usage.bytes.saturating_sub(size);
+            }
+            usage.objects = usage.objects.saturating_sub(1);
+        }
+    }
+}
+
+/// Argument of `BlobstoreQuota.GetStorageUsage`
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StorageUsageRequest {
+    /// container whose usage is returned as well, if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub container_id: Option<String>,
+}
+
+/// Result of `BlobstoreQuota.GetStorageUsage`
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StorageUsage {
+    /// bytes and objects stored by the actor, in all containers
+    pub usage: Usage,
+    /// bytes reserved by the actor's uploads in progress
+    pub reserved_bytes: u64,
+    /// the actor's limits
+    pub limits: Quota,
+    /// bytes and objects stored in the requested container
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub container: Option<Usage>,
+}
+
+/// Storage usage operations, an extension of the blobstore interface.
+/// Actors call them as `BlobstoreQuota.<Operation>` on the blobstore link.
+#[async_trait]
+pub trait BlobstoreQuota {
+    /// Returns the actor's usage and limits, and optionally the usage of one container
+    async fn get_storage_usage(
+        &self,
+        ctx: &Context,
+        arg: &StorageUsageRequest,
+    ) -> RpcResult<StorageUsage>;
+}
+
+/// Receives `BlobstoreQuota` messages, like the receivers generated for smithy interfaces
+#[async_trait]
+pub trait BlobstoreQuotaReceiver: MessageDispatch + BlobstoreQuota {
+    async fn dispatch(&self, ctx: &Context, message: Message<'_>) -> RpcResult<Vec<u8>> {
+        match message.method {
+            "GetStorageUsage" => {
+                let value: StorageUsageRequest = deserialize(&message.arg)
+                    .map_err(|e| RpcError::Deser(format!("'StorageUsageRequest': {}", e)))?;
+                let resp = BlobstoreQuota::get_storage_usage(self, ctx, &value).await?;
+                serialize(&resp)
+            }
+            _ => Err(RpcError::MethodNotHandled(format!(
+                "BlobstoreQuota::{}",
+                message.method
+            ))),
+        }
+    }
+}
+
+fn parse_limit(values: &HashMap<String, String>, key: &str) -> RpcResult<Option<u64>> {
+    match values.get(key) {
+        None => Ok(None),
+        Some(v) => v.parse::<u64>().map(Some).map_err(|_| {
+            RpcError::InvalidParameter(format!(
+                "{} must be a non-negative integer, got '{}'",
+                key, v
+            ))
+        }),
+    }
+}
+
+fn exceeded(msg: String) -> RpcError {
+    RpcError::Other(format!("{}: {}", QUOTA_EXCEEDED, msg))
+}
+
+/// Measures the objects stored below `dir`, ignoring the provider's internal files.
+/// The bytes of noncurrent object versions are counted, but they are not objects.
+/// Symbolic links are not followed.
+pub fn usage(dir: &Path) -> std::io::Result<Usage> {
+    measure(dir, None)
+}
+
+/// Measures the objects, and noncurrent object versions, stored below `dir` by actor `owner`.
+/// Used for shared namespaces, where each actor's objects count towards its own quota.
+pub fn owned_usage(dir: &Path, owner: &str) -> std::io::Result<Usage> {
+    measure(dir, Some(owner))
+}
+
+fn measure(dir: &Path, owner: Option<&str>) -> std::io::Result<Usage> {
+    let mut total = Usage::default();
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(total),
+        Err(e) => return Err(e),
+    };
+    for entry in entries {
+        let entry = entry?;
+        if entry.file_name() == versions::VERSIONS_DIR {
+            total.bytes += versions::stored_bytes(&entry.path(), owner)?;
+            continue;
+        }
+        if paths::is_reserved(Path::new(&entry.file_name())) {
+            continue;
+        }
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            let sub = measure(&entry.path(), owner)?;
+            total.bytes += sub.bytes;
+            total.objects += sub.objects;
+        } else if file_type.is_file() && owner.map_or(true, |owner| is_owned_by(dir, &entry, owner))
+        {
+            total.bytes += entry.metadata()?.len();
+            total.objects += 1;
+        }
+    }
+    Ok(total)
+}
+
+/// Returns true if the object of a directory entry was stored by actor `owner`
+fn is_owned_by(dir: &Path, entry: &std::fs::DirEntry, owner: &str) -> bool {
+    let object_id = entry.file_name().to_string_lossy().to_string();
+    sidecar::read(dir, &object_id)
+        .and_then(|s| s.owner)
+        .as_deref()
+        == Some(owner)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs_utils::clear_state;
+    use std::fs::create_dir_all;
+
+    #[test]
+    fn parse_values() {
+        let mut values = HashMap::new();
+        assert_eq!(Quota::from_values(&values).unwrap(), Quota::default());
+
+        values.insert(MAX_TOTAL_BYTES.to_string(), "1000".to_string());
+        values.insert(MAX_OBJECT_SIZE.to_string(), "100".to_string());
+        let quota = Quota::from_values(&values).unwrap();
+        assert_eq!(quota.max_total_bytes, Some(1000));
+        assert_eq!(quota.max_objects, None);
+        assert_eq!(quota.max_object_size, Some(100));
+
+        values.insert(MAX_OBJECTS.to_string(), "-1".to_string());
+        assert!(Quota::from_values(&values).is_err());
+    }
+
+    #[test]
+    fn check_limits() {
+        let quota = Quota {
+            max_total_bytes: Some(100),
+            max_objects: Some(2),
+            max_object_size: Some(60),
+        };
+        let current = Usage {
+            bytes: 50,
+            objects: 2,
+        };
+
+        assert!(quota.check_object_size("obj", 60).is_ok());
+        let too_large = quota.check_object_size("obj", 61).unwrap_err();
+        assert!(too_large.to_string().contains(QUOTA_EXCEEDED));
+        // a new object exceeds the object count, replacing one doesn't
+        assert!(quota.check_usage("obj", current, 10, None).is_err());
+        assert!(quota.check_usage("obj", current, 10, Some(40)).is_ok());
+        // replacing a 10 byte object with 70 bytes exceeds the total
+        assert!(quota.check_usage("obj", current, 70, Some(10)).is_err());
+        assert!(quota.check_usage("obj", current, 70, Some(40)).is_ok());
+        assert!(quota.check_usage("obj", current, 60, Some(10)).is_ok());
+    }
+
+    #[test]
+    fn reserve_for_uploads() {
+        let quota = Quota {
+            max_total_bytes: Some(100),
+            max_objects: Some(3),
+            max_object_size: None,
+        };
+        let now = Instant::now();
+        let mut account = Account::default();
+        assert_eq!(account.stored(now), None);
+        account.measured(
+            Usage {
+                bytes: 40,
+                objects: 1,
+            },
+            now,
+        );
+
+        // two uploads in progress can't exceed the limit together
+        assert!(account.reserve(&quota, "up1", "a", 30, None).is_ok());
+        assert!(account.reserve(&quota, "up2", "b", 31, None).is_err());
+        assert!(account.reserve(&quota, "up2", "b", 30, None).is_ok());
+        assert_eq!(account.reserved_bytes(), 60);
+        // a larger reservation for the same upload replaces the previous one
+        assert!(account.reserve(&quota, "up1", "a", 31, None).is_err());
+        assert_eq!(account.reserved_bytes(), 60);
+        // the object count includes uploads in progress
+        assert!(account.reserve(&quota, "up3", "c", 0, None).is_err());
+
+        account.release("up2");
+        account.commit("up1", Some(25), false);
+        assert_eq!(
+            account.stored(now),
+            Some(Usage {
+                bytes: 65,
+                objects: 2
+            })
+        );
+        // replacing an object frees its bytes, unless the object is versioned
+        account.reserve(&quota, "up4", "a", 10, Some(25)).unwrap();
+        account.commit("up4", None, false);
+        account.reserve(&quota, "up5", "a", 10, Some(10)).unwrap();
+        account.commit("up5", None, true);
+        account.removed(15, false);
+        assert_eq!(
+            account.stored(now),
+            Some(Usage {
+                bytes: 45,
+                objects: 1
+            })
+        );
+        assert_eq!(account.reserved_bytes(), 0);
+        assert_eq!(account.stored(now + USAGE_REFRESH_INTERVAL), None);
+        account.invalidate();
+        assert_eq!(account.stored(now), None);
+    }
+
+    #[test]
+    fn measure_usage() {
+        let root = Path::new("/tmp/rust_test/quota1");
+        create_dir_all(root.join("cont1/dir")).unwrap();
+        create_dir_all(root.join("cont2/.blobstore-meta")).unwrap();
+        std::fs::write(root.join("cont1/obj1"), [0u8; 10]).unwrap();
+        std::fs::write(root.join("cont1/dir/obj2"), [0u8; 20]).unwrap();
+        std::fs::write(root.join("cont2/obj3"), [0u8; 5]).unwrap();
+        std::fs::write(root.join("cont2/.blobstore-meta/obj3.json"), [0u8; 100]).unwrap();
+        // a noncurrent version of obj3
+        create_dir_all(root.join("cont2/.blobstore-versions/obj3")).unwrap();
+        std::fs::write(root.join("cont2/.blobstore-versions/obj3/v1"), [0u8; 7]).unwrap();
+        std::fs::write(
+            root.join("cont2/.blobstore-versions/obj3/v1.json"),
+            [0u8; 50],
+        )
+        .unwrap();
+
+        let total = usage(root).unwrap();
+        let cont2 = usage(&root.join("cont2")).unwrap();
+        let missing = usage(&root.join("missing")).unwrap();
+
+        clear_state(root);
+
+        assert_eq!(
+            total,
+            Usage {
+                bytes: 42,
+                objects: 3
+            }
+        );
+        assert_eq!(
+            cont2,
+            Usage {
+                bytes: 12,
+                objects: 1
+            }
+        );
+        assert_eq!(missing, Usage::default());
+    }
+
+    #[test]
+    fn measure_owned_usage() {
+        let root = Path::new("/tmp/rust_test/quota2");
+        create_dir_all(root.join("sub")).unwrap();
+        for (object_id, size, owner) in [
+            ("a", 5, Some("actor1")),
+            ("b", 7, Some("actor2")),
+            ("sub/c", 3, Some("actor1")),
+            ("d", 2, None),
+        ] {
+            std::fs::write(root.join(object_id), vec![0u8; size]).unwrap();
+            if let Some(owner) = owner {
+                let mut object_sidecar = sidecar::ObjectSidecar::new(None, None);
+                object_sidecar.owner = Some(owner.to_string());
+                sidecar::write(root, object_id, &object_sidecar).unwrap();
+            }
+        }
+        // a noncurrent version of a, which keeps its owner
+        versions::keep_current(root, "a").unwrap();
+
+        let actor1 = owned_usage(root, "actor1").unwrap();
+        let actor2 = owned_usage(root, "actor2").unwrap();
+        let total = usage(root).unwrap();
+
+        clear_state(root);
+
+        assert_eq!(
+            actor1,
+            Usage {
+                bytes: 13,
+                objects: 2
+            }
+        );
+        assert_eq!(
+            actor2,
+            Usage {
+                bytes: 7,
+                objects: 1
+            }
+        );
+        assert_eq!(
+            total,
+            Usage {
+                bytes: 22,
+                objects: 4
+            }
+        );
+    }
+}
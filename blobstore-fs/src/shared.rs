@@ -0,0 +1,128 @@
+//! Containers shared between actors.
+//!
+//! By default each actor only sees the containers in its own directory, `$ROOT/<actor_id>`.
+//! The link value `SHARED_CONTAINERS` mounts named shared namespaces into the actor's container
+//! space, e.g., `SHARED_CONTAINERS=uploads:rw,thumbnails:ro`. A shared namespace `<name>` is stored
+//! in `$ROOT/.blobstore-shared/<name>`, and is visible to every actor linked with the same root that
+//! mounts it, as the container `<name>` and the containers nested below it, e.g., `<name>/2024`.
+
+use crate::paths;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use wasmbus_rpc::provider::prelude::*;
+
+/// Link value listing the shared namespaces mounted for an actor
+pub const SHARED_CONTAINERS: &str = "SHARED_CONTAINERS";
+
+/// Name of the directory, inside the root, holding the shared namespaces.
+/// Starts with `paths::RESERVED_PREFIX` so it can't be an actor id.
+pub const SHARED_DIR: &str = ".blobstore-shared";
+
+/// How an actor may use a shared namespace
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+pub enum Mode {
+    ReadOnly,
+    ReadWrite,
+}
+
+/// What an operation does with the path it resolves
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+}
+
+/// Parses the value of `SHARED_CONTAINERS`: a comma-separated list of `<name>:ro` or `<name>:rw`.
+/// A name without a mode is mounted read-only.
+pub fn parse_mounts(value: &str) -> RpcResult<HashMap<String, Mode>> {
+    let mut mounts = HashMap::new();
+    for entry in value.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        let (name, mode) = match entry.split_once(':') {
+            None => (entry, Mode::ReadOnly),
+            Some((name, "ro")) => (name, Mode::ReadOnly),
+            Some((name, "rw")) => (name, Mode::ReadWrite),
+            Some((_, mode)) => {
+                return Err(RpcError::InvalidParameter(format!(
+                    "{}: invalid mode '{}' for '{}', expected 'ro' or 'rw'",
+                    SHARED_CONTAINERS, mode, entry
+                )))
+            }
+        };
+        paths::validate_id("shared container", name)?;
+        if name.contains('/') {
+            return Err(RpcError::InvalidParameter(format!(
+                "{}: shared container name '{}' must not contain '/'",
+                SHARED_CONTAINERS, name
+            )));
+        }
+        mounts.insert(name.to_string(), mode);
+    }
+    Ok(mounts)
+}
+
+/// Returns the directory of a shared namespace
+pub fn namespace_dir(root: &Path, name: &str) -> PathBuf {
+    root.join(SHARED_DIR).join(name)
+}
+
+/// Splits a container id into its first segment, which may name a shared namespace,
+/// and the rest of the id, which is empty if the id has a single segment.
+pub fn split_container_id(container_id: &str) -> (&str, &str) {
+    match container_id.split_once('/') {
+        Some((first, rest)) => (first, rest),
+        None => (container_id, ""),
+    }
+}
+
+/// Checks that an operation is allowed on a shared namespace
+pub fn check_access(name: &str, mode: Mode, access: Access) -> RpcResult<()> {
+    if mode == Mode::ReadOnly && access == Access::Write {
+        return Err(RpcError::InvalidParameter(format!(
+            "shared container '{}' is read-only",
+            name
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shared_dir_is_reserved() {
+        assert!(SHARED_DIR.starts_with(paths::RESERVED_PREFIX));
+    }
+
+    #[test]
+    fn parse_mount_values() {
+        let mounts = parse_mounts("uploads:rw, thumbnails:ro,logs").unwrap();
+        assert_eq!(mounts.len(), 3);
+        assert_eq!(mounts.get("uploads"), Some(&Mode::ReadWrite));
+        assert_eq!(mounts.get("thumbnails"), Some(&Mode::ReadOnly));
+        assert_eq!(mounts.get("logs"), Some(&Mode::ReadOnly));
+        assert!(parse_mounts("").unwrap().is_empty());
+
+        assert!(parse_mounts("uploads:rx").is_err());
+        assert!(parse_mounts("../escape:rw").is_err());
+        assert!(parse_mounts("a/b:rw").is_err());
+        assert!(parse_mounts(".blobstore-meta:ro").is_err());
+    }
+
+    #[test]
+    fn split_ids() {
+        assert_eq!(split_container_id("uploads"), ("uploads", ""));
+        assert_eq!(
+            split_container_id("uploads/2024/01"),
+            ("uploads", "2024/01")
+        );
+    }
+
+    #[test]
+    fn read_only_access() {
+        assert!(check_access("uploads", Mode::ReadOnly, Access::Read).is_ok());
+        assert!(check_access("uploads", Mode::ReadOnly, Access::Write).is_err());
+        assert!(check_access("uploads", Mode::ReadWrite, Access::Write).is_ok());
+    }
+}
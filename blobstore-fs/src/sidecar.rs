@@ -0,0 +1,319 @@
+//! Per-object metadata, stored next to the objects in a hidden directory of each container.
+//!
+//! The metadata of object `<container>/<object_id>` is kept as json in
+//! `<container>/.blobstore-meta/<object_id>.json`. For an object in a folder, i.e., with `/` in its id,
+//! the metadata is kept in the folder's own `.blobstore-meta` directory, so it is found whether the
+//! object is addressed through the container or through a nested container. Objects without a metadata file,
+//! for example files copied into the container by other processes, are still served,
+//! with a content type guessed from the object id's extension.
+//!
+//! User-defined metadata has no place in the blobstore interface, so actors set it with the
+//! `BlobstoreMetadata` service, which the provider implements alongside `Blobstore`, like the
+//! blobstore-s3 provider: `BlobstoreMetadata.PutObjectWithMetadata` instead of `Blobstore.PutObject`,
+//! continued with `Blobstore.PutChunk` for objects sent in several chunks, and
+//! `BlobstoreMetadata.GetObjectMetadata` to read it back.
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use tracing::warn;
+use wasmbus_rpc::common::{deserialize, serialize};
+use wasmbus_rpc::provider::prelude::*;
+use wasmbus_rpc::Timestamp;
+use wasmcloud_interface_blobstore::{Chunk, ContainerObject, PutObjectResponse};
+
+/// Name of the directory, inside each container, holding object metadata.
+/// Starts with `paths::RESERVED_PREFIX` so it is hidden from actors.
+pub const METADATA_DIR: &str = ".blobstore-meta";
+
+/// maximum size of the user-defined metadata of an object, the same as S3's (2KB)
+const MAX_USER_METADATA_SIZE: usize = 2048;
+
+/// Metadata stored for each object
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ObjectSidecar {
+    /// content type provided by the actor when the object was stored
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
+    /// content encoding provided by the actor when the object was stored
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_encoding: Option<String>,
+    /// time the object was stored, in seconds since the unix epoch
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<u64>,
+    /// hex-encoded SHA-256 hash of the contents, recorded when objects are deduplicated
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+    /// true if the object is stored compressed and/or encrypted, see `codec`
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub encoded: bool,
+    /// version id of the object, recorded when objects are versioned
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version_id: Option<String>,
+    /// id of the actor that stored the object, recorded for objects in shared namespaces,
+    /// whose size counts towards that actor's quota
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub owner: Option<String>,
+    /// user-defined key/value pairs, set with `BlobstoreMetadata.PutObjectWithMetadata`
+    /// and returned by `BlobstoreMetadata.GetObjectMetadata`
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub user_metadata: HashMap<String, String>,
+}
+
+impl ObjectSidecar {
+    /// Creates metadata for an object being stored now
+    pub fn new(content_type: Option<String>, content_encoding: Option<String>) -> Self {
+        ObjectSidecar {
+            content_type,
+            content_encoding,
+            created_at: SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .ok()
+                .map(|d| d.as_secs()),
+            sha256: None,
+            encoded: false,
+            version_id: None,
+            owner: None,
+            user_metadata: HashMap::new(),
+        }
+    }
+}
+
+/// Argument of `BlobstoreMetadata.PutObjectWithMetadata`
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PutObjectWithMetadataRequest {
+    /// first chunk of the object; if `is_last` is false, the rest is sent with `put_chunk`
+    pub chunk: Chunk,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_encoding: Option<String>,
+    /// user-defined metadata
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub metadata: HashMap<String, String>,
+}
+
+impl PutObjectWithMetadataRequest {
+    /// Checks that the user-defined metadata fits the limit
+    pub fn validate(&self) -> RpcResult<()> {
+        let size: usize = self.metadata.iter().map(|(k, v)| k.len() + v.len()).sum();
+        if size > MAX_USER_METADATA_SIZE {
+            return Err(RpcError::InvalidParameter(format!(
+                "user-defined metadata is {} bytes, the maximum is {}",
+                size, MAX_USER_METADATA_SIZE
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Result of `BlobstoreMetadata.GetObjectMetadata`
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ObjectMetadataWithHeaders {
+    pub container_id: String,
+    pub object_id: String,
+    pub content_length: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_modified: Option<Timestamp>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_encoding: Option<String>,
+    /// user-defined metadata
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub metadata: HashMap<String, String>,
+}
+
+/// Operations on the metadata of objects, an extension of the blobstore interface.
+/// Actors call them as `BlobstoreMetadata.<Operation>` on the blobstore link.
+#[async_trait]
+pub trait BlobstoreMetadata {
+    /// Puts an object, like `put_object`, with user-defined metadata
+    async fn put_object_with_metadata(
+        &self,
+        ctx: &Context,
+        arg: &PutObjectWithMetadataRequest,
+    ) -> RpcResult<PutObjectResponse>;
+
+    /// Returns the metadata of an object, with its user-defined metadata
+    async fn get_object_metadata(
+        &self,
+        ctx: &Context,
+        arg: &ContainerObject,
+    ) -> RpcResult<ObjectMetadataWithHeaders>;
+}
+
+/// Receives `BlobstoreMetadata` messages, like the receivers generated for smithy interfaces
+#[async_trait]
+pub trait BlobstoreMetadataReceiver: MessageDispatch + BlobstoreMetadata {
+    async fn dispatch(&self, ctx: &Context, message: Message<'_>) -> RpcResult<Vec<u8>> {
+        match message.method {
+            "PutObjectWithMetadata" => {
+                let value: PutObjectWithMetadataRequest =
+                    deserialize(&message.arg).map_err(|e| {
+                        RpcError::Deser(format!("'PutObjectWithMetadataRequest': {}", e))
+                    })?;
+                let resp = BlobstoreMetadata::put_object_with_metadata(self, ctx, &value).await?;
+                serialize(&resp)
+            }
+            "GetObjectMetadata" => {
+                let value: ContainerObject = deserialize(&message.arg)
+                    .map_err(|e| RpcError::Deser(format!("'ContainerObject': {}", e)))?;
+                let resp = BlobstoreMetadata::get_object_metadata(self, ctx, &value).await?;
+                serialize(&resp)
+            }
+            _ => Err(RpcError::MethodNotHandled(format!(
+                "BlobstoreMetadata::{}",
+                message.method
+            ))),
+        }
+    }
+}
+
+/// Returns the location of the metadata file for an object
+pub fn sidecar_path(container_dir: &Path, object_id: &str) -> PathBuf {
+    let object_path = container_dir.join(object_id);
+    let dir = object_path.parent().unwrap_or(container_dir);
+    let name = object_path
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_default();
+    dir.join(METADATA_DIR).join(format!("{}.json", name))
+}
+
+/// Reads the metadata of an object. Returns None if there is no metadata file,
+/// or if the file can't be read or parsed.
+pub fn read(container_dir: &Path, object_id: &str) -> Option<ObjectSidecar> {
+    let path = sidecar_path(container_dir, object_id);
+    let data = match std::fs::read(&path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == ErrorKind::NotFound => return None,
+        Err(e) => {
+            warn!("Could not read object metadata {:?}: {}", path, e);
+            return None;
+        }
+    };
+    match serde_json::from_slice(&data) {
+        Ok(sidecar) => Some(sidecar),
+        Err(e) => {
+            warn!("Ignoring corrupt object metadata {:?}: {}", path, e);
+            None
+        }
+    }
+}
+
+/// Writes the metadata of an object, replacing any previous metadata
+pub fn write(
+    container_dir: &Path,
+    object_id: &str,
+    sidecar: &ObjectSidecar,
+) -> std::io::Result<()> {
+    let path = sidecar_path(container_dir, object_id);
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let data = serde_json::to_vec(sidecar)?;
+    // write to a temporary file and rename it so readers never see partial json
+    let tmp_path = path.with_extension("json.tmp");
+    std::fs::write(&tmp_path, data)?;
+    std::fs::rename(&tmp_path, &path)
+}
+
+/// Removes the metadata of an object, if there is any
+pub fn remove(container_dir: &Path, object_id: &str) -> std::io::Result<()> {
+    match std::fs::remove_file(sidecar_path(container_dir, object_id)) {
+        Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// Returns true if the object was stored encoded, see `codec`
+pub fn is_encoded(container_dir: &Path, object_id: &str) -> bool {
+    read(container_dir, object_id)
+        .map(|s| s.encoded)
+        .unwrap_or(false)
+}
+
+/// Returns the content type and content encoding of an object.
+/// Values stored with the object take precedence; if no content type was stored,
+/// it is guessed from the extension of the object id.
+pub fn content_headers(container_dir: &Path, object_id: &str) -> (Option<String>, Option<String>) {
+    let sidecar = read(container_dir, object_id).unwrap_or_default();
+    let content_type = sidecar.content_type.or_else(|| {
+        mime_guess::from_path(object_id)
+            .first()
+            .map(|mime| mime.to_string())
+    });
+    (content_type, sidecar.content_encoding)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs_utils::clear_state;
+    use std::fs::create_dir_all;
+
+    #[test]
+    fn metadata_dir_is_reserved() {
+        assert!(METADATA_DIR.starts_with(crate::paths::RESERVED_PREFIX));
+    }
+
+    #[test]
+    fn write_read_remove() {
+        let root = Path::new("/tmp/rust_test/sidecar1");
+        create_dir_all(root).unwrap();
+
+        let mut sidecar = ObjectSidecar::new(Some("text/plain".into()), Some("gzip".into()));
+        sidecar.user_metadata.insert("owner".into(), "me".into());
+        write(root, "file1", &sidecar).unwrap();
+        let read_back = read(root, "file1");
+        let headers = content_headers(root, "file1");
+        remove(root, "file1").unwrap();
+        let removed = read(root, "file1");
+
+        clear_state(root);
+
+        assert_eq!(read_back, Some(sidecar));
+        assert_eq!(
+            headers,
+            (Some("text/plain".to_string()), Some("gzip".to_string()))
+        );
+        assert_eq!(removed, None);
+    }
+
+    #[test]
+    fn metadata_of_objects_in_folders() {
+        let root = Path::new("/tmp/rust_test/sidecar3");
+        create_dir_all(root.join("images")).unwrap();
+
+        let sidecar = ObjectSidecar::new(Some("image/webp".into()), None);
+        write(root, "images/a", &sidecar).unwrap();
+        // the same object, addressed through the folder
+        let nested = read(&root.join("images"), "a");
+
+        clear_state(root);
+
+        assert_eq!(
+            sidecar_path(root, "images/a"),
+            root.join("images/.blobstore-meta/a.json")
+        );
+        assert_eq!(nested, Some(sidecar));
+    }
+
+    #[test]
+    fn guess_content_type() {
+        let root = Path::new("/tmp/rust_test/sidecar2");
+
+        assert_eq!(
+            content_headers(root, "index.html"),
+            (Some("text/html".to_string()), None)
+        );
+        assert_eq!(
+            content_headers(root, "image.png"),
+            (Some("image/png".to_string()), None)
+        );
+        assert_eq!(content_headers(root, "no_extension"), (None, None));
+    }
+}
@@ -0,0 +1,724 @@
+//! Uploads in progress.
+//!
+//! Chunks of an object are written to a staging file in the hidden `.blobstore-staging` directory
+//! of the container. Chunks may arrive in any order: each one is written at its offset, and the
+//! ranges received so far are tracked. Once the chunk marked `is_last` has set the object's length
+//! and every byte up to it has been received, the staging file is flushed to disk and renamed over
+//! the object, so readers see either the previous version of the object or the complete new one,
+//! never a partially written file.
+//!
+//! If objects are versioned (see `versions`), the object being replaced is kept as a noncurrent
+//! version just before the staging file is renamed over it.
+//!
+//! If objects are stored encoded (see `codec`), the staging file is encoded when the upload completes,
+//! and the encoded file replaces the object. Chunks of encrypted uploads are encrypted as they arrive.
+//!
+//! The names of staging files begin with a tag derived from the actor id, so that the staging files
+//! left in a shared namespace (see `shared`) are only removed by the actor that created them.
+
+use crate::blobs;
+use crate::codec::{Codec, StagingLog};
+use crate::fs_utils::all_dirs;
+use crate::sidecar::{self, ObjectSidecar};
+use crate::versions::{self, Retention};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+use tracing::{info, warn};
+use wasmbus_rpc::provider::prelude::*;
+use wasmcloud_interface_blobstore::Chunk;
+
+/// Name of the directory, inside each container, holding the staging files of uploads in progress.
+/// Starts with `paths::RESERVED_PREFIX` so it is hidden from actors.
+pub const STAGING_DIR: &str = ".blobstore-staging";
+
+/// default time after which an upload that has not received a chunk is abandoned (10 minutes)
+pub const DEFAULT_UPLOAD_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// An upload in progress
+#[derive(Debug)]
+pub struct Upload {
+    /// unique id of the upload, returned to the actor as the stream id
+    pub id: String,
+    /// actor that started the upload
+    pub actor_id: String,
+    pub container_id: String,
+    pub object_id: String,
+    /// directory of the container
+    container_dir: PathBuf,
+    /// final location of the object
+    object_path: PathBuf,
+    /// file receiving the chunks. None once the upload has been committed or aborted
+    staging_path: PathBuf,
+    staging_file: Option<File>,
+    /// metadata to store with the object when the upload completes
+    sidecar: ObjectSidecar,
+    /// byte ranges received so far, as start offset -> end offset (exclusive).
+    /// Adjacent and overlapping ranges are merged.
+    received: BTreeMap<u64, u64>,
+    /// length of the object, known once the last chunk has been received
+    total_len: Option<u64>,
+    /// blob store the contents are moved to, if objects are deduplicated
+    blob_store: Option<PathBuf>,
+    /// encoding of the stored object, if it is compressed or encrypted
+    codec: Option<Codec>,
+    /// encrypted chunks in the staging file, if the object is encrypted
+    staging_log: Option<StagingLog>,
+    /// retention policy of the replaced object's versions, if objects are versioned
+    versioning: Option<Retention>,
+    /// time after which the upload is abandoned if no chunk was received
+    timeout: Duration,
+    last_activity: Instant,
+}
+
+impl Upload {
+    /// Starts an upload by creating an empty staging file for the object
+    pub fn create(
+        actor_id: &str,
+        container_id: &str,
+        object_id: &str,
+        container_dir: PathBuf,
+        object_path: PathBuf,
+        sidecar: ObjectSidecar,
+        timeout: Duration,
+    ) -> RpcResult<Self> {
+        let staging_dir = container_dir.join(STAGING_DIR);
+        std::fs::create_dir_all(&staging_dir)?;
+        let id = uuid::Uuid::new_v4().to_string();
+        let staging_path = staging_dir.join(format!("{}{}", staging_prefix(actor_id), &id));
+        let staging_file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&staging_path)
+            .map_err(|e| {
+                RpcError::InvalidParameter(format!(
+                    "Could not create file: {:?}: {}",
+                    staging_path, e
+                ))
+            })?;
+        Ok(Upload {
+            id,
+            actor_id: actor_id.to_string(),
+            container_id: container_id.to_string(),
+            object_id: object_id.to_string(),
+            container_dir,
+            object_path,
+            staging_path,
+            staging_file: Some(staging_file),
+            sidecar,
+            received: BTreeMap::new(),
+            total_len: None,
+            blob_store: None,
+            codec: None,
+            staging_log: None,
+            versioning: None,
+            timeout,
+            last_activity: Instant::now(),
+        })
+    }
+
+    /// Writes a chunk to the staging file at the chunk's offset. Chunks may be written in any order,
+    /// and a chunk may be sent again, for example when an upload is retried.
+    /// Returns true when all bytes of the object have been received.
+    pub fn write_chunk(&mut self, chunk: &Chunk) -> RpcResult<bool> {
+        let end = chunk.offset + chunk.bytes.len() as u64;
+        match self.total_len {
+            Some(total_len) if end > total_len => {
+                return Err(RpcError::InvalidParameter(format!(
+                    "Chunk at offset {} with {} bytes extends beyond the end of the object ({} bytes)",
+                    chunk.offset,
+                    chunk.bytes.len(),
+                    total_len
+                )));
+            }
+            Some(total_len) if chunk.is_last && end != total_len => {
+                return Err(RpcError::InvalidParameter(format!(
+                    "Last chunk ends at {}, but an earlier last chunk ended at {}",
+                    end, total_len
+                )));
+            }
+            None if chunk.is_last && end < self.received_end() => {
+                return Err(RpcError::InvalidParameter(format!(
+                    "Last chunk ends at {}, but bytes up to {} have already been received",
+                    end,
+                    self.received_end()
+                )));
+            }
+            _ => {}
+        }
+        let file = self.staging_file.as_mut().ok_or_else(|| {
+            RpcError::InvalidParameter(format!(
+                "Upload of {}/{} has already completed or was cancelled",
+                &self.container_id, &self.object_id
+            ))
+        })?;
+        info!(
+            "Receiving file chunk offset {} for {}/{}, size {}",
+            chunk.offset,
+            chunk.container_id,
+            chunk.object_id,
+            chunk.bytes.len()
+        );
+        match (&self.codec, &mut self.staging_log) {
+            (Some(codec), Some(log)) => {
+                log.append(file, codec, &self.id, chunk.offset, &chunk.bytes)?
+            }
+            _ => {
+                file.seek(SeekFrom::Start(chunk.offset))?;
+                file.write_all(&chunk.bytes)?;
+            }
+        }
+        self.add_range(chunk.offset, end);
+        if chunk.is_last {
+            self.total_len = Some(end);
+        }
+        self.last_activity = Instant::now();
+        Ok(self.is_complete())
+    }
+
+    /// Returns true when the last chunk and all bytes before it have been received
+    pub fn is_complete(&self) -> bool {
+        match self.total_len {
+            Some(0) => true,
+            Some(total_len) => self.received.get(&0) == Some(&total_len),
+            None => false,
+        }
+    }
+
+    /// Returns the end of the highest range received so far
+    fn received_end(&self) -> u64 {
+        self.received.values().next_back().copied().unwrap_or(0)
+    }
+
+    /// Returns the size of the object, as far as it is known from the chunks received so far
+    pub fn received_len(&self) -> u64 {
+        self.total_len.unwrap_or_else(|| self.received_end())
+    }
+
+    /// Records that bytes `start..end` have been received, merging overlapping and adjacent ranges
+    fn add_range(&mut self, mut start: u64, mut end: u64) {
+        if start == end {
+            return;
+        }
+        // merge with the range starting at or before `start`, if it reaches `start`
+        if let Some((&prev_start, &prev_end)) = self.received.range(..=start).next_back() {
+            if prev_end >= start {
+                start = prev_start;
+                end = end.max(prev_end);
+            }
+        }
+        // merge with ranges starting inside `start..=end`
+        let overlapping: Vec<(u64, u64)> = self
+            .received
+            .range(start..=end)
+            .map(|(&s, &e)| (s, e))
+            .collect();
+        for (s, e) in overlapping {
+            self.received.remove(&s);
+            end = end.max(e);
+        }
+        self.received.insert(start, end);
+    }
+
+    /// Stores the contents in the blob store when the upload is committed,
+    /// so that identical objects share their bytes. See `blobs`.
+    pub fn deduplicate_into(&mut self, blob_store: PathBuf) {
+        self.blob_store = Some(blob_store);
+    }
+
+    /// Stores the object encoded with `codec` when the upload is committed.
+    /// Must be called before the first chunk is written.
+    pub fn encode_with(&mut self, codec: Codec) {
+        if codec.encrypts() {
+            self.staging_log = Some(StagingLog::default());
+        }
+        self.sidecar.encoded = true;
+        self.codec = Some(codec);
+    }
+
+    /// Keeps the object being replaced as a noncurrent version when the upload is committed,
+    /// and prunes the object's versions with `retention`. See `versions`.
+    pub fn keep_versions(&mut self, retention: Retention) {
+        self.versioning = Some(retention);
+    }
+
+    /// Returns true if no chunk has been received within the upload timeout
+    pub fn is_expired(&self, now: Instant) -> bool {
+        now.duration_since(self.last_activity) > self.timeout
+    }
+
+    /// Completes the upload: flushes the staging file to disk, and atomically
+    /// replaces the object with it. The upload can't receive chunks afterwards.
+    /// Returns the size of the stored file.
+    pub fn commit(&mut self) -> RpcResult<u64> {
+        let staging_file = self.staging_file.take().ok_or_else(|| {
+            RpcError::InvalidParameter(format!(
+                "Upload of {}/{} has already completed or was cancelled",
+                &self.container_id, &self.object_id
+            ))
+        })?;
+        // close the staging file before renaming it
+        let synced = match &self.codec {
+            Some(codec) => self.encode_staging_file(codec, staging_file),
+            None => staging_file.sync_all().map_err(RpcError::from),
+        };
+        if let Err(e) = synced {
+            remove_staging_file(&self.staging_path);
+            return Err(e);
+        }
+        // blob of the object being replaced, released once the new object is in place
+        let mut replaced_blob = None;
+        if let Some(store) = &self.blob_store {
+            match blobs::deduplicate(store, &self.staging_path) {
+                Ok(sha256) => self.sidecar.sha256 = Some(sha256),
+                Err(e) => {
+                    remove_staging_file(&self.staging_path);
+                    return Err(e);
+                }
+            }
+            replaced_blob = sidecar::read(&self.container_dir, &self.object_id)
+                .and_then(|previous| previous.sha256);
+        }
+        if let Some(parent) = self.object_path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        if self.versioning.is_some() {
+            if let Err(e) = versions::keep_current(&self.container_dir, &self.object_id) {
+                remove_staging_file(&self.staging_path);
+                return Err(RpcError::Other(format!(
+                    "Could not keep the previous version of {}/{}: {}",
+                    &self.container_id, &self.object_id, e
+                )));
+            }
+            self.sidecar.version_id = Some(versions::new_version_id());
+        }
+        if let Err(e) = std::fs::rename(&self.staging_path, &self.object_path) {
+            remove_staging_file(&self.staging_path);
+            return Err(RpcError::Other(format!(
+                "Could not move upload into {:?}: {}",
+                &self.object_path, e
+            )));
+        }
+        sync_dir(self.object_path.parent());
+        let stored_len = std::fs::metadata(&self.object_path)
+            .map(|m| m.len())
+            .unwrap_or_else(|_| self.received_len());
+        sidecar::write(&self.container_dir, &self.object_id, &self.sidecar).map_err(|e| {
+            RpcError::Other(format!(
+                "Could not store metadata for {}/{}: {}",
+                &self.container_id, &self.object_id, e
+            ))
+        })?;
+        if let (Some(store), Some(sha256)) = (&self.blob_store, replaced_blob) {
+            blobs::release(store, &sha256);
+        }
+        if let Some(retention) = &self.versioning {
+            if let Err(e) = versions::apply_retention(
+                &self.container_dir,
+                &self.object_id,
+                retention,
+                self.blob_store.as_deref(),
+                SystemTime::now(),
+            ) {
+                warn!(
+                    "Could not prune versions of {}/{}: {}",
+                    &self.container_id, &self.object_id, e
+                );
+            }
+        }
+        Ok(stored_len)
+    }
+
+    /// Writes the encoded object to a new file, which then replaces the staging file
+    fn encode_staging_file(&self, codec: &Codec, mut staging_file: File) -> RpcResult<()> {
+        let encoded_path = self.staging_path.with_extension("encoded");
+        let mut encoded = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&encoded_path)?;
+        let len = self.total_len.unwrap_or(0);
+        let written = match &self.staging_log {
+            Some(log) => codec.encode(
+                len,
+                &mut encoded,
+                log.reader(&mut staging_file, codec, &self.id),
+            ),
+            None => codec.encode(len, &mut encoded, |offset, len| {
+                staging_file.seek(SeekFrom::Start(offset))?;
+                let mut bytes = vec![0u8; len];
+                staging_file.read_exact(&mut bytes)?;
+                Ok(bytes)
+            }),
+        }
+        .and_then(|_| Ok(encoded.sync_all()?));
+        drop(staging_file);
+        drop(encoded);
+        if let Err(e) =
+            written.and_then(|_| Ok(std::fs::rename(&encoded_path, &self.staging_path)?))
+        {
+            remove_staging_file(&encoded_path);
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Cancels the upload and removes the staging file
+    pub fn abort(&mut self) {
+        if let Some(staging_file) = self.staging_file.take() {
+            drop(staging_file);
+            remove_staging_file(&self.staging_path);
+        }
+    }
+}
+
+fn remove_staging_file(path: &Path) {
+    if let Err(e) = std::fs::remove_file(path) {
+        warn!("Could not remove staging file {:?}: {}", path, e);
+    }
+}
+
+/// Flushes a directory entry to disk, so a rename inside it survives a crash (best effort)
+fn sync_dir(dir: Option<&Path>) {
+    #[cfg(unix)]
+    if let Some(dir) = dir {
+        if let Err(e) = File::open(dir).and_then(|d| d.sync_all()) {
+            warn!("Could not sync directory {:?}: {}", dir, e);
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = dir;
+}
+
+/// Returns the beginning of the names of the staging files created for an actor
+pub fn staging_prefix(actor_id: &str) -> String {
+    format!(
+        "{}-",
+        hex::encode(&Sha256::digest(actor_id.as_bytes())[..8])
+    )
+}
+
+/// Returns true if a staging file was created by the actor with the staging prefix `prefix`.
+/// Staging files of earlier versions of the provider are named by an upload id alone; they
+/// can't belong to an upload in progress, so they are removed by any actor.
+fn is_own_staging_file(name: &str, prefix: &str) -> bool {
+    name.starts_with(prefix) || uuid::Uuid::parse_str(name).is_ok()
+}
+
+/// Removes staging files older than `max_age` in all containers under `root`.
+/// These are left behind if the provider stopped while uploads were in progress.
+/// If `owner` is set, only the staging files of that actor are removed, since other
+/// actors' uploads to a shared namespace may have a longer timeout.
+pub fn remove_stale_staging_files(root: &Path, max_age: Duration, owner: Option<&str>) {
+    let prefix = owner.map(staging_prefix);
+    let now = SystemTime::now();
+    let dirs = match all_dirs(root, root) {
+        Ok(dirs) => dirs,
+        Err(e) => {
+            warn!(
+                "Could not look for stale staging files in {:?}: {}",
+                root, e
+            );
+            return;
+        }
+    };
+    for dir in dirs
+        .iter()
+        .filter(|d| d.file_name() == Some(STAGING_DIR.as_ref()))
+    {
+        let entries = match std::fs::read_dir(root.join(dir)) {
+            Ok(entries) => entries,
+            Err(_) => continue,
+        };
+        for entry in entries.flatten() {
+            if let Some(prefix) = &prefix {
+                let name = entry.file_name();
+                let name = name.to_string_lossy();
+                // encoded staging files have an extension after the upload id
+                let stem = name.split('.').next().unwrap_or_default();
+                if !is_own_staging_file(stem, prefix) {
+                    continue;
+                }
+            }
+            let age = entry
+                .metadata()
+                .and_then(|m| m.modified())
+                .ok()
+                .and_then(|modified| now.duration_since(modified).ok());
+            if matches!(age, Some(age) if age > max_age) {
+                info!("Removing stale staging file {:?}", entry.path());
+                if let Err(e) = std::fs::remove_file(entry.path()) {
+                    warn!(
+                        "Could not remove stale staging file {:?}: {}",
+                        entry.path(),
+                        e
+                    );
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs_utils::clear_state;
+    use std::fs::create_dir_all;
+
+    fn create_upload(cdir: &Path, timeout: Duration) -> Upload {
+        Upload::create(
+            "actor",
+            "cont",
+            "obj",
+            cdir.to_path_buf(),
+            cdir.join("obj"),
+            ObjectSidecar::default(),
+            timeout,
+        )
+        .unwrap()
+    }
+
+    fn chunk(bytes: &[u8], offset: u64, is_last: bool) -> Chunk {
+        Chunk {
+            object_id: "obj".to_string(),
+            container_id: "cont".to_string(),
+            bytes: bytes.to_vec(),
+            offset,
+            is_last,
+        }
+    }
+
+    #[test]
+    fn staging_dir_is_reserved() {
+        assert!(STAGING_DIR.starts_with(crate::paths::RESERVED_PREFIX));
+    }
+
+    #[test]
+    fn object_replaced_on_commit() {
+        let root = Path::new("/tmp/rust_test/upload1");
+        let cdir = root.join("cont");
+        create_dir_all(&cdir).unwrap();
+        let object_path = cdir.join("obj");
+        std::fs::write(&object_path, b"old").unwrap();
+
+        let mut upload = create_upload(&cdir, DEFAULT_UPLOAD_TIMEOUT);
+        let complete_first = upload.write_chunk(&chunk(b"new ", 0, false)).unwrap();
+        let complete_last = upload.write_chunk(&chunk(b"data", 4, true)).unwrap();
+        // readers still see the old object until the upload is committed
+        let before_commit = std::fs::read(&object_path).unwrap();
+        upload.commit().unwrap();
+        let after_commit = std::fs::read(&object_path).unwrap();
+        let after_completed = upload.write_chunk(&chunk(b"more", 8, false));
+        let staging_files = std::fs::read_dir(cdir.join(STAGING_DIR)).unwrap().count();
+
+        clear_state(root);
+
+        assert!(!complete_first);
+        assert!(complete_last);
+        assert_eq!(before_commit, b"old".to_vec());
+        assert_eq!(after_commit, b"new data".to_vec());
+        assert!(after_completed.is_err());
+        assert_eq!(staging_files, 0);
+    }
+
+    #[test]
+    fn out_of_order_chunks() {
+        let root = Path::new("/tmp/rust_test/upload3");
+        let cdir = root.join("cont");
+        create_dir_all(&cdir).unwrap();
+
+        let mut upload = create_upload(&cdir, DEFAULT_UPLOAD_TIMEOUT);
+        let mut complete = Vec::new();
+        complete.push(upload.write_chunk(&chunk(b"cde", 2, false)).unwrap());
+        complete.push(upload.write_chunk(&chunk(b"hi", 7, true)).unwrap());
+        // a chunk that is sent twice, overlapping its neighbours
+        complete.push(upload.write_chunk(&chunk(b"cdef", 2, false)).unwrap());
+        let beyond_end = upload.write_chunk(&chunk(b"xyz", 8, false));
+        let short_last = upload.write_chunk(&chunk(b"x", 0, true));
+        complete.push(upload.write_chunk(&chunk(b"g", 6, false)).unwrap());
+        complete.push(upload.write_chunk(&chunk(b"ab", 0, false)).unwrap());
+        upload.commit().unwrap();
+        let object = std::fs::read(cdir.join("obj")).unwrap();
+
+        clear_state(root);
+
+        assert_eq!(complete, vec![false, false, false, false, true]);
+        assert!(beyond_end.is_err());
+        assert!(short_last.is_err());
+        assert_eq!(object, b"abcdefghi".to_vec());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn deduplicated_commit() {
+        use std::os::unix::fs::MetadataExt;
+
+        let root = Path::new("/tmp/rust_test/upload4");
+        let cdir = root.join("cont");
+        create_dir_all(&cdir).unwrap();
+        let store = crate::blobs::store_dir(root);
+
+        let commit = |object_id: &str, bytes: &[u8]| {
+            let mut upload = Upload::create(
+                "actor",
+                "cont",
+                object_id,
+                cdir.clone(),
+                cdir.join(object_id),
+                ObjectSidecar::default(),
+                DEFAULT_UPLOAD_TIMEOUT,
+            )
+            .unwrap();
+            upload.deduplicate_into(store.clone());
+            upload.write_chunk(&chunk(bytes, 0, true)).unwrap();
+            upload.commit().unwrap();
+            sidecar::read(&cdir, object_id).unwrap().sha256.unwrap()
+        };
+        let hash1 = commit("obj1", b"artifact");
+        let hash2 = commit("obj2", b"artifact");
+        let same_file = std::fs::metadata(cdir.join("obj1")).unwrap().ino()
+            == std::fs::metadata(cdir.join("obj2")).unwrap().ino();
+        // replacing both objects releases the blob of the old contents
+        commit("obj1", b"new");
+        let blob_kept = blobs::blob_path(&store, &hash1).exists();
+        commit("obj2", b"new");
+        let blob_released = !blobs::blob_path(&store, &hash1).exists();
+        let contents = std::fs::read(cdir.join("obj2")).unwrap();
+
+        clear_state(root);
+
+        assert_eq!(hash1, hash2);
+        assert!(same_file);
+        assert!(blob_kept);
+        assert!(blob_released);
+        assert_eq!(contents, b"new".to_vec());
+    }
+
+    #[test]
+    fn encrypted_commit() {
+        let root = Path::new("/tmp/rust_test/upload5");
+        let cdir = root.join("cont");
+        create_dir_all(&cdir).unwrap();
+        let mut values = std::collections::HashMap::new();
+        values.insert(
+            crate::codec::ENCRYPTION_KEY.to_string(),
+            "AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8=".to_string(),
+        );
+        values.insert(crate::codec::COMPRESSION.to_string(), "zstd".to_string());
+        let codec = Codec::from_values(&values).unwrap().unwrap();
+
+        let mut upload = create_upload(&cdir, DEFAULT_UPLOAD_TIMEOUT);
+        upload.encode_with(codec.clone());
+        upload.write_chunk(&chunk(b"secret", 6, true)).unwrap();
+        upload.write_chunk(&chunk(b"top - ", 0, false)).unwrap();
+        let staged = std::fs::read(&upload.staging_path).unwrap();
+        upload.commit().unwrap();
+        let stored = std::fs::read(cdir.join("obj")).unwrap();
+        let mut reader = crate::codec::ObjectReader::open(
+            File::open(cdir.join("obj")).unwrap(),
+            true,
+            Some(&codec),
+        )
+        .unwrap();
+        let encoded = sidecar::is_encoded(&cdir, "obj");
+        let staging_files = std::fs::read_dir(cdir.join(STAGING_DIR)).unwrap().count();
+
+        clear_state(root);
+
+        assert!(!staged.windows(6).any(|w| w == b"secret"));
+        assert!(!stored.windows(6).any(|w| w == b"secret"));
+        assert_eq!(reader.len(), 12);
+        assert_eq!(reader.read_range(0, 12).unwrap(), b"top - secret".to_vec());
+        assert!(encoded);
+        assert_eq!(staging_files, 0);
+    }
+
+    #[test]
+    fn versioned_commit() {
+        let root = Path::new("/tmp/rust_test/upload6");
+        let cdir = root.join("cont");
+        create_dir_all(&cdir).unwrap();
+        std::fs::write(cdir.join("obj"), b"unversioned").unwrap();
+        let retention = Retention {
+            max_versions: Some(1),
+            max_age: None,
+        };
+
+        let mut version_ids = Vec::new();
+        for bytes in [b"first", b"again"] {
+            let mut upload = create_upload(&cdir, DEFAULT_UPLOAD_TIMEOUT);
+            upload.keep_versions(retention);
+            upload.write_chunk(&chunk(bytes, 0, true)).unwrap();
+            upload.commit().unwrap();
+            version_ids.push(sidecar::read(&cdir, "obj").and_then(|s| s.version_id));
+        }
+        let versions = versions::list("cont", &cdir, "obj").unwrap();
+        let kept =
+            std::fs::read(versions::versions_dir(&cdir, "obj").join(&versions[1].version_id));
+
+        clear_state(root);
+
+        assert!(version_ids.iter().all(|id| id.is_some()));
+        // the unversioned object was pruned, the first upload is the noncurrent version
+        assert_eq!(versions.len(), 2);
+        assert_eq!(Some(&versions[0].version_id), version_ids[1].as_ref());
+        assert_eq!(Some(&versions[1].version_id), version_ids[0].as_ref());
+        assert_eq!(kept.unwrap(), b"first".to_vec());
+    }
+
+    #[test]
+    fn abort_removes_staging_file() {
+        let root = Path::new("/tmp/rust_test/upload2");
+        let cdir = root.join("cont");
+        create_dir_all(&cdir).unwrap();
+
+        let mut upload = create_upload(&cdir, Duration::from_secs(0));
+        upload.write_chunk(&chunk(b"partial", 0, false)).unwrap();
+        std::thread::sleep(Duration::from_millis(10));
+        let expired = upload.is_expired(Instant::now());
+        upload.abort();
+        let staging_files = std::fs::read_dir(cdir.join(STAGING_DIR)).unwrap().count();
+        let object_exists = cdir.join("obj").exists();
+
+        clear_state(root);
+
+        assert!(expired);
+        assert_eq!(staging_files, 0);
+        assert!(!object_exists);
+    }
+
+    #[test]
+    fn remove_own_stale_staging_files() {
+        let root = Path::new("/tmp/rust_test/upload7");
+        let staging_dir = root.join("cont").join(STAGING_DIR);
+        create_dir_all(&staging_dir).unwrap();
+
+        let upload_id = uuid::Uuid::new_v4().to_string();
+        let own = format!("{}{}", staging_prefix("actor1"), &upload_id);
+        let own_encoded = format!("{}.encoded", &own);
+        let other = format!("{}{}", staging_prefix("actor2"), &upload_id);
+        let legacy = uuid::Uuid::new_v4().to_string();
+        for name in [&own, &own_encoded, &other, &legacy] {
+            std::fs::write(staging_dir.join(name), b"partial").unwrap();
+        }
+        std::thread::sleep(Duration::from_millis(10));
+        remove_stale_staging_files(root, Duration::from_secs(0), Some("actor1"));
+        let mut remaining: Vec<String> = std::fs::read_dir(&staging_dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        // without an owner, all stale staging files are removed
+        remove_stale_staging_files(root, Duration::from_secs(0), None);
+        let left = std::fs::read_dir(&staging_dir).unwrap().count();
+
+        clear_state(root);
+
+        remaining.sort();
+        assert_ne!(staging_prefix("actor1"), staging_prefix("actor2"));
+        assert_eq!(remaining, vec![other]);
+        assert_eq!(left, 0);
+    }
+}
@@ -0,0 +1,854 @@
+//! Object versioning, enabled with the link value `VERSIONING=true`.
+//!
+//! As with S3 versioning, every object stored gets a new version id, and an object that is
+//! overwritten or removed is not lost: its contents become a noncurrent version, kept in
+//! `<folder>/.blobstore-versions/<object_name>/<version_id>`, next to a json record
+//! `<version_id>.json` with its metadata. Removing an object also records a delete marker, a
+//! version without contents that is the latest version of the object until it is stored again.
+//! Objects stored while versioning was off have the version id `null`. Versions are hard links
+//! to the replaced files where possible, so keeping a version doesn't copy the object; this relies on
+//! objects being replaced by new files rather than modified in place, as uploads do.
+//!
+//! Noncurrent versions are pruned by the retention policy: at most `VERSION_RETENTION_COUNT`
+//! noncurrent versions are kept for each object, and versions that have been noncurrent for
+//! longer than `VERSION_RETENTION_SECS` are removed. The policy is applied whenever an object
+//! changes, and periodically by a background task if a maximum age is set.
+//!
+//! The blobstore interface has no operations for versions, so actors list and restore them with
+//! the `BlobstoreVersions` service, which the provider implements alongside `Blobstore`.
+//! Restoring a version copies it over the object, making it the current version with a new
+//! version id, like copying a version onto its own key in S3.
+
+use crate::sidecar::{self, ObjectSidecar};
+use crate::{blobs, blocking, codec, paths, upload};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use tracing::{error, info, warn};
+use wasmbus_rpc::common::{deserialize, serialize};
+use wasmbus_rpc::provider::prelude::*;
+use wasmbus_rpc::Timestamp;
+
+/// Link value enabling versioning
+pub const VERSIONING: &str = "VERSIONING";
+/// Link value with the maximum number of noncurrent versions kept for each object
+pub const VERSION_RETENTION_COUNT: &str = "VERSION_RETENTION_COUNT";
+/// Link value with the number of seconds a noncurrent version is kept
+pub const VERSION_RETENTION_SECS: &str = "VERSION_RETENTION_SECS";
+
+/// Name of the directory, inside each container, holding the versions of its objects.
+/// Starts with `paths::RESERVED_PREFIX` so it is hidden from actors.
+pub const VERSIONS_DIR: &str = ".blobstore-versions";
+
+/// Version id of objects stored while versioning was off
+pub const NULL_VERSION: &str = "null";
+
+/// bounds of the time between retention passes, which is the maximum age of versions
+const MIN_SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+const MAX_SWEEP_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// Which noncurrent versions are kept. `None` means unlimited.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+pub struct Retention {
+    pub max_versions: Option<u64>,
+    pub max_age: Option<Duration>,
+}
+
+impl Retention {
+    /// Reads the retention policy from link definition values.
+    /// Returns None if versioning is not enabled.
+    pub fn from_values(values: &HashMap<String, String>) -> RpcResult<Option<Self>> {
+        let enabled = match values.get(VERSIONING) {
+            None => false,
+            Some(v) => v.parse::<bool>().map_err(|_| {
+                RpcError::InvalidParameter(format!(
+                    "{} must be 'true' or 'false', got '{}'",
+                    VERSIONING, v
+                ))
+            })?,
+        };
+        let max_versions = parse_number(values, VERSION_RETENTION_COUNT)?;
+        let max_age = parse_number(values, VERSION_RETENTION_SECS)?.map(Duration::from_secs);
+        if !enabled {
+            if max_versions.is_some() || max_age.is_some() {
+                return Err(RpcError::InvalidParameter(format!(
+                    "{} and {} require {}=true",
+                    VERSION_RETENTION_COUNT, VERSION_RETENTION_SECS, VERSIONING
+                )));
+            }
+            return Ok(None);
+        }
+        Ok(Some(Retention {
+            max_versions,
+            max_age,
+        }))
+    }
+}
+
+fn parse_number(values: &HashMap<String, String>, key: &str) -> RpcResult<Option<u64>> {
+    match values.get(key) {
+        None => Ok(None),
+        Some(v) => v.parse::<u64>().map(Some).map_err(|_| {
+            RpcError::InvalidParameter(format!(
+                "{} must be a non-negative integer, got '{}'",
+                key, v
+            ))
+        }),
+    }
+}
+
+/// Metadata of a noncurrent version or delete marker, stored in `<version_id>.json`
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VersionRecord {
+    pub version_id: String,
+    #[serde(default)]
+    pub delete_marker: bool,
+    /// size of the contents. For encoded objects, the size of the decoded contents.
+    #[serde(default)]
+    pub content_length: u64,
+    /// time the contents were stored, in seconds since the unix epoch
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_modified: Option<u64>,
+    /// time the version stopped being the current version, in microseconds since the unix epoch
+    pub noncurrent_since: u64,
+    /// metadata the object had while it was the current version
+    #[serde(default)]
+    pub metadata: ObjectSidecar,
+}
+
+/// Argument of `BlobstoreVersions.ListObjectVersions`
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ListObjectVersionsRequest {
+    pub container_id: String,
+    pub object_id: String,
+}
+
+/// Argument of `BlobstoreVersions.RestoreObjectVersion`
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RestoreObjectVersionRequest {
+    pub container_id: String,
+    pub object_id: String,
+    pub version_id: String,
+}
+
+/// A version of an object
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ObjectVersion {
+    pub container_id: String,
+    pub object_id: String,
+    pub version_id: String,
+    /// true for the current version, or for the delete marker of a removed object
+    pub is_latest: bool,
+    pub is_delete_marker: bool,
+    pub content_length: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_encoding: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_modified: Option<Timestamp>,
+}
+
+/// Versions of an object, latest first
+pub type ObjectVersions = Vec<ObjectVersion>;
+
+/// Operations on object versions, an extension of the blobstore interface.
+/// Actors call them as `BlobstoreVersions.<Operation>` on the blobstore link.
+#[async_trait]
+pub trait BlobstoreVersions {
+    /// Returns the versions of an object, latest first. Without versioning,
+    /// only the current version, with the version id `null`, is returned.
+    async fn list_object_versions(
+        &self,
+        ctx: &Context,
+        arg: &ListObjectVersionsRequest,
+    ) -> RpcResult<ObjectVersions>;
+
+    /// Makes a copy of a version the current version of the object, and returns it
+    async fn restore_object_version(
+        &self,
+        ctx: &Context,
+        arg: &RestoreObjectVersionRequest,
+    ) -> RpcResult<ObjectVersion>;
+}
+
+/// Receives `BlobstoreVersions` messages, like the receivers generated for smithy interfaces
+#[async_trait]
+pub trait BlobstoreVersionsReceiver: MessageDispatch + BlobstoreVersions {
+    async fn dispatch(&self, ctx: &Context, message: Message<'_>) -> RpcResult<Vec<u8>> {
+        match message.method {
+            "ListObjectVersions" => {
+                let value: ListObjectVersionsRequest = deserialize(&message.arg)
+                    .map_err(|e| RpcError::Deser(format!("'ListObjectVersionsRequest': {}", e)))?;
+                let resp = BlobstoreVersions::list_object_versions(self, ctx, &value).await?;
+                serialize(&resp)
+            }
+            "RestoreObjectVersion" => {
+                let value: RestoreObjectVersionRequest =
+                    deserialize(&message.arg).map_err(|e| {
+                        RpcError::Deser(format!("'RestoreObjectVersionRequest': {}", e))
+                    })?;
+                let resp = BlobstoreVersions::restore_object_version(self, ctx, &value).await?;
+                serialize(&resp)
+            }
+            _ => Err(RpcError::MethodNotHandled(format!(
+                "BlobstoreVersions::{}",
+                message.method
+            ))),
+        }
+    }
+}
+
+/// Returns the directory holding the versions of an object. Like its metadata (see `sidecar`),
+/// the versions of an object in a folder are kept in the folder.
+pub fn versions_dir(container_dir: &Path, object_id: &str) -> PathBuf {
+    let object_path = container_dir.join(object_id);
+    let dir = object_path.parent().unwrap_or(container_dir);
+    match object_path.file_name() {
+        Some(name) => dir.join(VERSIONS_DIR).join(name),
+        None => dir.join(VERSIONS_DIR),
+    }
+}
+
+fn micros_since_epoch(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_micros() as u64)
+        .unwrap_or(0)
+}
+
+fn secs_to_timestamp(secs: u64) -> Timestamp {
+    Timestamp {
+        sec: secs as i64,
+        nsec: 0u32,
+    }
+}
+
+/// Creates a version id. Ids sort in the order they were created.
+pub fn new_version_id() -> String {
+    let suffix = uuid::Uuid::new_v4().simple().to_string();
+    format!(
+        "{:016x}{}",
+        micros_since_epoch(SystemTime::now()),
+        &suffix[..8]
+    )
+}
+
+fn write_record(dir: &Path, record: &VersionRecord) -> std::io::Result<()> {
+    let path = dir.join(format!("{}.json", &record.version_id));
+    let tmp_path = path.with_extension("json.tmp");
+    std::fs::write(&tmp_path, serde_json::to_vec(record)?)?;
+    std::fs::rename(&tmp_path, &path)
+}
+
+fn remove_if_exists(path: &Path) -> std::io::Result<()> {
+    match std::fs::remove_file(path) {
+        Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// Keeps the current contents of an object as a noncurrent version, before the object
+/// is replaced or removed. Does nothing if the object doesn't exist.
+pub fn keep_current(container_dir: &Path, object_id: &str) -> std::io::Result<()> {
+    let object_path = container_dir.join(object_id);
+    let metadata = match std::fs::symlink_metadata(&object_path) {
+        Ok(metadata) if metadata.is_file() => metadata,
+        Ok(_) => return Ok(()),
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    let object_sidecar = sidecar::read(container_dir, object_id).unwrap_or_default();
+    let version_id = object_sidecar
+        .version_id
+        .clone()
+        .unwrap_or_else(|| NULL_VERSION.to_string());
+    let dir = versions_dir(container_dir, object_id);
+    std::fs::create_dir_all(&dir)?;
+    // there is only one null version, as in S3: a later one replaces it
+    let data_path = dir.join(&version_id);
+    remove_if_exists(&data_path)?;
+    if std::fs::hard_link(&object_path, &data_path).is_err() {
+        std::fs::copy(&object_path, &data_path)?;
+    }
+    let last_modified = object_sidecar.created_at.or_else(|| {
+        metadata
+            .modified()
+            .ok()
+            .and_then(|m| m.duration_since(SystemTime::UNIX_EPOCH).ok())
+            .map(|d| d.as_secs())
+    });
+    write_record(
+        &dir,
+        &VersionRecord {
+            version_id,
+            delete_marker: false,
+            content_length: codec::logical_len(&object_path, &metadata, object_sidecar.encoded)?,
+            last_modified,
+            noncurrent_since: micros_since_epoch(SystemTime::now()),
+            metadata: object_sidecar,
+        },
+    )
+}
+
+/// Records that an object was removed
+pub fn add_delete_marker(container_dir: &Path, object_id: &str) -> std::io::Result<()> {
+    let dir = versions_dir(container_dir, object_id);
+    std::fs::create_dir_all(&dir)?;
+    let now = SystemTime::now();
+    write_record(
+        &dir,
+        &VersionRecord {
+            version_id: new_version_id(),
+            delete_marker: true,
+            content_length: 0,
+            last_modified: now
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .ok()
+                .map(|d| d.as_secs()),
+            noncurrent_since: micros_since_epoch(now),
+            metadata: ObjectSidecar::default(),
+        },
+    )
+}
+
+/// Returns the noncurrent versions and delete markers of an object, latest first
+pub fn records(container_dir: &Path, object_id: &str) -> std::io::Result<Vec<VersionRecord>> {
+    let dir = versions_dir(container_dir, object_id);
+    let entries = match std::fs::read_dir(&dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let mut records = Vec::new();
+    for entry in entries {
+        let path = entry?.path();
+        if path.extension().and_then(|e| e.to_str()) != Some("json") || !path.is_file() {
+            continue;
+        }
+        match std::fs::read(&path)
+            .map_err(|e| e.to_string())
+            .and_then(|data| serde_json::from_slice(&data).map_err(|e| e.to_string()))
+        {
+            Ok(record) => records.push(record),
+            Err(e) => warn!("Ignoring unreadable version record {:?}: {}", path, e),
+        }
+    }
+    records.sort_by(|a: &VersionRecord, b: &VersionRecord| {
+        (b.noncurrent_since, &b.version_id).cmp(&(a.noncurrent_since, &a.version_id))
+    });
+    Ok(records)
+}
+
+/// Returns the current version of an object, or None if the object doesn't exist
+pub fn current(
+    container_id: &str,
+    container_dir: &Path,
+    object_id: &str,
+) -> std::io::Result<Option<ObjectVersion>> {
+    let object_path = container_dir.join(object_id);
+    let metadata = match std::fs::metadata(&object_path) {
+        Ok(metadata) if metadata.is_file() => metadata,
+        Ok(_) => return Ok(None),
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let object_sidecar = sidecar::read(container_dir, object_id).unwrap_or_default();
+    let version_id = object_sidecar
+        .version_id
+        .unwrap_or_else(|| NULL_VERSION.to_string());
+    let (content_type, content_encoding) = sidecar::content_headers(container_dir, object_id);
+    Ok(Some(ObjectVersion {
+        container_id: container_id.to_string(),
+        object_id: object_id.to_string(),
+        version_id,
+        is_latest: true,
+        is_delete_marker: false,
+        content_length: codec::logical_len(&object_path, &metadata, object_sidecar.encoded)?,
+        content_type,
+        content_encoding,
+        last_modified: metadata
+            .modified()
+            .ok()
+            .and_then(|m| m.duration_since(SystemTime::UNIX_EPOCH).ok())
+            .map(|d| secs_to_timestamp(d.as_secs())),
+    }))
+}
+
+/// Returns all versions of an object, latest first
+pub fn list(
+    container_id: &str,
+    container_dir: &Path,
+    object_id: &str,
+) -> std::io::Result<ObjectVersions> {
+    let mut versions: ObjectVersions = current(container_id, container_dir, object_id)?
+        .into_iter()
+        .collect();
+    for record in records(container_dir, object_id)? {
+        let content_type = record.metadata.content_type.or_else(|| {
+            mime_guess::from_path(object_id)
+                .first()
+                .map(|mime| mime.to_string())
+        });
+        versions.push(ObjectVersion {
+            container_id: container_id.to_string(),
+            object_id: object_id.to_string(),
+            version_id: record.version_id,
+            is_latest: versions.is_empty(),
+            is_delete_marker: record.delete_marker,
+            content_length: record.content_length,
+            content_type: content_type.filter(|_| !record.delete_marker),
+            content_encoding: record.metadata.content_encoding,
+            last_modified: record.last_modified.map(secs_to_timestamp),
+        });
+    }
+    Ok(versions)
+}
+
+/// Returns true if `version_id` is the version of the object's current contents
+pub fn is_current(container_dir: &Path, object_id: &str, version_id: &str) -> bool {
+    let current = sidecar::read(container_dir, object_id).unwrap_or_default();
+    container_dir.join(object_id).is_file()
+        && current.version_id.as_deref().unwrap_or(NULL_VERSION) == version_id
+}
+
+/// Returns the record of a noncurrent version, which must have contents
+pub fn find(container_dir: &Path, object_id: &str, version_id: &str) -> RpcResult<VersionRecord> {
+    let record = records(container_dir, object_id)?
+        .into_iter()
+        .find(|r| r.version_id == version_id)
+        .ok_or_else(|| {
+            RpcError::InvalidParameter(format!(
+                "object '{}' has no version '{}'",
+                object_id, version_id
+            ))
+        })?;
+    if record.delete_marker {
+        return Err(RpcError::InvalidParameter(format!(
+            "version '{}' of object '{}' is a delete marker, which can't be restored",
+            version_id, object_id
+        )));
+    }
+    Ok(record)
+}
+
+/// Makes a copy of a noncurrent version the current version of an object. The object
+/// it replaces, if any, becomes a noncurrent version. Restoring the current version does nothing.
+/// If `blob_store` is set, the restored object is deduplicated. `owner` is the actor restoring
+/// an object in a shared namespace, which then owns the restored object.
+pub fn restore(
+    container_dir: &Path,
+    object_id: &str,
+    version_id: &str,
+    blob_store: Option<&Path>,
+    owner: Option<&str>,
+) -> RpcResult<()> {
+    let object_path = container_dir.join(object_id);
+    if is_current(container_dir, object_id, version_id) {
+        return Ok(());
+    }
+    let record = find(container_dir, object_id, version_id)?;
+    let staging_dir = container_dir.join(upload::STAGING_DIR);
+    std::fs::create_dir_all(&staging_dir)?;
+    let staging_path = staging_dir.join(format!(
+        "{}{}",
+        owner.map(upload::staging_prefix).unwrap_or_default(),
+        uuid::Uuid::new_v4()
+    ));
+    let mut restored = ObjectSidecar::new(
+        record.metadata.content_type.clone(),
+        record.metadata.content_encoding.clone(),
+    );
+    restored.user_metadata = record.metadata.user_metadata;
+    // the copy is stored like the version was
+    restored.encoded = record.metadata.encoded;
+    restored.version_id = Some(new_version_id());
+    restored.owner = owner.map(String::from);
+    let moved = (|| -> RpcResult<()> {
+        // a copy, so the object gets a new modification time like any object stored now
+        std::fs::copy(
+            versions_dir(container_dir, object_id).join(version_id),
+            &staging_path,
+        )?;
+        std::fs::File::open(&staging_path)?.sync_all()?;
+        if let Some(store) = blob_store {
+            restored.sha256 = Some(blobs::deduplicate(store, &staging_path)?);
+        }
+        keep_current(container_dir, object_id)?;
+        if let Some(parent) = object_path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::rename(&staging_path, &object_path)?;
+        Ok(())
+    })();
+    if let Err(e) = moved {
+        let _ = std::fs::remove_file(&staging_path);
+        return Err(RpcError::Other(format!(
+            "Could not restore version '{}' of object '{}': {}",
+            version_id, object_id, e
+        )));
+    }
+    sidecar::write(container_dir, object_id, &restored)?;
+    // the replaced object's blob is still referenced by its noncurrent version
+    Ok(())
+}
+
+/// Removes the noncurrent versions of an object that the retention policy doesn't keep,
+/// releasing their blobs if objects are deduplicated. A delete marker that is the only
+/// version left is removed as well, since it no longer hides anything.
+/// Returns the number of versions removed.
+pub fn apply_retention(
+    container_dir: &Path,
+    object_id: &str,
+    retention: &Retention,
+    blob_store: Option<&Path>,
+    now: SystemTime,
+) -> std::io::Result<u64> {
+    let records = records(container_dir, object_id)?;
+    if records.is_empty() {
+        return Ok(0);
+    }
+    let dir = versions_dir(container_dir, object_id);
+    let current_exists = container_dir.join(object_id).is_file();
+    let now = micros_since_epoch(now);
+    let mut noncurrent = 0u64;
+    let mut removed = 0u64;
+    for (i, record) in records.iter().enumerate() {
+        // the delete marker of a removed object is its latest version, not a noncurrent one
+        if i == 0 && !current_exists && record.delete_marker {
+            continue;
+        }
+        noncurrent += 1;
+        let too_many = matches!(retention.max_versions, Some(max) if noncurrent > max);
+        let too_old = matches!(retention.max_age,
+            Some(max_age) if now.saturating_sub(record.noncurrent_since) > max_age.as_micros() as u64);
+        if too_many || too_old {
+            remove_version(&dir, record, blob_store)?;
+            removed += 1;
+        }
+    }
+    if !current_exists && removed as usize + 1 == records.len() && records[0].delete_marker {
+        remove_version(&dir, &records[0], blob_store)?;
+        removed += 1;
+    }
+    if removed as usize == records.len() {
+        // fails if versions of objects below this one's id are left, which is fine
+        let _ = std::fs::remove_dir(&dir);
+    }
+    Ok(removed)
+}
+
+fn remove_version(
+    dir: &Path,
+    record: &VersionRecord,
+    blob_store: Option<&Path>,
+) -> std::io::Result<()> {
+    remove_if_exists(&dir.join(&record.version_id))?;
+    remove_if_exists(&dir.join(format!("{}.json", &record.version_id)))?;
+    if let (Some(store), Some(sha256)) = (blob_store, &record.metadata.sha256) {
+        blobs::release(store, sha256);
+    }
+    Ok(())
+}
+
+/// Applies the retention policy to the versions of all objects below `dir`,
+/// which is an actor's directory or a shared namespace.
+/// Returns the number of versions removed.
+pub fn sweep(dir: &Path, retention: &Retention, blob_store: Option<&Path>) -> std::io::Result<u64> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e),
+    };
+    let mut removed = 0;
+    for entry in entries {
+        let entry = entry?;
+        if !entry.file_type()?.is_dir() {
+            continue;
+        }
+        let name = entry.file_name();
+        if name == VERSIONS_DIR {
+            removed += sweep_versions(dir, &entry.path(), retention, blob_store)?;
+        } else if !paths::is_reserved(Path::new(&name)) {
+            removed += sweep(&entry.path(), retention, blob_store)?;
+        }
+    }
+    Ok(removed)
+}
+
+/// Applies the retention policy to the objects in `dir` that have versions in `versions_dir`,
+/// the directory of `dir` that holds them
+fn sweep_versions(
+    dir: &Path,
+    versions_dir: &Path,
+    retention: &Retention,
+    blob_store: Option<&Path>,
+) -> std::io::Result<u64> {
+    let mut removed = 0;
+    for entry in std::fs::read_dir(versions_dir)? {
+        let entry = entry?;
+        if !entry.file_type()?.is_dir() {
+            continue;
+        }
+        if let Ok(object_name) = entry.file_name().into_string() {
+            removed +=
+                apply_retention(dir, &object_name, retention, blob_store, SystemTime::now())?;
+        }
+    }
+    Ok(removed)
+}
+
+/// Returns the bytes stored in noncurrent versions below a versions directory.
+/// If `owner` is set, only the versions stored by that actor are counted.
+pub fn stored_bytes(dir: &Path, owner: Option<&str>) -> std::io::Result<u64> {
+    let mut bytes = 0;
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            bytes += stored_bytes(&entry.path(), owner)?;
+        } else if file_type.is_file()
+            && entry.path().extension() != Some("json".as_ref())
+            && owner.map_or(true, |owner| is_owned_by(&entry.path(), owner))
+        {
+            bytes += entry.metadata()?.len();
+        }
+    }
+    Ok(bytes)
+}
+
+/// Returns true if the version stored at `data_path` was stored by actor `owner`
+fn is_owned_by(data_path: &Path, owner: &str) -> bool {
+    let record_path = data_path.with_extension("json");
+    std::fs::read(record_path)
+        .ok()
+        .and_then(|data| serde_json::from_slice::<VersionRecord>(&data).ok())
+        .and_then(|record| record.metadata.owner)
+        .as_deref()
+        == Some(owner)
+}
+
+/// Periodically applies the maximum age of the retention policy to the versions of one actor.
+/// Dropping it stops the task.
+pub struct RetentionSweeper {
+    task: tokio::task::JoinHandle<()>,
+}
+
+impl Drop for RetentionSweeper {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+impl RetentionSweeper {
+    /// Starts sweeping `dirs`, or returns None if the policy has no maximum age.
+    /// Limits on the number of versions are applied when objects change, so they need no sweeping.
+    pub fn start(
+        io: blocking::BlockingPool,
+        dirs: Vec<PathBuf>,
+        retention: Retention,
+        blob_store: Option<PathBuf>,
+    ) -> Option<Self> {
+        let max_age = retention.max_age?;
+        let interval = max_age.clamp(MIN_SWEEP_INTERVAL, MAX_SWEEP_INTERVAL);
+        let task = tokio::spawn(async move {
+            let mut interval = tokio::time::interval(interval);
+            loop {
+                interval.tick().await;
+                let (dirs, blob_store) = (dirs.clone(), blob_store.clone());
+                let swept = io
+                    .run(move || {
+                        let mut removed = 0;
+                        for dir in &dirs {
+                            removed += sweep(dir, &retention, blob_store.as_deref())?;
+                        }
+                        Ok(removed)
+                    })
+                    .await;
+                match swept {
+                    Ok(0) => {}
+                    Ok(removed) => info!("Removed {} expired object versions", removed),
+                    Err(e) => error!("Could not remove expired object versions: {}", e),
+                }
+            }
+        });
+        Some(RetentionSweeper { task })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs_utils::clear_state;
+    use std::fs::create_dir_all;
+
+    /// stores an object the way a committed upload does
+    fn store(cdir: &Path, object_id: &str, bytes: &[u8]) -> String {
+        keep_current(cdir, object_id).unwrap();
+        let mut object_sidecar = ObjectSidecar::new(Some("text/plain".into()), None);
+        let version_id = new_version_id();
+        object_sidecar.version_id = Some(version_id.clone());
+        // objects are replaced by renaming a new file over them, never modified in place
+        let tmp = cdir.join("tmp");
+        std::fs::write(&tmp, bytes).unwrap();
+        create_dir_all(cdir.join(object_id).parent().unwrap()).unwrap();
+        std::fs::rename(&tmp, cdir.join(object_id)).unwrap();
+        sidecar::write(cdir, object_id, &object_sidecar).unwrap();
+        version_id
+    }
+
+    #[test]
+    fn versions_dir_is_reserved() {
+        assert!(VERSIONS_DIR.starts_with(crate::paths::RESERVED_PREFIX));
+    }
+
+    #[test]
+    fn parse_values() {
+        let mut values = HashMap::new();
+        assert_eq!(Retention::from_values(&values).unwrap(), None);
+
+        values.insert(VERSION_RETENTION_COUNT.to_string(), "3".to_string());
+        assert!(Retention::from_values(&values).is_err());
+
+        values.insert(VERSIONING.to_string(), "true".to_string());
+        values.insert(VERSION_RETENTION_SECS.to_string(), "86400".to_string());
+        assert_eq!(
+            Retention::from_values(&values).unwrap(),
+            Some(Retention {
+                max_versions: Some(3),
+                max_age: Some(Duration::from_secs(86400)),
+            })
+        );
+
+        values.insert(VERSIONING.to_string(), "yes".to_string());
+        assert!(Retention::from_values(&values).is_err());
+    }
+
+    #[test]
+    fn version_ids_are_ordered() {
+        let first = new_version_id();
+        std::thread::sleep(Duration::from_millis(2));
+        let second = new_version_id();
+        assert!(first < second);
+        assert_ne!(first, NULL_VERSION);
+    }
+
+    #[test]
+    fn overwrite_delete_and_restore() {
+        let cdir = Path::new("/tmp/rust_test/versions1");
+        create_dir_all(cdir).unwrap();
+        // stored before versioning was enabled
+        std::fs::write(cdir.join("obj"), b"original").unwrap();
+
+        let v1 = store(cdir, "obj", b"first");
+        let v2 = store(cdir, "obj", b"second");
+        keep_current(cdir, "obj").unwrap();
+        std::fs::remove_file(cdir.join("obj")).unwrap();
+        sidecar::remove(cdir, "obj").unwrap();
+        add_delete_marker(cdir, "obj").unwrap();
+        let after_delete = list("cont", cdir, "obj").unwrap();
+
+        let v2_current = is_current(cdir, "obj", &v2);
+        let restore_marker = restore(cdir, "obj", &after_delete[0].version_id, None, None);
+        let restore_missing = restore(cdir, "obj", "0000000000000000deadbeef", None, None);
+        restore(cdir, "obj", &v1, None, None).unwrap();
+        let contents = std::fs::read(cdir.join("obj")).unwrap();
+        let after_restore = list("cont", cdir, "obj").unwrap();
+        let restored_current = is_current(cdir, "obj", &after_restore[0].version_id);
+        let v1_current = is_current(cdir, "obj", &v1);
+        let null_contents = std::fs::read(versions_dir(cdir, "obj").join(NULL_VERSION)).unwrap();
+
+        clear_state(cdir);
+
+        let ids: Vec<&str> = after_delete.iter().map(|v| v.version_id.as_str()).collect();
+        assert_eq!(&ids[1..], &[v2.as_str(), v1.as_str(), NULL_VERSION]);
+        assert!(after_delete[0].is_delete_marker);
+        assert!(after_delete[0].is_latest);
+        assert!(!after_delete[1].is_latest);
+        assert_eq!(after_delete[1].content_length, 6);
+        assert_eq!(after_delete[1].content_type.as_deref(), Some("text/plain"));
+        assert_eq!(after_delete[3].content_length, 8);
+        assert!(!v2_current);
+        assert!(restore_marker.is_err());
+        assert!(restore_missing.is_err());
+        // the restored copy is a new version, the delete marker and the restored version are kept
+        assert_eq!(contents, b"first".to_vec());
+        assert_eq!(after_restore.len(), 5);
+        assert!(after_restore[0].is_latest);
+        assert!(!after_restore[0].is_delete_marker);
+        assert!(after_restore[0].version_id > v2);
+        assert!(after_restore[1].is_delete_marker);
+        assert!(!after_restore[1].is_latest);
+        assert!(restored_current);
+        assert!(!v1_current);
+        assert_eq!(null_contents, b"original".to_vec());
+    }
+
+    #[test]
+    fn retention_by_count_and_age() {
+        let cdir = Path::new("/tmp/rust_test/versions2");
+        create_dir_all(cdir).unwrap();
+        for bytes in [b"1", b"2", b"3", b"4"] {
+            store(cdir, "obj", bytes);
+        }
+        let by_count = Retention {
+            max_versions: Some(2),
+            max_age: None,
+        };
+        let removed_by_count =
+            apply_retention(cdir, "obj", &by_count, None, SystemTime::now()).unwrap();
+        let kept = records(cdir, "obj").unwrap();
+
+        // versions of a removed object expire, then its delete marker is removed too
+        keep_current(cdir, "obj").unwrap();
+        std::fs::remove_file(cdir.join("obj")).unwrap();
+        add_delete_marker(cdir, "obj").unwrap();
+        let by_age = Retention {
+            max_versions: None,
+            max_age: Some(Duration::from_secs(60)),
+        };
+        let young = apply_retention(cdir, "obj", &by_age, None, SystemTime::now()).unwrap();
+        let later = SystemTime::now() + Duration::from_secs(120);
+        let removed_by_age = apply_retention(cdir, "obj", &by_age, None, later).unwrap();
+        let versions_dir_exists = versions_dir(cdir, "obj").exists();
+
+        clear_state(cdir);
+
+        assert_eq!(removed_by_count, 1);
+        let contents: Vec<u64> = kept.iter().map(|r| r.content_length).collect();
+        assert_eq!(contents, vec![1, 1]);
+        assert_eq!(young, 0);
+        assert_eq!(removed_by_age, 4);
+        assert!(!versions_dir_exists);
+    }
+
+    #[test]
+    fn sweep_and_measure() {
+        let root = Path::new("/tmp/rust_test/versions3");
+        let cdir = root.join("cont1/sub");
+        create_dir_all(&cdir).unwrap();
+        store(&cdir, "obj", b"12345");
+        store(&cdir, "obj", b"123");
+        store(&cdir, "dir/nested", b"1234567");
+        store(&cdir, "dir/nested", b"1");
+
+        let measure = || {
+            stored_bytes(&cdir.join(VERSIONS_DIR), None).unwrap()
+                + stored_bytes(&cdir.join("dir").join(VERSIONS_DIR), None).unwrap()
+        };
+        let bytes = measure();
+        let keep_none = Retention {
+            max_versions: Some(0),
+            max_age: None,
+        };
+        let removed = sweep(root, &keep_none, None).unwrap();
+        let bytes_after = measure();
+
+        clear_state(root);
+
+        assert_eq!(bytes, 12);
+        assert_eq!(removed, 2);
+        assert_eq!(bytes_after, 0);
+    }
+}
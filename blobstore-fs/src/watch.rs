@@ -0,0 +1,678 @@
+//! Notifications of changes to objects made outside of the blobstore interface.
+//!
+//! When the link value `WATCH_OPERATION` is set, the provider watches the actor's containers
+//! (and the shared namespaces mounted for it) with the operating system's file notification
+//! api (inotify on Linux), and calls that operation on the actor with an `ObjectEvent` for
+//! each object that was created, modified or deleted. Events are debounced: changes to the same
+//! object within `WATCH_DEBOUNCE_MS` milliseconds are reported once, with the object's
+//! metadata at the end of that period. While files keep changing, events are still sent every
+//! `MAX_DELAY_FACTOR` debounce periods, or as soon as `MAX_PENDING_PATHS` paths have changed.
+//!
+//! The watcher keeps the paths of the objects it knows about, found when it starts and
+//! updated with each change, so it can tell created objects from modified ones however the file
+//! was written (uploads are renamed into place), and report the objects of a directory that was
+//! removed or moved away with the ids they had, and nothing for the directory itself.
+
+use crate::{codec, fs_utils, paths, sidecar};
+use notify::{EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use tokio::sync::mpsc;
+use tracing::{debug, error, warn};
+use wasmbus_rpc::common::{serialize, Message, Transport};
+use wasmbus_rpc::provider::prelude::*;
+use wasmbus_rpc::Timestamp;
+use wasmcloud_interface_blobstore::ObjectMetadata;
+
+/// Link value with the actor operation that receives object events, e.g., `ObjectWatcher.ObjectChanged`
+pub const WATCH_OPERATION: &str = "WATCH_OPERATION";
+/// Link value with the time to wait for further changes before an event is sent
+pub const WATCH_DEBOUNCE_MS: &str = "WATCH_DEBOUNCE_MS";
+
+/// default time to wait for further changes to an object before an event is sent
+pub const DEFAULT_DEBOUNCE: Duration = Duration::from_millis(500);
+
+/// events are sent at the latest this many debounce periods after the first change,
+/// even if files keep changing
+pub const MAX_DELAY_FACTOR: u32 = 10;
+
+/// number of changed paths after which events are sent, even if files keep changing
+pub const MAX_PENDING_PATHS: usize = 4096;
+
+/// number of file notifications queued for the task that collects them. When the queue is full,
+/// the notification thread waits, and the operating system reports that events were lost.
+const EVENT_QUEUE_LEN: usize = 1024;
+
+/// What happened to an object
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChangeKind {
+    Created,
+    Modified,
+    Deleted,
+}
+
+/// Argument of the actor operation called when an object changes
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ObjectEvent {
+    pub kind: ChangeKind,
+    /// metadata of the object after the change. For deleted objects,
+    /// only the container id and object id are set.
+    pub object: ObjectMetadata,
+    /// hex-encoded SHA-256 hash of the contents, if objects are deduplicated
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+}
+
+/// A directory that is watched, and the container id it corresponds to
+#[derive(Clone, Debug)]
+pub struct WatchedDir {
+    pub dir: PathBuf,
+    /// id of the container for files directly in `dir`. None for the actor's directory,
+    /// which holds containers but no objects.
+    pub container_id: Option<String>,
+}
+
+/// Container id and object id of an object file
+type ObjectIds = (String, String);
+
+/// Watches the directories of one actor. Dropping it stops the notifications.
+pub struct ObjectWatcher {
+    _watcher: RecommendedWatcher,
+    task: tokio::task::JoinHandle<()>,
+}
+
+impl Drop for ObjectWatcher {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+impl ObjectWatcher {
+    /// Starts watching `dirs`, sending events for the objects in them to `operation` of the linked actor
+    pub fn start(
+        ld: LinkDefinition,
+        operation: String,
+        dirs: Vec<WatchedDir>,
+        debounce: Duration,
+    ) -> RpcResult<Self> {
+        Self::start_with(dirs, debounce, move |event| {
+            let (ld, operation) = (ld.clone(), operation.clone());
+            async move {
+                if let Err(e) = send_event(&ld, &operation, &event).await {
+                    error!(
+                        "Could not send {:?} event for {}/{} to actor {}: {}",
+                        event.kind,
+                        &event.object.container_id,
+                        &event.object.object_id,
+                        &ld.actor_id,
+                        e
+                    );
+                }
+            }
+        })
+    }
+
+    /// Starts watching `dirs`, calling `deliver` with each event. Reads the directories
+    /// to find the objects in them, so it should not run on the async executor.
+    pub fn start_with<F, Fut>(
+        dirs: Vec<WatchedDir>,
+        debounce: Duration,
+        deliver: F,
+    ) -> RpcResult<Self>
+    where
+        F: Fn(ObjectEvent) -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let (tx, rx) = mpsc::channel(EVENT_QUEUE_LEN);
+        let mut watcher = notify::recommended_watcher(move |res| {
+            // called on the watcher's own thread, which waits while the queue is full.
+            // The receiver is gone when the watcher is being stopped.
+            let _ = tx.blocking_send(res);
+        })
+        .map_err(|e| RpcError::ProviderInit(format!("Could not start file watcher: {}", e)))?;
+        for watched in &dirs {
+            watcher
+                .watch(&watched.dir, RecursiveMode::Recursive)
+                .map_err(|e| {
+                    RpcError::ProviderInit(format!("Could not watch {:?}: {}", &watched.dir, e))
+                })?;
+        }
+        // objects are listed after the watches are set, so none are missed
+        let known = scan(&dirs);
+        let task = tokio::spawn(deliver_events(dirs, debounce, rx, known, deliver));
+        Ok(ObjectWatcher {
+            _watcher: watcher,
+            task,
+        })
+    }
+}
+
+/// Collects file events until no new event has been received for the debounce period,
+/// or until the maximum delay or number of paths is reached, then delivers one event
+/// for each object that changed
+async fn deliver_events<F, Fut>(
+    dirs: Vec<WatchedDir>,
+    debounce: Duration,
+    mut rx: mpsc::Receiver<notify::Result<notify::Event>>,
+    mut known: BTreeMap<PathBuf, ObjectIds>,
+    deliver: F,
+) where
+    F: Fn(ObjectEvent) -> Fut,
+    Fut: Future<Output = ()>,
+{
+    let max_delay = debounce * MAX_DELAY_FACTOR;
+    loop {
+        // changed paths, and whether events were lost and the directories must be read again
+        let mut pending = BTreeSet::new();
+        let mut rescan = false;
+        match rx.recv().await {
+            Some(res) => add_event(&mut pending, &mut rescan, res),
+            None => return,
+        }
+        let deadline = tokio::time::Instant::now() + max_delay;
+        while pending.len() < MAX_PENDING_PATHS {
+            let wait =
+                debounce.min(deadline.saturating_duration_since(tokio::time::Instant::now()));
+            if wait.is_zero() {
+                break;
+            }
+            match tokio::time::timeout(wait, rx.recv()).await {
+                Ok(Some(res)) => add_event(&mut pending, &mut rescan, res),
+                Ok(None) => return,
+                Err(_) => break,
+            }
+        }
+        // the metadata of the changed objects is read from the disk, off the executor
+        let watched = dirs.clone();
+        let (events, still_known) = match tokio::task::spawn_blocking(move || {
+            if rescan {
+                // objects added or removed while events were lost
+                let found = scan(&watched);
+                pending.extend(
+                    found
+                        .keys()
+                        .filter(|path| !known.contains_key(*path))
+                        .chain(known.keys().filter(|path| !found.contains_key(*path)))
+                        .cloned()
+                        .collect::<Vec<_>>(),
+                );
+            }
+            let events = changes(&watched, &mut known, pending);
+            (events, known)
+        })
+        .await
+        {
+            Ok(changed) => changed,
+            Err(e) => {
+                error!(
+                    "Could not read changed objects, stopping object events: {}",
+                    e
+                );
+                return;
+            }
+        };
+        known = still_known;
+        for event in events {
+            deliver(event).await;
+        }
+    }
+}
+
+fn add_event(
+    pending: &mut BTreeSet<PathBuf>,
+    rescan: &mut bool,
+    res: notify::Result<notify::Event>,
+) {
+    let event = match res {
+        Ok(event) => event,
+        Err(e) => {
+            warn!("File watcher error: {}", e);
+            return;
+        }
+    };
+    debug!("File event: {:?}", &event);
+    if event.need_rescan() {
+        warn!("File events were lost, reading the watched directories again");
+        *rescan = true;
+    }
+    if matches!(event.kind, EventKind::Access(_)) {
+        return;
+    }
+    pending.extend(event.paths);
+}
+
+/// Returns the events for changed paths, and updates the objects that are known to exist.
+/// A path that is an object file is reported as created if it was not known, and as modified
+/// otherwise. A path that no longer exists is reported as deleted if it was a known object;
+/// if it was a directory, each known object below it is reported as deleted. The objects of
+/// a directory that appeared are reported as created.
+pub fn changes(
+    dirs: &[WatchedDir],
+    known: &mut BTreeMap<PathBuf, ObjectIds>,
+    pending: BTreeSet<PathBuf>,
+) -> Vec<ObjectEvent> {
+    let mut events = Vec::new();
+    // objects found in new directories, which may also have events of their own
+    let mut reported = BTreeSet::new();
+    for path in pending {
+        match std::fs::symlink_metadata(&path) {
+            Ok(_) if reported.contains(&path) => {}
+            Ok(metadata) if metadata.is_file() => {
+                if let Some(ids) = object_ids(dirs, &path) {
+                    let kind = match known.insert(path.clone(), ids.clone()) {
+                        Some(_) => ChangeKind::Modified,
+                        None => ChangeKind::Created,
+                    };
+                    events.push(object_event(ids, &path, &metadata, kind));
+                }
+            }
+            Ok(metadata) if metadata.is_dir() => {
+                // a directory moved into place is reported as a single path
+                let mut found = BTreeMap::new();
+                scan_dir(dirs, &path, &mut found);
+                for (file, ids) in found {
+                    if known.contains_key(&file) {
+                        continue;
+                    }
+                    if let Ok(metadata) = std::fs::symlink_metadata(&file) {
+                        events.push(object_event(
+                            ids.clone(),
+                            &file,
+                            &metadata,
+                            ChangeKind::Created,
+                        ));
+                        known.insert(file.clone(), ids);
+                        reported.insert(file);
+                    }
+                }
+            }
+            // symbolic links are not objects
+            Ok(_) => {}
+            Err(_) => {
+                // descendants of a path sort right after it
+                let removed: Vec<PathBuf> = known
+                    .range(path.clone()..)
+                    .map(|(file, _)| file)
+                    .take_while(|file| file.starts_with(&path))
+                    .cloned()
+                    .collect();
+                for file in removed {
+                    if let Some((container_id, object_id)) = known.remove(&file) {
+                        events.push(ObjectEvent {
+                            kind: ChangeKind::Deleted,
+                            object: ObjectMetadata {
+                                container_id,
+                                object_id,
+                                ..Default::default()
+                            },
+                            sha256: None,
+                        });
+                    }
+                }
+            }
+        }
+    }
+    events
+}
+
+/// Finds the objects in the watched directories
+pub fn scan(dirs: &[WatchedDir]) -> BTreeMap<PathBuf, ObjectIds> {
+    let mut found = BTreeMap::new();
+    for watched in dirs {
+        scan_dir(dirs, &watched.dir, &mut found);
+    }
+    found
+}
+
+/// Finds the objects below `dir`. Symbolic links are not followed.
+fn scan_dir(dirs: &[WatchedDir], dir: &Path, found: &mut BTreeMap<PathBuf, ObjectIds>) {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            warn!("Could not list {:?} for object events: {}", dir, e);
+            return;
+        }
+    };
+    for entry in entries.flatten() {
+        if paths::is_reserved(Path::new(&entry.file_name())) {
+            continue;
+        }
+        match entry.file_type() {
+            Ok(t) if t.is_dir() => scan_dir(dirs, &entry.path(), found),
+            Ok(t) if t.is_file() => {
+                if let Some(ids) = object_ids(dirs, &entry.path()) {
+                    found.insert(entry.path(), ids);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Returns the container id and object id of a path, or None if the path is not
+/// an object of a watched container
+pub fn object_ids(dirs: &[WatchedDir], path: &Path) -> Option<ObjectIds> {
+    let (watched, relative) = dirs
+        .iter()
+        .find_map(|w| path.strip_prefix(&w.dir).ok().map(|rel| (w, rel)))?;
+    if paths::is_reserved(relative) {
+        return None;
+    }
+    let mut segments: Vec<String> = relative
+        .components()
+        .map(|c| c.as_os_str().to_string_lossy().to_string())
+        .collect();
+    // the container is the shared namespace or the actor's top-level directory, or the deepest
+    // directory below it that was created as a container. The rest of the path is the object id.
+    let mut container_len = usize::from(watched.container_id.is_none());
+    if segments.len() <= container_len {
+        return None;
+    }
+    for len in container_len + 1..segments.len() {
+        if fs_utils::is_container(&watched.dir.join(segments[..len].join("/"))) {
+            container_len = len;
+        }
+    }
+    let object_id = segments.split_off(container_len).join("/");
+    let container_id = match &watched.container_id {
+        Some(cid) => std::iter::once(cid.clone())
+            .chain(segments)
+            .collect::<Vec<_>>(),
+        None => segments,
+    }
+    .join("/");
+    Some((container_id, object_id))
+}
+
+/// Builds the event for an object file that was created or modified
+fn object_event(
+    (container_id, object_id): ObjectIds,
+    path: &Path,
+    metadata: &std::fs::Metadata,
+    kind: ChangeKind,
+) -> ObjectEvent {
+    // metadata is kept in the object's folder, see `sidecar`
+    let folder = path.parent().unwrap_or(path);
+    let name = path
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let (content_type, content_encoding) = sidecar::content_headers(folder, &name);
+    let object_sidecar = sidecar::read(folder, &name).unwrap_or_default();
+    ObjectEvent {
+        kind,
+        object: ObjectMetadata {
+            container_id,
+            object_id,
+            content_length: codec::logical_len(path, metadata, object_sidecar.encoded)
+                .unwrap_or(metadata.len()),
+            content_type,
+            content_encoding,
+            last_modified: metadata
+                .modified()
+                .ok()
+                .and_then(|m| m.duration_since(SystemTime::UNIX_EPOCH).ok())
+                .map(|s| Timestamp {
+                    sec: s.as_secs() as i64,
+                    nsec: 0u32,
+                }),
+        },
+        sha256: object_sidecar.sha256,
+    }
+}
+
+async fn send_event(ld: &LinkDefinition, operation: &str, event: &ObjectEvent) -> RpcResult<()> {
+    let transport = ProviderTransport::new(ld, None);
+    let ctx = Context::default();
+    transport
+        .send(
+            &ctx,
+            Message {
+                method: operation,
+                arg: serialize(event)?.into(),
+            },
+            None,
+        )
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs_utils::clear_state;
+    use crate::upload::Upload;
+    use std::fs::{create_dir_all, remove_dir_all};
+    use wasmcloud_interface_blobstore::Chunk;
+
+    fn pending(paths: &[PathBuf]) -> BTreeSet<PathBuf> {
+        paths.iter().cloned().collect()
+    }
+
+    #[test]
+    fn events_for_objects() {
+        let root = Path::new("/tmp/rust_test/watch1");
+        let actor_dir = root.join("actor");
+        let shared_dir = root.join(".blobstore-shared/uploads");
+        create_dir_all(actor_dir.join("cont1/sub")).unwrap();
+        fs_utils::create_container(&actor_dir.join("cont1/nested")).unwrap();
+        std::fs::write(actor_dir.join("cont1/nested/obj"), b"data").unwrap();
+        create_dir_all(&shared_dir).unwrap();
+        std::fs::write(actor_dir.join("cont1/sub/old"), b"data").unwrap();
+        std::fs::write(actor_dir.join("stray"), b"data").unwrap();
+        let dirs = vec![
+            WatchedDir {
+                dir: actor_dir.clone(),
+                container_id: None,
+            },
+            WatchedDir {
+                dir: shared_dir.clone(),
+                container_id: Some("uploads".to_string()),
+            },
+        ];
+        let mut known = scan(&dirs);
+        let found: Vec<ObjectIds> = known.values().cloned().collect();
+
+        std::fs::write(actor_dir.join("cont1/new.txt"), b"hello").unwrap();
+        std::fs::write(actor_dir.join("cont1/sub/old"), b"changed").unwrap();
+        std::fs::write(shared_dir.join("image.png"), b"png").unwrap();
+        let written = changes(
+            &dirs,
+            &mut known,
+            pending(&[
+                actor_dir.join("cont1/new.txt"),
+                actor_dir.join("cont1/sub/old"),
+                shared_dir.join("image.png"),
+                // created and removed again, a folder, a file outside of containers and metadata
+                actor_dir.join("cont1/tmp"),
+                actor_dir.join("cont1/sub"),
+                actor_dir.join("stray"),
+                actor_dir.join("cont1/.blobstore-meta/new.txt.json"),
+            ]),
+        );
+        // removing a nested container reports its objects, not the container
+        remove_dir_all(actor_dir.join("cont1/nested")).unwrap();
+        let removed = changes(
+            &dirs,
+            &mut known,
+            pending(&[actor_dir.join("cont1/nested")]),
+        );
+        // the objects of a directory moved into a container are created
+        create_dir_all(root.join("outside/dir")).unwrap();
+        std::fs::write(root.join("outside/dir/moved"), b"data").unwrap();
+        std::fs::rename(root.join("outside/dir"), actor_dir.join("cont1/dir")).unwrap();
+        let moved = changes(
+            &dirs,
+            &mut known,
+            pending(&[
+                actor_dir.join("cont1/dir"),
+                actor_dir.join("cont1/dir/moved"),
+            ]),
+        );
+
+        clear_state(root);
+
+        assert_eq!(
+            found,
+            vec![
+                ("cont1/nested".to_string(), "obj".to_string()),
+                ("cont1".to_string(), "sub/old".to_string()),
+            ]
+        );
+        // events are sorted by path
+        assert_eq!(written.len(), 3);
+        let shared = &written[0];
+        assert_eq!(shared.kind, ChangeKind::Created);
+        assert_eq!(shared.object.container_id, "uploads");
+        assert_eq!(shared.object.content_length, 3);
+        let created = &written[1];
+        assert_eq!(created.kind, ChangeKind::Created);
+        assert_eq!(created.object.container_id, "cont1");
+        assert_eq!(created.object.object_id, "new.txt");
+        assert_eq!(created.object.content_length, 5);
+        assert_eq!(created.object.content_type.as_deref(), Some("text/plain"));
+        assert!(created.object.last_modified.is_some());
+        let modified = &written[2];
+        assert_eq!(modified.kind, ChangeKind::Modified);
+        // "sub" is a folder of cont1, "nested" a container of its own
+        assert_eq!(modified.object.container_id, "cont1");
+        assert_eq!(modified.object.object_id, "sub/old");
+        assert_eq!(modified.object.content_length, 7);
+        assert_eq!(removed.len(), 1);
+        assert_eq!(removed[0].kind, ChangeKind::Deleted);
+        assert_eq!(removed[0].object.container_id, "cont1/nested");
+        assert_eq!(removed[0].object.object_id, "obj");
+        assert_eq!(removed[0].object.last_modified, None);
+        assert_eq!(moved.len(), 1);
+        assert_eq!(moved[0].kind, ChangeKind::Created);
+        assert_eq!(moved[0].object.object_id, "dir/moved");
+    }
+
+    /// Starts a watcher whose events are received from the returned channel
+    fn watch(
+        dirs: Vec<WatchedDir>,
+        debounce: Duration,
+    ) -> (ObjectWatcher, mpsc::UnboundedReceiver<ObjectEvent>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let watcher = ObjectWatcher::start_with(dirs, debounce, move |event| {
+            let _ = tx.send(event);
+            async {}
+        })
+        .unwrap();
+        (watcher, rx)
+    }
+
+    /// Receives the next event, waiting at most 5 seconds
+    async fn next_event(rx: &mut mpsc::UnboundedReceiver<ObjectEvent>) -> Option<ObjectEvent> {
+        tokio::time::timeout(Duration::from_secs(5), rx.recv())
+            .await
+            .ok()
+            .flatten()
+    }
+
+    /// Stores an object like the provider does, through a staging file
+    fn upload(cdir: &Path, object_id: &str, bytes: &[u8]) {
+        let mut upload = Upload::create(
+            "actor",
+            "cont1",
+            object_id,
+            cdir.to_path_buf(),
+            cdir.join(object_id),
+            sidecar::ObjectSidecar::default(),
+            Duration::from_secs(60),
+        )
+        .unwrap();
+        upload
+            .write_chunk(&Chunk {
+                object_id: object_id.to_string(),
+                container_id: "cont1".to_string(),
+                bytes: bytes.to_vec(),
+                offset: 0,
+                is_last: true,
+            })
+            .unwrap();
+        upload.commit().unwrap();
+    }
+
+    #[tokio::test]
+    async fn watch_uploads_and_removals() {
+        let root = Path::new("/tmp/rust_test/watch2");
+        let cdir = root.join("cont1");
+        create_dir_all(&cdir).unwrap();
+        let (_watcher, mut rx) = watch(
+            vec![WatchedDir {
+                dir: root.to_path_buf(),
+                container_id: None,
+            }],
+            Duration::from_millis(50),
+        );
+
+        // an upload is renamed into place, and still reported as created
+        upload(&cdir, "obj", b"first");
+        let created = next_event(&mut rx).await;
+        upload(&cdir, "obj", b"second");
+        let modified = next_event(&mut rx).await;
+        fs_utils::create_container(&cdir.join("nested")).unwrap();
+        upload(&cdir.join("nested"), "inner", b"data");
+        let nested = next_event(&mut rx).await;
+        remove_dir_all(cdir.join("nested")).unwrap();
+        let removed = next_event(&mut rx).await;
+        // nothing else is reported, e.g., for the removed container
+        let extra = tokio::time::timeout(Duration::from_millis(300), rx.recv()).await;
+
+        clear_state(root);
+
+        let created = created.unwrap();
+        assert_eq!(created.kind, ChangeKind::Created);
+        assert_eq!(created.object.object_id, "obj");
+        let modified = modified.unwrap();
+        assert_eq!(modified.kind, ChangeKind::Modified);
+        assert_eq!(modified.object.content_length, 6);
+        let nested = nested.unwrap();
+        assert_eq!(nested.kind, ChangeKind::Created);
+        assert_eq!(nested.object.container_id, "cont1/nested");
+        let removed = removed.unwrap();
+        assert_eq!(removed.kind, ChangeKind::Deleted);
+        assert_eq!(removed.object.container_id, "cont1/nested");
+        assert_eq!(removed.object.object_id, "inner");
+        assert!(extra.is_err());
+    }
+
+    #[tokio::test]
+    async fn continuous_writes_are_reported() {
+        let root = Path::new("/tmp/rust_test/watch3");
+        let cdir = root.join("cont1");
+        create_dir_all(&cdir).unwrap();
+        let debounce = Duration::from_millis(100);
+        let (_watcher, mut rx) = watch(
+            vec![WatchedDir {
+                dir: root.to_path_buf(),
+                container_id: None,
+            }],
+            debounce,
+        );
+
+        // the file changes more often than the debounce period, for longer than the maximum delay
+        let writing = std::time::Instant::now();
+        let mut first_event = None;
+        while writing.elapsed() < debounce * MAX_DELAY_FACTOR * 3 {
+            std::fs::write(cdir.join("log"), writing.elapsed().as_nanos().to_string()).unwrap();
+            tokio::time::sleep(debounce / 5).await;
+            if let Ok(event) = rx.try_recv() {
+                first_event = Some((event, writing.elapsed()));
+                break;
+            }
+        }
+
+        clear_state(root);
+
+        let (event, elapsed) = first_event.expect("no event while the file kept changing");
+        assert_eq!(event.kind, ChangeKind::Created);
+        assert_eq!(event.object.object_id, "log");
+        assert!(elapsed < debounce * MAX_DELAY_FACTOR * 2);
+    }
+}
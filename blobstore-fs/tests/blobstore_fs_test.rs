@@ -10,15 +10,20 @@ use wasmcloud_test_util::{
 #[allow(unused_imports)]
 use wasmcloud_test_util::{run_selected, run_selected_spawn};
 
-/// number of get_object requests in this test
-const NUM_RPC: u32 = 1;
+/// number of chunks the mock actor expects to receive in this test
+const NUM_RPC: u32 = 2;
+
+/// size of the file used to test streamed downloads. With the provider's default
+/// chunk size of 512KB the first chunk is returned in the response and the
+/// remaining two are sent to the mock actor.
+const LARGE_FILE_SIZE: usize = 1200 * 1024;
 
 #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
 async fn run_all() {
     let opts = TestOptions::default();
 
     // launch the mock actor thread
-    let _join = mock_blobstore_actor(NUM_RPC).await;
+    let join = mock_blobstore_actor(NUM_RPC).await;
 
     let res = run_selected_spawn!(
         &opts,
@@ -29,6 +34,16 @@ async fn run_all() {
         upload_and_download_file,
         upload_chunked_download_file,
         upload_download_chunked_file,
+        upload_and_download_large_file,
+        list_objects_paginated,
+        reject_hostile_ids,
+        object_content_metadata,
+        cancel_upload_keeps_object,
+        concurrent_out_of_order_uploads,
+        large_reads_do_not_block_requests,
+        list_versions_without_versioning,
+        list_objects_in_folders,
+        storage_usage_of_actor,
     );
     print_test_results(&res);
 
@@ -36,6 +51,14 @@ async fn run_all() {
     let total = res.len();
     assert_eq!(passed, total, "{} passed out of {}", passed, total);
 
+    // the streamed chunks of the large file should have reached the mock actor
+    let completed = tokio::time::timeout(std::time::Duration::from_secs(10), join)
+        .await
+        .expect("mock actor timed out waiting for chunks")
+        .expect("mock actor panicked")
+        .expect("mock actor failed");
+    assert_eq!(completed, NUM_RPC);
+
     // try to let the provider shut dowwn gracefully
     let provider = test_provider().await;
     let _ = provider.shutdown().await;
@@ -466,6 +489,985 @@ async fn upload_download_chunked_file(_opt: &TestOptions) -> RpcResult<()> {
     Ok(())
 }
 
+// test that an object larger than the chunk size is returned partly in the
+// response and partly streamed to the actor
+async fn upload_and_download_large_file(_opt: &TestOptions) -> RpcResult<()> {
+    let prov = test_provider().await;
+
+    // create client and ctx
+    let client = BlobstoreSender::via(prov);
+    let mut ctx = Context::default();
+    ctx.actor = Some("actor_test".into());
+
+    // Create container cont1
+    let resp = client.create_container(&ctx, &"cont1".into()).await;
+    assert!(resp.is_ok());
+
+    // upload the file in three parts to stay below the nats message size
+    let bytes: Vec<u8> = (0..LARGE_FILE_SIZE).map(|i| (i % 251) as u8).collect();
+    let part_size = LARGE_FILE_SIZE / 3;
+    let upload_request = PutObjectRequest {
+        chunk: Chunk {
+            object_id: "large".into(),
+            container_id: "cont1".into(),
+            bytes: bytes[..part_size].to_vec(),
+            is_last: false,
+            offset: 0,
+        },
+        content_encoding: None,
+        content_type: None,
+    };
+    let stream_id = client.put_object(&ctx, &upload_request).await?.stream_id;
+    assert_ne!(stream_id, None);
+
+    for part in 1..3 {
+        let start = part * part_size;
+        let end = if part == 2 {
+            LARGE_FILE_SIZE
+        } else {
+            start + part_size
+        };
+        let put_chunk_request = PutChunkRequest {
+            chunk: Chunk {
+                object_id: "large".into(),
+                container_id: "cont1".into(),
+                bytes: bytes[start..end].to_vec(),
+                is_last: part == 2,
+                offset: start as u64,
+            },
+            stream_id: stream_id.clone(),
+            cancel_and_remove: false,
+        };
+        client.put_chunk(&ctx, &put_chunk_request).await?;
+    }
+
+    let get_object_request = GetObjectRequest {
+        object_id: "large".into(),
+        container_id: "cont1".into(),
+        range_start: None,
+        range_end: None,
+    };
+    let o = client.get_object(&ctx, &get_object_request).await?;
+    assert_eq!(o.success, true);
+    assert_eq!(o.content_length, LARGE_FILE_SIZE as u64);
+
+    // only the first chunk is in the response
+    let c = o.initial_chunk.unwrap();
+    assert_eq!(c.is_last, false);
+    assert_eq!(c.offset, 0);
+    assert!(c.bytes.len() < LARGE_FILE_SIZE);
+    assert_eq!(c.bytes, bytes[..c.bytes.len()].to_vec());
+
+    // remove container
+    let conts: ContainerIds = vec!["cont1".into()];
+    let resp2 = client.remove_containers(&ctx, &conts).await?;
+    assert_eq!(resp2.len(), 0);
+
+    Ok(())
+}
+
+/// test that list_objects returns sorted pages that can be resumed with the continuation token
+async fn list_objects_paginated(_opt: &TestOptions) -> RpcResult<()> {
+    let prov = test_provider().await;
+
+    // create client and ctx
+    let client = BlobstoreSender::via(prov);
+    let mut ctx = Context::default();
+    ctx.actor = Some("actor_test".into());
+
+    // Create container cont1
+    let resp = client.create_container(&ctx, &"cont1".into()).await;
+    assert!(resp.is_ok());
+
+    // upload files out of order
+    for name in ["file4", "file2", "file5", "file1", "file3"] {
+        let upload_request = PutObjectRequest {
+            chunk: Chunk {
+                object_id: name.into(),
+                container_id: "cont1".into(),
+                bytes: name.as_bytes().to_vec(),
+                is_last: true,
+                offset: 0,
+            },
+            content_encoding: None,
+            content_type: None,
+        };
+        client.put_object(&ctx, &upload_request).await?;
+    }
+
+    // range limited by start_with and end_with
+    let mut list_object_request = ListObjectsRequest::default();
+    list_object_request.container_id = "cont1".to_string();
+    list_object_request.start_with = Some("file2".to_string());
+    list_object_request.end_with = Some("file4".to_string());
+    let list_object_response = client.list_objects(&ctx, &list_object_request).await?;
+    let names: Vec<String> = list_object_response
+        .objects
+        .iter()
+        .map(|o| o.object_id.clone())
+        .collect();
+    assert_eq!(names, vec!["file2", "file3", "file4"]);
+    assert_eq!(list_object_response.is_last, true);
+
+    // page through all files, two at a time
+    let mut list_object_request = ListObjectsRequest::default();
+    list_object_request.container_id = "cont1".to_string();
+    list_object_request.max_items = Some(2);
+    let mut names = Vec::new();
+    let mut pages = 0;
+    loop {
+        let list_object_response = client.list_objects(&ctx, &list_object_request).await?;
+        pages += 1;
+        names.extend(
+            list_object_response
+                .objects
+                .into_iter()
+                .map(|o| o.object_id),
+        );
+        if list_object_response.is_last {
+            assert_eq!(list_object_response.continuation, None);
+            break;
+        }
+        assert_ne!(list_object_response.continuation, None);
+        list_object_request.continuation = list_object_response.continuation;
+    }
+    assert_eq!(pages, 3);
+    assert_eq!(names, vec!["file1", "file2", "file3", "file4", "file5"]);
+
+    // remove container
+    let conts: ContainerIds = vec!["cont1".into()];
+    let resp2 = client.remove_containers(&ctx, &conts).await?;
+    assert_eq!(resp2.len(), 0);
+
+    Ok(())
+}
+
+/// ids that try to escape the actor's directory
+const HOSTILE_IDS: &[&str] = &[
+    "",
+    ".",
+    "..",
+    "../blobstore_fs_escape",
+    "../../tmp/blobstore_fs_escape",
+    "cont1/../../blobstore_fs_escape",
+    "./cont1",
+    "/tmp/blobstore_fs_escape",
+];
+
+/// test that container and object ids cannot be used to reach outside the actor's directory
+async fn reject_hostile_ids(_opt: &TestOptions) -> RpcResult<()> {
+    let prov = test_provider().await;
+
+    // create client and ctx
+    let client = BlobstoreSender::via(prov);
+    let mut ctx = Context::default();
+    ctx.actor = Some("actor_test".into());
+
+    for id in HOSTILE_IDS {
+        let cid: ContainerId = id.to_string();
+        assert!(
+            client.container_exists(&ctx, &cid).await.is_err(),
+            "{:?}",
+            id
+        );
+        assert!(
+            client.create_container(&ctx, &cid).await.is_err(),
+            "{:?}",
+            id
+        );
+        assert!(
+            client.get_container_info(&ctx, &cid).await.is_err(),
+            "{:?}",
+            id
+        );
+
+        let mut list_object_request = ListObjectsRequest::default();
+        list_object_request.container_id = cid.clone();
+        assert!(
+            client
+                .list_objects(&ctx, &list_object_request)
+                .await
+                .is_err(),
+            "{:?}",
+            id
+        );
+
+        let resp = client.remove_containers(&ctx, &vec![cid]).await?;
+        assert_eq!(resp.len(), 1, "{:?}", id);
+    }
+
+    // Create container cont1 to use with hostile object ids
+    let resp = client.create_container(&ctx, &"cont1".into()).await;
+    assert!(resp.is_ok());
+
+    for id in HOSTILE_IDS {
+        let upload_request = PutObjectRequest {
+            chunk: Chunk {
+                object_id: id.to_string(),
+                container_id: "cont1".into(),
+                bytes: vec![0, 1, 2, 3],
+                is_last: true,
+                offset: 0,
+            },
+            content_encoding: None,
+            content_type: None,
+        };
+        assert!(
+            client.put_object(&ctx, &upload_request).await.is_err(),
+            "{:?}",
+            id
+        );
+
+        let container_object = ContainerObject {
+            container_id: "cont1".into(),
+            object_id: id.to_string(),
+        };
+        assert!(
+            client.object_exists(&ctx, &container_object).await.is_err(),
+            "{:?}",
+            id
+        );
+        assert!(
+            client
+                .get_object_info(&ctx, &container_object)
+                .await
+                .is_err(),
+            "{:?}",
+            id
+        );
+
+        let get_object_request = GetObjectRequest {
+            object_id: id.to_string(),
+            container_id: "cont1".into(),
+            range_start: None,
+            range_end: None,
+        };
+        assert!(
+            client.get_object(&ctx, &get_object_request).await.is_err(),
+            "{:?}",
+            id
+        );
+
+        let remove_object_request = RemoveObjectsRequest {
+            container_id: "cont1".into(),
+            objects: vec![id.to_string()],
+        };
+        let resp = client.remove_objects(&ctx, &remove_object_request).await?;
+        assert_eq!(resp.len(), 1, "{:?}", id);
+    }
+
+    // nothing was written outside of the actor's directory
+    assert!(!std::path::Path::new("/tmp/blobstore_fs_escape").exists());
+
+    // remove container
+    let conts: ContainerIds = vec!["cont1".into()];
+    let resp2 = client.remove_containers(&ctx, &conts).await?;
+    assert_eq!(resp2.len(), 0);
+
+    Ok(())
+}
+
+/// test that content type and encoding are stored with objects, or guessed from the extension
+async fn object_content_metadata(_opt: &TestOptions) -> RpcResult<()> {
+    let prov = test_provider().await;
+
+    // create client and ctx
+    let client = BlobstoreSender::via(prov);
+    let mut ctx = Context::default();
+    ctx.actor = Some("actor_test".into());
+
+    // Create container cont1
+    let resp = client.create_container(&ctx, &"cont1".into()).await;
+    assert!(resp.is_ok());
+
+    // upload one file with explicit content type and encoding, and one without
+    let upload_request = PutObjectRequest {
+        chunk: Chunk {
+            object_id: "data.bin".into(),
+            container_id: "cont1".into(),
+            bytes: vec![0, 1, 2, 3],
+            is_last: true,
+            offset: 0,
+        },
+        content_encoding: Some("gzip".into()),
+        content_type: Some("application/x-custom".into()),
+    };
+    client.put_object(&ctx, &upload_request).await?;
+    let upload_request = PutObjectRequest {
+        chunk: Chunk {
+            object_id: "page.html".into(),
+            container_id: "cont1".into(),
+            bytes: b"<html></html>".to_vec(),
+            is_last: true,
+            offset: 0,
+        },
+        content_encoding: None,
+        content_type: None,
+    };
+    client.put_object(&ctx, &upload_request).await?;
+
+    let container_object = ContainerObject {
+        container_id: "cont1".into(),
+        object_id: "data.bin".into(),
+    };
+    let object_info = client.get_object_info(&ctx, &container_object).await?;
+    assert_eq!(
+        object_info.content_type.as_deref(),
+        Some("application/x-custom")
+    );
+    assert_eq!(object_info.content_encoding.as_deref(), Some("gzip"));
+
+    let get_object_request = GetObjectRequest {
+        object_id: "data.bin".into(),
+        container_id: "cont1".into(),
+        range_start: None,
+        range_end: None,
+    };
+    let o = client.get_object(&ctx, &get_object_request).await?;
+    assert_eq!(o.content_type.as_deref(), Some("application/x-custom"));
+    assert_eq!(o.content_encoding.as_deref(), Some("gzip"));
+
+    let container_object = ContainerObject {
+        container_id: "cont1".into(),
+        object_id: "page.html".into(),
+    };
+    let object_info = client.get_object_info(&ctx, &container_object).await?;
+    assert_eq!(object_info.content_type.as_deref(), Some("text/html"));
+    assert_eq!(object_info.content_encoding, None);
+
+    // the metadata directory is not listed as an object or container
+    let mut list_object_request = ListObjectsRequest::default();
+    list_object_request.container_id = "cont1".to_string();
+    let list_object_response = client.list_objects(&ctx, &list_object_request).await?;
+    assert_eq!(list_object_response.objects.len(), 2);
+    assert_eq!(
+        list_object_response.objects[0].content_type.as_deref(),
+        Some("application/x-custom")
+    );
+    assert_eq!(
+        list_object_response.objects[1].content_type.as_deref(),
+        Some("text/html")
+    );
+    let containers = client.list_containers(&ctx).await?;
+    assert_eq!(containers.len(), 1);
+
+    // remove container
+    let conts: ContainerIds = vec!["cont1".into()];
+    let resp2 = client.remove_containers(&ctx, &conts).await?;
+    assert_eq!(resp2.len(), 0);
+
+    Ok(())
+}
+
+/// test that a cancelled upload leaves the previously stored object intact
+async fn cancel_upload_keeps_object(_opt: &TestOptions) -> RpcResult<()> {
+    let prov = test_provider().await;
+
+    // create client and ctx
+    let client = BlobstoreSender::via(prov);
+    let mut ctx = Context::default();
+    ctx.actor = Some("actor_test".into());
+
+    // Create container cont1
+    let resp = client.create_container(&ctx, &"cont1".into()).await;
+    assert!(resp.is_ok());
+
+    // store the first version of file1
+    let upload_request = PutObjectRequest {
+        chunk: Chunk {
+            object_id: "file1".into(),
+            container_id: "cont1".into(),
+            bytes: b"version 1".to_vec(),
+            is_last: true,
+            offset: 0,
+        },
+        content_encoding: None,
+        content_type: None,
+    };
+    client.put_object(&ctx, &upload_request).await?;
+
+    // start uploading a second version, and cancel it
+    let upload_request = PutObjectRequest {
+        chunk: Chunk {
+            object_id: "file1".into(),
+            container_id: "cont1".into(),
+            bytes: b"version 2, part 1".to_vec(),
+            is_last: false,
+            offset: 0,
+        },
+        content_encoding: None,
+        content_type: None,
+    };
+    let resp = client.put_object(&ctx, &upload_request).await?;
+    assert!(resp.stream_id.is_some());
+
+    let cancel_request = PutChunkRequest {
+        chunk: Chunk {
+            object_id: "file1".into(),
+            container_id: "cont1".into(),
+            bytes: vec![],
+            is_last: false,
+            offset: 17,
+        },
+        stream_id: resp.stream_id.clone(),
+        cancel_and_remove: true,
+    };
+    client.put_chunk(&ctx, &cancel_request).await?;
+
+    // the first version is still there, and the upload can't be continued
+    let get_object_request = GetObjectRequest {
+        object_id: "file1".into(),
+        container_id: "cont1".into(),
+        range_start: None,
+        range_end: None,
+    };
+    let o = client.get_object(&ctx, &get_object_request).await?;
+    assert_eq!(o.initial_chunk.unwrap().bytes, b"version 1".to_vec());
+    let continue_request = PutChunkRequest {
+        chunk: Chunk {
+            object_id: "file1".into(),
+            container_id: "cont1".into(),
+            bytes: b"version 2, part 2".to_vec(),
+            is_last: true,
+            offset: 17,
+        },
+        stream_id: resp.stream_id,
+        cancel_and_remove: false,
+    };
+    assert!(client.put_chunk(&ctx, &continue_request).await.is_err());
+
+    // the staging directory is not listed as an object
+    let mut list_object_request = ListObjectsRequest::default();
+    list_object_request.container_id = "cont1".to_string();
+    let list_object_response = client.list_objects(&ctx, &list_object_request).await?;
+    assert_eq!(list_object_response.objects.len(), 1);
+
+    // remove container
+    let conts: ContainerIds = vec!["cont1".into()];
+    let resp2 = client.remove_containers(&ctx, &conts).await?;
+    assert_eq!(resp2.len(), 0);
+
+    Ok(())
+}
+
+/// `BlobstoreVersions.ListObjectVersions` argument, as defined by the provider
+#[derive(serde::Serialize)]
+struct ListObjectVersionsRequest {
+    container_id: String,
+    object_id: String,
+}
+
+/// `BlobstoreVersions.RestoreObjectVersion` argument, as defined by the provider
+#[derive(serde::Serialize)]
+struct RestoreObjectVersionRequest {
+    container_id: String,
+    object_id: String,
+    version_id: String,
+}
+
+/// the fields of `ObjectVersion` checked here
+#[derive(serde::Deserialize)]
+struct ObjectVersion {
+    version_id: String,
+    is_latest: bool,
+    is_delete_marker: bool,
+    content_length: u64,
+}
+
+/// test the versions extension when the link doesn't enable versioning: an object has only
+/// its current version, with the id "null", and versions can't be restored
+async fn list_versions_without_versioning(_opt: &TestOptions) -> RpcResult<()> {
+    let prov = test_provider().await;
+
+    // create client and ctx. The versions extension is called with raw messages.
+    let client = BlobstoreSender::via(test_provider().await);
+    let mut ctx = Context::default();
+    ctx.actor = Some("actor_test".into());
+
+    client.create_container(&ctx, &"cont1".into()).await?;
+    let upload_request = PutObjectRequest {
+        chunk: Chunk {
+            object_id: "file1".into(),
+            container_id: "cont1".into(),
+            bytes: b"version 1".to_vec(),
+            is_last: true,
+            offset: 0,
+        },
+        content_encoding: None,
+        content_type: None,
+    };
+    client.put_object(&ctx, &upload_request).await?;
+
+    let list_request = ListObjectVersionsRequest {
+        container_id: "cont1".into(),
+        object_id: "file1".into(),
+    };
+    let resp = prov
+        .send(
+            &ctx,
+            Message {
+                method: "BlobstoreVersions.ListObjectVersions",
+                arg: wasmbus_rpc::common::serialize(&list_request)?.into(),
+            },
+            None,
+        )
+        .await?;
+    let versions: Vec<ObjectVersion> = wasmbus_rpc::common::deserialize(&resp)?;
+    assert_eq!(versions.len(), 1);
+    assert_eq!(versions[0].version_id, "null");
+    assert!(versions[0].is_latest);
+    assert!(!versions[0].is_delete_marker);
+    assert_eq!(versions[0].content_length, 9);
+
+    let restore_request = RestoreObjectVersionRequest {
+        container_id: "cont1".into(),
+        object_id: "file1".into(),
+        version_id: "null".into(),
+    };
+    let restored = prov
+        .send(
+            &ctx,
+            Message {
+                method: "BlobstoreVersions.RestoreObjectVersion",
+                arg: wasmbus_rpc::common::serialize(&restore_request)?.into(),
+            },
+            None,
+        )
+        .await;
+    assert!(restored.is_err());
+
+    // remove container
+    let conts: ContainerIds = vec!["cont1".into()];
+    let resp2 = client.remove_containers(&ctx, &conts).await?;
+    assert_eq!(resp2.len(), 0);
+
+    Ok(())
+}
+
+/// `BlobstoreListing.ListObjectsByPrefix` argument, as defined by the provider
+#[derive(serde::Serialize)]
+struct ListByPrefixRequest {
+    container_id: String,
+    prefix: Option<String>,
+    delimiter: Option<String>,
+}
+
+/// the fields of `ListByPrefixResponse` checked here
+#[derive(serde::Deserialize)]
+struct ListByPrefixResponse {
+    objects: Vec<ObjectMetadata>,
+    common_prefixes: Vec<String>,
+    is_last: bool,
+}
+
+/// test objects with `/` in their ids: they are stored in folders, listed with their full ids,
+/// and rolled up into common prefixes when listed with a delimiter
+async fn list_objects_in_folders(_opt: &TestOptions) -> RpcResult<()> {
+    let prov = test_provider().await;
+
+    // create client and ctx. The listing extension is called with raw messages.
+    let client = BlobstoreSender::via(test_provider().await);
+    let mut ctx = Context::default();
+    ctx.actor = Some("actor_test".into());
+
+    client.create_container(&ctx, &"cont1".into()).await?;
+    for object_id in ["a.txt", "images/logo.png", "images/2023/a.png"] {
+        let upload_request = PutObjectRequest {
+            chunk: Chunk {
+                object_id: object_id.into(),
+                container_id: "cont1".into(),
+                bytes: object_id.as_bytes().to_vec(),
+                is_last: true,
+                offset: 0,
+            },
+            content_encoding: None,
+            content_type: None,
+        };
+        client.put_object(&ctx, &upload_request).await?;
+    }
+
+    // without a delimiter, every object is listed with its full id
+    let list_object_request = ListObjectsRequest {
+        container_id: "cont1".into(),
+        ..Default::default()
+    };
+    let all = client.list_objects(&ctx, &list_object_request).await?;
+    let ids: Vec<&str> = all.objects.iter().map(|o| o.object_id.as_str()).collect();
+    assert_eq!(ids, vec!["a.txt", "images/2023/a.png", "images/logo.png"]);
+
+    let list_by_prefix = |prefix: Option<&str>| {
+        let req = ListByPrefixRequest {
+            container_id: "cont1".into(),
+            prefix: prefix.map(String::from),
+            delimiter: Some("/".into()),
+        };
+        let prov = &prov;
+        let ctx = &ctx;
+        async move {
+            let resp = prov
+                .send(
+                    ctx,
+                    Message {
+                        method: "BlobstoreListing.ListObjectsByPrefix",
+                        arg: wasmbus_rpc::common::serialize(&req)?.into(),
+                    },
+                    None,
+                )
+                .await?;
+            wasmbus_rpc::common::deserialize::<ListByPrefixResponse>(&resp)
+        }
+    };
+
+    let top = list_by_prefix(None).await?;
+    assert!(top.is_last);
+    assert_eq!(top.objects.len(), 1);
+    assert_eq!(top.objects[0].object_id, "a.txt");
+    assert_eq!(top.common_prefixes, vec!["images/".to_string()]);
+
+    let images = list_by_prefix(Some("images/")).await?;
+    assert_eq!(images.objects.len(), 1);
+    assert_eq!(images.objects[0].object_id, "images/logo.png");
+    assert_eq!(images.objects[0].content_length, 15);
+    assert_eq!(images.common_prefixes, vec!["images/2023/".to_string()]);
+
+    // removing the last object of a folder removes the folder
+    let removed = client
+        .remove_objects(
+            &ctx,
+            &RemoveObjectsRequest {
+                container_id: "cont1".into(),
+                objects: vec!["images/2023/a.png".into()],
+            },
+        )
+        .await?;
+    assert!(removed.is_empty());
+    let images = list_by_prefix(Some("images/")).await?;
+    assert!(images.common_prefixes.is_empty());
+
+    // folders are not containers
+    let containers = client.list_containers(&ctx).await?;
+    assert!(!containers
+        .iter()
+        .any(|c| c.container_id.starts_with("cont1/")));
+
+    // remove container
+    let conts: ContainerIds = vec!["cont1".into()];
+    let resp2 = client.remove_containers(&ctx, &conts).await?;
+    assert_eq!(resp2.len(), 0);
+
+    Ok(())
+}
+
+/// `BlobstoreQuota.GetStorageUsage` argument, as defined by the provider
+#[derive(serde::Serialize)]
+struct StorageUsageRequest {
+    container_id: Option<String>,
+}
+
+/// `Usage`, as defined by the provider
+#[derive(Debug, PartialEq, serde::Deserialize)]
+struct Usage {
+    bytes: u64,
+    objects: u64,
+}
+
+/// the fields of `StorageUsage` checked here
+#[derive(serde::Deserialize)]
+struct StorageUsage {
+    usage: Usage,
+    reserved_bytes: u64,
+    limits: Limits,
+    container: Option<Usage>,
+}
+
+/// `Quota`, as defined by the provider
+#[derive(serde::Deserialize)]
+struct Limits {
+    max_total_bytes: Option<u64>,
+    max_objects: Option<u64>,
+}
+
+/// test the quota extension: the usage of the actor and of a container is returned
+/// with the actor's limits, and follows objects being stored and removed
+async fn storage_usage_of_actor(_opt: &TestOptions) -> RpcResult<()> {
+    let prov = test_provider().await;
+
+    // create client and ctx. The quota extension is called with raw messages.
+    let client = BlobstoreSender::via(test_provider().await);
+    let mut ctx = Context::default();
+    ctx.actor = Some("actor_test".into());
+
+    let storage_usage = |container_id: Option<&str>| {
+        let req = StorageUsageRequest {
+            container_id: container_id.map(String::from),
+        };
+        let prov = &prov;
+        let ctx = &ctx;
+        async move {
+            let resp = prov
+                .send(
+                    ctx,
+                    Message {
+                        method: "BlobstoreQuota.GetStorageUsage",
+                        arg: wasmbus_rpc::common::serialize(&req)?.into(),
+                    },
+                    None,
+                )
+                .await?;
+            wasmbus_rpc::common::deserialize::<StorageUsage>(&resp)
+        }
+    };
+
+    // objects left by other tests are counted as well
+    let before = storage_usage(None).await?.usage;
+
+    client.create_container(&ctx, &"quota1".into()).await?;
+    client.create_container(&ctx, &"quota2".into()).await?;
+    for (container_id, object_id, bytes) in [
+        ("quota1", "file1", &b"12345"[..]),
+        ("quota1", "file2", &b"1234567"[..]),
+        ("quota2", "file3", &b"123"[..]),
+    ] {
+        let upload_request = PutObjectRequest {
+            chunk: Chunk {
+                object_id: object_id.into(),
+                container_id: container_id.into(),
+                bytes: bytes.to_vec(),
+                is_last: true,
+                offset: 0,
+            },
+            content_encoding: None,
+            content_type: None,
+        };
+        client.put_object(&ctx, &upload_request).await?;
+    }
+
+    // the test link sets no limits
+    let usage = storage_usage(Some("quota1")).await?;
+    assert_eq!(
+        usage.usage,
+        Usage {
+            bytes: before.bytes + 15,
+            objects: before.objects + 3
+        }
+    );
+    assert_eq!(
+        usage.container,
+        Some(Usage {
+            bytes: 12,
+            objects: 2
+        })
+    );
+    assert_eq!(usage.reserved_bytes, 0);
+    assert_eq!(usage.limits.max_total_bytes, None);
+    assert_eq!(usage.limits.max_objects, None);
+
+    let removed = client
+        .remove_objects(
+            &ctx,
+            &RemoveObjectsRequest {
+                container_id: "quota1".into(),
+                objects: vec!["file2".into()],
+            },
+        )
+        .await?;
+    assert!(removed.is_empty());
+    let usage = storage_usage(None).await?;
+    assert_eq!(
+        usage.usage,
+        Usage {
+            bytes: before.bytes + 8,
+            objects: before.objects + 2
+        }
+    );
+    assert!(usage.container.is_none());
+
+    // get_container_info doesn't measure usage, but still works
+    let info = client.get_container_info(&ctx, &"quota1".into()).await?;
+    assert_eq!(info.container_id, "quota1");
+
+    // remove containers
+    let conts: ContainerIds = vec!["quota1".into(), "quota2".into()];
+    let resp2 = client.remove_containers(&ctx, &conts).await?;
+    assert_eq!(resp2.len(), 0);
+
+    Ok(())
+}
+
+/// test two uploads of the same object in progress at the same time, with chunks sent out of order
+async fn concurrent_out_of_order_uploads(_opt: &TestOptions) -> RpcResult<()> {
+    let prov = test_provider().await;
+
+    // create client and ctx
+    let client = BlobstoreSender::via(prov);
+    let mut ctx = Context::default();
+    ctx.actor = Some("actor_test".into());
+
+    // Create container cont1
+    let resp = client.create_container(&ctx, &"cont1".into()).await;
+    assert!(resp.is_ok());
+
+    let chunk = |bytes: &[u8], offset: u64, is_last: bool| Chunk {
+        object_id: "file1".into(),
+        container_id: "cont1".into(),
+        bytes: bytes.to_vec(),
+        is_last,
+        offset,
+    };
+
+    // both uploads start with a chunk from the middle of the object
+    let upload_request = PutObjectRequest {
+        chunk: chunk(b"BBBB", 4, false),
+        content_encoding: None,
+        content_type: None,
+    };
+    let stream_1 = client.put_object(&ctx, &upload_request).await?.stream_id;
+    let upload_request = PutObjectRequest {
+        chunk: chunk(b"bbbb", 4, false),
+        content_encoding: None,
+        content_type: None,
+    };
+    let stream_2 = client.put_object(&ctx, &upload_request).await?.stream_id;
+    assert!(stream_1.is_some());
+    assert!(stream_2.is_some());
+    assert_ne!(stream_1, stream_2);
+
+    // send the remaining chunks of both uploads in parallel, last chunk first
+    let put_chunk_request =
+        |bytes: &[u8], offset: u64, is_last: bool, stream_id: &Option<String>| PutChunkRequest {
+            chunk: chunk(bytes, offset, is_last),
+            stream_id: stream_id.clone(),
+            cancel_and_remove: false,
+        };
+    let requests_1 = vec![
+        put_chunk_request(b"CC", 8, true, &stream_1),
+        put_chunk_request(b"AAAA", 0, false, &stream_1),
+    ];
+    let requests_2 = vec![
+        put_chunk_request(b"cc", 8, true, &stream_2),
+        put_chunk_request(b"aaaa", 0, false, &stream_2),
+    ];
+    let (resp_1, resp_2) = tokio::join!(
+        futures_util::future::try_join_all(requests_1.iter().map(|r| client.put_chunk(&ctx, r))),
+        futures_util::future::try_join_all(requests_2.iter().map(|r| client.put_chunk(&ctx, r))),
+    );
+    resp_1?;
+    resp_2?;
+
+    // the upload that completed last replaced the object
+    let get_object_request = GetObjectRequest {
+        object_id: "file1".into(),
+        container_id: "cont1".into(),
+        range_start: None,
+        range_end: None,
+    };
+    let o = client.get_object(&ctx, &get_object_request).await?;
+    let bytes = o.initial_chunk.unwrap().bytes;
+    assert!(bytes == b"AAAABBBBCC".to_vec() || bytes == b"aaaabbbbcc".to_vec());
+
+    // completed uploads can't receive more chunks
+    let late_request = put_chunk_request(b"dd", 10, true, &stream_2);
+    assert!(client.put_chunk(&ctx, &late_request).await.is_err());
+
+    // remove container
+    let conts: ContainerIds = vec!["cont1".into()];
+    let resp2 = client.remove_containers(&ctx, &conts).await?;
+    assert_eq!(resp2.len(), 0);
+
+    Ok(())
+}
+
+/// test that reading large objects doesn't stall requests that don't need the same data:
+/// container_exists answers about as fast as without load while many large get_object
+/// requests are being served
+async fn large_reads_do_not_block_requests(_opt: &TestOptions) -> RpcResult<()> {
+    use std::time::{Duration, Instant};
+
+    let prov = test_provider().await;
+
+    // create client and ctx
+    let client = BlobstoreSender::via(prov);
+    let mut ctx = Context::default();
+    ctx.actor = Some("actor_test".into());
+
+    // Create container cont1
+    let resp = client.create_container(&ctx, &"cont1".into()).await;
+    assert!(resp.is_ok());
+
+    // upload an object of one full response chunk
+    const OBJECT_SIZE: usize = 512 * 1024;
+    let bytes: Vec<u8> = (0..OBJECT_SIZE).map(|i| (i % 241) as u8).collect();
+    let upload_request = PutObjectRequest {
+        chunk: Chunk {
+            object_id: "big".into(),
+            container_id: "cont1".into(),
+            bytes: bytes.clone(),
+            is_last: true,
+            offset: 0,
+        },
+        content_encoding: None,
+        content_type: None,
+    };
+    client.put_object(&ctx, &upload_request).await?;
+
+    // latency of container_exists without load: the slowest of a few calls
+    let mut baseline = Duration::ZERO;
+    for _ in 0..5 {
+        let started = Instant::now();
+        assert_eq!(client.container_exists(&ctx, &"cont1".into()).await?, true);
+        baseline = baseline.max(started.elapsed());
+    }
+
+    // the whole object is returned in the response, so nothing is streamed to the mock actor
+    let get_object_request = GetObjectRequest {
+        object_id: "big".into(),
+        container_id: "cont1".into(),
+        range_start: Some(0),
+        range_end: Some(OBJECT_SIZE as u64 - 1),
+    };
+    let reads = async {
+        let reads = futures_util::future::try_join_all(
+            (0..64).map(|_| client.get_object(&ctx, &get_object_request)),
+        )
+        .await;
+        (reads, Instant::now())
+    };
+    let probe = async {
+        // let the reads reach the provider first
+        tokio::task::yield_now().await;
+        let started = Instant::now();
+        let exists = client.container_exists(&ctx, &"cont1".into()).await;
+        (exists, started.elapsed(), Instant::now())
+    };
+    let ((reads, reads_done), (exists, probe_time, probe_done)) = tokio::join!(reads, probe);
+
+    for o in reads? {
+        assert_eq!(o.success, true);
+        let c = o.initial_chunk.unwrap();
+        assert_eq!(c.is_last, true);
+        assert_eq!(c.bytes.len(), OBJECT_SIZE);
+    }
+    assert_eq!(exists?, true);
+    // the probe was answered while the reads were still being served
+    assert!(
+        probe_done < reads_done,
+        "container_exists returned after the reads completed"
+    );
+    assert!(
+        probe_time <= baseline * 4 + Duration::from_millis(100),
+        "container_exists took {:?} while objects were read, {:?} without load",
+        probe_time,
+        baseline
+    );
+
+    // remove container
+    let conts: ContainerIds = vec!["cont1".into()];
+    let resp2 = client.remove_containers(&ctx, &conts).await?;
+    assert_eq!(resp2.len(), 0);
+
+    Ok(())
+}
+
 /// This mock actor runs in a separate thread, listening for rpc requests.
 /// It responds to receive chunk requests.
 /// The thread quits if the number of expected messages has been completed,
@@ -502,8 +1504,8 @@ async fn mock_blobstore_actor(num_requests: u32) -> tokio::task::JoinHandle<RpcR
                 let rec_chunk: Chunk = wasmbus_rpc::common::decode(&inv.msg, &decode_chunk)
                     .map_err(|e| RpcError::Deser(format!("'Chunk': {}", e)))?;
 
-                // do something with chunk
-                assert_eq!(rec_chunk.is_last, true);
+                // only the final chunk of the stream is marked as last
+                assert_eq!(rec_chunk.is_last, completed + 1 >= num_requests);
 
                 let chunk_resp = ChunkResponse {
                     cancel_download: false,
@@ -9,6 +9,8 @@ use serde::Deserialize;
 use std::{collections::HashMap, env};
 use wasmbus_rpc::error::{RpcError, RpcResult};
 
+use crate::{BucketScope, Encryption, EventsConfig};
+
 const DEFAULT_STS_SESSION: &str = "blobstore_s3_provider";
 
 /// Configuration for connecting to S3.
@@ -32,6 +34,35 @@ pub struct StorageConfig {
     /// optional map of bucket aliases to names
     #[serde(default)]
     pub aliases: HashMap<String, String>,
+    /// seconds after which a multipart upload that has not received a chunk is aborted (default 300)
+    pub upload_timeout_secs: Option<u64>,
+    /// whether list_objects returns the content type and encoding of objects,
+    /// which takes an additional request to S3 per object
+    #[serde(default)]
+    pub list_object_metadata: bool,
+    /// whether `create_container` creates buckets with Object Lock enabled, so that retention and
+    /// legal holds can be set on their objects. S3 also enables versioning on these buckets.
+    #[serde(default)]
+    pub object_lock: bool,
+    /// number of objects streamed to the actor at a time (default 8)
+    pub max_concurrent_downloads: Option<usize>,
+    /// actor operation that receives a `DownloadError` when streaming an object aborts
+    /// (default `ChunkReceiver.DownloadError`)
+    pub download_error_operation: Option<String>,
+    /// number of ranged GetObject requests a download makes at a time (default 1, no parallel parts)
+    pub download_concurrency: Option<usize>,
+    /// size in bytes of the parts of a parallel download (default 8MB)
+    pub download_part_size: Option<u64>,
+    /// server-side encryption of the objects written by the link
+    pub encryption: Option<Encryption>,
+    /// server-side encryption by bucket name or alias, replacing `encryption` for those buckets
+    #[serde(default)]
+    pub bucket_encryption: HashMap<String, Encryption>,
+    /// optional subscription to the objects added or changed in buckets
+    pub events: Option<EventsConfig>,
+    /// buckets the link may use, by name or alias, with optional key prefixes.
+    /// If not set, the link may use any bucket.
+    pub allowed_buckets: Option<HashMap<String, BucketScope>>,
 }
 
 #[derive(Clone, Default, Deserialize)]
@@ -88,6 +119,19 @@ impl StorageConfig {
         if let Ok(endpoint) = env::var("AWS_ENDPOINT") {
             config.endpoint = Some(endpoint)
         }
+        for encryption in config
+            .encryption
+            .iter()
+            .chain(config.bucket_encryption.values())
+        {
+            encryption.validate()?;
+        }
+        if let Some(events) = config.events.as_ref() {
+            events.validate()?;
+        }
+        for (bucket, scope) in config.allowed_buckets.iter().flatten() {
+            scope.validate(bucket)?;
+        }
         // aliases are added from linkdefs in StorageClient::new()
         Ok(config)
     }
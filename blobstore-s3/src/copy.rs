@@ -0,0 +1,382 @@
+//! Server-side copy and move
+//!
+//! Actors call `BlobstoreCopy.CopyObject` or `BlobstoreCopy.MoveObject` on the blobstore link to
+//! copy or move an object within or between buckets without downloading it. Bucket names may be
+//! aliases. Objects up to 5GB are copied with one CopyObject request, larger objects with a
+//! multipart upload whose parts are copied with UploadPartCopy. The copy keeps the content
+//! headers and user-defined metadata of the source, unless the request has `headers`, which
+//! replace them. A move copies the object, then removes the source.
+
+use aws_sdk_s3::{
+    error::{HeadObjectError, HeadObjectErrorKind},
+    model::{CompletedMultipartUpload, CompletedPart, MetadataDirective},
+    output::HeadObjectOutput,
+    types::SdkError,
+};
+use futures::{StreamExt, TryStreamExt};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, error, instrument};
+use wasmbus_rpc::common::deserialize;
+use wasmbus_rpc::provider::prelude::*;
+
+use crate::{
+    encryption::{DecryptCopySource, EncryptRequest},
+    ObjectHeaders, StorageClient,
+};
+
+/// largest object S3 copies with a single CopyObject request (5GB)
+const MAX_COPY_OBJECT_SIZE: u64 = 5 * 1024 * 1024 * 1024;
+
+/// size of the parts of a multipart copy, unless more than `MAX_PARTS` parts would be needed
+const COPY_PART_SIZE: u64 = 512 * 1024 * 1024;
+
+/// most parts S3 accepts in a multipart upload
+const MAX_PARTS: u64 = 10_000;
+
+/// number of UploadPartCopy requests in flight
+const COPY_CONCURRENCY: usize = 4;
+
+/// Argument of `BlobstoreCopy.CopyObject` and `BlobstoreCopy.MoveObject`
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CopyObjectRequest {
+    pub source_container_id: String,
+    pub source_object_id: String,
+    pub container_id: String,
+    pub object_id: String,
+    /// headers and metadata of the copy, replacing those of the source
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub headers: Option<ObjectHeaders>,
+}
+
+/// Server-side copies, an extension of the blobstore interface.
+/// Actors call them as `BlobstoreCopy.<Operation>` on the blobstore link.
+#[async_trait]
+pub trait BlobstoreCopy {
+    /// Copies an object, to the same or another bucket
+    async fn copy_object(&self, ctx: &Context, arg: &CopyObjectRequest) -> RpcResult<()>;
+
+    /// Copies an object, then removes the source
+    async fn move_object(&self, ctx: &Context, arg: &CopyObjectRequest) -> RpcResult<()>;
+}
+
+/// Receives `BlobstoreCopy` messages, like the receivers generated for smithy interfaces
+#[async_trait]
+pub trait BlobstoreCopyReceiver: MessageDispatch + BlobstoreCopy {
+    async fn dispatch(&self, ctx: &Context, message: Message<'_>) -> RpcResult<Vec<u8>> {
+        match message.method {
+            "CopyObject" => {
+                let value: CopyObjectRequest = deserialize(&message.arg)
+                    .map_err(|e| RpcError::Deser(format!("'CopyObjectRequest': {}", e)))?;
+                BlobstoreCopy::copy_object(self, ctx, &value).await?;
+                Ok(Vec::new())
+            }
+            "MoveObject" => {
+                let value: CopyObjectRequest = deserialize(&message.arg)
+                    .map_err(|e| RpcError::Deser(format!("'CopyObjectRequest': {}", e)))?;
+                BlobstoreCopy::move_object(self, ctx, &value).await?;
+                Ok(Vec::new())
+            }
+            _ => Err(RpcError::MethodNotHandled(format!(
+                "BlobstoreCopy::{}",
+                message.method
+            ))),
+        }
+    }
+}
+
+/// Returns the part size of a multipart copy of an object of `size` bytes
+fn part_size(size: u64) -> u64 {
+    COPY_PART_SIZE.max((size + MAX_PARTS - 1) / MAX_PARTS)
+}
+
+/// Returns the part numbers and http byte ranges of the parts of a multipart copy
+fn copy_ranges(size: u64) -> Vec<(i32, String)> {
+    let part_size = part_size(size);
+    (0..size)
+        .step_by(part_size as usize)
+        .enumerate()
+        .map(|(n, start)| {
+            let end = (start + part_size).min(size) - 1;
+            (n as i32 + 1, format!("bytes={}-{}", start, end))
+        })
+        .collect()
+}
+
+/// Returns the `x-amz-copy-source` of an object: its bucket and url-encoded key
+fn copy_source(bucket_id: &str, object_id: &str) -> String {
+    format!(
+        "{}/{}",
+        bucket_id,
+        aws_smithy_http::label::fmt_string(object_id, true)
+    )
+}
+
+impl StorageClient {
+    /// Returns the size and headers of the source of a copy
+    async fn head_copy_source(
+        &self,
+        bucket_id: &str,
+        object_id: &str,
+    ) -> RpcResult<(u64, ObjectHeaders)> {
+        match self
+            .s3_client
+            .head_object()
+            .bucket(bucket_id)
+            .key(self.key(bucket_id, object_id))
+            .encryption(self.encryption(bucket_id))
+            .send()
+            .await
+        {
+            Ok(HeadObjectOutput {
+                content_length,
+                content_type,
+                content_encoding,
+                cache_control,
+                metadata,
+                ..
+            }) => Ok((
+                content_length as u64,
+                ObjectHeaders {
+                    content_type,
+                    content_encoding,
+                    cache_control,
+                    metadata: metadata.unwrap_or_default(),
+                },
+            )),
+            Err(SdkError::ServiceError {
+                err:
+                    HeadObjectError {
+                        kind: HeadObjectErrorKind::NotFound(_),
+                        ..
+                    },
+                ..
+            }) => Err(RpcError::Other(format!(
+                "Not found: Bucket({}) Object({})",
+                bucket_id, object_id,
+            ))),
+            Err(e) => Err(RpcError::Other(format!(
+                "copy source Bucket({}) Object({}): {}",
+                bucket_id, object_id, e
+            ))),
+        }
+    }
+
+    /// Copies an object with a single CopyObject request
+    async fn copy_whole_object(
+        &self,
+        source_bucket: &str,
+        bucket_id: &str,
+        arg: &CopyObjectRequest,
+    ) -> RpcResult<()> {
+        let headers = arg.headers.clone().unwrap_or_default();
+        let directive = match arg.headers {
+            Some(_) => MetadataDirective::Replace,
+            None => MetadataDirective::Copy,
+        };
+        self.s3_client
+            .copy_object()
+            .copy_source(copy_source(
+                source_bucket,
+                &self.key(source_bucket, &arg.source_object_id),
+            ))
+            .bucket(bucket_id)
+            .key(self.key(bucket_id, &arg.object_id))
+            .metadata_directive(directive)
+            .set_content_type(headers.content_type.clone())
+            .set_content_encoding(headers.content_encoding.clone())
+            .set_cache_control(headers.cache_control.clone())
+            .set_metadata(headers.user_metadata())
+            .encryption(self.encryption(bucket_id))
+            .source_encryption(self.encryption(source_bucket))
+            .send()
+            .await
+            .map_err(|e| {
+                error!(error = %e, "Error copying object");
+                RpcError::Other(e.to_string())
+            })?;
+        Ok(())
+    }
+
+    /// Copies an object in parts with a multipart upload, which is aborted if a part fails
+    async fn copy_object_in_parts(
+        &self,
+        source_bucket: &str,
+        bucket_id: &str,
+        arg: &CopyObjectRequest,
+        size: u64,
+        headers: &ObjectHeaders,
+    ) -> RpcResult<()> {
+        let upload_id = self
+            .s3_client
+            .create_multipart_upload()
+            .bucket(bucket_id)
+            .key(self.key(bucket_id, &arg.object_id))
+            .set_content_type(headers.content_type.clone())
+            .set_content_encoding(headers.content_encoding.clone())
+            .set_cache_control(headers.cache_control.clone())
+            .set_metadata(headers.user_metadata())
+            .encryption(self.encryption(bucket_id))
+            .send()
+            .await
+            .map_err(|e| {
+                error!(error = %e, "Error starting multipart copy");
+                RpcError::Other(e.to_string())
+            })?
+            .upload_id
+            .ok_or_else(|| {
+                RpcError::Other("S3 did not return an id for the multipart upload".to_string())
+            })?;
+        let source = copy_source(
+            source_bucket,
+            &self.key(source_bucket, &arg.source_object_id),
+        );
+        let ranges = copy_ranges(size);
+        debug!(%upload_id, parts = %ranges.len(), "multipart copy started");
+
+        let parts: RpcResult<Vec<CompletedPart>> = futures::stream::iter(ranges)
+            .map(|(part_number, range)| {
+                let (source, upload_id) = (&source, &upload_id);
+                async move {
+                    let output = self
+                        .s3_client
+                        .upload_part_copy()
+                        .copy_source(source)
+                        .copy_source_range(range)
+                        .bucket(bucket_id)
+                        .key(self.key(bucket_id, &arg.object_id))
+                        .upload_id(upload_id)
+                        .part_number(part_number)
+                        .encryption(self.encryption(bucket_id))
+                        .source_encryption(self.encryption(source_bucket))
+                        .send()
+                        .await
+                        .map_err(|e| {
+                            error!(error = %e, %part_number, "Error copying part");
+                            RpcError::Other(e.to_string())
+                        })?;
+                    Ok(CompletedPart::builder()
+                        .set_e_tag(output.copy_part_result.and_then(|r| r.e_tag))
+                        .part_number(part_number)
+                        .build())
+                }
+            })
+            .buffered(COPY_CONCURRENCY)
+            .try_collect()
+            .await;
+
+        let result = match parts {
+            Ok(parts) => self
+                .s3_client
+                .complete_multipart_upload()
+                .bucket(bucket_id)
+                .key(self.key(bucket_id, &arg.object_id))
+                .upload_id(&upload_id)
+                .multipart_upload(
+                    CompletedMultipartUpload::builder()
+                        .set_parts(Some(parts))
+                        .build(),
+                )
+                .send()
+                .await
+                .map(|_| ())
+                .map_err(|e| {
+                    error!(error = %e, "Error completing multipart copy");
+                    RpcError::Other(e.to_string())
+                }),
+            Err(e) => Err(e),
+        };
+        if result.is_err() {
+            if let Err(e) = self
+                .s3_client
+                .abort_multipart_upload()
+                .bucket(bucket_id)
+                .key(self.key(bucket_id, &arg.object_id))
+                .upload_id(&upload_id)
+                .send()
+                .await
+            {
+                error!(error = %e, %upload_id, "Error aborting multipart copy");
+            }
+        }
+        result
+    }
+}
+
+#[async_trait]
+impl BlobstoreCopy for StorageClient {
+    #[instrument(level = "debug", skip(self, _ctx, arg), fields(actor_id = ?_ctx.actor, source_bucket = %self.unalias(&arg.source_container_id), source_object_id = %arg.source_object_id, bucket_id = %self.unalias(&arg.container_id), object_id = %arg.object_id))]
+    async fn copy_object(&self, _ctx: &Context, arg: &CopyObjectRequest) -> RpcResult<()> {
+        let source_bucket = self.bucket(&arg.source_container_id)?;
+        let bucket_id = self.bucket(&arg.container_id)?;
+        if let Some(headers) = arg.headers.as_ref() {
+            headers.validate()?;
+        }
+        let (size, source_headers) = self
+            .head_copy_source(source_bucket, &arg.source_object_id)
+            .await?;
+        if size <= MAX_COPY_OBJECT_SIZE {
+            self.copy_whole_object(source_bucket, bucket_id, arg).await
+        } else {
+            // a multipart upload doesn't copy the headers of the source
+            let headers = arg.headers.as_ref().unwrap_or(&source_headers);
+            self.copy_object_in_parts(source_bucket, bucket_id, arg, size, headers)
+                .await
+        }
+    }
+
+    #[instrument(level = "debug", skip(self, ctx, arg), fields(actor_id = ?ctx.actor, source_bucket = %self.unalias(&arg.source_container_id), source_object_id = %arg.source_object_id, bucket_id = %self.unalias(&arg.container_id), object_id = %arg.object_id))]
+    async fn move_object(&self, ctx: &Context, arg: &CopyObjectRequest) -> RpcResult<()> {
+        let source_bucket = self.bucket(&arg.source_container_id)?;
+        if source_bucket == self.unalias(&arg.container_id) && arg.source_object_id == arg.object_id
+        {
+            return Err(RpcError::InvalidParameter(
+                "an object can't be moved to itself".to_string(),
+            ));
+        }
+        self.copy_object(ctx, arg).await?;
+        self.s3_client
+            .delete_object()
+            .bucket(source_bucket)
+            .key(self.key(source_bucket, &arg.source_object_id))
+            .send()
+            .await
+            .map_err(|e| {
+                error!(error = %e, "Error removing source of moved object");
+                RpcError::Other(format!(
+                    "object copied, but removing Bucket({}) Object({}) failed: {}",
+                    source_bucket, &arg.source_object_id, e
+                ))
+            })?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn part_ranges() {
+        let size = MAX_COPY_OBJECT_SIZE + 1;
+        let ranges = copy_ranges(size);
+        assert_eq!(ranges.len(), 11);
+        assert_eq!(ranges[0], (1, format!("bytes=0-{}", COPY_PART_SIZE - 1)));
+        assert_eq!(
+            ranges[10],
+            (11, format!("bytes={}-{}", MAX_COPY_OBJECT_SIZE, size - 1))
+        );
+
+        // objects over 5TB would need more than the maximum number of parts of the default size
+        let huge = MAX_PARTS * COPY_PART_SIZE * 2;
+        assert_eq!(part_size(huge), COPY_PART_SIZE * 2);
+        assert_eq!(copy_ranges(huge).len() as u64, MAX_PARTS);
+    }
+
+    #[test]
+    fn source_key_is_encoded() {
+        assert_eq!(
+            copy_source("bucket", "images/a b+c.png"),
+            "bucket/images/a%20b%2Bc.png"
+        );
+    }
+}
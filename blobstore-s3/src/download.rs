@@ -0,0 +1,130 @@
+//! Streaming downloads
+//!
+//! When an object doesn't fit in the response to `get_object`, the rest of it is sent to the
+//! actor in chunks by a task. The task stops when the actor returns `cancel_download` from
+//! `receive_chunk`. Each link streams at most `max_concurrent_downloads` objects at a time;
+//! `get_object` returns an error when an object must be streamed and no more streams are
+//! available. Sending a chunk is retried when it fails with a timeout or a nats error.
+//!
+//! If the stream aborts before the last chunk, because S3 or the actor failed, no further chunk
+//! is sent: a chunk with `is_last` set always ends a complete download. Instead, a `DownloadError`
+//! with the offset reached is sent to the actor's `download_error_operation`, by default
+//! `ChunkReceiver.DownloadError`, next to the `ChunkReceiver.ReceiveChunk` operation receiving the chunks.
+
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tracing::{debug, error, warn};
+use wasmbus_rpc::common::{serialize, Message, Transport};
+use wasmbus_rpc::provider::prelude::*;
+
+use crate::{wasmcloud_interface_blobstore::ContainerObject, StorageClient};
+
+/// operation of the actor receiving a `DownloadError`, if not configured
+pub(crate) const DEFAULT_DOWNLOAD_ERROR_OPERATION: &str = "ChunkReceiver.DownloadError";
+
+/// number of objects a link streams at a time, if not configured
+pub(crate) const DEFAULT_MAX_CONCURRENT_DOWNLOADS: usize = 8;
+
+/// attempts to send a chunk to the actor before the download is aborted
+pub(crate) const SEND_CHUNK_ATTEMPTS: u32 = 3;
+
+/// delay before the first retry of a chunk, doubled for each further retry
+pub(crate) const SEND_CHUNK_RETRY_DELAY: Duration = Duration::from_millis(200);
+
+/// Argument of the actor's `download_error_operation`, sent when a download aborts
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DownloadError {
+    pub container_id: String,
+    pub object_id: String,
+    /// offset of the first byte that was not sent
+    pub offset: u64,
+    pub error: String,
+}
+
+/// Why streaming an object to an actor stopped before its last chunk
+#[derive(Debug)]
+pub(crate) enum StreamStop {
+    /// the actor returned `cancel_download`
+    Cancelled,
+    Failed(RpcError),
+}
+
+impl From<RpcError> for StreamStop {
+    fn from(e: RpcError) -> Self {
+        StreamStop::Failed(e)
+    }
+}
+
+/// Returns true for errors that may not happen again if the message is resent
+pub(crate) fn is_transient(e: &RpcError) -> bool {
+    matches!(e, RpcError::Timeout(_) | RpcError::Nats(_))
+}
+
+/// Returns the delay before resending a chunk after `attempt` failed attempts
+pub(crate) fn retry_delay(attempt: u32) -> Duration {
+    SEND_CHUNK_RETRY_DELAY * 2u32.pow(attempt.saturating_sub(1))
+}
+
+impl StorageClient {
+    /// Tells the actor that a download aborted, by sending the error and the offset reached
+    /// to its download error operation. No chunk is sent, so the chunks received so far
+    /// can't be taken for the complete object.
+    pub(crate) async fn notify_download_error(
+        &self,
+        ctx: &Context,
+        cobj: &ContainerObject,
+        offset: u64,
+        e: &RpcError,
+    ) {
+        error!(error = %e, %offset, "download aborted");
+        let operation = self.download_error_operation.as_str();
+        let notification = DownloadError {
+            container_id: cobj.container_id.clone(),
+            object_id: cobj.object_id.clone(),
+            offset,
+            error: e.to_string(),
+        };
+        let result = match serialize(&notification) {
+            Ok(arg) => {
+                ProviderTransport::new(self.ld.as_ref(), None)
+                    .send(
+                        ctx,
+                        Message {
+                            method: operation,
+                            arg: arg.into(),
+                        },
+                        None,
+                    )
+                    .await
+            }
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(_) => {}
+            // the actor doesn't handle download errors
+            Err(RpcError::MethodNotHandled(_)) => {
+                debug!(%operation, "Actor doesn't handle download errors")
+            }
+            Err(e) => warn!(error = %e, %operation, "Unable to send download error to actor"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn transient_errors() {
+        assert!(is_transient(&RpcError::Timeout("receive_chunk".into())));
+        assert!(is_transient(&RpcError::Nats("no responders".into())));
+        assert!(!is_transient(&RpcError::MethodNotHandled(
+            "ReceiveChunk".into()
+        )));
+        assert!(!is_transient(&RpcError::Deser("'Chunk'".into())));
+
+        assert_eq!(retry_delay(1), SEND_CHUNK_RETRY_DELAY);
+        assert_eq!(retry_delay(2), SEND_CHUNK_RETRY_DELAY * 2);
+    }
+}
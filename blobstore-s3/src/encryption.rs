@@ -0,0 +1,273 @@
+//! Server-side encryption
+//!
+//! The link's `encryption` setting, or the `bucket_encryption` setting of a bucket or alias,
+//! selects how S3 encrypts the objects the provider writes:
+//! - `sse-s3`: with keys managed by S3
+//! - `sse-kms`: with a KMS key, `key_id`, or the account's `aws/s3` key if no key id is set
+//! - `sse-c`: with a customer-provided 256-bit `key`, base64 encoded, that S3 does not store.
+//!   The key is sent with every request that reads or writes an object.
+//!
+//! When the provider creates a bucket with SSE-S3 or SSE-KMS settings, they also become
+//! the bucket's default encryption.
+
+use aws_sdk_s3::{
+    client::fluent_builders,
+    model::{
+        ServerSideEncryption, ServerSideEncryptionByDefault, ServerSideEncryptionConfiguration,
+        ServerSideEncryptionRule,
+    },
+};
+use md5::{Digest, Md5};
+use serde::Deserialize;
+use tracing::error;
+use wasmbus_rpc::error::{RpcError, RpcResult};
+
+use crate::StorageClient;
+
+/// the only algorithm S3 accepts for customer-provided keys
+const SSE_C_ALGORITHM: &str = "AES256";
+
+/// Encryption of the objects written to a bucket
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum Encryption {
+    /// SSE-S3, with keys managed by S3
+    SseS3,
+    /// SSE-KMS, with the key `key_id` or the account's default `aws/s3` key
+    SseKms {
+        key_id: Option<String>,
+        /// use an S3 bucket key to reduce the number of requests to KMS
+        #[serde(default)]
+        bucket_key: bool,
+    },
+    /// SSE-C, with a customer-provided 256-bit key, base64 encoded
+    SseC { key: String },
+}
+
+/// A customer-provided key as sent to S3
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct CustomerKey {
+    pub(crate) key: String,
+    pub(crate) key_md5: String,
+}
+
+impl Encryption {
+    /// Checks that a customer-provided key is a base64 encoded 256-bit key
+    pub fn validate(&self) -> RpcResult<()> {
+        if let Encryption::SseC { key } = self {
+            match base64::decode(key) {
+                Ok(bytes) if bytes.len() == 32 => {}
+                _ => {
+                    return Err(RpcError::InvalidParameter(
+                        "sse-c key must be 32 bytes, base64 encoded".to_string(),
+                    ))
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn server_side_encryption(&self) -> Option<ServerSideEncryption> {
+        match self {
+            Encryption::SseS3 => Some(ServerSideEncryption::Aes256),
+            Encryption::SseKms { .. } => Some(ServerSideEncryption::AwsKms),
+            Encryption::SseC { .. } => None,
+        }
+    }
+
+    fn kms_key_id(&self) -> Option<String> {
+        match self {
+            Encryption::SseKms { key_id, .. } => key_id.clone(),
+            _ => None,
+        }
+    }
+
+    fn bucket_key_enabled(&self) -> Option<bool> {
+        match self {
+            Encryption::SseKms { bucket_key, .. } => Some(*bucket_key),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn customer_key(&self) -> Option<CustomerKey> {
+        match self {
+            Encryption::SseC { key } => {
+                let bytes = base64::decode(key).unwrap_or_default();
+                Some(CustomerKey {
+                    key: key.clone(),
+                    key_md5: base64::encode(Md5::digest(&bytes)),
+                })
+            }
+            _ => None,
+        }
+    }
+
+    /// Returns the default encryption of a bucket with these settings.
+    /// Customer-provided keys can't be a bucket default.
+    fn bucket_default(&self) -> Option<ServerSideEncryptionConfiguration> {
+        let algorithm = self.server_side_encryption()?;
+        Some(
+            ServerSideEncryptionConfiguration::builder()
+                .rules(
+                    ServerSideEncryptionRule::builder()
+                        .apply_server_side_encryption_by_default(
+                            ServerSideEncryptionByDefault::builder()
+                                .sse_algorithm(algorithm)
+                                .set_kms_master_key_id(self.kms_key_id())
+                                .build(),
+                        )
+                        .set_bucket_key_enabled(self.bucket_key_enabled())
+                        .build(),
+                )
+                .build(),
+        )
+    }
+}
+
+/// Adds the encryption headers to an S3 request
+pub(crate) trait EncryptRequest {
+    fn encryption(self, encryption: Option<&Encryption>) -> Self;
+}
+
+/// requests that write an object, which take all encryption settings
+macro_rules! encrypt_writes {
+    ($($builder:ident),*) => {$(
+        impl EncryptRequest for fluent_builders::$builder {
+            fn encryption(self, encryption: Option<&Encryption>) -> Self {
+                let key = encryption.and_then(Encryption::customer_key);
+                self.set_server_side_encryption(
+                    encryption.and_then(Encryption::server_side_encryption),
+                )
+                .set_ssekms_key_id(encryption.and_then(Encryption::kms_key_id))
+                .set_bucket_key_enabled(encryption.and_then(Encryption::bucket_key_enabled))
+                .set_sse_customer_algorithm(key.as_ref().map(|_| SSE_C_ALGORITHM.to_string()))
+                .set_sse_customer_key(key.as_ref().map(|k| k.key.clone()))
+                .set_sse_customer_key_md5(key.map(|k| k.key_md5))
+            }
+        }
+    )*};
+}
+
+/// requests that read an object or write a part, which only need a customer-provided key
+macro_rules! encrypt_reads {
+    ($($builder:ident),*) => {$(
+        impl EncryptRequest for fluent_builders::$builder {
+            fn encryption(self, encryption: Option<&Encryption>) -> Self {
+                let key = encryption.and_then(Encryption::customer_key);
+                self.set_sse_customer_algorithm(key.as_ref().map(|_| SSE_C_ALGORITHM.to_string()))
+                    .set_sse_customer_key(key.as_ref().map(|k| k.key.clone()))
+                    .set_sse_customer_key_md5(key.map(|k| k.key_md5))
+            }
+        }
+    )*};
+}
+
+encrypt_writes!(PutObject, CreateMultipartUpload, CopyObject);
+encrypt_reads!(GetObject, HeadObject, UploadPart, UploadPartCopy);
+
+/// Adds the customer-provided key of the source object to a copy request
+pub(crate) trait DecryptCopySource {
+    fn source_encryption(self, encryption: Option<&Encryption>) -> Self;
+}
+
+macro_rules! decrypt_copy_source {
+    ($($builder:ident),*) => {$(
+        impl DecryptCopySource for fluent_builders::$builder {
+            fn source_encryption(self, encryption: Option<&Encryption>) -> Self {
+                let key = encryption.and_then(Encryption::customer_key);
+                self.set_copy_source_sse_customer_algorithm(
+                    key.as_ref().map(|_| SSE_C_ALGORITHM.to_string()),
+                )
+                .set_copy_source_sse_customer_key(key.as_ref().map(|k| k.key.clone()))
+                .set_copy_source_sse_customer_key_md5(key.map(|k| k.key_md5))
+            }
+        }
+    )*};
+}
+
+decrypt_copy_source!(CopyObject, UploadPartCopy);
+
+impl StorageClient {
+    /// Returns the encryption settings of a bucket, by bucket name (not alias)
+    pub(crate) fn encryption(&self, bucket_id: &str) -> Option<&Encryption> {
+        self.bucket_encryption
+            .get(bucket_id)
+            .or(self.default_encryption.as_ref())
+    }
+
+    /// Sets the default encryption of a bucket created by the provider
+    pub(crate) async fn put_bucket_encryption(&self, bucket_id: &str) -> RpcResult<()> {
+        let configuration = match self
+            .encryption(bucket_id)
+            .and_then(Encryption::bucket_default)
+        {
+            Some(configuration) => configuration,
+            None => return Ok(()),
+        };
+        self.s3_client
+            .put_bucket_encryption()
+            .bucket(bucket_id)
+            .server_side_encryption_configuration(configuration)
+            .send()
+            .await
+            .map_err(|e| {
+                error!(error = %e, "Error setting default encryption of bucket");
+                RpcError::Other(format!(
+                    "setting default encryption of Bucket({}): {}",
+                    bucket_id, e
+                ))
+            })?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn config_formats() {
+        let kms: Encryption =
+            serde_json::from_str(r#"{"type": "sse-kms", "key_id": "alias/blobs"}"#).unwrap();
+        assert_eq!(
+            kms,
+            Encryption::SseKms {
+                key_id: Some("alias/blobs".to_string()),
+                bucket_key: false
+            }
+        );
+        assert_eq!(
+            kms.server_side_encryption(),
+            Some(ServerSideEncryption::AwsKms)
+        );
+        assert!(kms.customer_key().is_none());
+
+        let s3: Encryption = serde_json::from_str(r#"{"type": "sse-s3"}"#).unwrap();
+        assert_eq!(
+            s3.server_side_encryption(),
+            Some(ServerSideEncryption::Aes256)
+        );
+        assert!(s3.bucket_default().is_some());
+    }
+
+    #[test]
+    fn customer_key() {
+        let key = base64::encode([7u8; 32]);
+        let sse_c = Encryption::SseC { key: key.clone() };
+        assert!(sse_c.validate().is_ok());
+        assert!(sse_c.server_side_encryption().is_none());
+        assert!(sse_c.bucket_default().is_none());
+        let customer_key = sse_c.customer_key().unwrap();
+        assert_eq!(customer_key.key, key);
+        assert_eq!(customer_key.key_md5, "Y4HAEFCYWuvAXWFTtA1Qpg==");
+
+        let short = Encryption::SseC {
+            key: base64::encode([7u8; 16]),
+        };
+        assert!(short.validate().is_err());
+        let not_base64 = Encryption::SseC {
+            key: "not a key".to_string(),
+        };
+        assert!(not_base64.validate().is_err());
+    }
+}
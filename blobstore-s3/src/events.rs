@@ -0,0 +1,369 @@
+//! Object events
+//!
+//! With the `events` setting, the provider polls the listed buckets with ListObjectsV2 and calls
+//! the configured operation on the actor with the `ObjectMetadata` of each object that was added
+//! or changed since the previous poll, oldest first. The ETag of every object seen is kept in a
+//! checkpoint file per bucket, in `checkpoint_dir`, so that objects changed while the provider
+//! was not running are reported when it starts. The checkpoint is written after each poll, and
+//! an object is recorded in it only after the actor received its event, so events are delivered
+//! at least once: an event that failed, or whose poll was interrupted, is sent again.
+//! A checkpoint that can't be read is rebuilt, as if the bucket had never been polled.
+
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::{io::AsyncWriteExt, task::JoinHandle};
+use tracing::{debug, error, info, warn};
+use wasmbus_rpc::common::{serialize, Message, Transport};
+use wasmbus_rpc::provider::prelude::*;
+
+use crate::{to_timestamp, wasmcloud_interface_blobstore::ObjectMetadata};
+
+/// time between polls of a bucket, if not configured
+const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Subscription to the objects added or changed in buckets
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct EventsConfig {
+    /// names or aliases of the buckets to watch
+    pub buckets: Vec<String>,
+    /// actor operation that receives the `ObjectMetadata` of each added or changed object,
+    /// e.g., `ObjectWatcher.ObjectChanged`
+    pub operation: String,
+    /// directory where the checkpoint of each bucket is stored
+    pub checkpoint_dir: PathBuf,
+    /// seconds between polls (default 30)
+    pub poll_interval_secs: Option<u64>,
+    /// whether the objects in a bucket when it is first polled are reported
+    #[serde(default)]
+    pub notify_existing: bool,
+}
+
+impl EventsConfig {
+    pub fn validate(&self) -> RpcResult<()> {
+        if self.buckets.is_empty() || self.operation.is_empty() {
+            return Err(RpcError::InvalidParameter(
+                "events require buckets and an operation".to_string(),
+            ));
+        }
+        if self.checkpoint_dir.as_os_str().is_empty() {
+            return Err(RpcError::InvalidParameter(
+                "events require a checkpoint_dir".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// ETags of the objects seen in a bucket, by object id
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+struct Checkpoint {
+    objects: BTreeMap<String, String>,
+}
+
+/// An object returned by ListObjectsV2
+#[derive(Clone, Debug)]
+struct ListedObject {
+    metadata: ObjectMetadata,
+    e_tag: String,
+}
+
+impl Checkpoint {
+    /// Reads the checkpoint of a bucket. Returns None if the bucket was never polled, or if the
+    /// checkpoint can't be read or parsed: the poll is then a first poll, which rebuilds it.
+    async fn load(path: &Path) -> Option<Checkpoint> {
+        match tokio::fs::read(path).await {
+            Ok(data) => match serde_json::from_slice(&data) {
+                Ok(checkpoint) => Some(checkpoint),
+                Err(e) => {
+                    error!(error = %e, path = %path.display(), "Corrupt checkpoint, rebuilding it");
+                    None
+                }
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => {
+                error!(error = %e, path = %path.display(), "Unable to read checkpoint, rebuilding it");
+                None
+            }
+        }
+    }
+
+    /// Replaces the checkpoint file, with a rename so that it is never partly written.
+    /// The file and its directory are flushed to disk, so the checkpoint survives a crash.
+    async fn save(&self, path: &Path) -> RpcResult<()> {
+        let data = serde_json::to_vec(self).map_err(|e| RpcError::Ser(e.to_string()))?;
+        let tmp = path.with_extension("json.tmp");
+        let result = async {
+            if let Some(dir) = path.parent() {
+                tokio::fs::create_dir_all(dir).await?;
+            }
+            let mut file = tokio::fs::File::create(&tmp).await?;
+            file.write_all(&data).await?;
+            file.sync_all().await?;
+            tokio::fs::rename(&tmp, path).await?;
+            #[cfg(unix)]
+            if let Some(dir) = path.parent() {
+                tokio::fs::File::open(dir).await?.sync_all().await?;
+            }
+            Ok::<(), std::io::Error>(())
+        }
+        .await;
+        result.map_err(|e| RpcError::Other(format!("writing checkpoint {}: {}", path.display(), e)))
+    }
+
+    /// Splits the listed objects into those already seen, as the checkpoint of the objects that
+    /// still exist, and those added or changed, oldest first
+    fn changes(&self, listed: Vec<ListedObject>) -> (Checkpoint, Vec<ListedObject>) {
+        let mut seen = Checkpoint::default();
+        let mut changed = Vec::new();
+        for object in listed {
+            if self.objects.get(&object.metadata.object_id) == Some(&object.e_tag) {
+                seen.objects.insert(object.metadata.object_id, object.e_tag);
+            } else {
+                changed.push(object);
+            }
+        }
+        changed.sort_by_key(|o| {
+            o.metadata
+                .last_modified
+                .as_ref()
+                .map(|t| (t.sec, t.nsec))
+                .unwrap_or_default()
+        });
+        (seen, changed)
+    }
+}
+
+/// Task that polls the buckets of a link, aborted when dropped
+pub(crate) struct EventPoller {
+    task: JoinHandle<()>,
+}
+
+impl Drop for EventPoller {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// State of the polling task
+struct Watch {
+    s3_client: aws_sdk_s3::Client,
+    ld: Arc<LinkDefinition>,
+    /// bucket names, after alias lookup, with their key prefixes
+    buckets: Vec<(String, String)>,
+    config: EventsConfig,
+}
+
+impl EventPoller {
+    pub(crate) fn start(
+        s3_client: aws_sdk_s3::Client,
+        ld: Arc<LinkDefinition>,
+        buckets: Vec<(String, String)>,
+        config: EventsConfig,
+    ) -> Self {
+        let interval = config
+            .poll_interval_secs
+            .map(Duration::from_secs)
+            .unwrap_or(DEFAULT_POLL_INTERVAL);
+        let watch = Watch {
+            s3_client,
+            ld,
+            buckets,
+            config,
+        };
+        let task = tokio::spawn(async move {
+            let mut interval = tokio::time::interval(interval);
+            loop {
+                interval.tick().await;
+                for (bucket, prefix) in watch.buckets.iter() {
+                    if let Err(e) = watch.poll(bucket, prefix).await {
+                        error!(error = %e, %bucket, "Unable to poll bucket for object events");
+                    }
+                }
+            }
+        });
+        EventPoller { task }
+    }
+
+    pub(crate) fn stop(&self) {
+        self.task.abort();
+    }
+}
+
+impl Watch {
+    fn checkpoint_path(&self, bucket: &str) -> PathBuf {
+        self.config
+            .checkpoint_dir
+            .join(&self.ld.actor_id)
+            .join(format!("{}.json", bucket))
+    }
+
+    /// Sends the events for the objects added or changed in a bucket since the checkpoint
+    async fn poll(&self, bucket: &str, prefix: &str) -> RpcResult<()> {
+        let path = self.checkpoint_path(bucket);
+        let previous = Checkpoint::load(&path).await;
+        let listed = self.list(bucket, prefix).await?;
+        let previous = match previous {
+            Some(previous) => previous,
+            None if !self.config.notify_existing => {
+                info!(%bucket, objects = %listed.len(), "first poll of bucket, existing objects are not reported");
+                let checkpoint = Checkpoint {
+                    objects: listed
+                        .into_iter()
+                        .map(|o| (o.metadata.object_id, o.e_tag))
+                        .collect(),
+                };
+                return checkpoint.save(&path).await;
+            }
+            None => Checkpoint::default(),
+        };
+        let (mut checkpoint, changed) = previous.changes(listed);
+        debug!(%bucket, changed = %changed.len(), "polled bucket");
+        let mut failed = false;
+        for object in changed {
+            let object_id = object.metadata.object_id.clone();
+            if !failed {
+                match self.send_event(&object.metadata).await {
+                    Ok(()) => {
+                        checkpoint.objects.insert(object_id, object.e_tag);
+                        continue;
+                    }
+                    Err(e) => {
+                        // the actor may be unavailable: the rest is sent after the next poll
+                        warn!(error = %e, %bucket, %object_id, "Unable to send object event");
+                        failed = true;
+                    }
+                }
+            }
+            // keep the ETag of the previous poll, if any, so that the event is sent again
+            if let Some(e_tag) = previous.objects.get(&object_id) {
+                checkpoint.objects.insert(object_id, e_tag.clone());
+            }
+        }
+        checkpoint.save(&path).await
+    }
+
+    /// Lists all objects of a bucket below the key prefix, with the prefix stripped from their ids
+    async fn list(&self, bucket: &str, prefix: &str) -> RpcResult<Vec<ListedObject>> {
+        let mut objects = Vec::new();
+        let mut continuation = None;
+        loop {
+            let list = self
+                .s3_client
+                .list_objects_v2()
+                .bucket(bucket)
+                .set_prefix((!prefix.is_empty()).then(|| prefix.to_string()))
+                .set_continuation_token(continuation)
+                .send()
+                .await
+                .map_err(|e| RpcError::Other(format!("listing Bucket({}): {}", bucket, e)))?;
+            objects.extend(list.contents.unwrap_or_default().into_iter().map(|o| {
+                ListedObject {
+                    metadata: ObjectMetadata {
+                        container_id: bucket.to_string(),
+                        object_id: o
+                            .key
+                            .as_deref()
+                            .and_then(|key| key.strip_prefix(prefix))
+                            .unwrap_or_default()
+                            .to_string(),
+                        last_modified: to_timestamp(o.last_modified),
+                        content_length: o.size as u64,
+                        content_encoding: None,
+                        content_type: None,
+                    },
+                    e_tag: o.e_tag.unwrap_or_default(),
+                }
+            }));
+            if !list.is_truncated {
+                break;
+            }
+            continuation = list.next_continuation_token;
+        }
+        Ok(objects)
+    }
+
+    async fn send_event(&self, object: &ObjectMetadata) -> RpcResult<()> {
+        let transport = ProviderTransport::new(self.ld.as_ref(), None);
+        let ctx = Context::default();
+        transport
+            .send(
+                &ctx,
+                Message {
+                    method: &self.config.operation,
+                    arg: serialize(object)?.into(),
+                },
+                None,
+            )
+            .await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use wasmbus_rpc::Timestamp;
+
+    fn listed(object_id: &str, e_tag: &str, sec: i64) -> ListedObject {
+        ListedObject {
+            metadata: ObjectMetadata {
+                container_id: "bucket".to_string(),
+                object_id: object_id.to_string(),
+                last_modified: Some(Timestamp { sec, nsec: 0 }),
+                ..Default::default()
+            },
+            e_tag: e_tag.to_string(),
+        }
+    }
+
+    #[test]
+    fn changed_objects() {
+        let previous = Checkpoint {
+            objects: [
+                ("a".to_string(), "1".to_string()),
+                ("b".to_string(), "1".to_string()),
+                ("removed".to_string(), "1".to_string()),
+            ]
+            .into(),
+        };
+        let (seen, changed) = previous.changes(vec![
+            listed("a", "1", 10),
+            listed("b", "2", 30),
+            listed("c", "1", 20),
+        ]);
+
+        assert_eq!(seen.objects.len(), 1);
+        assert_eq!(seen.objects.get("a").map(String::as_str), Some("1"));
+        let changed: Vec<&str> = changed
+            .iter()
+            .map(|o| o.metadata.object_id.as_str())
+            .collect();
+        assert_eq!(changed, vec!["c", "b"], "oldest first");
+    }
+
+    #[tokio::test]
+    async fn checkpoint_file() {
+        let dir = std::env::temp_dir().join(format!("s3-events-{}", rand::random::<u64>()));
+        let path = dir.join("actor").join("bucket.json");
+        assert_eq!(Checkpoint::load(&path).await, None);
+
+        let checkpoint = Checkpoint {
+            objects: [("a".to_string(), "\"etag\"".to_string())].into(),
+        };
+        checkpoint.save(&path).await.unwrap();
+        assert_eq!(Checkpoint::load(&path).await, Some(checkpoint.clone()));
+
+        // a corrupt checkpoint is rebuilt, as on the first poll
+        std::fs::write(&path, "{").unwrap();
+        assert_eq!(Checkpoint::load(&path).await, None);
+        checkpoint.save(&path).await.unwrap();
+        assert_eq!(Checkpoint::load(&path).await, Some(checkpoint));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
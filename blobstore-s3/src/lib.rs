@@ -5,6 +5,7 @@
 
 use std::collections::HashMap;
 use std::sync::Arc;
+use std::time::Duration;
 
 use aws_sdk_s3::{
     error::{HeadBucketError, HeadBucketErrorKind, HeadObjectError, HeadObjectErrorKind},
@@ -13,11 +14,13 @@ use aws_sdk_s3::{
     types::{ByteStream, SdkError},
 };
 use bytes::Bytes;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
 use tokio_stream::StreamExt;
-use tracing::{debug, error, instrument, warn};
+use tracing::{debug, error, info, instrument, warn};
 use tracing_futures::Instrument;
 use wasmbus_rpc::{core::LinkDefinition, provider::prelude::*};
 
+use crate::encryption::EncryptRequest;
 use crate::wasmcloud_interface_blobstore::{
     self as blobstore, Blobstore, Chunk, ChunkReceiver, ChunkReceiverSender, ContainerId,
     ContainerIds, ContainerMetadata, ContainerObject, ContainersInfo, GetObjectResponse,
@@ -25,7 +28,32 @@ use crate::wasmcloud_interface_blobstore::{
 };
 
 mod config;
+mod copy;
+mod download;
+mod encryption;
+mod events;
+mod metadata;
+mod multipart;
+mod presign;
+mod ranged;
+mod retention;
+mod scope;
 pub use config::StorageConfig;
+pub use copy::{BlobstoreCopy, BlobstoreCopyReceiver, CopyObjectRequest};
+pub use download::DownloadError;
+pub use encryption::Encryption;
+pub use events::EventsConfig;
+pub use metadata::{
+    BlobstoreMetadata, BlobstoreMetadataReceiver, ObjectHeaders, ObjectMetadataWithHeaders,
+    PutObjectWithMetadataRequest,
+};
+pub use presign::{BlobstorePresign, BlobstorePresignReceiver, PresignRequest, PresignedUrl};
+pub use ranged::ObjectStream;
+pub use retention::{
+    BlobstoreRetention, BlobstoreRetentionReceiver, ObjectLock, ObjectTags, RetentionMode,
+    SetLegalHoldRequest, SetObjectRetentionRequest, SetObjectTagsRequest,
+};
+pub use scope::BucketScope;
 
 // this is not an external library - built locally via build.rs & codegen.toml
 #[allow(dead_code)]
@@ -46,11 +74,53 @@ pub struct StorageClient {
     s3_client: aws_sdk_s3::Client,
     ld: Arc<LinkDefinition>,
     aliases: Arc<HashMap<String, String>>,
+    uploads: multipart::Uploads,
+    upload_timeout: Duration,
+    list_object_metadata: bool,
+    /// whether buckets are created with Object Lock enabled
+    object_lock: bool,
+    /// permits for the objects streamed to the actor at a time
+    downloads: Arc<Semaphore>,
+    /// operation of the actor receiving a `DownloadError` when a download aborts
+    download_error_operation: String,
+    /// ranged GetObject requests in flight for each download, if more than 1
+    download_concurrency: usize,
+    download_part_size: u64,
+    default_encryption: Option<Encryption>,
+    /// encryption settings by bucket name
+    bucket_encryption: Arc<HashMap<String, Encryption>>,
+    /// polls buckets for object events, until the client and its clones are dropped
+    event_poller: Option<Arc<events::EventPoller>>,
+    /// buckets the link may use, with their key prefixes
+    scopes: Arc<scope::Scopes>,
 }
 
 impl StorageClient {
     pub async fn new(config: StorageConfig, ld: LinkDefinition) -> Self {
         let mut aliases = config.aliases.clone();
+        let list_object_metadata = config.list_object_metadata;
+        let object_lock = config.object_lock;
+        let upload_timeout = config
+            .upload_timeout_secs
+            .map(Duration::from_secs)
+            .unwrap_or(multipart::DEFAULT_UPLOAD_TIMEOUT);
+        let max_downloads = config
+            .max_concurrent_downloads
+            .unwrap_or(download::DEFAULT_MAX_CONCURRENT_DOWNLOADS)
+            .max(1);
+        let download_error_operation = config
+            .download_error_operation
+            .clone()
+            .unwrap_or_else(|| download::DEFAULT_DOWNLOAD_ERROR_OPERATION.to_string());
+        let download_concurrency = config.download_concurrency.unwrap_or(1).max(1);
+        let download_part_size = config
+            .download_part_size
+            .unwrap_or(ranged::DEFAULT_DOWNLOAD_PART_SIZE)
+            .max(1);
+        let default_encryption = config.encryption.clone();
+        let bucket_encryption = config.bucket_encryption.clone();
+        let events = config.events.clone();
+        let allowed_buckets = config.allowed_buckets.clone();
         let s3_config = aws_sdk_s3::Config::from(&config.configure_aws().await);
         let s3_client = aws_sdk_s3::Client::from_conf(s3_config);
         for (k, v) in ld.values.iter() {
@@ -62,11 +132,53 @@ impl StorageClient {
                 }
             }
         }
-        StorageClient {
+        let mut client = StorageClient {
             s3_client,
             ld: Arc::new(ld),
             aliases: Arc::new(aliases),
+            uploads: Default::default(),
+            upload_timeout,
+            list_object_metadata,
+            object_lock,
+            downloads: Arc::new(Semaphore::new(max_downloads)),
+            download_error_operation,
+            download_concurrency,
+            download_part_size,
+            default_encryption,
+            bucket_encryption: Default::default(),
+            event_poller: None,
+            scopes: Default::default(),
+        };
+        // settings of aliases apply to the buckets they name
+        let bucket_encryption = bucket_encryption
+            .into_iter()
+            .map(|(name, encryption)| (client.unalias(&name).to_string(), encryption))
+            .collect();
+        client.bucket_encryption = Arc::new(bucket_encryption);
+        client.scopes = Arc::new(scope::resolve(&client, allowed_buckets));
+        if let Some(events) = events {
+            let buckets = events
+                .buckets
+                .iter()
+                .map(|name| client.unalias(name))
+                .filter(|bucket_id| {
+                    let allowed = client.is_allowed(bucket_id);
+                    if !allowed {
+                        error!(%bucket_id, "events for a bucket that is not allowed are ignored");
+                    }
+                    allowed
+                })
+                .map(|bucket_id| (bucket_id.to_string(), client.prefix(bucket_id).to_string()))
+                .collect();
+            client.event_poller = Some(Arc::new(events::EventPoller::start(
+                client.s3_client.clone(),
+                client.ld.clone(),
+                buckets,
+                events,
+            )));
         }
+        client.start_upload_reaper();
+        client
     }
 
     /// perform alias lookup on bucket name
@@ -99,13 +211,16 @@ impl StorageClient {
     /// Perform any cleanup necessary for a link + s3 connection
     pub async fn close(&self) {
         debug!(actor_id = %self.ld.actor_id, "blobstore-s3 dropping linkdef");
-        // If there were any https clients, caches, or other link-specific data,
-        // we would delete those here
+        // uploads that can't be finished any more would keep their parts in S3
+        self.abort_all_uploads().await;
+        if let Some(poller) = self.event_poller.as_ref() {
+            poller.stop();
+        }
     }
 
     /// Retrieves metadata about the object
     #[instrument(level = "debug", skip(self, _ctx), fields(actor_id = ?_ctx.actor))]
-    async fn get_object_metadata(
+    async fn head_object_metadata(
         &self,
         _ctx: &Context,
         bucket_id: &str,
@@ -116,7 +231,8 @@ impl StorageClient {
             .s3_client
             .head_object()
             .bucket(bucket_id)
-            .key(object_id)
+            .key(self.key(bucket_id, object_id))
+            .encryption(self.encryption(bucket_id))
             .send()
             .await
         {
@@ -152,57 +268,138 @@ impl StorageClient {
         }
     }
 
-    /// Sends bytes to actor in a single rpc message.
-    /// If successful, returns number of bytes sent (same as chunk.content_length)
+    /// Puts an object, or starts a multipart upload if the chunk is not the last,
+    /// storing the headers with the object
+    pub(crate) async fn put_object_with_headers(
+        &self,
+        chunk: &Chunk,
+        headers: &ObjectHeaders,
+    ) -> RpcResult<PutObjectResponse> {
+        let bucket_id = self.bucket(&chunk.container_id)?;
+        headers.validate()?;
+        if !chunk.is_last {
+            // the rest of the object is sent with put_chunk
+            return self.start_multipart_upload(bucket_id, chunk, headers).await;
+        }
+        if chunk.offset != 0 {
+            error!("put_object with initial offset non-zero: not implemented!");
+            return Err(RpcError::InvalidParameter(
+                "non-zero offset not supported".to_string(),
+            ));
+        }
+        if chunk.bytes.is_empty() {
+            error!("put_object with zero bytes");
+            return Err(RpcError::InvalidParameter(
+                "cannot put zero-length objects".to_string(),
+            ));
+        }
+        // TODO: make sure put_object takes an owned `PutObjectRequest` to avoid cloning the whole chunk
+        let bytes = chunk.bytes.to_owned();
+        match self
+            .s3_client
+            .put_object()
+            .bucket(bucket_id)
+            .key(self.key(bucket_id, &chunk.object_id))
+            .encryption(self.encryption(bucket_id))
+            .set_content_type(headers.content_type.clone())
+            .set_content_encoding(headers.content_encoding.clone())
+            .set_cache_control(headers.cache_control.clone())
+            .set_metadata(headers.user_metadata())
+            .body(ByteStream::from(bytes))
+            .send()
+            .await
+        {
+            Ok(_) => Ok(PutObjectResponse::default()),
+            Err(e) => {
+                error!(
+                    error = %e,
+                    "Error putting object",
+                );
+                Err(RpcError::Other(e.to_string()))
+            }
+        }
+    }
+
+    /// Sends bytes to actor in a single rpc message, retrying transient failures.
+    /// Returns false if the actor cancelled the download.
     #[instrument(level = "debug", skip(self, ctx, chunk), fields(actor_id = ?ctx.actor, object_id = %chunk.object_id, container_id = %self.unalias(&chunk.container_id)))]
-    async fn send_chunk(&self, ctx: &Context, mut chunk: Chunk) -> Result<u64, RpcError> {
+    async fn send_chunk(&self, ctx: &Context, mut chunk: Chunk) -> Result<bool, RpcError> {
         chunk.container_id = self.unalias(&chunk.container_id).to_string();
         let receiver = ChunkReceiverSender::for_actor(self.ld.as_ref());
-        if let Err(e) = receiver.receive_chunk(ctx, &chunk).await {
-            let err = format!(
-                "sending chunk error: Bucket({}) Object({}) to Actor({}): {}",
-                &chunk.container_id, &chunk.object_id, &self.ld.actor_id, e
-            );
-            error!(error = %e, "sending chunk error");
-            Err(RpcError::Rpc(err))
-        } else {
-            Ok(chunk.bytes.len() as u64)
+        let mut attempt = 1;
+        loop {
+            match receiver.receive_chunk(ctx, &chunk).await {
+                Ok(resp) => return Ok(!resp.cancel_download),
+                Err(e) if attempt < download::SEND_CHUNK_ATTEMPTS && download::is_transient(&e) => {
+                    warn!(error = %e, %attempt, "sending chunk failed, retrying");
+                    tokio::time::sleep(download::retry_delay(attempt)).await;
+                    attempt += 1;
+                }
+                Err(e) => {
+                    let err = format!(
+                        "sending chunk error: Bucket({}) Object({}) to Actor({}): {}",
+                        &chunk.container_id, &chunk.object_id, &self.ld.actor_id, e
+                    );
+                    error!(error = %e, "sending chunk error");
+                    return Err(RpcError::Rpc(err));
+                }
+            }
+        }
+    }
+
+    /// Removes a bucket that was just created but could not be configured, and returns the
+    /// error to report: the configuration error, or both errors if the bucket could not be removed
+    async fn remove_unconfigured_bucket(&self, bucket_id: &str, e: RpcError) -> RpcError {
+        match self
+            .s3_client
+            .delete_bucket()
+            .bucket(bucket_id)
+            .send()
+            .await
+        {
+            Ok(_) => e,
+            Err(delete_error) => {
+                error!(error = %delete_error, "Error removing bucket that could not be configured");
+                RpcError::Other(format!(
+                    "{}; Bucket({}) was created, but could not be removed: {}",
+                    e, bucket_id, delete_error
+                ))
+            }
         }
     }
 
     /// send any-size array of bytes to actor via streaming api,
-    /// as one or more chunks of length <= MAX_CHUNK_SIZE
-    /// Returns total number of bytes sent: (bytes.len())
+    /// as one or more chunks of length <= MAX_CHUNK_SIZE,
+    /// advancing `offset` past each chunk sent
     #[instrument(level = "debug", skip(self, ctx, cobj, bytes), fields(actor_id = ?ctx.actor, bucket_id = %self.unalias(&cobj.container_id), object_id = %cobj.object_id))]
     async fn stream_bytes(
         &self,
         ctx: &Context,
-        offset: u64,
+        offset: &mut u64,
         end_range: u64, // last byte (inclusive) in requested range
         cobj: &ContainerObject,
         bytes: &[u8],
-    ) -> Result<u64, RpcError> {
+    ) -> Result<(), download::StreamStop> {
         let bucket_id = self.unalias(&cobj.container_id);
         let mut bytes_sent = 0u64;
         let bytes_to_send = bytes.len() as u64;
         while bytes_sent < bytes_to_send {
-            let chunk_offset = offset + bytes_sent;
+            let chunk_offset = *offset;
             let chunk_len = (self.max_chunk_size() as u64).min(bytes_to_send - bytes_sent);
-            bytes_sent += self
-                .send_chunk(
-                    ctx,
-                    Chunk {
-                        is_last: offset + chunk_len > end_range,
-                        bytes: bytes[bytes_sent as usize..(bytes_sent + chunk_len) as usize]
-                            .to_vec(),
-                        offset: chunk_offset as u64,
-                        container_id: bucket_id.to_string(),
-                        object_id: cobj.object_id.clone(),
-                    },
-                )
-                .await?;
+            let chunk = Chunk {
+                is_last: chunk_offset + chunk_len > end_range,
+                bytes: bytes[bytes_sent as usize..(bytes_sent + chunk_len) as usize].to_vec(),
+                offset: chunk_offset as u64,
+                container_id: bucket_id.to_string(),
+                object_id: cobj.object_id.clone(),
+            };
+            if !self.send_chunk(ctx, chunk).await? {
+                return Err(download::StreamStop::Cancelled);
+            }
+            bytes_sent += chunk_len;
+            *offset += chunk_len;
         }
-        Ok(bytes_sent)
+        Ok(())
     }
 
     /// Async tokio task to accept chunks from S3 and send to actor.
@@ -213,6 +410,8 @@ impl StorageClient {
     ///    (on entry, this should be the initial range offset requested plus the number
     ///    of bytes already sent to the actor in the GetObjectResponse)
     /// `end_range` the byte offset (inclusive) of the last byte to be returned to the client
+    /// `permit` is held until the stream ends, to limit the number of concurrent downloads
+    #[allow(clippy::too_many_arguments)]
     async fn stream_from_s3(
         &self,
         ctx: &Context,
@@ -220,38 +419,38 @@ impl StorageClient {
         excess: Vec<u8>, // excess bytes from first chunk
         offset: u64,
         end_range: u64, // last object offset in requested range (inclusive),
-        mut stream: ByteStream,
+        stream: ObjectStream,
+        permit: OwnedSemaphorePermit,
     ) {
         let ctx = ctx.clone();
         let this = self.clone();
         container_object.container_id = self.unalias(&container_object.container_id).to_string();
         let actor_id = ctx.actor.clone();
         let excess_len = excess.len();
-        let _ = tokio::spawn(
+        tokio::spawn(
             async move {
                 let mut offset = offset;
-                if !excess.is_empty() {
-                    offset += this
-                        .stream_bytes(&ctx, offset, end_range, &container_object, &excess)
-                        .await?;
-                    if offset > end_range {
-                        return Ok::<(), RpcError>(());
-                    }
-                }
-
-                while let Some(Ok(bytes)) = stream.next().await {
-                    if bytes.is_empty() {
-                        warn!("object stream returned zero bytes, quitting stream");
-                        break;
+                let result = this
+                    .stream_chunks(
+                        &ctx,
+                        &container_object,
+                        excess,
+                        &mut offset,
+                        end_range,
+                        stream,
+                    )
+                    .await;
+                match result {
+                    Ok(()) => debug!(%offset, "download complete"),
+                    Err(download::StreamStop::Cancelled) => {
+                        info!(%offset, "download cancelled by actor")
                     }
-                    offset += this
-                        .stream_bytes(&ctx, offset, end_range, &container_object, &bytes)
-                        .await?;
-                    if offset > end_range {
-                        break;
+                    Err(download::StreamStop::Failed(e)) => {
+                        this.notify_download_error(&ctx, &container_object, offset, &e)
+                            .await
                     }
                 }
-                Ok(())
+                drop(permit);
             }
             .instrument(tracing::debug_span!(
                 "stream_from_s3",
@@ -262,6 +461,43 @@ impl StorageClient {
             )),
         );
     }
+
+    /// Sends the excess bytes and the rest of the S3 stream to the actor, advancing `offset`
+    /// past the bytes sent
+    async fn stream_chunks(
+        &self,
+        ctx: &Context,
+        container_object: &ContainerObject,
+        excess: Vec<u8>,
+        offset: &mut u64,
+        end_range: u64,
+        mut stream: ObjectStream,
+    ) -> Result<(), download::StreamStop> {
+        if !excess.is_empty() {
+            self.stream_bytes(ctx, offset, end_range, container_object, &excess)
+                .await?;
+        }
+        while *offset <= end_range {
+            let bytes = match stream.next().await {
+                Some(Ok(bytes)) => bytes,
+                Some(Err(e)) => return Err(e.into()),
+                None => {
+                    return Err(RpcError::Other(format!(
+                        "object stream from s3 ended at offset {}, before offset {}",
+                        offset, end_range
+                    ))
+                    .into())
+                }
+            };
+            if bytes.is_empty() {
+                debug!("object stream returned zero bytes");
+                continue;
+            }
+            self.stream_bytes(ctx, offset, end_range, container_object, &bytes)
+                .await?;
+        }
+        Ok(())
+    }
 }
 
 #[async_trait]
@@ -269,7 +505,7 @@ impl Blobstore for StorageClient {
     /// Find out whether container exists
     #[instrument(level = "debug", skip(self, _ctx, arg), fields(actor_id = ?_ctx.actor, bucket_id = %self.unalias(arg)))]
     async fn container_exists(&self, _ctx: &Context, arg: &ContainerId) -> RpcResult<bool> {
-        let bucket_id = self.unalias(arg);
+        let bucket_id = self.bucket(arg)?;
         match self.s3_client.head_bucket().bucket(bucket_id).send().await {
             Ok(_) => Ok(true),
             Err(SdkError::ServiceError {
@@ -290,7 +526,7 @@ impl Blobstore for StorageClient {
     /// Creates container if it does not exist
     #[instrument(level = "debug", skip(self, ctx, arg), fields(actor_id = ?ctx.actor, bucket_id = %self.unalias(arg)))]
     async fn create_container(&self, ctx: &Context, arg: &ContainerId) -> RpcResult<()> {
-        let bucket_id = self.unalias(arg);
+        let bucket_id = self.bucket(arg)?;
         match self.container_exists(ctx, &bucket_id.to_string()).await {
             Ok(true) => Ok(()),
             _ => {
@@ -305,11 +541,17 @@ impl Blobstore for StorageClient {
                     .s3_client
                     .create_bucket()
                     .bucket(bucket_id)
+                    .set_object_lock_enabled_for_bucket(self.object_lock.then(|| true))
                     .send()
                     .await
                 {
                     Ok(CreateBucketOutput { location, .. }) => {
                         debug!(?location, "bucket created");
+                        // a bucket without its default encryption is not left behind,
+                        // so that creating the container again sets it
+                        if let Err(e) = self.put_bucket_encryption(bucket_id).await {
+                            return Err(self.remove_unconfigured_bucket(bucket_id, e).await);
+                        }
                         Ok(())
                     }
                     Err(SdkError::ServiceError { err, .. }) => {
@@ -337,7 +579,7 @@ impl Blobstore for StorageClient {
         _ctx: &Context,
         arg: &ContainerId,
     ) -> RpcResult<ContainerMetadata> {
-        let bucket_id = self.unalias(arg);
+        let bucket_id = self.bucket(arg)?;
         match self.s3_client.head_bucket().bucket(bucket_id).send().await {
             Ok(_) => Ok(ContainerMetadata {
                 container_id: bucket_id.to_string(),
@@ -365,6 +607,7 @@ impl Blobstore for StorageClient {
                 ..
             }) => Ok(list
                 .iter()
+                .filter(|bucket| self.is_allowed(bucket.name.as_deref().unwrap_or_default()))
                 .map(|bucket| ContainerMetadata {
                     container_id: bucket.name.clone().unwrap_or_default(),
                     created_at: to_timestamp(bucket.creation_date),
@@ -391,6 +634,14 @@ impl Blobstore for StorageClient {
         let mut results = Vec::with_capacity(arg.len());
         for bucket in arg.iter() {
             let bucket = self.unalias(bucket);
+            if !self.is_allowed(bucket) || !self.prefix(bucket).is_empty() {
+                results.push(blobstore::ItemResult {
+                    key: bucket.to_string(),
+                    error: Some(format!("Bucket({}) can't be removed by this link", bucket)),
+                    success: false,
+                });
+                continue;
+            }
             match self.s3_client.delete_bucket().bucket(bucket).send().await {
                 Ok(_) => {}
                 Err(SdkError::ServiceError { err, .. }) => {
@@ -419,12 +670,13 @@ impl Blobstore for StorageClient {
     /// Find out whether object exists
     #[instrument(level = "debug", skip(self, _ctx, arg), fields(actor_id = ?_ctx.actor, bucket_id = %self.unalias(&arg.container_id), object_id = %arg.object_id))]
     async fn object_exists(&self, _ctx: &Context, arg: &ContainerObject) -> RpcResult<bool> {
-        let bucket_id = self.unalias(&arg.container_id);
+        let bucket_id = self.bucket(&arg.container_id)?;
         match self
             .s3_client
             .head_object()
             .bucket(bucket_id)
-            .key(&arg.object_id)
+            .key(self.key(bucket_id, &arg.object_id))
+            .encryption(self.encryption(bucket_id))
             .send()
             .await
         {
@@ -454,12 +706,13 @@ impl Blobstore for StorageClient {
         _ctx: &Context,
         arg: &ContainerObject,
     ) -> Result<ObjectMetadata, RpcError> {
-        let bucket_id = self.unalias(&arg.container_id);
+        let bucket_id = self.bucket(&arg.container_id)?;
         match self
             .s3_client
             .head_object()
             .bucket(bucket_id)
-            .key(arg.object_id.clone())
+            .key(self.key(bucket_id, &arg.object_id))
+            .encryption(self.encryption(bucket_id))
             .send()
             .await
         {
@@ -501,9 +754,13 @@ impl Blobstore for StorageClient {
         _ctx: &Context,
         arg: &blobstore::ListObjectsRequest,
     ) -> RpcResult<blobstore::ListObjectsResponse> {
-        let bucket_id = self.unalias(&arg.container_id);
+        let bucket_id = self.bucket(&arg.container_id)?;
         debug!("asking for list_objects bucket: {}", bucket_id);
+        let prefix = self.prefix(bucket_id);
         let mut req = self.s3_client.list_objects_v2().bucket(bucket_id);
+        if !prefix.is_empty() {
+            req = req.prefix(prefix);
+        }
         if let Some(max_items) = arg.max_items {
             if max_items > i32::MAX as u32 {
                 // edge case to avoid panic
@@ -518,7 +775,7 @@ impl Blobstore for StorageClient {
         if let Some(continuation) = &arg.continuation {
             req = req.set_continuation_token(Some(continuation.clone()));
         } else if let Some(start_with) = &arg.start_with {
-            req = req.set_start_after(Some(start_with.clone()));
+            req = req.set_start_after(Some(self.key(bucket_id, start_with)));
         }
         match req.send().await {
             Ok(list) => {
@@ -529,19 +786,14 @@ impl Blobstore for StorageClient {
                 );
                 let is_last = !list.is_truncated;
                 let objects = match list.contents {
-                    Some(items) => items
-                        .iter()
-                        .map(|o| ObjectMetadata {
-                            container_id: bucket_id.to_string(),
-                            last_modified: to_timestamp(o.last_modified),
-                            object_id: o.key.clone().unwrap_or_default(),
-                            content_length: o.size as u64,
-                            content_encoding: None,
-                            content_type: None,
-                        })
-                        .collect(),
+                    Some(items) => self.listed_objects(bucket_id, &items),
                     None => Vec::<ObjectMetadata>::new(),
                 };
+                let objects = if self.list_object_metadata {
+                    self.add_content_headers(objects).await
+                } else {
+                    objects
+                };
                 Ok(blobstore::ListObjectsResponse {
                     continuation: list.next_continuation_token,
                     objects,
@@ -561,7 +813,7 @@ impl Blobstore for StorageClient {
         _ctx: &Context,
         arg: &RemoveObjectsRequest,
     ) -> RpcResult<MultiResult> {
-        let bucket_id = self.unalias(&arg.container_id);
+        let bucket_id = self.bucket(&arg.container_id)?;
         match self
             .s3_client
             .delete_objects()
@@ -571,7 +823,11 @@ impl Blobstore for StorageClient {
                     .set_objects(Some(
                         arg.objects
                             .iter()
-                            .map(|id| ObjectIdentifier::builder().key(id).build())
+                            .map(|id| {
+                                ObjectIdentifier::builder()
+                                    .key(self.key(bucket_id, id))
+                                    .build()
+                            })
                             .collect(),
                     ))
                     .quiet(true)
@@ -585,7 +841,12 @@ impl Blobstore for StorageClient {
                     let mut results = Vec::with_capacity(errors.len());
                     for e in errors.iter() {
                         results.push(blobstore::ItemResult {
-                            key: e.key.clone().unwrap_or_default(),
+                            key: e
+                                .key
+                                .as_deref()
+                                .and_then(|key| self.object_id(bucket_id, key))
+                                .unwrap_or_default()
+                                .to_string(),
                             error: e.message.clone(),
                             success: false,
                         });
@@ -619,45 +880,15 @@ impl Blobstore for StorageClient {
         _ctx: &Context,
         arg: &blobstore::PutObjectRequest,
     ) -> RpcResult<PutObjectResponse> {
-        let bucket_id = self.unalias(&arg.chunk.container_id);
-        if !arg.chunk.is_last {
-            error!("put_object for multi-part upload: not implemented!");
-            return Err(RpcError::InvalidParameter(
-                "multipart upload not implemented".to_string(),
-            ));
-        }
-        if arg.chunk.offset != 0 {
-            error!("put_object with initial offset non-zero: not implemented!");
-            return Err(RpcError::InvalidParameter(
-                "non-zero offset not supported".to_string(),
-            ));
-        }
-        if arg.chunk.bytes.is_empty() {
-            error!("put_object with zero bytes");
-            return Err(RpcError::InvalidParameter(
-                "cannot put zero-length objects".to_string(),
-            ));
-        }
-        // TODO: make sure put_object takes an owned `PutObjectRequest` to avoid cloning the whole chunk
-        let bytes = arg.chunk.bytes.to_owned();
-        match self
-            .s3_client
-            .put_object()
-            .bucket(bucket_id)
-            .key(&arg.chunk.object_id)
-            .body(ByteStream::from(bytes))
-            .send()
-            .await
-        {
-            Ok(_) => Ok(PutObjectResponse::default()),
-            Err(e) => {
-                error!(
-                    error = %e,
-                    "Error putting object",
-                );
-                Err(RpcError::Other(e.to_string()))
-            }
-        }
+        self.put_object_with_headers(
+            &arg.chunk,
+            &ObjectHeaders {
+                content_type: arg.content_type.clone(),
+                content_encoding: arg.content_encoding.clone(),
+                ..Default::default()
+            },
+        )
+        .await
     }
 
     /// Retrieve object from s3 storage.
@@ -667,11 +898,11 @@ impl Blobstore for StorageClient {
         ctx: &Context,
         arg: &blobstore::GetObjectRequest,
     ) -> RpcResult<GetObjectResponse> {
-        let bucket_id = self.unalias(&arg.container_id);
+        let bucket_id = self.bucket(&arg.container_id)?;
         let max_chunk_size = self.max_chunk_size();
         // If the object is not found, or not readable, get_object_metadata will return error.
         let meta = self
-            .get_object_metadata(ctx, bucket_id, &arg.object_id)
+            .head_object_metadata(ctx, bucket_id, &arg.object_id)
             .await?;
         // calculate content_length requested, with error checking for range bounds
         let bytes_requested = match (arg.range_start, arg.range_end) {
@@ -704,12 +935,26 @@ impl Blobstore for StorageClient {
             });
         }
 
+        let range_start = arg.range_start.unwrap_or(0);
+        let end_range = range_start + bytes_requested - 1;
+        // a parallel download gets the first part here, and the other parts while streaming
+        let parallel = self.is_parallel_download(bytes_requested);
+        let range = if parallel {
+            Some(format!(
+                "bytes={}-{}",
+                range_start,
+                range_start + self.download_part_size - 1
+            ))
+        } else {
+            to_range_header(arg.range_start, arg.range_end)
+        };
         let get_object_req = self
             .s3_client
             .get_object()
             .bucket(bucket_id)
-            .key(&arg.object_id)
-            .set_range(to_range_header(arg.range_start, arg.range_end));
+            .key(self.key(bucket_id, &arg.object_id))
+            .encryption(self.encryption(bucket_id))
+            .set_range(range);
         match get_object_req.send().await {
             Ok(mut object_output) => {
                 let len = object_output.content_length as u64;
@@ -752,8 +997,26 @@ impl Blobstore for StorageClient {
                     } else {
                         (bytes, Bytes::new())
                     };
+                    let permit = self.downloads.clone().try_acquire_owned().map_err(|_| {
+                        warn!("too many concurrent downloads");
+                        RpcError::Other(format!(
+                            "too many downloads in progress for Actor({}), try again later",
+                            &self.ld.actor_id
+                        ))
+                    })?;
+                    let mut stream = ranged::body_stream(object_output.body);
+                    if parallel {
+                        let rest = self.get_parts(
+                            bucket_id,
+                            &self.key(bucket_id, &arg.object_id),
+                            object_output.e_tag.clone(),
+                            range_start + self.download_part_size,
+                            end_range,
+                        );
+                        stream = Box::pin(stream.chain(rest));
+                    }
                     // create task to deliver remaining chunks
-                    let offset = arg.range_start.unwrap_or(0) + bytes.len() as u64;
+                    let offset = range_start + bytes.len() as u64;
                     self.stream_from_s3(
                         ctx,
                         ContainerObject {
@@ -762,8 +1025,9 @@ impl Blobstore for StorageClient {
                         },
                         excess.into(),
                         offset,
-                        offset + bytes_requested,
-                        object_output.body,
+                        end_range,
+                        stream,
+                        permit,
                     )
                     .await;
                     Vec::from(bytes)
@@ -779,7 +1043,7 @@ impl Blobstore for StorageClient {
                         bytes,
                         container_id: bucket_id.to_string(),
                         object_id: arg.object_id.clone(),
-                        offset: arg.range_start.unwrap_or(0),
+                        offset: range_start,
                     }),
                     content_length: bytes_requested,
                     content_type: object_output.content_type.clone(),
@@ -797,9 +1061,10 @@ impl Blobstore for StorageClient {
         }
     }
 
-    async fn put_chunk(&self, _ctx: &Context, _arg: &PutChunkRequest) -> RpcResult<()> {
-        error!("put_chunk is unimplemented");
-        Err(RpcError::NotImplemented)
+    /// Uploads a chunk of an object started with `put_object`. Chunks must be sent in order.
+    #[instrument(level = "debug", skip(self, _ctx, arg), fields(actor_id = ?_ctx.actor, bucket_id = %self.unalias(&arg.chunk.container_id), object_id = %arg.chunk.object_id))]
+    async fn put_chunk(&self, _ctx: &Context, arg: &PutChunkRequest) -> RpcResult<()> {
+        self.put_multipart_chunk(arg).await
     }
 }
 
@@ -922,4 +1187,40 @@ mod test {
         // undefined alias
         assert_eq!(client.unalias(&format!("{}baz", ALIAS_PREFIX)), "baz");
     }
+
+    #[tokio::test]
+    async fn allowed_buckets() {
+        let mut ld = LinkDefinition::default();
+        ld.values
+            .insert(format!("{}shared", ALIAS_PREFIX), "tenants".to_string());
+        let config = StorageConfig {
+            allowed_buckets: Some(HashMap::from([
+                (
+                    "alias_shared".to_string(),
+                    BucketScope {
+                        prefix: Some("tenant-a/".to_string()),
+                    },
+                ),
+                ("private".to_string(), BucketScope::default()),
+            ])),
+            ..Default::default()
+        };
+        let client = StorageClient::new(config, ld).await;
+
+        assert_eq!(client.bucket("shared").unwrap(), "tenants");
+        assert_eq!(client.bucket("private").unwrap(), "private");
+        assert!(client.bucket("other").is_err());
+
+        assert_eq!(client.key("tenants", "a/b.txt"), "tenant-a/a/b.txt");
+        assert_eq!(client.key("private", "a/b.txt"), "a/b.txt");
+        assert_eq!(
+            client.object_id("tenants", "tenant-a/a/b.txt"),
+            Some("a/b.txt")
+        );
+        assert_eq!(client.object_id("tenants", "tenant-b/a/b.txt"), None);
+
+        let open = StorageClient::new(StorageConfig::default(), Default::default()).await;
+        assert_eq!(open.bucket("other").unwrap(), "other");
+        assert_eq!(open.key("other", "a"), "a");
+    }
 }
@@ -8,7 +8,11 @@ use blobstore_s3_lib::{
         ListObjectsResponse, MultiResult, ObjectMetadata, PutChunkRequest, PutObjectRequest,
         PutObjectResponse, RemoveObjectsRequest,
     },
-    StorageClient, StorageConfig,
+    BlobstoreCopy, BlobstoreCopyReceiver, BlobstoreMetadata, BlobstoreMetadataReceiver,
+    BlobstorePresign, BlobstorePresignReceiver, BlobstoreRetention, BlobstoreRetentionReceiver,
+    CopyObjectRequest, ObjectLock, ObjectMetadataWithHeaders, ObjectTags, PresignRequest,
+    PresignedUrl, PutObjectWithMetadataRequest, SetLegalHoldRequest, SetObjectRetentionRequest,
+    SetObjectTagsRequest, StorageClient, StorageConfig,
 };
 use std::{collections::HashMap, convert::Infallible, sync::Arc};
 use tokio::sync::RwLock;
@@ -27,7 +31,13 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 }
 
 #[derive(Default, Clone, Provider)]
-#[services(Blobstore)]
+#[services(
+    Blobstore,
+    BlobstoreMetadata,
+    BlobstorePresign,
+    BlobstoreCopy,
+    BlobstoreRetention
+)]
 struct S3BlobstoreProvider {
     // store nats connection client per actor
     actors: Arc<RwLock<HashMap<String, StorageClient>>>,
@@ -178,3 +188,86 @@ impl Blobstore for S3BlobstoreProvider {
         client.put_chunk(ctx, arg).await
     }
 }
+
+/// Handle metadata methods of the BlobstoreMetadata extension
+#[async_trait]
+impl BlobstoreMetadata for S3BlobstoreProvider {
+    async fn put_object_with_metadata(
+        &self,
+        ctx: &Context,
+        arg: &PutObjectWithMetadataRequest,
+    ) -> RpcResult<PutObjectResponse> {
+        let client = self.client(ctx).await?;
+        client.put_object_with_metadata(ctx, arg).await
+    }
+
+    async fn get_object_metadata(
+        &self,
+        ctx: &Context,
+        arg: &ContainerObject,
+    ) -> RpcResult<ObjectMetadataWithHeaders> {
+        let client = self.client(ctx).await?;
+        client.get_object_metadata(ctx, arg).await
+    }
+}
+
+/// Handle presigned URL requests of the BlobstorePresign extension
+#[async_trait]
+impl BlobstorePresign for S3BlobstoreProvider {
+    async fn presign_url(&self, ctx: &Context, arg: &PresignRequest) -> RpcResult<PresignedUrl> {
+        let client = self.client(ctx).await?;
+        client.presign_url(ctx, arg).await
+    }
+}
+
+/// Handle BlobstoreCopy methods
+#[async_trait]
+impl BlobstoreCopy for S3BlobstoreProvider {
+    async fn copy_object(&self, ctx: &Context, arg: &CopyObjectRequest) -> RpcResult<()> {
+        let client = self.client(ctx).await?;
+        client.copy_object(ctx, arg).await
+    }
+
+    async fn move_object(&self, ctx: &Context, arg: &CopyObjectRequest) -> RpcResult<()> {
+        let client = self.client(ctx).await?;
+        client.move_object(ctx, arg).await
+    }
+}
+
+/// Handle tag, retention and legal hold methods of the BlobstoreRetention extension
+#[async_trait]
+impl BlobstoreRetention for S3BlobstoreProvider {
+    async fn get_object_tags(&self, ctx: &Context, arg: &ContainerObject) -> RpcResult<ObjectTags> {
+        let client = self.client(ctx).await?;
+        client.get_object_tags(ctx, arg).await
+    }
+
+    async fn set_object_tags(&self, ctx: &Context, arg: &SetObjectTagsRequest) -> RpcResult<()> {
+        let client = self.client(ctx).await?;
+        client.set_object_tags(ctx, arg).await
+    }
+
+    async fn delete_object_tags(&self, ctx: &Context, arg: &ContainerObject) -> RpcResult<()> {
+        let client = self.client(ctx).await?;
+        client.delete_object_tags(ctx, arg).await
+    }
+
+    async fn set_object_retention(
+        &self,
+        ctx: &Context,
+        arg: &SetObjectRetentionRequest,
+    ) -> RpcResult<()> {
+        let client = self.client(ctx).await?;
+        client.set_object_retention(ctx, arg).await
+    }
+
+    async fn set_legal_hold(&self, ctx: &Context, arg: &SetLegalHoldRequest) -> RpcResult<()> {
+        let client = self.client(ctx).await?;
+        client.set_legal_hold(ctx, arg).await
+    }
+
+    async fn get_object_lock(&self, ctx: &Context, arg: &ContainerObject) -> RpcResult<ObjectLock> {
+        let client = self.client(ctx).await?;
+        client.get_object_lock(ctx, arg).await
+    }
+}
@@ -0,0 +1,305 @@
+//! Object metadata beyond the blobstore interface
+//!
+//! `put_object` stores the content type and content encoding of the request with the object,
+//! and `get_object_info`, `get_object` and (with `list_object_metadata`) `list_objects` return
+//! them. Cache-control and user-defined metadata have no place in the interface's structures,
+//! so the provider also implements the `BlobstoreMetadata` service: actors call
+//! `BlobstoreMetadata.PutObjectWithMetadata` instead of `Blobstore.PutObject` to set them,
+//! continue with `Blobstore.PutChunk` for objects sent in several chunks, and read them back
+//! with `BlobstoreMetadata.GetObjectMetadata`, which also returns the Object Lock state.
+
+use std::collections::HashMap;
+
+use aws_sdk_s3::{
+    error::{HeadObjectError, HeadObjectErrorKind},
+    output::HeadObjectOutput,
+    types::SdkError,
+};
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use tracing::{instrument, warn};
+use wasmbus_rpc::common::{deserialize, serialize};
+use wasmbus_rpc::provider::prelude::*;
+use wasmbus_rpc::Timestamp;
+
+use crate::{
+    encryption::EncryptRequest,
+    retention::ObjectLock,
+    to_timestamp,
+    wasmcloud_interface_blobstore::{Chunk, ContainerObject, ObjectMetadata},
+    StorageClient,
+};
+
+/// maximum size of the user-defined metadata of an object, as counted by S3 (2KB)
+const MAX_USER_METADATA_SIZE: usize = 2048;
+
+/// number of HeadObject requests in flight when `list_objects` returns content headers
+const LIST_METADATA_CONCURRENCY: usize = 16;
+
+/// Headers stored with an object when it is put
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ObjectHeaders {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_encoding: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cache_control: Option<String>,
+    /// user-defined metadata, stored by S3 as `x-amz-meta-` headers. Names are lower-cased by S3.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub metadata: HashMap<String, String>,
+}
+
+impl ObjectHeaders {
+    /// Checks that the user-defined metadata can be sent as http headers and fits S3's limit
+    pub fn validate(&self) -> RpcResult<()> {
+        let mut size = 0;
+        for (name, value) in self.metadata.iter() {
+            if name.is_empty()
+                || !name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+            {
+                return Err(RpcError::InvalidParameter(format!(
+                    "invalid metadata name '{}': only letters, digits, '-' and '_' are allowed",
+                    name
+                )));
+            }
+            if !value.chars().all(|c| c == ' ' || c.is_ascii_graphic()) {
+                return Err(RpcError::InvalidParameter(format!(
+                    "invalid value of metadata '{}': only printable ascii characters are allowed",
+                    name
+                )));
+            }
+            size += name.len() + value.len();
+        }
+        if size > MAX_USER_METADATA_SIZE {
+            return Err(RpcError::InvalidParameter(format!(
+                "user-defined metadata is {} bytes, the maximum is {}",
+                size, MAX_USER_METADATA_SIZE
+            )));
+        }
+        Ok(())
+    }
+
+    pub(crate) fn user_metadata(&self) -> Option<HashMap<String, String>> {
+        (!self.metadata.is_empty()).then(|| self.metadata.clone())
+    }
+}
+
+/// Argument of `BlobstoreMetadata.PutObjectWithMetadata`
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PutObjectWithMetadataRequest {
+    /// first chunk of the object; if `is_last` is false, the rest is sent with `put_chunk`
+    pub chunk: Chunk,
+    #[serde(flatten)]
+    pub headers: ObjectHeaders,
+}
+
+/// Result of `BlobstoreMetadata.GetObjectMetadata`
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct ObjectMetadataWithHeaders {
+    pub container_id: String,
+    pub object_id: String,
+    pub content_length: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_modified: Option<Timestamp>,
+    #[serde(flatten)]
+    pub headers: ObjectHeaders,
+    /// Object Lock retention and legal hold
+    #[serde(flatten)]
+    pub lock: ObjectLock,
+}
+
+/// Operations on the metadata of objects, an extension of the blobstore interface.
+/// Actors call them as `BlobstoreMetadata.<Operation>` on the blobstore link.
+#[async_trait]
+pub trait BlobstoreMetadata {
+    /// Puts an object, like `put_object`, with cache-control and user-defined metadata
+    async fn put_object_with_metadata(
+        &self,
+        ctx: &Context,
+        arg: &PutObjectWithMetadataRequest,
+    ) -> RpcResult<crate::wasmcloud_interface_blobstore::PutObjectResponse>;
+
+    /// Returns the metadata of an object, with all of its headers
+    async fn get_object_metadata(
+        &self,
+        ctx: &Context,
+        arg: &ContainerObject,
+    ) -> RpcResult<ObjectMetadataWithHeaders>;
+}
+
+/// Receives `BlobstoreMetadata` messages, like the receivers generated for smithy interfaces
+#[async_trait]
+pub trait BlobstoreMetadataReceiver: MessageDispatch + BlobstoreMetadata {
+    async fn dispatch(&self, ctx: &Context, message: Message<'_>) -> RpcResult<Vec<u8>> {
+        match message.method {
+            "PutObjectWithMetadata" => {
+                let value: PutObjectWithMetadataRequest =
+                    deserialize(&message.arg).map_err(|e| {
+                        RpcError::Deser(format!("'PutObjectWithMetadataRequest': {}", e))
+                    })?;
+                let resp = BlobstoreMetadata::put_object_with_metadata(self, ctx, &value).await?;
+                serialize(&resp)
+            }
+            "GetObjectMetadata" => {
+                let value: ContainerObject = deserialize(&message.arg)
+                    .map_err(|e| RpcError::Deser(format!("'ContainerObject': {}", e)))?;
+                let resp = BlobstoreMetadata::get_object_metadata(self, ctx, &value).await?;
+                serialize(&resp)
+            }
+            _ => Err(RpcError::MethodNotHandled(format!(
+                "BlobstoreMetadata::{}",
+                message.method
+            ))),
+        }
+    }
+}
+
+#[async_trait]
+impl BlobstoreMetadata for StorageClient {
+    async fn put_object_with_metadata(
+        &self,
+        _ctx: &Context,
+        arg: &PutObjectWithMetadataRequest,
+    ) -> RpcResult<crate::wasmcloud_interface_blobstore::PutObjectResponse> {
+        self.put_object_with_headers(&arg.chunk, &arg.headers).await
+    }
+
+    #[instrument(level = "debug", skip(self, _ctx, arg), fields(actor_id = ?_ctx.actor, bucket_id = %self.unalias(&arg.container_id), object_id = %arg.object_id))]
+    async fn get_object_metadata(
+        &self,
+        _ctx: &Context,
+        arg: &ContainerObject,
+    ) -> RpcResult<ObjectMetadataWithHeaders> {
+        let bucket_id = self.bucket(&arg.container_id)?;
+        match self
+            .s3_client
+            .head_object()
+            .bucket(bucket_id)
+            .key(self.key(bucket_id, &arg.object_id))
+            .encryption(self.encryption(bucket_id))
+            .send()
+            .await
+        {
+            Ok(HeadObjectOutput {
+                last_modified,
+                content_length,
+                content_type,
+                content_encoding,
+                cache_control,
+                metadata,
+                object_lock_mode,
+                object_lock_retain_until_date,
+                object_lock_legal_hold_status,
+                ..
+            }) => Ok(ObjectMetadataWithHeaders {
+                container_id: bucket_id.to_string(),
+                object_id: arg.object_id.clone(),
+                content_length: content_length as u64,
+                last_modified: to_timestamp(last_modified),
+                headers: ObjectHeaders {
+                    content_type,
+                    content_encoding,
+                    cache_control,
+                    metadata: metadata.unwrap_or_default(),
+                },
+                lock: ObjectLock::from_head(
+                    object_lock_mode,
+                    object_lock_retain_until_date,
+                    object_lock_legal_hold_status,
+                ),
+            }),
+            Err(SdkError::ServiceError {
+                err:
+                    HeadObjectError {
+                        kind: HeadObjectErrorKind::NotFound(_),
+                        ..
+                    },
+                ..
+            }) => Err(RpcError::Other(format!(
+                "Not found: Bucket({}) Object({})",
+                bucket_id, &arg.object_id,
+            ))),
+            Err(e) => Err(RpcError::Other(format!(
+                "get_object_metadata for Bucket({}) Object({}): {}",
+                bucket_id, &arg.object_id, e
+            ))),
+        }
+    }
+}
+
+impl StorageClient {
+    /// Fills in the content type and encoding of listed objects, which ListObjectsV2 doesn't return,
+    /// with a HeadObject request for each object
+    pub(crate) async fn add_content_headers(
+        &self,
+        objects: Vec<ObjectMetadata>,
+    ) -> Vec<ObjectMetadata> {
+        futures::stream::iter(objects)
+            .map(|mut object| async move {
+                match self
+                    .s3_client
+                    .head_object()
+                    .bucket(&object.container_id)
+                    .key(self.key(&object.container_id, &object.object_id))
+                    .encryption(self.encryption(&object.container_id))
+                    .send()
+                    .await
+                {
+                    Ok(head) => {
+                        object.content_type = head.content_type;
+                        object.content_encoding = head.content_encoding;
+                    }
+                    // the object may have been removed since it was listed
+                    Err(e) => warn!(error = %e, object_id = %object.object_id, "Unable to head listed object"),
+                }
+                object
+            })
+            .buffered(LIST_METADATA_CONCURRENCY)
+            .collect()
+            .await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn headers(metadata: &[(&str, &str)]) -> ObjectHeaders {
+        ObjectHeaders {
+            metadata: metadata
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn user_metadata() {
+        assert!(headers(&[]).validate().is_ok());
+        assert!(headers(&[("Owner-id", "team a_1")]).validate().is_ok());
+        assert!(headers(&[("", "x")]).validate().is_err(), "empty name");
+        assert!(
+            headers(&[("a b", "x")]).validate().is_err(),
+            "space in name"
+        );
+        assert!(
+            headers(&[("a:b", "x")]).validate().is_err(),
+            "colon in name"
+        );
+        assert!(
+            headers(&[("a", "line\nbreak")]).validate().is_err(),
+            "newline"
+        );
+        assert!(
+            headers(&[("a", "caf\u{e9}")]).validate().is_err(),
+            "non-ascii"
+        );
+        let long = "x".repeat(MAX_USER_METADATA_SIZE);
+        assert!(headers(&[("a", &long)]).validate().is_err(), "too large");
+        assert_eq!(headers(&[]).user_metadata(), None);
+    }
+}
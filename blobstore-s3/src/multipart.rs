@@ -0,0 +1,395 @@
+//! Multipart uploads
+//!
+//! Objects sent in more than one chunk are stored with S3's multipart upload api. `put_object`
+//! starts the upload and returns its upload id as the `stream_id`, and each `put_chunk` appends
+//! to a buffer that is uploaded as a part whenever it holds at least `MIN_PART_SIZE` bytes,
+//! the smallest part S3 accepts except for the last. The last chunk uploads the rest of the
+//! buffer and completes the upload. Chunks must be sent in order; a chunk that was already
+//! received (e.g., resent after a timeout) is ignored.
+//!
+//! Uploads are aborted on `cancel_and_remove`, when any of their S3 requests fails, and when no
+//! chunk was received for `upload_timeout_secs`, so that S3 does not keep their parts.
+
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use aws_sdk_s3::{
+    model::{CompletedMultipartUpload, CompletedPart},
+    types::ByteStream,
+};
+use tokio::sync::{Mutex, RwLock};
+use tracing::{debug, error, info, instrument};
+use wasmbus_rpc::provider::prelude::*;
+
+use crate::{
+    encryption::EncryptRequest,
+    wasmcloud_interface_blobstore::{Chunk, PutChunkRequest, PutObjectResponse},
+    ObjectHeaders, StorageClient,
+};
+
+/// smallest part accepted by S3, except for the last part of an upload (5MB)
+pub(crate) const MIN_PART_SIZE: usize = 5 * 1024 * 1024;
+
+/// time after which an upload that has not received a chunk is aborted, if not configured
+pub(crate) const DEFAULT_UPLOAD_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// how often the reaper looks for abandoned uploads
+const REAPER_INTERVAL: Duration = Duration::from_secs(30);
+
+/// uploads in progress, by stream id
+pub(crate) type UploadMap = HashMap<String, Arc<Mutex<MultipartUpload>>>;
+pub(crate) type Uploads = Arc<RwLock<UploadMap>>;
+
+/// State of a multipart upload between chunks
+pub(crate) struct MultipartUpload {
+    bucket: String,
+    key: String,
+    upload_id: String,
+    /// bytes received but not yet uploaded
+    buffer: Vec<u8>,
+    /// offset of the next byte expected
+    next_offset: u64,
+    /// parts uploaded so far
+    parts: Vec<CompletedPart>,
+    last_activity: Instant,
+    /// set when the upload was completed or aborted, for requests that were waiting for it
+    finished: bool,
+}
+
+impl MultipartUpload {
+    fn new(bucket: &str, key: &str, upload_id: &str) -> Self {
+        MultipartUpload {
+            bucket: bucket.to_string(),
+            key: key.to_string(),
+            upload_id: upload_id.to_string(),
+            buffer: Vec::new(),
+            next_offset: 0,
+            parts: Vec::new(),
+            last_activity: Instant::now(),
+            finished: false,
+        }
+    }
+
+    /// Appends the bytes of a chunk to the buffer. Returns false if the chunk was received before.
+    fn append(&mut self, chunk: &Chunk) -> RpcResult<bool> {
+        let end = chunk.offset + chunk.bytes.len() as u64;
+        if chunk.offset == self.next_offset {
+            self.buffer.extend_from_slice(&chunk.bytes);
+            self.next_offset = end;
+            Ok(true)
+        } else if end <= self.next_offset && !chunk.is_last {
+            Ok(false)
+        } else {
+            Err(RpcError::InvalidParameter(format!(
+                "chunks must be sent in order: expected offset {}, got {}",
+                self.next_offset, chunk.offset
+            )))
+        }
+    }
+
+    /// Takes the bytes of the next part out of the buffer, if there are enough of them.
+    /// The last part may be smaller, and an upload has at least one part, even if it is empty.
+    fn take_part(&mut self, is_last: bool) -> Option<(i32, Vec<u8>)> {
+        if self.buffer.len() >= MIN_PART_SIZE
+            || (is_last && (!self.buffer.is_empty() || self.parts.is_empty()))
+        {
+            Some((
+                self.parts.len() as i32 + 1,
+                std::mem::take(&mut self.buffer),
+            ))
+        } else {
+            None
+        }
+    }
+}
+
+impl StorageClient {
+    /// Starts a multipart upload with the first chunk of an object, returning its stream id
+    #[instrument(level = "debug", skip(self, chunk), fields(object_id = %chunk.object_id))]
+    pub(crate) async fn start_multipart_upload(
+        &self,
+        bucket_id: &str,
+        chunk: &Chunk,
+        headers: &ObjectHeaders,
+    ) -> RpcResult<PutObjectResponse> {
+        if chunk.offset != 0 {
+            return Err(RpcError::InvalidParameter(
+                "non-zero offset not supported".to_string(),
+            ));
+        }
+        let output = self
+            .s3_client
+            .create_multipart_upload()
+            .bucket(bucket_id)
+            .key(self.key(bucket_id, &chunk.object_id))
+            .encryption(self.encryption(bucket_id))
+            .set_content_type(headers.content_type.clone())
+            .set_content_encoding(headers.content_encoding.clone())
+            .set_cache_control(headers.cache_control.clone())
+            .set_metadata(headers.user_metadata())
+            .send()
+            .await
+            .map_err(|e| {
+                error!(error = %e, "Error starting multipart upload");
+                RpcError::Other(e.to_string())
+            })?;
+        let upload_id = output.upload_id.ok_or_else(|| {
+            RpcError::Other("S3 did not return an id for the multipart upload".to_string())
+        })?;
+        debug!(%upload_id, "multipart upload started");
+
+        let upload = Arc::new(Mutex::new(MultipartUpload::new(
+            bucket_id,
+            &self.key(bucket_id, &chunk.object_id),
+            &upload_id,
+        )));
+        self.uploads
+            .write()
+            .await
+            .insert(upload_id.clone(), upload.clone());
+        let mut upload = upload.lock().await;
+        self.add_chunk(&mut upload, chunk).await?;
+        Ok(PutObjectResponse {
+            stream_id: Some(upload_id),
+        })
+    }
+
+    /// Adds a chunk to a multipart upload started by `put_object`, or cancels the upload
+    #[instrument(level = "debug", skip(self, arg), fields(stream_id = ?arg.stream_id, offset = %arg.chunk.offset, is_last = %arg.chunk.is_last))]
+    pub(crate) async fn put_multipart_chunk(&self, arg: &PutChunkRequest) -> RpcResult<()> {
+        let stream_id = arg.stream_id.as_ref().ok_or_else(|| {
+            RpcError::InvalidParameter("put_chunk requires the stream_id from put_object".into())
+        })?;
+        let upload = self.uploads.read().await.get(stream_id).cloned();
+        let upload = upload.ok_or_else(|| {
+            RpcError::InvalidParameter(format!("no upload in progress for stream {}", stream_id))
+        })?;
+        let mut upload = upload.lock().await;
+        if upload.finished {
+            return Err(RpcError::InvalidParameter(format!(
+                "no upload in progress for stream {}",
+                stream_id
+            )));
+        }
+        if arg.cancel_and_remove {
+            info!(%stream_id, "multipart upload cancelled");
+            self.abort_upload(&mut upload).await;
+            return Ok(());
+        }
+        if self.unalias(&arg.chunk.container_id) != upload.bucket
+            || self.key(&upload.bucket, &arg.chunk.object_id) != upload.key
+        {
+            return Err(RpcError::InvalidParameter(format!(
+                "stream {} uploads another object than Bucket({}) Object({})",
+                stream_id, upload.bucket, arg.chunk.object_id
+            )));
+        }
+        self.add_chunk(&mut upload, &arg.chunk).await
+    }
+
+    /// Buffers a chunk, uploads the parts that are complete, and completes the upload after the
+    /// last chunk. The upload is aborted if any of this fails.
+    async fn add_chunk(&self, upload: &mut MultipartUpload, chunk: &Chunk) -> RpcResult<()> {
+        upload.last_activity = Instant::now();
+        if !upload.append(chunk)? {
+            debug!(offset = %chunk.offset, "ignoring chunk that was already received");
+            return Ok(());
+        }
+        let result: RpcResult<()> = async {
+            while let Some((part_number, bytes)) = upload.take_part(chunk.is_last) {
+                self.upload_part(upload, part_number, bytes).await?;
+            }
+            if chunk.is_last {
+                self.complete_upload(upload).await?;
+            }
+            Ok(())
+        }
+        .await;
+        if result.is_err() {
+            self.abort_upload(upload).await;
+        }
+        result
+    }
+
+    async fn upload_part(
+        &self,
+        upload: &mut MultipartUpload,
+        part_number: i32,
+        bytes: Vec<u8>,
+    ) -> RpcResult<()> {
+        debug!(%part_number, part_len = %bytes.len(), "uploading part");
+        let output = self
+            .s3_client
+            .upload_part()
+            .bucket(&upload.bucket)
+            .key(&upload.key)
+            .encryption(self.encryption(&upload.bucket))
+            .upload_id(&upload.upload_id)
+            .part_number(part_number)
+            .body(ByteStream::from(bytes))
+            .send()
+            .await
+            .map_err(|e| {
+                error!(error = %e, %part_number, "Error uploading part");
+                RpcError::Other(e.to_string())
+            })?;
+        upload.parts.push(
+            CompletedPart::builder()
+                .set_e_tag(output.e_tag)
+                .part_number(part_number)
+                .build(),
+        );
+        Ok(())
+    }
+
+    async fn complete_upload(&self, upload: &mut MultipartUpload) -> RpcResult<()> {
+        self.s3_client
+            .complete_multipart_upload()
+            .bucket(&upload.bucket)
+            .key(&upload.key)
+            .upload_id(&upload.upload_id)
+            .multipart_upload(
+                CompletedMultipartUpload::builder()
+                    .set_parts(Some(upload.parts.clone()))
+                    .build(),
+            )
+            .send()
+            .await
+            .map_err(|e| {
+                error!(error = %e, "Error completing multipart upload");
+                RpcError::Other(e.to_string())
+            })?;
+        debug!(parts = %upload.parts.len(), bytes = %upload.next_offset, "multipart upload completed");
+        upload.finished = true;
+        self.uploads.write().await.remove(&upload.upload_id);
+        Ok(())
+    }
+
+    /// Aborts an upload, so that S3 removes its parts, and forgets it
+    pub(crate) async fn abort_upload(&self, upload: &mut MultipartUpload) {
+        self.uploads.write().await.remove(&upload.upload_id);
+        abort(&self.s3_client, upload).await;
+    }
+
+    /// Aborts all uploads in progress, when the link is removed
+    pub(crate) async fn abort_all_uploads(&self) {
+        let uploads: Vec<_> = self.uploads.write().await.drain().map(|(_, u)| u).collect();
+        for upload in uploads {
+            abort(&self.s3_client, &mut *upload.lock().await).await;
+        }
+    }
+
+    /// Starts the task that aborts uploads that have not received a chunk for the upload timeout.
+    /// The task ends when the client and all of its clones are dropped.
+    pub(crate) fn start_upload_reaper(&self) {
+        let (s3_client, timeout) = (self.s3_client.clone(), self.upload_timeout);
+        let uploads = Arc::downgrade(&self.uploads);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(REAPER_INTERVAL);
+            loop {
+                interval.tick().await;
+                match uploads.upgrade() {
+                    Some(uploads) => reap_uploads(&s3_client, &uploads, timeout).await,
+                    None => break,
+                }
+            }
+        });
+    }
+}
+
+/// Aborts an upload, so that S3 removes its parts
+async fn abort(s3_client: &aws_sdk_s3::Client, upload: &mut MultipartUpload) {
+    upload.finished = true;
+    if let Err(e) = s3_client
+        .abort_multipart_upload()
+        .bucket(&upload.bucket)
+        .key(&upload.key)
+        .upload_id(&upload.upload_id)
+        .send()
+        .await
+    {
+        error!(error = %e, upload_id = %upload.upload_id, "Error aborting multipart upload");
+    }
+}
+
+/// Aborts the uploads that have not received a chunk for `timeout`
+async fn reap_uploads(
+    s3_client: &aws_sdk_s3::Client,
+    uploads: &RwLock<UploadMap>,
+    timeout: Duration,
+) {
+    let in_progress: Vec<_> = uploads.read().await.values().cloned().collect();
+    for upload in in_progress {
+        // an upload busy sending a part is not abandoned
+        if let Ok(mut upload) = upload.try_lock() {
+            if !upload.finished && upload.last_activity.elapsed() > timeout {
+                info!(upload_id = %upload.upload_id, key = %upload.key, "aborting abandoned upload");
+                uploads.write().await.remove(&upload.upload_id);
+                abort(s3_client, &mut upload).await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn chunk(offset: u64, len: usize, is_last: bool) -> Chunk {
+        Chunk {
+            object_id: "object.1".to_string(),
+            container_id: "bucket".to_string(),
+            bytes: vec![7u8; len],
+            offset,
+            is_last,
+        }
+    }
+
+    #[test]
+    fn chunks_in_order() {
+        let mut upload = MultipartUpload::new("bucket", "object.1", "id");
+
+        assert!(upload.append(&chunk(0, 100, false)).unwrap());
+        assert!(upload.append(&chunk(100, 100, false)).unwrap());
+        // resent chunk
+        assert!(!upload.append(&chunk(0, 100, false)).unwrap());
+        // gap
+        assert!(upload.append(&chunk(300, 100, false)).is_err());
+        assert_eq!(upload.next_offset, 200);
+        assert_eq!(upload.buffer.len(), 200);
+    }
+
+    #[test]
+    fn parts_have_minimum_size() {
+        let mut upload = MultipartUpload::new("bucket", "object.1", "id");
+
+        upload.append(&chunk(0, MIN_PART_SIZE - 1, false)).unwrap();
+        assert!(upload.take_part(false).is_none());
+
+        upload
+            .append(&chunk(MIN_PART_SIZE as u64 - 1, 2, false))
+            .unwrap();
+        let (part_number, bytes) = upload.take_part(false).unwrap();
+        assert_eq!(part_number, 1);
+        assert_eq!(bytes.len(), MIN_PART_SIZE + 1);
+        upload
+            .parts
+            .push(CompletedPart::builder().part_number(1).build());
+
+        // the last part may be small, but is not empty unless it is the only one
+        assert!(upload.take_part(true).is_none());
+        upload
+            .append(&chunk(MIN_PART_SIZE as u64 + 1, 10, true))
+            .unwrap();
+        let (part_number, bytes) = upload.take_part(true).unwrap();
+        assert_eq!(part_number, 2);
+        assert_eq!(bytes.len(), 10);
+
+        let mut empty = MultipartUpload::new("bucket", "object.2", "id2");
+        empty.append(&chunk(0, 0, true)).unwrap();
+        assert_eq!(empty.take_part(true), Some((1, Vec::new())));
+    }
+}
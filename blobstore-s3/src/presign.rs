@@ -0,0 +1,170 @@
+//! Presigned URLs
+//!
+//! A presigned URL lets a client that has no AWS credentials, such as a browser, download or
+//! upload one object directly from or to S3, until the URL expires. The URL is signed with the
+//! link's credentials, so it grants no more than the link itself. Actors request URLs with
+//! `BlobstorePresign.PresignUrl` on the blobstore link, e.g., to redirect an http request to S3
+//! instead of streaming the object through the actor.
+
+use std::{collections::HashMap, time::Duration};
+
+use aws_sdk_s3::presigning::{config::PresigningConfig, request::PresignedRequest};
+use serde::{Deserialize, Serialize};
+use tracing::{error, instrument};
+use wasmbus_rpc::common::{deserialize, serialize};
+use wasmbus_rpc::provider::prelude::*;
+
+use crate::{encryption::EncryptRequest, Encryption, StorageClient};
+
+/// expiry of presigned URLs if not requested
+const DEFAULT_EXPIRY: Duration = Duration::from_secs(3600);
+
+/// longest expiry allowed by S3 signatures (7 days)
+const MAX_EXPIRY: Duration = Duration::from_secs(7 * 24 * 3600);
+
+/// Argument of `BlobstorePresign.PresignUrl`
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PresignRequest {
+    pub container_id: String,
+    pub object_id: String,
+    /// `GET` to download the object, or `PUT` to upload it
+    pub method: String,
+    /// seconds the URL is valid, at most 7 days (default 3600)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_in_secs: Option<u64>,
+    /// for `PUT`, the content type the client must send with the object
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
+}
+
+/// Result of `BlobstorePresign.PresignUrl`
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PresignedUrl {
+    pub url: String,
+    pub method: String,
+    /// headers the client must send with the request, e.g., `content-type`
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    /// seconds the URL is valid
+    pub expires_in_secs: u64,
+}
+
+/// Presigned URLs, an extension of the blobstore interface.
+/// Actors call it as `BlobstorePresign.PresignUrl` on the blobstore link.
+#[async_trait]
+pub trait BlobstorePresign {
+    /// Returns a URL to download or upload an object without credentials
+    async fn presign_url(&self, ctx: &Context, arg: &PresignRequest) -> RpcResult<PresignedUrl>;
+}
+
+/// Receives `BlobstorePresign` messages, like the receivers generated for smithy interfaces
+#[async_trait]
+pub trait BlobstorePresignReceiver: MessageDispatch + BlobstorePresign {
+    async fn dispatch(&self, ctx: &Context, message: Message<'_>) -> RpcResult<Vec<u8>> {
+        match message.method {
+            "PresignUrl" => {
+                let value: PresignRequest = deserialize(&message.arg)
+                    .map_err(|e| RpcError::Deser(format!("'PresignRequest': {}", e)))?;
+                let resp = BlobstorePresign::presign_url(self, ctx, &value).await?;
+                serialize(&resp)
+            }
+            _ => Err(RpcError::MethodNotHandled(format!(
+                "BlobstorePresign::{}",
+                message.method
+            ))),
+        }
+    }
+}
+
+/// Returns the requested expiry, or an error if S3 would not accept it
+fn expiry(expires_in_secs: Option<u64>) -> RpcResult<Duration> {
+    match expires_in_secs.map(Duration::from_secs) {
+        None => Ok(DEFAULT_EXPIRY),
+        Some(expiry) if expiry.is_zero() || expiry > MAX_EXPIRY => {
+            Err(RpcError::InvalidParameter(format!(
+                "expires_in_secs must be between 1 and {}",
+                MAX_EXPIRY.as_secs()
+            )))
+        }
+        Some(expiry) => Ok(expiry),
+    }
+}
+
+#[async_trait]
+impl BlobstorePresign for StorageClient {
+    #[instrument(level = "debug", skip(self, _ctx, arg), fields(actor_id = ?_ctx.actor, bucket_id = %self.unalias(&arg.container_id), object_id = %arg.object_id, method = %arg.method))]
+    async fn presign_url(&self, _ctx: &Context, arg: &PresignRequest) -> RpcResult<PresignedUrl> {
+        let bucket_id = self.bucket(&arg.container_id)?;
+        // the client would need the key to use a url for an object with a customer-provided key
+        let encryption = self.encryption(bucket_id);
+        if let Some(Encryption::SseC { .. }) = encryption {
+            return Err(RpcError::InvalidParameter(format!(
+                "presigned urls are not available for Bucket({}), which uses sse-c",
+                bucket_id
+            )));
+        }
+        let expires_in = expiry(arg.expires_in_secs)?;
+        let config = PresigningConfig::expires_in(expires_in)
+            .map_err(|e| RpcError::InvalidParameter(e.to_string()))?;
+        let presigned: Result<PresignedRequest, String> =
+            match arg.method.to_ascii_uppercase().as_str() {
+                "GET" => self
+                    .s3_client
+                    .get_object()
+                    .bucket(bucket_id)
+                    .key(self.key(bucket_id, &arg.object_id))
+                    .presigned(config)
+                    .await
+                    .map_err(|e| e.to_string()),
+                "PUT" => self
+                    .s3_client
+                    .put_object()
+                    .bucket(bucket_id)
+                    .key(self.key(bucket_id, &arg.object_id))
+                    .set_content_type(arg.content_type.clone())
+                    .encryption(encryption)
+                    .presigned(config)
+                    .await
+                    .map_err(|e| e.to_string()),
+                _ => {
+                    return Err(RpcError::InvalidParameter(format!(
+                        "method must be GET or PUT, got '{}'",
+                        arg.method
+                    )))
+                }
+            };
+        let presigned = presigned.map_err(|e| {
+            error!(error = %e, "Unable to presign request");
+            RpcError::Other(e)
+        })?;
+        Ok(PresignedUrl {
+            url: presigned.uri().to_string(),
+            method: presigned.method().to_string(),
+            headers: presigned
+                .headers()
+                .iter()
+                .filter_map(|(name, value)| {
+                    value
+                        .to_str()
+                        .ok()
+                        .map(|value| (name.to_string(), value.to_string()))
+                })
+                .collect(),
+            expires_in_secs: expires_in.as_secs(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn expiry_limits() {
+        assert_eq!(expiry(None).unwrap(), DEFAULT_EXPIRY);
+        assert_eq!(expiry(Some(60)).unwrap(), Duration::from_secs(60));
+        assert!(expiry(Some(0)).is_err());
+        assert!(expiry(Some(MAX_EXPIRY.as_secs())).is_ok());
+        assert!(expiry(Some(MAX_EXPIRY.as_secs() + 1)).is_err());
+    }
+}
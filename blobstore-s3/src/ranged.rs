@@ -0,0 +1,151 @@
+//! Parallel ranged downloads
+//!
+//! A single GetObject request delivers an object as fast as one connection allows. With
+//! `download_concurrency` above 1, `get_object` fetches objects larger than
+//! `download_part_size` in parts: the first part with the request that returns the first chunk,
+//! and the other parts with ranged GetObject requests, up to `download_concurrency` at a time.
+//! Parts are sent to the actor in order, so at most `download_concurrency` parts are held in
+//! memory for each download. Every part is requested with `If-Match` and the ETag of the object
+//! when the download started, so if the object is replaced during the download, the next part
+//! fails instead of mixing bytes of the old and the new object.
+
+use std::pin::Pin;
+
+use aws_sdk_s3::types::{ByteStream, SdkError};
+use bytes::Bytes;
+use futures::{Stream, StreamExt};
+use tracing::error;
+use wasmbus_rpc::error::{RpcError, RpcResult};
+
+use crate::{encryption::EncryptRequest, StorageClient};
+
+/// size of the parts of a parallel download, if not configured (8MB)
+pub(crate) const DEFAULT_DOWNLOAD_PART_SIZE: u64 = 8 * 1024 * 1024;
+
+/// The bytes of an object, in order
+pub type ObjectStream = Pin<Box<dyn Stream<Item = RpcResult<Bytes>> + Send>>;
+
+/// Returns the body of a GetObject response as an object stream
+pub(crate) fn body_stream(body: ByteStream) -> ObjectStream {
+    Box::pin(
+        body.map(|bytes| {
+            bytes.map_err(|e| RpcError::Other(format!("reading object from s3: {}", e)))
+        }),
+    )
+}
+
+/// Splits the bytes `start..=end` into ranges of at most `part_size` bytes
+fn part_ranges(start: u64, end: u64, part_size: u64) -> Vec<(u64, u64)> {
+    (start..=end)
+        .step_by(part_size as usize)
+        .map(|part_start| (part_start, (part_start + part_size - 1).min(end)))
+        .collect()
+}
+
+impl StorageClient {
+    /// Whether `get_object` should fetch `bytes_requested` bytes in parts
+    pub(crate) fn is_parallel_download(&self, bytes_requested: u64) -> bool {
+        self.download_concurrency > 1 && bytes_requested > self.download_part_size
+    }
+
+    /// Returns the bytes `start..=end` of an object, fetched in parts of `download_part_size`
+    /// with up to `download_concurrency` requests at a time, in order
+    pub async fn get_object_range(
+        &self,
+        container_id: &str,
+        object_id: &str,
+        start: u64,
+        end: u64,
+    ) -> RpcResult<ObjectStream> {
+        let bucket_id = self.bucket(container_id)?;
+        let key = self.key(bucket_id, object_id);
+        let head = self
+            .s3_client
+            .head_object()
+            .bucket(bucket_id)
+            .key(&key)
+            .encryption(self.encryption(bucket_id))
+            .send()
+            .await
+            .map_err(|e| {
+                error!(error = %e, "Error getting ETag of object");
+                RpcError::Other(e.to_string())
+            })?;
+        Ok(self.get_parts(bucket_id, &key, head.e_tag, start, end))
+    }
+
+    /// Returns the bytes `start..=end` of an S3 object, in parts, failing if its ETag no longer
+    /// matches `e_tag`
+    pub(crate) fn get_parts(
+        &self,
+        bucket_id: &str,
+        key: &str,
+        e_tag: Option<String>,
+        start: u64,
+        end: u64,
+    ) -> ObjectStream {
+        let this = self.clone();
+        let (bucket_id, key) = (bucket_id.to_string(), key.to_string());
+        let parts = futures::stream::iter(part_ranges(start, end, self.download_part_size))
+            .map(move |(part_start, part_end)| {
+                let (this, bucket_id, key, e_tag) =
+                    (this.clone(), bucket_id.clone(), key.clone(), e_tag.clone());
+                async move {
+                    this.get_part(&bucket_id, &key, e_tag, part_start, part_end)
+                        .await
+                }
+            })
+            .buffered(self.download_concurrency.max(1));
+        Box::pin(parts)
+    }
+
+    /// Fetches the bytes `start..=end` of an object with a single request
+    async fn get_part(
+        &self,
+        bucket_id: &str,
+        key: &str,
+        e_tag: Option<String>,
+        start: u64,
+        end: u64,
+    ) -> RpcResult<Bytes> {
+        let output = self
+            .s3_client
+            .get_object()
+            .bucket(bucket_id)
+            .key(key)
+            .encryption(self.encryption(bucket_id))
+            .set_if_match(e_tag)
+            .range(format!("bytes={}-{}", start, end))
+            .send()
+            .await
+            .map_err(|e| match &e {
+                SdkError::ServiceError { raw, .. } if raw.http().status().as_u16() == 412 => {
+                    error!(%start, %end, "object changed during download");
+                    RpcError::Other(format!(
+                        "object changed while downloading bytes {}-{}",
+                        start, end
+                    ))
+                }
+                _ => {
+                    error!(error = %e, %start, %end, "Error getting part of object");
+                    RpcError::Other(e.to_string())
+                }
+            })?;
+        let data = output.body.collect().await.map_err(|e| {
+            RpcError::Other(format!("reading part {}-{} from s3: {}", start, end, e))
+        })?;
+        Ok(data.into_bytes())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn ranges_of_parts() {
+        assert_eq!(part_ranges(0, 9, 4), vec![(0, 3), (4, 7), (8, 9)]);
+        assert_eq!(part_ranges(100, 107, 4), vec![(100, 103), (104, 107)]);
+        assert_eq!(part_ranges(5, 5, 4), vec![(5, 5)]);
+    }
+}
@@ -0,0 +1,411 @@
+//! Object tags, retention and legal holds
+//!
+//! Actors call the `BlobstoreRetention` service on the blobstore link to read and replace the
+//! tags of an object, to apply an Object Lock retention period, and to place or remove a legal
+//! hold. Retention and legal holds need a bucket created with Object Lock enabled, which
+//! `create_container` does when the link's `object_lock` setting is true. The lock state of an
+//! object is returned by `BlobstoreRetention.GetObjectLock`, and with the other metadata of the
+//! object by `BlobstoreMetadata.GetObjectMetadata`, because the interface's `ObjectMetadata`,
+//! returned by `get_object_info`, has no fields for it.
+
+use std::collections::HashMap;
+
+use aws_sdk_s3::{
+    model::{
+        ObjectLockLegalHold, ObjectLockLegalHoldStatus, ObjectLockMode, ObjectLockRetention,
+        ObjectLockRetentionMode, Tag, Tagging,
+    },
+    types::DateTime,
+};
+use serde::{Deserialize, Serialize};
+use tracing::{error, instrument};
+use wasmbus_rpc::common::{deserialize, serialize};
+use wasmbus_rpc::provider::prelude::*;
+use wasmbus_rpc::Timestamp;
+
+use crate::{
+    encryption::EncryptRequest, to_timestamp, wasmcloud_interface_blobstore::ContainerObject,
+    StorageClient,
+};
+
+/// most tags S3 accepts on an object
+const MAX_TAGS: usize = 10;
+
+/// longest tag key S3 accepts, in characters
+const MAX_TAG_KEY_LEN: usize = 128;
+
+/// longest tag value S3 accepts, in characters
+const MAX_TAG_VALUE_LEN: usize = 256;
+
+/// Tags of an object, by key
+pub type ObjectTags = HashMap<String, String>;
+
+/// Argument of `BlobstoreRetention.SetObjectTags`
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SetObjectTagsRequest {
+    pub container_id: String,
+    pub object_id: String,
+    /// tags of the object, replacing all its current tags
+    pub tags: ObjectTags,
+}
+
+/// Object Lock retention mode
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RetentionMode {
+    /// the object can't be overwritten or removed, except by users with the
+    /// `s3:BypassGovernanceRetention` permission
+    Governance,
+    /// the object can't be overwritten or removed by anyone until the retention date
+    Compliance,
+}
+
+/// Argument of `BlobstoreRetention.SetObjectRetention`
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SetObjectRetentionRequest {
+    pub container_id: String,
+    pub object_id: String,
+    pub mode: RetentionMode,
+    /// the object is locked until this time
+    pub retain_until: Timestamp,
+    /// whether a governance retention may be shortened, which needs the
+    /// `s3:BypassGovernanceRetention` permission
+    #[serde(default)]
+    pub bypass_governance: bool,
+}
+
+/// Argument of `BlobstoreRetention.SetLegalHold`
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SetLegalHoldRequest {
+    pub container_id: String,
+    pub object_id: String,
+    /// true to place a legal hold on the object, false to remove it
+    pub legal_hold: bool,
+}
+
+/// Object Lock state of an object, returned by `GetObjectLock` and in `ObjectMetadataWithHeaders`.
+/// S3 returns it only to credentials with the `s3:GetObjectRetention` and
+/// `s3:GetObjectLegalHold` permissions.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct ObjectLock {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retention_mode: Option<RetentionMode>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retain_until: Option<Timestamp>,
+    #[serde(default)]
+    pub legal_hold: bool,
+}
+
+impl ObjectLock {
+    pub(crate) fn from_head(
+        mode: Option<ObjectLockMode>,
+        retain_until: Option<DateTime>,
+        legal_hold: Option<ObjectLockLegalHoldStatus>,
+    ) -> ObjectLock {
+        ObjectLock {
+            retention_mode: match mode {
+                Some(ObjectLockMode::Governance) => Some(RetentionMode::Governance),
+                Some(ObjectLockMode::Compliance) => Some(RetentionMode::Compliance),
+                _ => None,
+            },
+            retain_until: to_timestamp(retain_until),
+            legal_hold: legal_hold == Some(ObjectLockLegalHoldStatus::On),
+        }
+    }
+}
+
+/// Checks that tags fit the limits of S3
+fn validate_tags(tags: &ObjectTags) -> RpcResult<()> {
+    if tags.len() > MAX_TAGS {
+        return Err(RpcError::InvalidParameter(format!(
+            "an object may have at most {} tags",
+            MAX_TAGS
+        )));
+    }
+    for (key, value) in tags.iter() {
+        if key.is_empty() || key.chars().count() > MAX_TAG_KEY_LEN {
+            return Err(RpcError::InvalidParameter(format!(
+                "invalid tag key '{}': it must have 1 to {} characters",
+                key, MAX_TAG_KEY_LEN
+            )));
+        }
+        if value.chars().count() > MAX_TAG_VALUE_LEN {
+            return Err(RpcError::InvalidParameter(format!(
+                "value of tag '{}' is longer than {} characters",
+                key, MAX_TAG_VALUE_LEN
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Tags, retention and legal holds of objects, an extension of the blobstore interface.
+/// Actors call them as `BlobstoreRetention.<Operation>` on the blobstore link.
+#[async_trait]
+pub trait BlobstoreRetention {
+    /// Returns the tags of an object
+    async fn get_object_tags(&self, ctx: &Context, arg: &ContainerObject) -> RpcResult<ObjectTags>;
+
+    /// Replaces the tags of an object
+    async fn set_object_tags(&self, ctx: &Context, arg: &SetObjectTagsRequest) -> RpcResult<()>;
+
+    /// Removes all tags of an object
+    async fn delete_object_tags(&self, ctx: &Context, arg: &ContainerObject) -> RpcResult<()>;
+
+    /// Locks an object until a date
+    async fn set_object_retention(
+        &self,
+        ctx: &Context,
+        arg: &SetObjectRetentionRequest,
+    ) -> RpcResult<()>;
+
+    /// Places or removes a legal hold on an object
+    async fn set_legal_hold(&self, ctx: &Context, arg: &SetLegalHoldRequest) -> RpcResult<()>;
+
+    /// Returns the retention and legal hold of an object
+    async fn get_object_lock(&self, ctx: &Context, arg: &ContainerObject) -> RpcResult<ObjectLock>;
+}
+
+/// Receives `BlobstoreRetention` messages, like the receivers generated for smithy interfaces
+#[async_trait]
+pub trait BlobstoreRetentionReceiver: MessageDispatch + BlobstoreRetention {
+    async fn dispatch(&self, ctx: &Context, message: Message<'_>) -> RpcResult<Vec<u8>> {
+        match message.method {
+            "GetObjectTags" => {
+                let value: ContainerObject = deserialize(&message.arg)
+                    .map_err(|e| RpcError::Deser(format!("'ContainerObject': {}", e)))?;
+                let resp = BlobstoreRetention::get_object_tags(self, ctx, &value).await?;
+                serialize(&resp)
+            }
+            "SetObjectTags" => {
+                let value: SetObjectTagsRequest = deserialize(&message.arg)
+                    .map_err(|e| RpcError::Deser(format!("'SetObjectTagsRequest': {}", e)))?;
+                BlobstoreRetention::set_object_tags(self, ctx, &value).await?;
+                Ok(Vec::new())
+            }
+            "DeleteObjectTags" => {
+                let value: ContainerObject = deserialize(&message.arg)
+                    .map_err(|e| RpcError::Deser(format!("'ContainerObject': {}", e)))?;
+                BlobstoreRetention::delete_object_tags(self, ctx, &value).await?;
+                Ok(Vec::new())
+            }
+            "SetObjectRetention" => {
+                let value: SetObjectRetentionRequest = deserialize(&message.arg)
+                    .map_err(|e| RpcError::Deser(format!("'SetObjectRetentionRequest': {}", e)))?;
+                BlobstoreRetention::set_object_retention(self, ctx, &value).await?;
+                Ok(Vec::new())
+            }
+            "SetLegalHold" => {
+                let value: SetLegalHoldRequest = deserialize(&message.arg)
+                    .map_err(|e| RpcError::Deser(format!("'SetLegalHoldRequest': {}", e)))?;
+                BlobstoreRetention::set_legal_hold(self, ctx, &value).await?;
+                Ok(Vec::new())
+            }
+            "GetObjectLock" => {
+                let value: ContainerObject = deserialize(&message.arg)
+                    .map_err(|e| RpcError::Deser(format!("'ContainerObject': {}", e)))?;
+                let resp = BlobstoreRetention::get_object_lock(self, ctx, &value).await?;
+                serialize(&resp)
+            }
+            _ => Err(RpcError::MethodNotHandled(format!(
+                "BlobstoreRetention::{}",
+                message.method
+            ))),
+        }
+    }
+}
+
+#[async_trait]
+impl BlobstoreRetention for StorageClient {
+    #[instrument(level = "debug", skip(self, _ctx, arg), fields(actor_id = ?_ctx.actor, bucket_id = %self.unalias(&arg.container_id), object_id = %arg.object_id))]
+    async fn get_object_tags(
+        &self,
+        _ctx: &Context,
+        arg: &ContainerObject,
+    ) -> RpcResult<ObjectTags> {
+        let bucket_id = self.bucket(&arg.container_id)?;
+        let output = self
+            .s3_client
+            .get_object_tagging()
+            .bucket(bucket_id)
+            .key(self.key(bucket_id, &arg.object_id))
+            .send()
+            .await
+            .map_err(|e| {
+                RpcError::Other(format!(
+                    "getting tags of Bucket({}) Object({}): {}",
+                    bucket_id, &arg.object_id, e
+                ))
+            })?;
+        Ok(output
+            .tag_set
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|tag| Some((tag.key?, tag.value.unwrap_or_default())))
+            .collect())
+    }
+
+    #[instrument(level = "debug", skip(self, _ctx, arg), fields(actor_id = ?_ctx.actor, bucket_id = %self.unalias(&arg.container_id), object_id = %arg.object_id))]
+    async fn set_object_tags(&self, _ctx: &Context, arg: &SetObjectTagsRequest) -> RpcResult<()> {
+        let bucket_id = self.bucket(&arg.container_id)?;
+        validate_tags(&arg.tags)?;
+        let tags = arg
+            .tags
+            .iter()
+            .map(|(key, value)| Tag::builder().key(key).value(value).build())
+            .collect();
+        self.s3_client
+            .put_object_tagging()
+            .bucket(bucket_id)
+            .key(self.key(bucket_id, &arg.object_id))
+            .tagging(Tagging::builder().set_tag_set(Some(tags)).build())
+            .send()
+            .await
+            .map_err(|e| {
+                error!(error = %e, "Error setting object tags");
+                RpcError::Other(e.to_string())
+            })?;
+        Ok(())
+    }
+
+    #[instrument(level = "debug", skip(self, _ctx, arg), fields(actor_id = ?_ctx.actor, bucket_id = %self.unalias(&arg.container_id), object_id = %arg.object_id))]
+    async fn delete_object_tags(&self, _ctx: &Context, arg: &ContainerObject) -> RpcResult<()> {
+        let bucket_id = self.bucket(&arg.container_id)?;
+        self.s3_client
+            .delete_object_tagging()
+            .bucket(bucket_id)
+            .key(self.key(bucket_id, &arg.object_id))
+            .send()
+            .await
+            .map_err(|e| {
+                error!(error = %e, "Error removing object tags");
+                RpcError::Other(e.to_string())
+            })?;
+        Ok(())
+    }
+
+    #[instrument(level = "debug", skip(self, _ctx, arg), fields(actor_id = ?_ctx.actor, bucket_id = %self.unalias(&arg.container_id), object_id = %arg.object_id, mode = ?arg.mode))]
+    async fn set_object_retention(
+        &self,
+        _ctx: &Context,
+        arg: &SetObjectRetentionRequest,
+    ) -> RpcResult<()> {
+        let bucket_id = self.bucket(&arg.container_id)?;
+        let mode = match arg.mode {
+            RetentionMode::Governance => ObjectLockRetentionMode::Governance,
+            RetentionMode::Compliance => ObjectLockRetentionMode::Compliance,
+        };
+        let retention = ObjectLockRetention::builder()
+            .mode(mode)
+            .retain_until_date(DateTime::from_secs_and_nanos(
+                arg.retain_until.sec,
+                arg.retain_until.nsec,
+            ))
+            .build();
+        self.s3_client
+            .put_object_retention()
+            .bucket(bucket_id)
+            .key(self.key(bucket_id, &arg.object_id))
+            .retention(retention)
+            .bypass_governance_retention(arg.bypass_governance)
+            .send()
+            .await
+            .map_err(|e| {
+                error!(error = %e, "Error setting object retention");
+                RpcError::Other(e.to_string())
+            })?;
+        Ok(())
+    }
+
+    #[instrument(level = "debug", skip(self, _ctx, arg), fields(actor_id = ?_ctx.actor, bucket_id = %self.unalias(&arg.container_id), object_id = %arg.object_id, legal_hold = %arg.legal_hold))]
+    async fn set_legal_hold(&self, _ctx: &Context, arg: &SetLegalHoldRequest) -> RpcResult<()> {
+        let bucket_id = self.bucket(&arg.container_id)?;
+        let status = if arg.legal_hold {
+            ObjectLockLegalHoldStatus::On
+        } else {
+            ObjectLockLegalHoldStatus::Off
+        };
+        self.s3_client
+            .put_object_legal_hold()
+            .bucket(bucket_id)
+            .key(self.key(bucket_id, &arg.object_id))
+            .legal_hold(ObjectLockLegalHold::builder().status(status).build())
+            .send()
+            .await
+            .map_err(|e| {
+                error!(error = %e, "Error setting legal hold");
+                RpcError::Other(e.to_string())
+            })?;
+        Ok(())
+    }
+
+    #[instrument(level = "debug", skip(self, _ctx, arg), fields(actor_id = ?_ctx.actor, bucket_id = %self.unalias(&arg.container_id), object_id = %arg.object_id))]
+    async fn get_object_lock(
+        &self,
+        _ctx: &Context,
+        arg: &ContainerObject,
+    ) -> RpcResult<ObjectLock> {
+        let bucket_id = self.bucket(&arg.container_id)?;
+        let head = self
+            .s3_client
+            .head_object()
+            .bucket(bucket_id)
+            .key(self.key(bucket_id, &arg.object_id))
+            .encryption(self.encryption(bucket_id))
+            .send()
+            .await
+            .map_err(|e| {
+                RpcError::Other(format!(
+                    "getting lock of Bucket({}) Object({}): {}",
+                    bucket_id, &arg.object_id, e
+                ))
+            })?;
+        Ok(ObjectLock::from_head(
+            head.object_lock_mode,
+            head.object_lock_retain_until_date,
+            head.object_lock_legal_hold_status,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn tag_limits() {
+        let tags = |pairs: &[(&str, &str)]| -> ObjectTags {
+            pairs
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect()
+        };
+        assert!(validate_tags(&tags(&[])).is_ok());
+        assert!(validate_tags(&tags(&[("retention", "7y"), ("owner", "")])).is_ok());
+        assert!(validate_tags(&tags(&[("", "x")])).is_err(), "empty key");
+        let long_key = "k".repeat(MAX_TAG_KEY_LEN + 1);
+        assert!(validate_tags(&tags(&[(&long_key, "x")])).is_err());
+        let long_value = "v".repeat(MAX_TAG_VALUE_LEN + 1);
+        assert!(validate_tags(&tags(&[("k", &long_value)])).is_err());
+        let many: ObjectTags = (0..=MAX_TAGS)
+            .map(|n| (n.to_string(), String::new()))
+            .collect();
+        assert!(validate_tags(&many).is_err(), "too many tags");
+    }
+
+    #[test]
+    fn lock_state() {
+        let lock = ObjectLock::from_head(
+            Some(ObjectLockMode::Compliance),
+            Some(DateTime::from_secs(1_900_000_000)),
+            Some(ObjectLockLegalHoldStatus::On),
+        );
+        assert_eq!(lock.retention_mode, Some(RetentionMode::Compliance));
+        assert_eq!(lock.retain_until.map(|t| t.sec), Some(1_900_000_000));
+        assert!(lock.legal_hold);
+        assert_eq!(
+            ObjectLock::from_head(None, None, Some(ObjectLockLegalHoldStatus::Off)),
+            ObjectLock::default()
+        );
+    }
+}
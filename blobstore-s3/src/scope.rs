@@ -0,0 +1,217 @@
+//! Bucket allow-list and key prefixes
+//!
+//! With the `allowed_buckets` setting, a link can only use the buckets listed, by name or alias,
+//! and every other bucket is reported as not allowed. A bucket may have a key `prefix`: the
+//! actor's object ids are keys below the prefix, which is prepended to object ids in requests and
+//! stripped from the keys in results, so the actor never sees it, and objects outside the prefix
+//! can't be reached or listed. A prefix must end with `/`, so that `tenant-a/` doesn't also match
+//! the keys of `tenant-ab/`. Buckets with a prefix are usually shared, so they can't be removed with
+//! `remove_containers`. Without `allowed_buckets`, all buckets the credentials can reach are
+//! allowed, without a prefix.
+
+use std::collections::HashMap;
+
+use aws_sdk_s3::model::Object;
+use serde::Deserialize;
+use wasmbus_rpc::error::{RpcError, RpcResult};
+
+use crate::{to_timestamp, wasmcloud_interface_blobstore::ObjectMetadata, StorageClient};
+
+/// Settings of a bucket in the allow-list
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+pub struct BucketScope {
+    /// prefix of the keys of the link's objects, e.g., `tenant-a/`
+    #[serde(default)]
+    pub prefix: Option<String>,
+}
+
+impl BucketScope {
+    pub fn validate(&self, bucket: &str) -> RpcResult<()> {
+        match self.prefix.as_deref() {
+            Some(prefix) if prefix.starts_with('/') || !prefix.ends_with('/') => {
+                Err(RpcError::InvalidParameter(format!(
+                    "prefix of Bucket({}) must end with '/' and not start with '/'",
+                    bucket
+                )))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// The buckets a link may use, by bucket name, or None if it may use all buckets
+pub(crate) type Scopes = Option<HashMap<String, BucketScope>>;
+
+impl StorageClient {
+    /// Returns the bucket of a container id, after alias lookup, if the link may use it
+    pub(crate) fn bucket<'n, 's: 'n>(&'s self, container_id: &'n str) -> RpcResult<&'n str> {
+        let bucket_id = self.unalias(container_id);
+        if self.is_allowed(bucket_id) {
+            Ok(bucket_id)
+        } else {
+            Err(RpcError::InvalidParameter(format!(
+                "Bucket({}) is not allowed for this link",
+                bucket_id
+            )))
+        }
+    }
+
+    pub(crate) fn is_allowed(&self, bucket_id: &str) -> bool {
+        match self.scopes.as_ref() {
+            Some(scopes) => scopes.contains_key(bucket_id),
+            None => true,
+        }
+    }
+
+    /// Returns the key prefix of a bucket, or "" if it has none
+    pub(crate) fn prefix(&self, bucket_id: &str) -> &str {
+        self.scopes
+            .as_ref()
+            .and_then(|scopes| scopes.get(bucket_id))
+            .and_then(|scope| scope.prefix.as_deref())
+            .unwrap_or_default()
+    }
+
+    /// Returns the S3 key of an object
+    pub(crate) fn key(&self, bucket_id: &str, object_id: &str) -> String {
+        format!("{}{}", self.prefix(bucket_id), object_id)
+    }
+
+    /// Returns the object id of an S3 key, or None if the key is outside the bucket's prefix
+    pub(crate) fn object_id<'k>(&self, bucket_id: &str, key: &'k str) -> Option<&'k str> {
+        key.strip_prefix(self.prefix(bucket_id))
+    }
+
+    /// Returns the metadata of listed objects, skipping the keys outside the bucket's prefix
+    pub(crate) fn listed_objects(&self, bucket_id: &str, items: &[Object]) -> Vec<ObjectMetadata> {
+        items
+            .iter()
+            .filter_map(|o| {
+                let object_id = self.object_id(bucket_id, o.key.as_deref()?)?;
+                Some(ObjectMetadata {
+                    container_id: bucket_id.to_string(),
+                    last_modified: to_timestamp(o.last_modified),
+                    object_id: object_id.to_string(),
+                    content_length: o.size as u64,
+                    content_encoding: None,
+                    content_type: None,
+                })
+            })
+            .collect()
+    }
+}
+
+/// Resolves the aliases in the names of allowed buckets
+pub(crate) fn resolve(
+    client: &StorageClient,
+    allowed: Option<HashMap<String, BucketScope>>,
+) -> Scopes {
+    allowed.map(|allowed| {
+        allowed
+            .into_iter()
+            .map(|(name, scope)| (client.unalias(&name).to_string(), scope))
+            .collect()
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::StorageConfig;
+    use aws_sdk_s3::types::DateTime;
+
+    fn scoped(prefix: &str) -> Scopes {
+        Some(HashMap::from([
+            (
+                "tenants".to_string(),
+                BucketScope {
+                    prefix: Some(prefix.to_string()),
+                },
+            ),
+            ("private".to_string(), BucketScope::default()),
+        ]))
+    }
+
+    #[tokio::test]
+    async fn keys_and_object_ids() {
+        let mut client = StorageClient::new(StorageConfig::default(), Default::default()).await;
+        client.scopes = std::sync::Arc::new(scoped("tenant-a/"));
+
+        assert_eq!(client.bucket("tenants").unwrap(), "tenants");
+        assert_eq!(client.bucket("private").unwrap(), "private");
+        assert!(matches!(
+            client.bucket("other"),
+            Err(RpcError::InvalidParameter(_))
+        ));
+        assert!(!client.is_allowed("tenant-a"));
+
+        assert_eq!(client.prefix("tenants"), "tenant-a/");
+        assert_eq!(client.prefix("private"), "");
+        assert_eq!(client.key("tenants", "x.txt"), "tenant-a/x.txt");
+        assert_eq!(client.key("private", "x.txt"), "x.txt");
+        assert_eq!(client.object_id("tenants", "tenant-a/x.txt"), Some("x.txt"));
+        assert_eq!(client.object_id("tenants", "tenant-ab/x.txt"), None);
+        assert_eq!(
+            client.object_id("private", "tenant-a/x.txt"),
+            Some("tenant-a/x.txt")
+        );
+    }
+
+    #[tokio::test]
+    async fn listing_strips_prefix() {
+        let mut client = StorageClient::new(StorageConfig::default(), Default::default()).await;
+        client.scopes = std::sync::Arc::new(scoped("tenant-a/"));
+
+        let items = vec![
+            Object::builder()
+                .key("tenant-a/x.txt")
+                .size(3)
+                .last_modified(DateTime::from_secs(1_000))
+                .build(),
+            Object::builder().key("tenant-b/y.txt").size(4).build(),
+            Object::builder().key("tenant-a/dir/z.txt").size(5).build(),
+            Object::builder().size(6).build(),
+        ];
+        let listed = client.listed_objects("tenants", &items);
+        assert_eq!(
+            listed
+                .iter()
+                .map(|o| (o.object_id.as_str(), o.content_length))
+                .collect::<Vec<_>>(),
+            vec![("x.txt", 3), ("dir/z.txt", 5)]
+        );
+        assert!(listed.iter().all(|o| o.container_id == "tenants"));
+        assert_eq!(listed[0].last_modified.as_ref().unwrap().sec, 1_000);
+
+        let listed = client.listed_objects("private", &items[..2]);
+        assert_eq!(listed[0].object_id, "tenant-a/x.txt");
+        assert_eq!(listed[1].object_id, "tenant-b/y.txt");
+    }
+
+    #[test]
+    fn prefix_must_end_with_slash() {
+        let scope = |prefix: Option<&str>| BucketScope {
+            prefix: prefix.map(str::to_string),
+        };
+        assert!(scope(None).validate("b").is_ok());
+        assert!(scope(Some("tenant-a/")).validate("b").is_ok());
+        assert!(scope(Some("a/b/")).validate("b").is_ok());
+        assert!(scope(Some("tenant-a")).validate("b").is_err());
+        assert!(scope(Some("")).validate("b").is_err());
+        assert!(scope(Some("/tenant-a/")).validate("b").is_err());
+
+        let values = HashMap::from([(
+            "config_json".to_string(),
+            r#"{"allowed_buckets":{"tenants":{"prefix":"tenant-a"}}}"#.to_string(),
+        )]);
+        assert!(matches!(
+            StorageConfig::from_values(&values),
+            Err(RpcError::InvalidParameter(_))
+        ));
+        let values = HashMap::from([(
+            "config_json".to_string(),
+            r#"{"allowed_buckets":{"tenants":{"prefix":"tenant-a/"}}}"#.to_string(),
+        )]);
+        assert!(StorageConfig::from_values(&values).is_ok());
+    }
+}
@@ -1,9 +1,26 @@
-use std::iter::repeat_with;
+use std::{
+    env,
+    iter::repeat_with,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use blobstore_s3_lib::{
+    wasmcloud_interface_blobstore as s3_blobstore, StorageClient, StorageConfig,
+};
+use futures::StreamExt;
+use s3_blobstore::{Blobstore as _, ChunkReceiver, ChunkReceiverReceiver, ChunkResponse};
+use tokio::sync::{Mutex, Notify};
 use tracing::debug;
 #[allow(unused_imports)]
 use wasmbus_rpc::{
-    common::{Context, Message, Transport},
+    common::{deserialize, serialize, Context, Message, MessageDispatch, Transport},
+    core::{HostData, Invocation, InvocationResponse, LinkDefinition},
     error::{RpcError, RpcResult},
+    provider::{
+        init_host_bridge_for_test,
+        prelude::{async_trait, Provider},
+    },
 };
 use wasmcloud_interface_blobstore::{Blobstore, BlobstoreSender, Chunk, PutObjectRequest};
 //use wasmcloud_interface_blobstore::{Blobstore, BlobstoreSender};
@@ -182,3 +199,207 @@ async fn get_set_large(_opt: &TestOptions) -> RpcResult<()> {
         .expect("remove container");
     Ok(())
 }
+
+/// lattice and actor of the chunk receiver in the download benchmark
+const BENCH_LATTICE: &str = "blobstore-s3-bench";
+const BENCH_ACTOR: &str = "MBENCHCHUNKRECEIVER";
+/// largest chunk sent to the actor, below the size at which rpc messages are chunked through nats
+const BENCH_CHUNK_SIZE: usize = 512 * 1024;
+
+/// Receives the chunks of a download, in place of an actor
+#[derive(Clone, Default, Provider)]
+#[services(ChunkReceiver)]
+struct ChunkCounter {
+    /// number of bytes received, and their checksum
+    received: Arc<Mutex<(u64, crc32fast::Hasher)>>,
+    /// notified when the last chunk is received
+    done: Arc<Notify>,
+}
+
+#[async_trait]
+impl ChunkReceiver for ChunkCounter {
+    async fn receive_chunk(
+        &self,
+        _ctx: &Context,
+        arg: &s3_blobstore::Chunk,
+    ) -> RpcResult<ChunkResponse> {
+        let mut received = self.received.lock().await;
+        received.0 += arg.bytes.len() as u64;
+        received.1.update(&arg.bytes);
+        if arg.is_last {
+            self.done.notify_one();
+        }
+        Ok(ChunkResponse {
+            cancel_download: false,
+        })
+    }
+}
+
+/// Connects the provider's rpc client to nats, and answers the rpc messages sent to the
+/// benchmark actor with `actor`
+async fn start_actor(actor: ChunkCounter) {
+    let mut host_data = HostData::default();
+    host_data.host_id = "_TEST_".to_string();
+    host_data.lattice_rpc_prefix = BENCH_LATTICE.to_string();
+    host_data.lattice_rpc_url = env::var("NATS_URL").unwrap_or_default();
+    let nc = host_data.nats_connect().await.expect("connect to nats");
+    init_host_bridge_for_test(nc.clone(), &host_data).expect("init host bridge");
+
+    let mut sub = nc
+        .subscribe(format!("wasmbus.rpc.{}.{}", BENCH_LATTICE, BENCH_ACTOR))
+        .await
+        .expect("subscribe to actor rpc");
+    nc.flush().await.expect("flush subscription");
+    tokio::spawn(async move {
+        while let Some(msg) = sub.next().await {
+            let inv = match deserialize::<Invocation>(&msg.payload) {
+                Ok(inv) => inv,
+                Err(e) => {
+                    eprintln!("invalid invocation: {}", e);
+                    continue;
+                }
+            };
+            let mut resp = InvocationResponse::default();
+            resp.invocation_id = inv.id.clone();
+            let message = Message {
+                method: &inv.operation,
+                arg: inv.msg.into(),
+            };
+            match MessageDispatch::dispatch(&actor, &Context::default(), message).await {
+                Ok(bytes) => {
+                    resp.content_length = Some(bytes.len() as u64);
+                    resp.msg = bytes;
+                }
+                Err(e) => resp.error = Some(e.to_string()),
+            }
+            if let Some(reply) = msg.reply {
+                let resp = serialize(&resp).expect("serialize response");
+                nc.publish(reply, resp.into()).await.ok();
+            }
+        }
+    });
+}
+
+/// Returns a client of the local S3 stand-in, linked to the benchmark actor, that downloads in
+/// parts of `part_size` bytes, `concurrency` at a time
+async fn ranged_client(part_size: u64, concurrency: usize) -> StorageClient {
+    let conf = StorageConfig {
+        endpoint: env::var("AWS_ENDPOINT").ok(),
+        access_key_id: env::var("AWS_ACCESS_KEY_ID").ok(),
+        secret_access_key: env::var("AWS_SECRET_ACCESS_KEY").ok(),
+        download_part_size: Some(part_size),
+        download_concurrency: Some(concurrency),
+        ..Default::default()
+    };
+    let mut ld = LinkDefinition::default();
+    ld.actor_id = BENCH_ACTOR.to_string();
+    ld.provider_id = "VBENCHBLOBSTORES3".to_string();
+    StorageClient::new(conf, ld).await
+}
+
+/// Downloads an object with `get_object`, returning the time until the actor received the last
+/// chunk, and the checksum of all bytes received
+async fn download(
+    client: &StorageClient,
+    actor: &ChunkCounter,
+    bucket: &str,
+    name: &str,
+    len: u64,
+) -> (Duration, u32) {
+    *actor.received.lock().await = Default::default();
+    let start = Instant::now();
+    let resp = client
+        .get_object(
+            &Context::default(),
+            &s3_blobstore::GetObjectRequest {
+                container_id: bucket.to_string(),
+                object_id: name.to_string(),
+                range_start: None,
+                range_end: None,
+            },
+        )
+        .await
+        .expect("get object");
+    let first = resp.initial_chunk.expect("initial chunk");
+    if !first.is_last {
+        tokio::time::timeout(Duration::from_secs(300), actor.done.notified())
+            .await
+            .expect("actor should receive the last chunk");
+    }
+    let elapsed = start.elapsed();
+
+    let received = actor.received.lock().await;
+    assert_eq!(first.bytes.len() as u64 + received.0, len, "bytes received");
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&first.bytes);
+    hasher.combine(&received.1);
+    (elapsed, hasher.finalize())
+}
+
+/// Compares the throughput of serial and parallel downloads of a 64MB object, from `get_object`
+/// until the actor received the last chunk with `ChunkReceiver.ReceiveChunk`.
+/// Needs a local S3 stand-in (e.g., minio at AWS_ENDPOINT) and nats (at NATS_URL, or the default
+/// address); run with `cargo test --test large_data -- --ignored --nocapture parallel_download`
+#[tokio::test]
+#[ignore]
+async fn parallel_download_throughput() {
+    const LEN: usize = 64 * 1024 * 1024;
+    const PART_SIZE: u64 = 8 * 1024 * 1024;
+
+    env::set_var("MAX_CHUNK_SIZE", BENCH_CHUNK_SIZE.to_string());
+    let actor = ChunkCounter::default();
+    start_actor(actor.clone()).await;
+    let serial = ranged_client(PART_SIZE, 1).await;
+    let parallel = ranged_client(PART_SIZE, 8).await;
+    let ctx = Context::default();
+    let bucket = format!("test.ranged.{}", rand::random::<u64>());
+    serial.create_container(&ctx, &bucket).await.unwrap();
+
+    let (bytes, sum) = gen_bytes(LEN);
+    serial
+        .put_object(
+            &ctx,
+            &s3_blobstore::PutObjectRequest {
+                chunk: s3_blobstore::Chunk {
+                    bytes,
+                    container_id: bucket.clone(),
+                    is_last: true,
+                    object_id: "arr64MB".to_string(),
+                    offset: 0,
+                },
+                content_encoding: None,
+                content_type: Some(TYPE_OCTET_STREAM.to_string()),
+            },
+        )
+        .await
+        .expect("put object");
+
+    let mb = LEN as f64 / (1024.0 * 1024.0);
+    let (serial_time, serial_sum) = download(&serial, &actor, &bucket, "arr64MB", LEN as u64).await;
+    let (parallel_time, parallel_sum) =
+        download(&parallel, &actor, &bucket, "arr64MB", LEN as u64).await;
+    assert_eq!(serial_sum, sum, "serial download checksum");
+    assert_eq!(parallel_sum, sum, "parallel download checksum");
+    println!(
+        "64MB in 8MB parts, sent in {}KB chunks: serial {:.1} MB/s, 8 parallel {:.1} MB/s ({:.2}x)",
+        BENCH_CHUNK_SIZE / 1024,
+        mb / serial_time.as_secs_f64(),
+        mb / parallel_time.as_secs_f64(),
+        serial_time.as_secs_f64() / parallel_time.as_secs_f64()
+    );
+
+    serial
+        .remove_objects(
+            &ctx,
+            &s3_blobstore::RemoveObjectsRequest {
+                container_id: bucket.clone(),
+                objects: vec!["arr64MB".to_string()],
+            },
+        )
+        .await
+        .expect("remove object");
+    serial
+        .remove_containers(&ctx, &vec![bucket])
+        .await
+        .expect("remove container");
+}
@@ -1,6 +1,13 @@
-use std::env;
+use std::{collections::HashMap, env};
 
-use blobstore_s3_lib::{wasmcloud_interface_blobstore::*, StorageClient, StorageConfig};
+use blobstore_s3_lib::{
+    wasmcloud_interface_blobstore::*, BlobstoreCopy, BlobstoreMetadata, BlobstorePresign,
+    BlobstoreRetention, BucketScope, CopyObjectRequest, Encryption, ObjectHeaders, ObjectLock,
+    PresignRequest, PutObjectWithMetadataRequest, RetentionMode, SetLegalHoldRequest,
+    SetObjectRetentionRequest, SetObjectTagsRequest, StorageClient, StorageConfig,
+};
+use futures::StreamExt;
+use wasmbus_rpc::Timestamp;
 
 /// Helper function to create a StorageClient with local testing overrides
 async fn test_client() -> StorageClient {
@@ -14,6 +21,39 @@ async fn test_client() -> StorageClient {
     StorageClient::new(conf, Default::default()).await
 }
 
+/// Helper function to create a StorageClient limited to one bucket and key prefix
+async fn scoped_client(bucket: &str, prefix: &str) -> StorageClient {
+    let conf = StorageConfig {
+        endpoint: env::var("AWS_ENDPOINT").ok(),
+        access_key_id: env::var("AWS_ACCESS_KEY_ID").ok(),
+        secret_access_key: env::var("AWS_SECRET_ACCESS_KEY").ok(),
+        allowed_buckets: Some(HashMap::from([(
+            bucket.to_string(),
+            BucketScope {
+                prefix: Some(prefix.to_string()),
+            },
+        )])),
+        ..Default::default()
+    };
+
+    StorageClient::new(conf, Default::default()).await
+}
+
+/// Helper function to create a StorageClient encrypting objects with a customer-provided key
+async fn sse_c_client(key: &[u8; 32]) -> StorageClient {
+    let conf = StorageConfig {
+        endpoint: env::var("AWS_ENDPOINT").ok(),
+        access_key_id: env::var("AWS_ACCESS_KEY_ID").ok(),
+        secret_access_key: env::var("AWS_SECRET_ACCESS_KEY").ok(),
+        encryption: Some(Encryption::SseC {
+            key: base64::encode(key),
+        }),
+        ..Default::default()
+    };
+
+    StorageClient::new(conf, Default::default()).await
+}
+
 /// Tests
 /// - create_container
 /// - remove_container
@@ -331,3 +371,845 @@ async fn test_get_object_chunks() {
         .expect("get-object-chunk");
     assert_eq!(obj.initial_chunk.unwrap().bytes.len(), 300);
 }
+
+/// Tests
+/// - put_object and put_chunk with a multipart upload
+/// - cancel_and_remove of an upload in progress
+#[tokio::test]
+async fn test_multipart_upload() {
+    let s3 = test_client().await;
+    let ctx = wasmbus_rpc::common::Context::default();
+    let num = rand::random::<u64>();
+    let bucket = format!("test.multipart.{}", num);
+
+    s3.create_container(&ctx, &bucket).await.unwrap();
+
+    // the first part is larger than the 5MB minimum, the last is smaller
+    let first = b"abcdefghijklmnopqrstuvwxy".repeat(240 * 1024);
+    let last = b"0123456789".repeat(1000);
+    let chunk = |bytes: &[u8], offset: u64, is_last: bool| Chunk {
+        bytes: bytes.to_vec(),
+        container_id: bucket.clone(),
+        is_last,
+        object_id: "object.1".to_string(),
+        offset,
+    };
+    let resp = s3
+        .put_object(
+            &ctx,
+            &PutObjectRequest {
+                chunk: chunk(&first, 0, false),
+                content_encoding: None,
+                content_type: None,
+            },
+        )
+        .await
+        .expect("put object");
+    assert!(resp.stream_id.is_some());
+
+    s3.put_chunk(
+        &ctx,
+        &PutChunkRequest {
+            chunk: chunk(&last, first.len() as u64, true),
+            stream_id: resp.stream_id.clone(),
+            cancel_and_remove: false,
+        },
+    )
+    .await
+    .expect("put last chunk");
+
+    let meta = s3
+        .get_object_info(
+            &ctx,
+            &ContainerObject {
+                container_id: bucket.clone(),
+                object_id: "object.1".to_string(),
+            },
+        )
+        .await
+        .expect("get object info");
+    assert_eq!(meta.content_length as usize, first.len() + last.len());
+
+    // the upload is finished, so its stream id is no longer valid
+    assert!(s3
+        .put_chunk(
+            &ctx,
+            &PutChunkRequest {
+                chunk: chunk(&last, first.len() as u64, true),
+                stream_id: resp.stream_id,
+                cancel_and_remove: false,
+            },
+        )
+        .await
+        .is_err());
+
+    // a cancelled upload stores nothing
+    let resp = s3
+        .put_object(
+            &ctx,
+            &PutObjectRequest {
+                chunk: Chunk {
+                    object_id: "object.2".to_string(),
+                    ..chunk(&last, 0, false)
+                },
+                content_encoding: None,
+                content_type: None,
+            },
+        )
+        .await
+        .expect("put object");
+    s3.put_chunk(
+        &ctx,
+        &PutChunkRequest {
+            chunk: Chunk {
+                object_id: "object.2".to_string(),
+                ..chunk(&[], last.len() as u64, true)
+            },
+            stream_id: resp.stream_id,
+            cancel_and_remove: true,
+        },
+    )
+    .await
+    .expect("cancel upload");
+    assert!(!s3
+        .object_exists(
+            &ctx,
+            &ContainerObject {
+                container_id: bucket.clone(),
+                object_id: "object.2".to_string(),
+            },
+        )
+        .await
+        .unwrap());
+
+    s3.remove_objects(
+        &ctx,
+        &RemoveObjectsRequest {
+            container_id: bucket.clone(),
+            objects: vec!["object.1".to_string()],
+        },
+    )
+    .await
+    .expect("remove object");
+    s3.remove_containers(&ctx, &vec![bucket])
+        .await
+        .expect("remove containers");
+}
+
+/// Tests
+/// - content type and encoding of put_object returned by get_object_info and get_object
+/// - cache-control and user metadata of put_object_with_metadata
+#[tokio::test]
+async fn test_object_headers() {
+    let s3 = test_client().await;
+    let ctx = wasmbus_rpc::common::Context::default();
+    let num = rand::random::<u64>();
+    let bucket = format!("test.headers.{}", num);
+
+    s3.create_container(&ctx, &bucket).await.unwrap();
+    let chunk = |object_id: &str| Chunk {
+        bytes: b"<html></html>".to_vec(),
+        container_id: bucket.clone(),
+        is_last: true,
+        object_id: object_id.to_string(),
+        offset: 0,
+    };
+    s3.put_object(
+        &ctx,
+        &PutObjectRequest {
+            chunk: chunk("index.html"),
+            content_encoding: Some("identity".to_string()),
+            content_type: Some("text/html".to_string()),
+        },
+    )
+    .await
+    .expect("put object");
+    let object = ContainerObject {
+        container_id: bucket.clone(),
+        object_id: "index.html".to_string(),
+    };
+    let info = s3.get_object_info(&ctx, &object).await.expect("info");
+    assert_eq!(info.content_type.as_deref(), Some("text/html"));
+    assert_eq!(info.content_encoding.as_deref(), Some("identity"));
+    let obj = s3
+        .get_object(
+            &ctx,
+            &GetObjectRequest {
+                container_id: bucket.clone(),
+                object_id: "index.html".to_string(),
+                ..Default::default()
+            },
+        )
+        .await
+        .expect("get object");
+    assert_eq!(obj.content_type.as_deref(), Some("text/html"));
+
+    let headers = ObjectHeaders {
+        content_type: Some("text/html".to_string()),
+        cache_control: Some("max-age=3600".to_string()),
+        metadata: [("owner".to_string(), "team-a".to_string())].into(),
+        ..Default::default()
+    };
+    s3.put_object_with_metadata(
+        &ctx,
+        &PutObjectWithMetadataRequest {
+            chunk: chunk("cached.html"),
+            headers: headers.clone(),
+        },
+    )
+    .await
+    .expect("put object with metadata");
+    let meta = s3
+        .get_object_metadata(
+            &ctx,
+            &ContainerObject {
+                container_id: bucket.clone(),
+                object_id: "cached.html".to_string(),
+            },
+        )
+        .await
+        .expect("get object metadata");
+    assert_eq!(meta.headers, headers);
+    assert_eq!(meta.content_length, 13);
+
+    s3.remove_objects(
+        &ctx,
+        &RemoveObjectsRequest {
+            container_id: bucket.clone(),
+            objects: vec!["index.html".to_string(), "cached.html".to_string()],
+        },
+    )
+    .await
+    .expect("remove objects");
+    s3.remove_containers(&ctx, &vec![bucket])
+        .await
+        .expect("remove containers");
+}
+
+/// Tests
+/// - presigned PUT and GET urls, used without credentials
+#[tokio::test]
+async fn test_presigned_urls() {
+    let s3 = test_client().await;
+    let ctx = wasmbus_rpc::common::Context::default();
+    let num = rand::random::<u64>();
+    let bucket = format!("test.presign.{}", num);
+    s3.create_container(&ctx, &bucket).await.unwrap();
+
+    let presign = |method: &str| PresignRequest {
+        container_id: bucket.clone(),
+        object_id: "object.1".to_string(),
+        method: method.to_string(),
+        expires_in_secs: Some(300),
+        content_type: Some("text/plain".to_string()),
+    };
+    let http = reqwest::Client::new();
+
+    let put = s3
+        .presign_url(&ctx, &presign("PUT"))
+        .await
+        .expect("presign put");
+    assert_eq!(put.method, "PUT");
+    let resp = http
+        .put(&put.url)
+        .header("content-type", "text/plain")
+        .body("uploaded directly")
+        .send()
+        .await
+        .expect("put with presigned url");
+    assert!(resp.status().is_success(), "put status {}", resp.status());
+
+    let get = s3
+        .presign_url(&ctx, &presign("GET"))
+        .await
+        .expect("presign get");
+    let resp = http
+        .get(&get.url)
+        .send()
+        .await
+        .expect("get with presigned url");
+    assert!(resp.status().is_success(), "get status {}", resp.status());
+    assert_eq!(resp.text().await.unwrap(), "uploaded directly");
+
+    let mut invalid = presign("DELETE");
+    assert!(s3.presign_url(&ctx, &invalid).await.is_err());
+    invalid.method = "GET".to_string();
+    invalid.expires_in_secs = Some(8 * 24 * 3600);
+    assert!(s3.presign_url(&ctx, &invalid).await.is_err());
+
+    s3.remove_objects(
+        &ctx,
+        &RemoveObjectsRequest {
+            container_id: bucket.clone(),
+            objects: vec!["object.1".to_string()],
+        },
+    )
+    .await
+    .expect("remove object");
+    s3.remove_containers(&ctx, &vec![bucket])
+        .await
+        .expect("remove containers");
+}
+
+/// Tests
+/// - copy_object, keeping and replacing metadata
+/// - move_object
+#[tokio::test]
+async fn test_copy_and_move() {
+    let s3 = test_client().await;
+    let ctx = wasmbus_rpc::common::Context::default();
+    let num = rand::random::<u64>();
+    let staging = format!("test.staging.{}", num);
+    let published = format!("test.published.{}", num);
+    for bucket in [&staging, &published] {
+        s3.create_container(&ctx, bucket).await.unwrap();
+    }
+
+    let headers = ObjectHeaders {
+        content_type: Some("text/plain".to_string()),
+        metadata: [("stage".to_string(), "draft".to_string())].into(),
+        ..Default::default()
+    };
+    s3.put_object_with_metadata(
+        &ctx,
+        &PutObjectWithMetadataRequest {
+            chunk: Chunk {
+                bytes: b"release notes".to_vec(),
+                container_id: staging.clone(),
+                is_last: true,
+                object_id: "notes/v1 draft.txt".to_string(),
+                offset: 0,
+            },
+            headers: headers.clone(),
+        },
+    )
+    .await
+    .expect("put object with metadata");
+    let object = |container_id: &str, object_id: &str| ContainerObject {
+        container_id: container_id.to_string(),
+        object_id: object_id.to_string(),
+    };
+
+    s3.copy_object(
+        &ctx,
+        &CopyObjectRequest {
+            source_container_id: staging.clone(),
+            source_object_id: "notes/v1 draft.txt".to_string(),
+            container_id: published.clone(),
+            object_id: "notes/v1.txt".to_string(),
+            headers: None,
+        },
+    )
+    .await
+    .expect("copy object");
+    let meta = s3
+        .get_object_metadata(&ctx, &object(&published, "notes/v1.txt"))
+        .await
+        .expect("metadata of copy");
+    assert_eq!(meta.headers, headers, "metadata copied");
+    assert_eq!(meta.content_length, 13);
+
+    let replaced = ObjectHeaders {
+        content_type: Some("text/markdown".to_string()),
+        metadata: [("stage".to_string(), "final".to_string())].into(),
+        ..Default::default()
+    };
+    s3.move_object(
+        &ctx,
+        &CopyObjectRequest {
+            source_container_id: staging.clone(),
+            source_object_id: "notes/v1 draft.txt".to_string(),
+            container_id: published.clone(),
+            object_id: "notes/v1.md".to_string(),
+            headers: Some(replaced.clone()),
+        },
+    )
+    .await
+    .expect("move object");
+    let meta = s3
+        .get_object_metadata(&ctx, &object(&published, "notes/v1.md"))
+        .await
+        .expect("metadata of moved object");
+    assert_eq!(meta.headers, replaced, "metadata replaced");
+    assert!(!s3
+        .object_exists(&ctx, &object(&staging, "notes/v1 draft.txt"))
+        .await
+        .unwrap());
+
+    s3.remove_objects(
+        &ctx,
+        &RemoveObjectsRequest {
+            container_id: published.clone(),
+            objects: vec!["notes/v1.txt".to_string(), "notes/v1.md".to_string()],
+        },
+    )
+    .await
+    .expect("remove objects");
+    s3.remove_containers(&ctx, &vec![staging, published])
+        .await
+        .expect("remove containers");
+}
+
+/// Tests
+/// - set_object_tags, get_object_tags, delete_object_tags
+/// - get_object_metadata and get_object_lock of an object without a lock
+#[tokio::test]
+async fn test_object_tags() {
+    let s3 = test_client().await;
+    let ctx = wasmbus_rpc::common::Context::default();
+    let bucket = format!("test.tags.{}", rand::random::<u64>());
+    s3.create_container(&ctx, &bucket).await.unwrap();
+    s3.put_object(
+        &ctx,
+        &PutObjectRequest {
+            chunk: Chunk {
+                bytes: b"invoice".to_vec(),
+                container_id: bucket.clone(),
+                is_last: true,
+                object_id: "invoice.txt".to_string(),
+                offset: 0,
+            },
+            content_encoding: None,
+            content_type: None,
+        },
+    )
+    .await
+    .expect("put object");
+    let object = ContainerObject {
+        container_id: bucket.clone(),
+        object_id: "invoice.txt".to_string(),
+    };
+
+    let tags = [
+        ("retention".to_string(), "7y".to_string()),
+        ("department".to_string(), "finance".to_string()),
+    ]
+    .into();
+    s3.set_object_tags(
+        &ctx,
+        &SetObjectTagsRequest {
+            container_id: bucket.clone(),
+            object_id: "invoice.txt".to_string(),
+            tags,
+        },
+    )
+    .await
+    .expect("set tags");
+    let tags = s3.get_object_tags(&ctx, &object).await.expect("get tags");
+    assert_eq!(tags.len(), 2);
+    assert_eq!(tags.get("retention").map(String::as_str), Some("7y"));
+
+    s3.delete_object_tags(&ctx, &object)
+        .await
+        .expect("delete tags");
+    assert!(s3.get_object_tags(&ctx, &object).await.unwrap().is_empty());
+
+    let meta = s3.get_object_metadata(&ctx, &object).await.unwrap();
+    assert_eq!(meta.lock, ObjectLock::default(), "object is not locked");
+    let lock = s3.get_object_lock(&ctx, &object).await.unwrap();
+    assert_eq!(lock, ObjectLock::default(), "object is not locked");
+
+    s3.remove_objects(
+        &ctx,
+        &RemoveObjectsRequest {
+            container_id: bucket.clone(),
+            objects: vec!["invoice.txt".to_string()],
+        },
+    )
+    .await
+    .expect("remove objects");
+    s3.remove_containers(&ctx, &vec![bucket])
+        .await
+        .expect("remove container");
+}
+
+/// Tests
+/// - put_object and get_object with a customer-provided key
+/// - get_object without the key fails
+#[tokio::test]
+async fn test_sse_c_objects() {
+    let s3 = sse_c_client(&[7u8; 32]).await;
+    let ctx = wasmbus_rpc::common::Context::default();
+    let num = rand::random::<u64>();
+    let bucket = format!("test.ssec.{}", num);
+
+    s3.create_container(&ctx, &bucket).await.unwrap();
+    let object_bytes = b"encrypted with my own key".to_vec();
+    s3.put_object(
+        &ctx,
+        &PutObjectRequest {
+            chunk: Chunk {
+                bytes: object_bytes.clone(),
+                container_id: bucket.clone(),
+                is_last: true,
+                object_id: "object.1".to_string(),
+                offset: 0,
+            },
+            content_encoding: None,
+            content_type: None,
+        },
+    )
+    .await
+    .expect("put object");
+
+    let get_request = GetObjectRequest {
+        container_id: bucket.clone(),
+        object_id: "object.1".to_string(),
+        range_start: Some(0),
+        range_end: None,
+    };
+    let resp = s3
+        .get_object(&ctx, &get_request)
+        .await
+        .expect("get object with key");
+    assert_eq!(resp.content_length, object_bytes.len() as u64);
+    assert_eq!(resp.initial_chunk.unwrap().bytes, object_bytes);
+
+    // S3 doesn't store the key, so the object can't be read without it, or with another key
+    let without_key = test_client().await.get_object(&ctx, &get_request).await;
+    assert!(
+        without_key.is_err() || !without_key.unwrap().success,
+        "object should not be readable without the key"
+    );
+    let wrong_key = sse_c_client(&[8u8; 32])
+        .await
+        .get_object(&ctx, &get_request)
+        .await;
+    assert!(
+        wrong_key.is_err() || !wrong_key.unwrap().success,
+        "object should not be readable with another key"
+    );
+
+    s3.remove_objects(
+        &ctx,
+        &RemoveObjectsRequest {
+            container_id: bucket.clone(),
+            objects: vec!["object.1".to_string()],
+        },
+    )
+    .await
+    .expect("remove objects");
+    s3.remove_containers(&ctx, &vec![bucket])
+        .await
+        .expect("remove containers");
+}
+
+/// Tests
+/// - two links scoped to different prefixes of one bucket only see their own objects
+/// - object ids are listed and read without the prefix
+/// - other buckets are rejected, and the shared bucket can't be removed through a scoped link
+#[tokio::test]
+async fn test_scoped_prefixes() {
+    let s3 = test_client().await;
+    let ctx = wasmbus_rpc::common::Context::default();
+    let num = rand::random::<u64>();
+    let bucket = format!("test.scope.{}", num);
+    s3.create_container(&ctx, &bucket).await.unwrap();
+
+    let tenant_a = scoped_client(&bucket, "tenant-a/").await;
+    let tenant_b = scoped_client(&bucket, "tenant-b/").await;
+    for (client, body) in [(&tenant_a, "from a"), (&tenant_b, "from b")] {
+        client
+            .put_object(
+                &ctx,
+                &PutObjectRequest {
+                    chunk: Chunk {
+                        bytes: body.as_bytes().to_vec(),
+                        container_id: bucket.clone(),
+                        is_last: true,
+                        object_id: "same.txt".to_string(),
+                        offset: 0,
+                    },
+                    content_encoding: None,
+                    content_type: None,
+                },
+            )
+            .await
+            .expect("put object");
+    }
+    tenant_a
+        .put_object(
+            &ctx,
+            &PutObjectRequest {
+                chunk: Chunk {
+                    bytes: b"only a".to_vec(),
+                    container_id: bucket.clone(),
+                    is_last: true,
+                    object_id: "dir/own.txt".to_string(),
+                    offset: 0,
+                },
+                content_encoding: None,
+                content_type: None,
+            },
+        )
+        .await
+        .expect("put object");
+
+    let list = |client: &StorageClient| {
+        let req = ListObjectsRequest {
+            container_id: bucket.clone(),
+            continuation: None,
+            end_before: None,
+            end_with: None,
+            max_items: None,
+            start_with: None,
+        };
+        let ctx = ctx.clone();
+        async move {
+            let mut ids = client
+                .list_objects(&ctx, &req)
+                .await
+                .expect("list objects")
+                .objects
+                .into_iter()
+                .map(|o| o.object_id)
+                .collect::<Vec<_>>();
+            ids.sort();
+            ids
+        }
+    };
+    assert_eq!(list(&tenant_a).await, vec!["dir/own.txt", "same.txt"]);
+    assert_eq!(list(&tenant_b).await, vec!["same.txt"]);
+    assert_eq!(
+        list(&s3).await,
+        vec![
+            "tenant-a/dir/own.txt",
+            "tenant-a/same.txt",
+            "tenant-b/same.txt"
+        ]
+    );
+
+    for (client, body) in [(&tenant_a, "from a"), (&tenant_b, "from b")] {
+        let resp = client
+            .get_object(
+                &ctx,
+                &GetObjectRequest {
+                    container_id: bucket.clone(),
+                    object_id: "same.txt".to_string(),
+                    range_start: Some(0),
+                    range_end: None,
+                },
+            )
+            .await
+            .expect("get object");
+        assert_eq!(resp.initial_chunk.unwrap().bytes, body.as_bytes());
+    }
+    assert!(!tenant_b
+        .object_exists(
+            &ctx,
+            &ContainerObject {
+                container_id: bucket.clone(),
+                object_id: "dir/own.txt".to_string(),
+            },
+        )
+        .await
+        .expect("object exists"));
+
+    let other = tenant_a
+        .list_objects(
+            &ctx,
+            &ListObjectsRequest {
+                container_id: format!("test.other.{}", num),
+                continuation: None,
+                end_before: None,
+                end_with: None,
+                max_items: None,
+                start_with: None,
+            },
+        )
+        .await;
+    assert!(other.is_err(), "other buckets should not be allowed");
+    let removed = tenant_a
+        .remove_containers(&ctx, &vec![bucket.clone()])
+        .await
+        .expect("remove containers");
+    assert!(!removed.is_empty() && !removed[0].success);
+    assert!(s3.container_exists(&ctx, &bucket).await.unwrap());
+
+    s3.remove_objects(
+        &ctx,
+        &RemoveObjectsRequest {
+            container_id: bucket.clone(),
+            objects: vec![
+                "tenant-a/dir/own.txt".to_string(),
+                "tenant-a/same.txt".to_string(),
+                "tenant-b/same.txt".to_string(),
+            ],
+        },
+    )
+    .await
+    .expect("remove objects");
+    s3.remove_containers(&ctx, &vec![bucket])
+        .await
+        .expect("remove containers");
+}
+
+/// Tests
+/// - get_object_range returns the object in parts
+/// - the parts of an object replaced after the download started are not returned
+#[tokio::test]
+async fn test_ranged_download_of_replaced_object() {
+    let conf = StorageConfig {
+        endpoint: env::var("AWS_ENDPOINT").ok(),
+        access_key_id: env::var("AWS_ACCESS_KEY_ID").ok(),
+        secret_access_key: env::var("AWS_SECRET_ACCESS_KEY").ok(),
+        download_part_size: Some(4),
+        download_concurrency: Some(2),
+        ..Default::default()
+    };
+    let s3 = StorageClient::new(conf, Default::default()).await;
+    let ctx = wasmbus_rpc::common::Context::default();
+    let num = rand::random::<u64>();
+    let bucket = format!("test.ranged.{}", num);
+    s3.create_container(&ctx, &bucket).await.unwrap();
+
+    let put = |bytes: &[u8]| PutObjectRequest {
+        chunk: Chunk {
+            bytes: bytes.to_vec(),
+            container_id: bucket.clone(),
+            is_last: true,
+            object_id: "object.1".to_string(),
+            offset: 0,
+        },
+        content_encoding: None,
+        content_type: None,
+    };
+    s3.put_object(&ctx, &put(b"abcdefghijklmnopqrstuvwxyz"))
+        .await
+        .expect("put object");
+
+    let mut parts = s3
+        .get_object_range(&bucket, "object.1", 0, 25)
+        .await
+        .expect("get object range");
+    let mut bytes = Vec::new();
+    while let Some(part) = parts.next().await {
+        bytes.extend_from_slice(&part.expect("read part"));
+    }
+    assert_eq!(bytes, b"abcdefghijklmnopqrstuvwxyz");
+
+    // parts are requested while the stream is read, after the object was replaced
+    let mut parts = s3
+        .get_object_range(&bucket, "object.1", 0, 25)
+        .await
+        .expect("get object range");
+    s3.put_object(&ctx, &put(b"ABCDEFGHIJKLMNOPQRSTUVWXYZ"))
+        .await
+        .expect("replace object");
+    let part = parts.next().await.expect("first part");
+    assert!(part.is_err(), "part of the replaced object should fail");
+
+    s3.remove_objects(
+        &ctx,
+        &RemoveObjectsRequest {
+            container_id: bucket.clone(),
+            objects: vec!["object.1".to_string()],
+        },
+    )
+    .await
+    .expect("remove objects");
+    s3.remove_containers(&ctx, &vec![bucket])
+        .await
+        .expect("remove containers");
+}
+
+/// Tests
+/// - create_container with `object_lock` creates a bucket that accepts retention and legal holds
+/// - set_object_retention and set_legal_hold, read back with get_object_lock
+///   and get_object_metadata
+#[tokio::test]
+async fn test_object_lock() {
+    let conf = StorageConfig {
+        endpoint: env::var("AWS_ENDPOINT").ok(),
+        access_key_id: env::var("AWS_ACCESS_KEY_ID").ok(),
+        secret_access_key: env::var("AWS_SECRET_ACCESS_KEY").ok(),
+        object_lock: true,
+        ..Default::default()
+    };
+    let s3 = StorageClient::new(conf.clone(), Default::default()).await;
+    let ctx = wasmbus_rpc::common::Context::default();
+    let bucket = format!("test.lock.{}", rand::random::<u64>());
+    s3.create_container(&ctx, &bucket).await.unwrap();
+    s3.put_object(
+        &ctx,
+        &PutObjectRequest {
+            chunk: Chunk {
+                bytes: b"contract".to_vec(),
+                container_id: bucket.clone(),
+                is_last: true,
+                object_id: "contract.pdf".to_string(),
+                offset: 0,
+            },
+            content_encoding: None,
+            content_type: None,
+        },
+    )
+    .await
+    .expect("put object");
+    let object = ContainerObject {
+        container_id: bucket.clone(),
+        object_id: "contract.pdf".to_string(),
+    };
+
+    let retain_until = Timestamp::new(Timestamp::now().sec + 3600, 0).unwrap();
+    s3.set_object_retention(
+        &ctx,
+        &SetObjectRetentionRequest {
+            container_id: bucket.clone(),
+            object_id: "contract.pdf".to_string(),
+            mode: RetentionMode::Governance,
+            retain_until,
+            bypass_governance: false,
+        },
+    )
+    .await
+    .expect("set retention");
+    let set_legal_hold = |legal_hold| SetLegalHoldRequest {
+        container_id: bucket.clone(),
+        object_id: "contract.pdf".to_string(),
+        legal_hold,
+    };
+    s3.set_legal_hold(&ctx, &set_legal_hold(true))
+        .await
+        .expect("place legal hold");
+
+    let locked = ObjectLock {
+        retention_mode: Some(RetentionMode::Governance),
+        retain_until: Some(retain_until),
+        legal_hold: true,
+    };
+    let lock = s3.get_object_lock(&ctx, &object).await.unwrap();
+    assert_eq!(lock, locked);
+    let meta = s3.get_object_metadata(&ctx, &object).await.unwrap();
+    assert_eq!(meta.lock, locked);
+    assert_eq!(meta.content_length, 8);
+
+    s3.set_legal_hold(&ctx, &set_legal_hold(false))
+        .await
+        .expect("remove legal hold");
+    let lock = s3.get_object_lock(&ctx, &object).await.unwrap();
+    assert!(!lock.legal_hold);
+    assert_eq!(lock.retention_mode, Some(RetentionMode::Governance));
+
+    // the locked version can only be removed by bypassing the governance retention
+    let aws = aws_sdk_s3::Client::from_conf(aws_sdk_s3::Config::from(&conf.configure_aws().await));
+    let versions = aws
+        .list_object_versions()
+        .bucket(&bucket)
+        .send()
+        .await
+        .expect("list object versions");
+    for version in versions.versions.unwrap_or_default() {
+        aws.delete_object()
+            .bucket(&bucket)
+            .set_key(version.key)
+            .set_version_id(version.version_id)
+            .bypass_governance_retention(true)
+            .send()
+            .await
+            .expect("remove locked version");
+    }
+    s3.remove_containers(&ctx, &vec![bucket])
+        .await
+        .expect("remove container");
+}